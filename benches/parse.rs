@@ -0,0 +1,46 @@
+// parser throughput on a simulated log
+//   cargo bench --bench parse
+use mysensors_logparser::encoder::render_line;
+use mysensors_logparser::simulator::{SimConfig, Simulator, Topology};
+use mysensors_logparser::{parse_log_line, LogParser};
+use std::time::{Duration, Instant};
+
+fn report(name: &str, lines: usize, elapsed: Duration) {
+    println!(
+        "{:20} {:8} lines {:8.1} ms {:10.0} lines/s",
+        name,
+        lines,
+        elapsed.as_secs_f64() * 1000.0,
+        lines as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    let config = SimConfig {
+        nodes: 50,
+        battery_nodes: 20,
+        topology: Topology::Tree(4),
+        duration: chrono::Duration::hours(24),
+        restart_interval: Some(chrono::Duration::hours(6)),
+        ..Default::default()
+    };
+    let start = Instant::now();
+    let raw: Vec<String> = Simulator::new(config).collect();
+    report("simulate", raw.len(), start.elapsed());
+
+    let start = Instant::now();
+    let lines: Vec<_> = raw.iter().map(|l| parse_log_line(l)).collect();
+    report("parse_log_line", raw.len(), start.elapsed());
+
+    let start = Instant::now();
+    let mut parser = LogParser::new();
+    for l in &raw {
+        parser.parse_line(l);
+    }
+    report("LogParser", raw.len(), start.elapsed());
+
+    let start = Instant::now();
+    let rendered: usize = lines.iter().map(|l| render_line(l).len()).sum();
+    report("render_line", raw.len(), start.elapsed());
+    assert!(rendered > 0);
+}
@@ -0,0 +1,662 @@
+// alert rules evaluated over the lines of a log as they are read
+//
+// rules come from a TOML file, each with a condition, the actions to take
+// when it fires and how often it may fire:
+//
+//   [[rule]]
+//   name = "transport failure"
+//   when = "state"
+//   state = "FAIL"
+//   actions = [{ webhook = "http://hooks.local/mysensors" }]
+//
+//   [[rule]]
+//   name = "node 12 loses messages"
+//   when = "nack_rate"
+//   node = 12
+//   above = 20          # percent of the sends in the window
+//   window_mins = 10
+//   min_interval_mins = 60
+//   actions = [{ command = "notify-send \"$ALERT_MESSAGE\"" }, { file = "alerts.log" }]
+//
+// conditions are
+// - match: a line matching a filter expression, see filter.rs
+// - state: the transport state machine entering a state, e.g. FAIL
+// - restart: the gateway restarting, the first start in the log not counted
+// - signing_failed: a message failing to be signed or verified
+// - nack_rate: the share of sends to a node (any node if not given) the
+//   next hop did not ack, over a sliding window
+//
+// a rule fires at most once per min_interval_mins (15 by default); alerts
+// held back meanwhile are counted and mentioned by the next one. a webhook
+// gets the alert POSTed as JSON, a command is run by sh with the alert in
+// ALERT_RULE, ALERT_MESSAGE, ALERT_TIME and ALERT_LINE and as JSON on stdin,
+// a file gets a line appended. actions run while the log is read, so a
+// webhook gets http::TIMEOUT to answer and a command COMMAND_TIMEOUT to
+// finish before it is given up on (and killed). rule names must be unique
+use crate::filter::Filter;
+use crate::http::{post, HttpUrl};
+use crate::protocol::{MsgKind, XportState};
+use crate::session::SessionTracker;
+use crate::{BoxError, LogLine, MsgFields};
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+pub const COMMAND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "when", rename_all = "snake_case")]
+pub enum Condition {
+    Match {
+        filter: String,
+    },
+    State {
+        state: String, //TSM code, e.g. FAIL or READY
+    },
+    Restart,
+    SigningFailed,
+    NackRate {
+        node: Option<u8>,
+        above: f64, //percent
+        #[serde(default = "default_window_mins")]
+        window_mins: i64,
+        #[serde(default = "default_min_sends")]
+        min_sends: usize, //fewer sends in the window are not judged
+    },
+}
+
+fn default_window_mins() -> i64 {
+    10
+}
+
+fn default_min_sends() -> usize {
+    5
+}
+
+fn default_min_interval_mins() -> i64 {
+    15
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Webhook(String), //URL the alert is POSTed to
+    Command(String), //run by sh -c
+    File(String),    //appended to
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    #[serde(flatten)]
+    pub condition: Condition,
+    #[serde(default)]
+    pub actions: Vec<Action>,
+    #[serde(default = "default_min_interval_mins")]
+    pub min_interval_mins: i64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct AlertConfig {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+impl AlertConfig {
+    pub fn from_toml(s: &str) -> Result<AlertConfig, BoxError> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn load(path: &str) -> Result<AlertConfig, BoxError> {
+        let s = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        AlertConfig::from_toml(&s).map_err(|e| format!("{}: {}", path, e).into())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    pub rule: String,
+    pub datetime: DateTime<Local>,
+    pub message: String,
+    pub line: String, //the line that fired the rule
+}
+
+impl Alert {
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "rule": self.rule,
+            "time": self.datetime.to_rfc3339(),
+            "message": self.message,
+            "line": self.line,
+        })
+        .to_string()
+    }
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {}",
+            self.datetime.format("%F %T"),
+            self.rule,
+            self.message
+        )
+    }
+}
+
+// a condition made ready for evaluation
+#[derive(Debug)]
+enum Check {
+    Match(Filter),
+    State(XportState),
+    Restart,
+    SigningFailed,
+    NackRate {
+        node: Option<u8>,
+        above: f64,
+        window: Duration,
+        min_sends: usize,
+    },
+}
+
+#[derive(Debug)]
+struct RuleState {
+    rule: Rule,
+    check: Check,
+    fired: Option<DateTime<Local>>,
+    suppressed: usize, //alerts held back since the last one
+}
+
+#[derive(Debug)]
+pub struct AlertEngine {
+    rules: Vec<RuleState>,
+    state: Option<XportState>,
+    sessions: SessionTracker,
+    starts: usize,
+    sends: BTreeMap<u8, VecDeque<(DateTime<Local>, bool)>>, //per destination, ok or not
+}
+
+fn signing_failed(line: &LogLine) -> bool {
+    let msg = line.msg.to_uppercase();
+    (msg.contains("SIGN") || msg.starts_with("SGN:")) && msg.contains("FAIL")
+}
+
+impl AlertEngine {
+    pub fn new(config: AlertConfig) -> Result<AlertEngine, BoxError> {
+        let mut rules = Vec::new();
+        for rule in config.rules {
+            // alerts find the actions to take by the name of their rule
+            if rules.iter().any(|r: &RuleState| r.rule.name == rule.name) {
+                return Err(format!("rule {}: defined twice", rule.name).into());
+            }
+            let check = match &rule.condition {
+                Condition::Match { filter } => Check::Match(
+                    Filter::parse(filter).map_err(|e| format!("rule {}: {}", rule.name, e))?,
+                ),
+                Condition::State { state } => {
+                    Check::State(XportState::from_code(&state.to_uppercase()).ok_or_else(|| {
+                        format!(
+                            "rule {}: unknown state {}, one of INIT, FPAR, ID, UPL, READY, FAIL",
+                            rule.name, state
+                        )
+                    })?)
+                }
+                Condition::Restart => Check::Restart,
+                Condition::SigningFailed => Check::SigningFailed,
+                Condition::NackRate {
+                    node,
+                    above,
+                    window_mins,
+                    min_sends,
+                } => Check::NackRate {
+                    node: *node,
+                    above: *above,
+                    window: Duration::minutes(*window_mins),
+                    min_sends: *min_sends,
+                },
+            };
+            for action in &rule.actions {
+                if let Action::Webhook(url) = action {
+                    HttpUrl::parse(url).map_err(|e| format!("rule {}: {}", rule.name, e))?;
+                }
+            }
+            rules.push(RuleState {
+                rule,
+                check,
+                fired: None,
+                suppressed: 0,
+            });
+        }
+        Ok(AlertEngine {
+            rules,
+            state: None,
+            sessions: SessionTracker::new(),
+            starts: 0,
+            sends: BTreeMap::new(),
+        })
+    }
+
+    // the alerts a line raises; lines without a time (serial gateway logs)
+    // are taken to happen now
+    pub fn feed(&mut self, line: &LogLine) -> Vec<Alert> {
+        let now = line.datetime.unwrap_or_else(Local::now);
+
+        let previous = self.state;
+        if let Some(state) = line.fields.as_ref().and_then(|f| f.xport_state()) {
+            self.state = Some(state);
+        }
+        let restart = self.sessions.feed(line) && {
+            self.starts += 1;
+            self.starts > 1
+        };
+        // the destination of a send, with its NACKs and sends in the window
+        let mut send = None;
+        if let Some(MsgFields::Msg(m)) = &line.fields {
+            if let (MsgKind::Send, Some(ok)) = (m.kind, m.send_ok) {
+                let sends = self.sends.entry(m.destination).or_default();
+                sends.push_back((now, ok));
+                send = Some(m.destination);
+            }
+        }
+
+        let mut alerts = Vec::new();
+        for rule in self.rules.iter_mut() {
+            let message = match &rule.check {
+                Check::Match(filter) => filter.matches(line).then(|| line.to_string()),
+                Check::State(state) => (self.state == Some(*state) && previous != self.state)
+                    .then(|| format!("transport entered {}", state)),
+                Check::Restart => restart.then(|| "gateway restarted".to_string()),
+                Check::SigningFailed => signing_failed(line).then(|| line.to_string()),
+                Check::NackRate {
+                    node,
+                    above,
+                    window,
+                    min_sends,
+                } => match send {
+                    Some(destination) if node.is_none_or(|n| n == destination) => {
+                        let sends = &self.sends[&destination];
+                        let recent: Vec<bool> = sends
+                            .iter()
+                            .filter(|(t, _)| now - *t < *window)
+                            .map(|(_, ok)| *ok)
+                            .collect();
+                        let nacks = recent.iter().filter(|ok| !**ok).count();
+                        let rate = 100.0 * nacks as f64 / recent.len() as f64;
+                        (recent.len() >= *min_sends && rate > *above).then(|| {
+                            format!(
+                                "node {} NACK rate {:.0}% ({} of {} sends) in {} min",
+                                destination,
+                                rate,
+                                nacks,
+                                recent.len(),
+                                window.num_minutes()
+                            )
+                        })
+                    }
+                    _ => None,
+                },
+            };
+            let Some(mut message) = message else {
+                continue;
+            };
+            let interval = Duration::minutes(rule.rule.min_interval_mins);
+            if rule.fired.is_some_and(|fired| now - fired < interval) {
+                rule.suppressed += 1;
+                continue;
+            }
+            if rule.suppressed > 0 {
+                message.push_str(&format!(" ({} more suppressed)", rule.suppressed));
+            }
+            rule.fired = Some(now);
+            rule.suppressed = 0;
+            alerts.push(Alert {
+                rule: rule.rule.name.clone(),
+                datetime: now,
+                message,
+                line: line.to_string(),
+            });
+        }
+
+        // forget sends no window looks at anymore
+        let longest = self
+            .rules
+            .iter()
+            .filter_map(|r| match r.check {
+                Check::NackRate { window, .. } => Some(window),
+                _ => None,
+            })
+            .max()
+            .unwrap_or_else(Duration::zero);
+        if let Some(destination) = send {
+            let sends = self.sends.get_mut(&destination).unwrap();
+            while sends.front().is_some_and(|(t, _)| now - *t >= longest) {
+                sends.pop_front();
+            }
+        }
+        alerts
+    }
+
+    // take the actions of the rule that raised the alert; all are tried, the
+    // errors of those that failed are returned together
+    pub fn dispatch(&self, alert: &Alert) -> Result<(), BoxError> {
+        let Some(rule) = self.rules.iter().find(|r| r.rule.name == alert.rule) else {
+            return Ok(());
+        };
+        let errors: Vec<String> = rule
+            .rule
+            .actions
+            .iter()
+            .filter_map(|action| run(action, alert).err().map(|e| e.to_string()))
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; ").into())
+        }
+    }
+}
+
+fn run(action: &Action, alert: &Alert) -> Result<(), BoxError> {
+    match action {
+        Action::Webhook(url) => post(
+            &HttpUrl::parse(url)?,
+            "application/json",
+            &[],
+            &alert.to_json(),
+        ),
+        Action::Command(command) => run_command(command, alert, COMMAND_TIMEOUT),
+        Action::File(path) => {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("{}: {}", path, e))?;
+            writeln!(file, "{}", alert)?;
+            Ok(())
+        }
+    }
+}
+
+fn run_command(command: &str, alert: &Alert, timeout: std::time::Duration) -> Result<(), BoxError> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("ALERT_RULE", &alert.rule)
+        .env("ALERT_MESSAGE", &alert.message)
+        .env("ALERT_TIME", alert.datetime.to_rfc3339())
+        .env("ALERT_LINE", &alert.line)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("{}: {}", command, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // a command not reading its input is fine
+        let _ = stdin.write_all(alert.to_json().as_bytes());
+    }
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("{}: killed after {}s", command, timeout.as_secs_f64()).into());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    };
+    if !status.success() {
+        return Err(format!("{}: {}", command, status).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+
+    fn load(toml: &str) -> AlertEngine {
+        AlertEngine::new(AlertConfig::from_toml(toml).unwrap()).unwrap()
+    }
+
+    fn feed(engine: &mut AlertEngine, lines: &[impl AsRef<str>]) -> Vec<Alert> {
+        lines
+            .iter()
+            .flat_map(|l| engine.feed(&parse_log_line(l.as_ref())))
+            .collect()
+    }
+
+    #[test]
+    fn test_config() {
+        let config = AlertConfig::from_toml(
+            r#"
+            [[rule]]
+            name = "down"
+            when = "state"
+            state = "FAIL"
+            actions = [{ webhook = "http://localhost:8080/hook" }, { file = "alerts.log" }]
+
+            [[rule]]
+            name = "nacks"
+            when = "nack_rate"
+            node = 12
+            above = 20
+            min_interval_mins = 60
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.rules.len(), 2);
+        assert_eq!(
+            config.rules[0].condition,
+            Condition::State {
+                state: "FAIL".to_string()
+            }
+        );
+        assert_eq!(
+            config.rules[0].actions,
+            vec![
+                Action::Webhook("http://localhost:8080/hook".to_string()),
+                Action::File("alerts.log".to_string())
+            ]
+        );
+        assert_eq!(config.rules[0].min_interval_mins, 15);
+        assert_eq!(
+            config.rules[1].condition,
+            Condition::NackRate {
+                node: Some(12),
+                above: 20.0,
+                window_mins: 10,
+                min_sends: 5
+            }
+        );
+        assert_eq!(config.rules[1].min_interval_mins, 60);
+
+        assert!(AlertConfig::from_toml("[[rule]]\nname = \"x\"\nwhen = \"bogus\"").is_err());
+        let bad_state =
+            AlertConfig::from_toml("[[rule]]\nname = \"x\"\nwhen = \"state\"\nstate = \"DOWN\"");
+        assert!(AlertEngine::new(bad_state.unwrap()).is_err());
+        let bad_url = AlertConfig::from_toml(
+            "[[rule]]\nname = \"x\"\nwhen = \"restart\"\nactions = [{ webhook = \"https://x\" }]",
+        );
+        assert!(AlertEngine::new(bad_url.unwrap()).is_err());
+        let twice = AlertConfig::from_toml(
+            "[[rule]]\nname = \"x\"\nwhen = \"restart\"\n[[rule]]\nname = \"x\"\nwhen = \"signing_failed\"",
+        );
+        assert!(AlertEngine::new(twice.unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut engine = load(
+            r#"
+            [[rule]]
+            name = "down"
+            when = "state"
+            state = "fail"
+            [[rule]]
+            name = "restart"
+            when = "restart"
+            [[rule]]
+            name = "signing"
+            when = "signing_failed"
+            [[rule]]
+            name = "node 3"
+            when = "match"
+            filter = "node=3 and cmd=C_SET"
+            "#,
+        );
+        let alerts = feed(
+            &mut engine,
+            &[
+                "Oct 18 13:00:00 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2",
+                "Oct 18 13:00:00 DEBUG TSM:READY:ID=0,PAR=0,DIS=0",
+                "Oct 18 13:00:01 DEBUG TSF:MSG:READ,3-3-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5",
+                "Oct 18 13:00:02 DEBUG !TSF:MSG:SIGN VERIFY FAIL",
+                "Oct 18 13:00:03 DEBUG !TSM:FAIL:CNT=1",
+                "Oct 18 13:00:04 DEBUG !TSM:FAIL:CNT=2",
+                "Oct 18 13:01:00 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2",
+            ],
+        );
+        let rules: Vec<&str> = alerts.iter().map(|a| a.rule.as_str()).collect();
+        assert_eq!(rules, vec!["node 3", "signing", "down", "restart"]);
+        assert_eq!(alerts[2].message, "transport entered FAIL");
+        assert!(alerts[2].line.contains("Failure counter (1)"));
+    }
+
+    #[test]
+    fn test_nack_rate_and_rate_limit() {
+        let mut engine = load(
+            r#"
+            [[rule]]
+            name = "nacks"
+            when = "nack_rate"
+            node = 12
+            above = 20
+            min_interval_mins = 30
+            "#,
+        );
+        let send = |minute: u32, ok: bool| {
+            format!(
+                "Oct 18 13:{:02}:00 DEBUG {}TSF:MSG:SEND,0-0-12-12,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=0,st={}:1",
+                minute,
+                if ok { "" } else { "!" },
+                if ok { "OK" } else { "NACK" }
+            )
+        };
+        // 1 of 5 is not above 20%, and node 7 is not watched
+        let mut lines: Vec<String> = (0..4).map(|m| send(m, true)).collect();
+        lines.push(send(4, false));
+        lines.push(send(5, false).replace("0-0-12-12", "0-0-7-7"));
+        assert!(feed(&mut engine, &lines).is_empty());
+
+        let alerts = feed(&mut engine, &[&send(6, false)]);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(
+            alerts[0].message,
+            "node 12 NACK rate 33% (2 of 6 sends) in 10 min"
+        );
+        // held back for 30 minutes, then counted
+        assert!(feed(&mut engine, &[&send(7, false), &send(8, false)]).is_empty());
+        let lines: Vec<String> = (36..41).map(|m| send(m, false)).collect();
+        let alerts = feed(&mut engine, &lines);
+        assert_eq!(alerts.len(), 1);
+        assert!(alerts[0].message.ends_with("(2 more suppressed)"));
+        assert!(alerts[0].message.contains("(5 of 5 sends)"));
+    }
+
+    #[test]
+    fn test_actions() {
+        let dir = std::env::temp_dir().join(format!("mysensors-alerts-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("alerts.log");
+        let out = dir.join("command.out");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if let Some(value) = header.strip_prefix("Content-Length: ") {
+                    length = value.trim().parse().unwrap();
+                }
+                request.push_str(&header);
+                if header == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let mut stream = stream;
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+            (request, String::from_utf8(body).unwrap())
+        });
+
+        let mut engine = load(&format!(
+            r#"
+            [[rule]]
+            name = "restart"
+            when = "restart"
+            actions = [
+                {{ webhook = "http://{}/hook" }},
+                {{ command = "cat > '{}'; echo \"$ALERT_RULE: $ALERT_MESSAGE\" >> '{}'" }},
+                {{ file = '{}' }},
+            ]
+            "#,
+            addr,
+            out.display(),
+            out.display(),
+            log.display()
+        ));
+        let alerts = feed(
+            &mut engine,
+            &[
+                "Oct 18 13:00:00 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2",
+                "Oct 18 13:05:00 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2",
+            ],
+        );
+        assert_eq!(alerts.len(), 1);
+        engine.dispatch(&alerts[0]).unwrap();
+
+        let (request, body) = server.join().unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Type: application/json\r\n"));
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["rule"], "restart");
+        assert_eq!(json["message"], "gateway restarted");
+
+        let output = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(output, format!("{}restart: gateway restarted\n", body));
+        let logged = std::fs::read_to_string(&log).unwrap();
+        assert!(logged.ends_with(" restart: gateway restarted\n"));
+
+        // a failing action does not keep the others from running
+        let engine = load(&format!(
+            "[[rule]]\nname = \"r\"\nwhen = \"restart\"\nactions = [{{ command = \"exit 3\" }}, {{ file = '{}' }}]",
+            log.display()
+        ));
+        let alert = Alert {
+            rule: "r".to_string(),
+            ..alerts[0].clone()
+        };
+        assert!(engine.dispatch(&alert).is_err());
+        assert_eq!(std::fs::read_to_string(&log).unwrap().lines().count(), 2);
+
+        // a command that hangs is killed
+        let start = Instant::now();
+        let result = run_command("sleep 10", &alert, std::time::Duration::from_millis(200));
+        assert!(result.unwrap_err().to_string().contains("killed"));
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
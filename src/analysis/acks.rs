@@ -0,0 +1,419 @@
+// pairs sends that asked for an ack with the echo coming back from the
+// destination and measures the round trip
+//
+// a send requesting an ack is logged as
+//   TSF:MSG:SEND,0-0-12-12,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=0,st=OK:1
+// and the destination echoes it back with sender and destination swapped,
+// logged as the READ of the echo followed by TSF:MSG:ACK (ECHO since 3.x)
+//   TSF:MSG:READ,12-12-0,s=2,c=1,t=2,pt=0,l=1,sg=0:1
+//   TSF:MSG:ACK
+//
+// the log does not show whether a send asked for an ack, so only sends of a
+// (sender, destination, sensor, type) that has been acked at least once are
+// expected to be acked; those without an echo within the timeout are lost
+//
+// syslog times are in whole seconds, so latencies from syslog are multiples
+// of a second; serial gateway logs have the milliseconds of each debug message
+//
+// to run on a followed log for good, the latencies are a sample of the latest
+// round trips of each node and timed out sends of keys that are not acked
+// within an hour are forgotten
+use super::serial_debug;
+use crate::dictionary::dictionary;
+use crate::protocol::{MsgKind, TransportMsg, Version};
+use crate::session::SessionTracker;
+use crate::{LogLine, MsgFields};
+use chrono::{DateTime, Duration, Local};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+
+// (sender, destination, sensor, type) of a send
+pub type SendKey = (u8, u8, u8, u8);
+
+const LATENCY_SAMPLE: usize = 1000; //latest round trips kept per node
+const LOST_KEPT: usize = 1000; //latest lost sends kept for lost()
+const UNMATCHED_KEPT: usize = 10000;
+const UNMATCHED_AGE: i64 = 3_600_000; //ms a timed out send waits for its key to be acked
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SentMsg {
+    pub datetime: Option<DateTime<Local>>, //None for serial gateway logs
+    pub millis: i64,                       //time in ms, see AckCorrelator::feed
+    pub key: SendKey,
+    pub send_ok: bool, //st=OK, a NACK send can still be echoed
+    pub payload: String,
+}
+
+// round trip times of one destination node, in ms
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeAcks {
+    pub acked: usize,
+    pub lost: usize,
+    pub latencies: Vec<i64>, //the latest LATENCY_SAMPLE, oldest overwritten first
+}
+
+impl NodeAcks {
+    fn add_latency(&mut self, latency: i64) {
+        if self.latencies.len() < LATENCY_SAMPLE {
+            self.latencies.push(latency);
+        } else {
+            self.latencies[(self.acked - 1) % LATENCY_SAMPLE] = latency;
+        }
+    }
+
+    // latency below which the given fraction (0.0 to 1.0) of the echoes came back
+    pub fn percentile(&self, fraction: f64) -> Option<i64> {
+        let mut sorted = self.latencies.clone();
+        sorted.sort_unstable();
+        let last = sorted.len().checked_sub(1)?;
+        let index = (fraction.clamp(0.0, 1.0) * last as f64).round() as usize;
+        Some(sorted[index])
+    }
+
+    pub fn min(&self) -> Option<i64> {
+        self.latencies.iter().min().copied()
+    }
+
+    pub fn median(&self) -> Option<i64> {
+        self.percentile(0.5)
+    }
+
+    pub fn max(&self) -> Option<i64> {
+        self.latencies.iter().max().copied()
+    }
+
+    // fraction of the expected echoes that never came
+    pub fn loss_rate(&self) -> f64 {
+        match self.acked + self.lost {
+            0 => 0.0,
+            expected => self.lost as f64 / expected as f64,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AckCorrelator {
+    timeout: i64, //ms
+    sessions: SessionTracker,
+    echo_messages: Vec<String>, //what TSF:MSG:ACK / ECHO are logged as
+    last_millis: Option<i64>,
+    last_read: Option<(TransportMsg, i64)>, //READ that an ACK line would belong to
+    pending: HashMap<SendKey, VecDeque<SentMsg>>,
+    unmatched: VecDeque<SentMsg>, //timed out, lost if their key is acked later
+    lost: VecDeque<SentMsg>,
+    acked_keys: HashSet<SendKey>,
+    nodes: BTreeMap<u8, NodeAcks>,
+}
+
+impl Default for AckCorrelator {
+    fn default() -> Self {
+        AckCorrelator::with_timeout(Duration::seconds(10))
+    }
+}
+
+impl AckCorrelator {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // sends not echoed within timeout count as lost
+    pub fn with_timeout(timeout: Duration) -> Self {
+        let d = dictionary();
+        let mut echo_messages = vec!["ACK".to_string(), "ECHO".to_string()];
+        for d in [d.clone(), Arc::new(d.for_version(&Version::new(3, 0, 0)))] {
+            if let Some(tsf) = d.system("TSF") {
+                for code in ["ACK", "ECHO"] {
+                    echo_messages.extend(tsf.message("MSG", code).map(|m| m.to_string()));
+                }
+            }
+        }
+        AckCorrelator {
+            timeout: timeout.num_milliseconds(),
+            sessions: SessionTracker::new(),
+            echo_messages,
+            last_millis: None,
+            last_read: None,
+            pending: HashMap::new(),
+            unmatched: VecDeque::new(),
+            lost: VecDeque::new(),
+            acked_keys: HashSet::new(),
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    // lines are timed by their syslog time or, for debug messages of a serial
+    // gateway, by the milliseconds since the gateway started
+    pub fn feed(&mut self, line: &LogLine) {
+        let (millis, datetime, line) = match serial_debug(line) {
+            Some((millis, inner)) => (millis, None, inner),
+            None => match line.datetime {
+                Some(dt) => (dt.timestamp_millis(), Some(dt), line.clone()),
+                None => return,
+            },
+        };
+        // a restart starts the clock of a serial gateway over
+        let restarted = self.sessions.feed(&line) || self.last_millis.is_some_and(|l| millis < l);
+        if restarted {
+            self.expire(i64::MAX);
+        }
+        self.last_millis = Some(millis);
+        self.expire(millis);
+
+        match &line.fields {
+            Some(MsgFields::Msg(m)) if m.kind == MsgKind::Send => {
+                let send = SentMsg {
+                    datetime,
+                    millis,
+                    key: (m.sender, m.destination, m.sensor, m.msg_type),
+                    send_ok: m.send_ok.unwrap_or(true),
+                    payload: m.payload.clone(),
+                };
+                self.pending.entry(send.key).or_default().push_back(send);
+                self.last_read = None;
+            }
+            Some(MsgFields::Msg(m)) if m.kind == MsgKind::Read => {
+                self.last_read = Some((m.clone(), millis));
+            }
+            None if self.is_echo(&line) => {
+                if let Some((echo, at)) = self.last_read.take() {
+                    self.echoed(&echo, at);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn is_echo(&self, line: &LogLine) -> bool {
+        let msg = match (&line.system, &line.subsystem) {
+            (Some(system), Some(subsystem)) => line
+                .msg
+                .strip_prefix(&format!("{}:{}:", system, subsystem))
+                .unwrap_or(&line.msg),
+            _ => &line.msg,
+        };
+        line.fields.is_none() && self.echo_messages.iter().any(|m| m == msg)
+    }
+
+    // match an echo to the oldest pending send it answers
+    fn echoed(&mut self, echo: &TransportMsg, at: i64) {
+        let key = (echo.destination, echo.sender, echo.sensor, echo.msg_type);
+        let send = match self.pending.get_mut(&key).and_then(|p| p.pop_front()) {
+            Some(send) => send,
+            None => return, //send not in the log, e.g. before the start of the file
+        };
+        let node = self.nodes.entry(key.1).or_default();
+        node.acked += 1;
+        node.add_latency(at - send.millis);
+        if self.acked_keys.insert(key) {
+            // the key is acked after all, its timed out sends are lost
+            let (lost, unmatched) = self.unmatched.drain(..).partition(|s| s.key == key);
+            self.unmatched = unmatched;
+            for send in lost {
+                self.add_lost(send);
+            }
+        }
+    }
+
+    fn add_lost(&mut self, send: SentMsg) {
+        self.nodes.entry(send.key.1).or_default().lost += 1;
+        if self.lost.len() == LOST_KEPT {
+            self.lost.pop_front();
+        }
+        self.lost.push_back(send);
+    }
+
+    // move sends older than the timeout out of pending
+    fn expire(&mut self, now: i64) {
+        let timeout = self.timeout;
+        let mut expired = Vec::new();
+        for sends in self.pending.values_mut() {
+            while sends
+                .front()
+                .is_some_and(|s| now == i64::MAX || now - s.millis > timeout)
+            {
+                expired.extend(sends.pop_front());
+            }
+        }
+        self.pending.retain(|_, sends| !sends.is_empty());
+        expired.sort_by_key(|s| s.millis);
+        for send in expired {
+            if self.acked_keys.contains(&send.key) {
+                self.add_lost(send);
+            } else {
+                self.unmatched.push_back(send);
+            }
+        }
+        // keys never acked are most likely sent without asking for an ack
+        while self.unmatched.len() > UNMATCHED_KEPT
+            || self
+                .unmatched
+                .front()
+                .is_some_and(|s| now != i64::MAX && now - s.millis > UNMATCHED_AGE)
+        {
+            self.unmatched.pop_front();
+        }
+    }
+
+    // the latest sends that should have been acked but were not, in the order
+    // they were found lost; sends still within the timeout are not included
+    pub fn lost(&self) -> Vec<&SentMsg> {
+        self.lost.iter().collect()
+    }
+
+    // echo statistics per destination node
+    pub fn nodes(&self) -> BTreeMap<u8, NodeAcks> {
+        self.nodes.clone()
+    }
+
+    // sends waiting for their echo
+    pub fn pending(&self) -> usize {
+        self.pending.values().map(|p| p.len()).sum()
+    }
+}
+
+impl fmt::Display for AckCorrelator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |v: Option<i64>| v.map_or("-".to_string(), |v| v.to_string());
+        writeln!(
+            f,
+            "{:>4} {:>6} {:>6} {:>6} {:>8} {:>8} {:>8}",
+            "node", "acked", "lost", "loss%", "min ms", "med ms", "max ms"
+        )?;
+        for (id, node) in self.nodes() {
+            writeln!(
+                f,
+                "{:>4} {:>6} {:>6} {:>6.1} {:>8} {:>8} {:>8}",
+                id,
+                node.acked,
+                node.lost,
+                100.0 * node.loss_rate(),
+                ms(node.min()),
+                ms(node.median()),
+                ms(node.max())
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+    use crate::simulator::{OutputFormat, SimConfig, Simulator};
+
+    #[test]
+    fn test_syslog() {
+        let mut acks = AckCorrelator::new();
+        for line in [
+            "Oct 18 13:00:00 DEBUG TSF:MSG:SEND,0-0-12-12,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=0,st=OK:1",
+            "Oct 18 13:00:01 DEBUG TSF:MSG:READ,12-12-0,s=2,c=1,t=2,pt=0,l=1,sg=0:1",
+            "Oct 18 13:00:01 DEBUG TSF:MSG:ACK",
+            // a value the node reports on its own is not an echo
+            "Oct 18 13:00:05 DEBUG TSF:MSG:SEND,0-0-12-12,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=0,st=OK:0",
+            "Oct 18 13:00:06 DEBUG TSF:MSG:READ,12-12-0,s=2,c=1,t=2,pt=0,l=1,sg=0:0",
+            "Oct 18 13:01:00 DEBUG !TSF:MSG:SEND,0-0-12-12,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=3,st=NACK:1",
+            "Oct 18 13:01:00 DEBUG TSF:MSG:READ,12-12-0,s=2,c=1,t=2,pt=0,l=1,sg=0:1",
+            "Oct 18 13:01:00 DEBUG TSF:MSG:ACK",
+            // never acked, so not expected to be
+            "Oct 18 13:01:10 DEBUG TSF:MSG:SEND,0-0-7-7,s=1,c=1,t=2,pt=0,l=1,sg=0,ft=0,st=OK:1",
+            "Oct 18 13:05:00 DEBUG TSM:READY:ID=0,PAR=0,DIS=0",
+        ] {
+            acks.feed(&parse_log_line(line));
+        }
+
+        let lost = acks.lost();
+        assert_eq!(lost.len(), 1);
+        assert_eq!(
+            (lost[0].key, lost[0].payload.as_str()),
+            ((0, 12, 2, 2), "0")
+        );
+        let nodes = acks.nodes();
+        assert_eq!(nodes.keys().collect::<Vec<_>>(), vec![&12]);
+        // the NACK send was matched first, the timed out one is lost
+        assert_eq!(nodes[&12].latencies, vec![1000, 0]);
+        assert_eq!((nodes[&12].acked, nodes[&12].lost), (2, 1));
+        assert_eq!(nodes[&12].median(), Some(1000));
+        assert_eq!(acks.pending(), 0);
+    }
+
+    #[test]
+    fn test_serial() {
+        let config = SimConfig {
+            format: OutputFormat::Serial,
+            failure_probability: 0.1,
+            ..Default::default()
+        };
+        let mut acks = AckCorrelator::new();
+        for line in Simulator::new(config) {
+            acks.feed(&parse_log_line(&line));
+        }
+        let nodes = acks.nodes();
+        assert!(!nodes.is_empty());
+        let acked: usize = nodes.values().map(|n| n.acked).sum();
+        let lost: usize = nodes.values().map(|n| n.lost).sum();
+        assert!(acked > 0 && lost > 0);
+        for node in nodes.values() {
+            // serial logs time the echoes in ms
+            assert!(node.min().unwrap() >= 15);
+            assert!(node.max().unwrap() < 1000);
+        }
+        assert!(acks.to_string().starts_with("node"));
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut acks = AckCorrelator::new();
+        let start = parse_log_line("Oct 18 13:00:00 DEBUG TSF:MSG:ACK")
+            .datetime
+            .unwrap();
+        let line = |seconds: i64, msg: &str| {
+            let datetime = start + Duration::seconds(seconds);
+            parse_log_line(&format!(
+                "{} DEBUG {}",
+                datetime.format("%b %e %H:%M:%S"),
+                msg
+            ))
+        };
+        // a day of acked sends to node 12 and sends to node 7 that are never acked
+        for minute in 0..1440 {
+            let t = minute * 60;
+            for (seconds, msg) in [
+                (
+                    t,
+                    "TSF:MSG:SEND,0-0-12-12,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=0,st=OK:1",
+                ),
+                (t + 1, "TSF:MSG:READ,12-12-0,s=2,c=1,t=2,pt=0,l=1,sg=0:1"),
+                (t + 1, "TSF:MSG:ACK"),
+                (
+                    t + 2,
+                    "TSF:MSG:SEND,0-0-7-7,s=1,c=1,t=2,pt=0,l=1,sg=0,ft=0,st=OK:1",
+                ),
+            ] {
+                acks.feed(&line(seconds, msg));
+            }
+        }
+        let nodes = acks.nodes();
+        assert_eq!((nodes[&12].acked, nodes[&12].lost), (1440, 0));
+        assert_eq!(nodes[&12].latencies.len(), LATENCY_SAMPLE);
+        assert_eq!(nodes[&12].median(), Some(1000));
+        // an hour of them at most
+        assert!(acks.unmatched.len() <= 61, "{}", acks.unmatched.len());
+
+        // a key acked late makes the sends it still knows lost
+        acks.feed(&line(
+            86400,
+            "TSF:MSG:SEND,0-0-7-7,s=1,c=1,t=2,pt=0,l=1,sg=0,ft=0,st=OK:1",
+        ));
+        acks.feed(&line(
+            86401,
+            "TSF:MSG:READ,7-7-0,s=1,c=1,t=2,pt=0,l=1,sg=0:1",
+        ));
+        acks.feed(&line(86401, "TSF:MSG:ACK"));
+        let lost = acks.nodes()[&7].lost;
+        assert!((59..=61).contains(&lost), "{}", lost);
+        assert_eq!(acks.lost().len(), lost);
+        assert!(acks.unmatched.is_empty());
+    }
+}
@@ -0,0 +1,358 @@
+// battery levels of the nodes over time, to replace batteries before they
+// run out instead of after
+//
+// nodes report their battery in percent as I_BATTERY_LEVEL (c=3,t=0) and often
+// the voltage as a V_VOLTAGE child sensor. the discharge rate is the least
+// squares slope of the levels since the last battery change (a jump of 20% or
+// more), the projected empty date follows from it
+//
+// how long a node sleeps between reports drives its battery use: sleeps come
+// from I_POST_SLEEP_NOTIFICATION in gateway logs and MCO:SLP in the log of the
+// node itself (the node being the one TSM:READY reports)
+use crate::protocol::{MsgKind, C_INTERNAL, C_SET, I_BATTERY_LEVEL, I_POST_SLEEP_NOTIFICATION};
+use crate::{LogLine, MsgFields};
+use chrono::{DateTime, Duration, Local};
+use std::collections::BTreeMap;
+use std::fmt;
+
+const V_VOLTAGE: u8 = 38;
+const BATTERY_CHANGE: f64 = 20.0; //rise in percent taken as new batteries
+const SAME_REPORT_SECS: i64 = 5; //a value read and then forwarded is one report
+const MAX_EMPTY_DAYS: f64 = 3650.0; //slower discharge is not projected
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    pub datetime: DateTime<Local>,
+    pub value: f64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct NodeBattery {
+    levels: Vec<Sample>, //since the last battery change
+    voltages: Vec<Sample>,
+    changes: Vec<DateTime<Local>>,
+    sleeps: Vec<Duration>,
+    sleeps_at_change: usize, //sleeps before the last battery change
+}
+
+impl NodeBattery {
+    fn add(samples: &mut Vec<Sample>, sample: Sample) {
+        if let Some(last) = samples.last() {
+            if sample.datetime - last.datetime <= Duration::seconds(SAME_REPORT_SECS)
+                && sample.value == last.value
+            {
+                return;
+            }
+        }
+        samples.push(sample);
+    }
+
+    fn add_level(&mut self, sample: Sample) {
+        if let Some(last) = self.levels.last() {
+            if sample.value - last.value >= BATTERY_CHANGE {
+                self.changes.push(sample.datetime);
+                self.levels.clear();
+                self.sleeps_at_change = self.sleeps.len();
+            }
+        }
+        NodeBattery::add(&mut self.levels, sample);
+    }
+}
+
+// least squares slope of the samples, in units per day
+fn slope_per_day(samples: &[Sample]) -> Option<f64> {
+    let first = samples.first()?.datetime;
+    let points: Vec<(f64, f64)> = samples
+        .iter()
+        .map(|s| ((s.datetime - first).num_seconds() as f64 / 86400.0, s.value))
+        .collect();
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    let sxy: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+    if sxx == 0.0 {
+        return None; //a single point in time
+    }
+    Some(sxy / sxx)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatteryStatus {
+    pub node: u8,
+    pub level: Option<Sample>,          //last reported percentage
+    pub voltage: Option<Sample>,        //last reported voltage
+    pub rate: Option<f64>,              //percent per day, negative while discharging
+    pub voltage_rate: Option<f64>,      //volts per day
+    pub empty: Option<DateTime<Local>>, //projected from the rate
+    pub changes: Vec<DateTime<Local>>,  //battery changes seen
+    pub sleeps: usize,
+    pub mean_sleep: Option<Duration>,
+    pub per_wake_up: Option<f64>, //percent used per sleep/wake cycle since the last change
+}
+
+impl fmt::Display for BatteryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let or_dash = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+        write!(
+            f,
+            "{:>4} {:>4} {:>6} {:>9} {:>10} {:>6} {:>9} {:>10}",
+            self.node,
+            or_dash(self.level.map(|l| format!("{:.0}%", l.value))),
+            or_dash(self.voltage.map(|v| format!("{:.2}V", v.value))),
+            or_dash(self.rate.map(|r| format!("{:.2}%/d", r))),
+            or_dash(self.empty.map(|e| e.format("%F").to_string())),
+            self.sleeps,
+            or_dash(self.mean_sleep.map(super::format_duration)),
+            or_dash(self.per_wake_up.map(|p| format!("{:.4}%", p)))
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BatteryTracker {
+    nodes: BTreeMap<u8, NodeBattery>,
+    local_node: Option<u8>, //node whose own log this is
+}
+
+impl BatteryTracker {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // lines without a time (e.g. serial gateway logs) are ignored
+    pub fn feed(&mut self, line: &LogLine) {
+        let datetime = match line.datetime {
+            Some(dt) => dt,
+            None => return,
+        };
+        match &line.fields {
+            Some(MsgFields::Ready { id, .. }) => self.local_node = Some(*id),
+            Some(MsgFields::Sleep { ms, .. }) => {
+                if let Some(id) = self.local_node.filter(|id| *id != 0) {
+                    let node = self.nodes.entry(id).or_default();
+                    node.sleeps.push(Duration::milliseconds(*ms as i64));
+                }
+            }
+            // a node's report on its way to the gateway or the controller
+            Some(MsgFields::Msg(m)) if m.sender != 0 && m.kind != MsgKind::MqttIn => {
+                let value = match m.payload.parse::<f64>() {
+                    Ok(value) => value,
+                    Err(_) => return,
+                };
+                let sample = Sample { datetime, value };
+                match (m.command, m.msg_type) {
+                    (C_INTERNAL, I_BATTERY_LEVEL) => {
+                        self.nodes.entry(m.sender).or_default().add_level(sample)
+                    }
+                    (C_SET, V_VOLTAGE) => {
+                        let node = self.nodes.entry(m.sender).or_default();
+                        NodeBattery::add(&mut node.voltages, sample);
+                    }
+                    // as read by the gateway, not the copy passed on to the controller
+                    (C_INTERNAL, I_POST_SLEEP_NOTIFICATION) if m.kind == MsgKind::Read => {
+                        let node = self.nodes.entry(m.sender).or_default();
+                        node.sleeps.push(Duration::milliseconds(value as i64));
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn status(id: u8, node: &NodeBattery) -> BatteryStatus {
+        let level = node.levels.last().copied();
+        let rate = slope_per_day(&node.levels);
+        let empty = match (level, rate) {
+            // an almost flat series would be empty in centuries or overflow
+            (Some(level), Some(rate)) if rate < 0.0 => {
+                let days = level.value / -rate;
+                (days <= MAX_EMPTY_DAYS)
+                    .then(|| Duration::try_seconds((days * 86400.0) as i64))
+                    .flatten()
+                    .and_then(|d| level.datetime.checked_add_signed(d))
+            }
+            _ => None,
+        };
+        let mean_sleep = match node.sleeps.len() {
+            0 => None,
+            n => Some(node.sleeps.iter().fold(Duration::zero(), |a, b| a + *b) / n as i32),
+        };
+        // the battery used while the node went through its sleeps since the
+        // last battery change
+        let since_change = node.sleeps.len() - node.sleeps_at_change;
+        let per_wake_up = match (node.levels.first(), level, since_change) {
+            (Some(first), Some(last), n) if n > 0 && first.value > last.value => {
+                Some((first.value - last.value) / n as f64)
+            }
+            _ => None,
+        };
+        BatteryStatus {
+            node: id,
+            level,
+            voltage: node.voltages.last().copied(),
+            rate,
+            voltage_rate: slope_per_day(&node.voltages),
+            empty,
+            changes: node.changes.clone(),
+            sleeps: node.sleeps.len(),
+            mean_sleep,
+            per_wake_up,
+        }
+    }
+
+    // nodes that reported a battery level or voltage
+    pub fn nodes(&self) -> Vec<BatteryStatus> {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| !node.levels.is_empty() || !node.voltages.is_empty())
+            .map(|(id, node)| BatteryTracker::status(*id, node))
+            .collect()
+    }
+
+    // nodes whose battery is projected to run out before the given time,
+    // soonest first
+    pub fn empty_before(&self, time: DateTime<Local>) -> Vec<BatteryStatus> {
+        let mut nodes: Vec<BatteryStatus> = self
+            .nodes()
+            .into_iter()
+            .filter(|s| s.empty.is_some_and(|e| e < time))
+            .collect();
+        nodes.sort_by_key(|s| s.empty);
+        nodes
+    }
+}
+
+impl fmt::Display for BatteryTracker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>4} {:>4} {:>6} {:>9} {:>10} {:>6} {:>9} {:>10}",
+            "node", "bat", "volt", "rate", "empty", "sleeps", "sleep", "per wake"
+        )?;
+        for status in self.nodes() {
+            writeln!(f, "{}", status)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+    use crate::simulator::{SimConfig, Simulator};
+
+    #[test]
+    fn test_battery() {
+        let mut tracker = BatteryTracker::new();
+        for line in [
+            "Oct 10 12:00:00 DEBUG TSF:MSG:READ,21-21-0,s=255,c=3,t=0,pt=1,l=2,sg=0:90",
+            "Oct 10 12:00:00 DEBUG GWT:TPS:TOPIC=mygateway1-out/21/255/3/0/0,MSG SENT",
+            "Oct 10 12:00:01 DEBUG TSF:MSG:READ,21-21-0,s=1,c=1,t=38,pt=7,l=4,sg=0:2.95",
+            "Oct 11 12:00:00 DEBUG TSF:MSG:READ,21-21-0,s=255,c=3,t=0,pt=1,l=2,sg=0:88",
+            "Oct 11 12:00:01 DEBUG TSF:MSG:READ,21-21-0,s=1,c=1,t=38,pt=7,l=4,sg=0:2.93",
+            "Oct 12 12:00:00 DEBUG TSF:MSG:READ,21-21-0,s=255,c=3,t=0,pt=1,l=2,sg=0:86",
+            "Oct 12 12:00:00 DEBUG TSF:MSG:READ,21-21-0,s=255,c=3,t=33,pt=5,l=8,sg=0:86400000",
+            // new batteries in another node
+            "Oct 10 12:00:00 DEBUG TSF:MSG:READ,22-22-0,s=255,c=3,t=0,pt=1,l=2,sg=0:5",
+            "Oct 11 12:00:00 DEBUG TSF:MSG:READ,22-22-0,s=255,c=3,t=0,pt=1,l=2,sg=0:100",
+        ] {
+            tracker.feed(&parse_log_line(line));
+        }
+        let nodes = tracker.nodes();
+        assert_eq!(nodes.len(), 2);
+
+        let node = &nodes[0];
+        assert_eq!(node.level.unwrap().value, 86.0);
+        assert!((node.rate.unwrap() + 2.0).abs() < 1e-9);
+        assert!((node.voltage_rate.unwrap() + 0.02).abs() < 1e-6);
+        assert_eq!(node.voltage.unwrap().value, 2.93);
+        // 86% at 2% a day
+        let last = node.level.unwrap().datetime;
+        assert_eq!(node.empty, Some(last + Duration::days(43)));
+        assert_eq!((node.sleeps, node.mean_sleep), (1, Some(Duration::days(1))));
+        assert_eq!(node.per_wake_up, Some(4.0));
+
+        let node = &nodes[1];
+        assert_eq!(node.changes.len(), 1);
+        assert_eq!(node.rate, None);
+
+        assert_eq!(tracker.empty_before(last + Duration::days(60)).len(), 1);
+        assert!(tracker.empty_before(last + Duration::days(30)).is_empty());
+    }
+
+    #[test]
+    fn test_change_between_sleeps() {
+        let mut tracker = BatteryTracker::new();
+        for line in [
+            "Oct 10 12:00:00 DEBUG TSF:MSG:READ,21-21-0,s=255,c=3,t=0,pt=1,l=2,sg=0:10",
+            "Oct 10 12:00:00 DEBUG TSF:MSG:READ,21-21-0,s=255,c=3,t=33,pt=5,l=7,sg=0:3600000",
+            "Oct 10 13:00:00 DEBUG TSF:MSG:READ,21-21-0,s=255,c=3,t=33,pt=5,l=7,sg=0:3600000",
+            "Oct 10 14:00:00 DEBUG TSF:MSG:READ,21-21-0,s=255,c=3,t=33,pt=5,l=7,sg=0:3600000",
+            // new batteries, then two sleeps using 2%
+            "Oct 11 12:00:00 DEBUG TSF:MSG:READ,21-21-0,s=255,c=3,t=0,pt=1,l=3,sg=0:100",
+            "Oct 11 12:00:00 DEBUG TSF:MSG:READ,21-21-0,s=255,c=3,t=33,pt=5,l=7,sg=0:3600000",
+            "Oct 11 13:00:00 DEBUG TSF:MSG:READ,21-21-0,s=255,c=3,t=33,pt=5,l=7,sg=0:3600000",
+            "Oct 11 14:00:00 DEBUG TSF:MSG:READ,21-21-0,s=255,c=3,t=0,pt=1,l=2,sg=0:98",
+        ] {
+            tracker.feed(&parse_log_line(line));
+        }
+        let node = &tracker.nodes()[0];
+        assert_eq!(node.changes.len(), 1);
+        assert_eq!(node.sleeps, 5);
+        assert_eq!(node.per_wake_up, Some(1.0));
+    }
+
+    #[test]
+    fn test_flat() {
+        let mut tracker = BatteryTracker::new();
+        for line in [
+            "Oct 10 12:00:00 DEBUG TSF:MSG:READ,21-21-0,s=255,c=3,t=0,pt=7,l=2,sg=0:90",
+            "Oct 11 12:00:00 DEBUG TSF:MSG:READ,21-21-0,s=255,c=3,t=0,pt=7,l=13,sg=0:89.9999999999",
+        ] {
+            tracker.feed(&parse_log_line(line));
+        }
+        let node = &tracker.nodes()[0];
+        assert!(node.rate.unwrap() < 0.0 && node.rate.unwrap() > -1e-9);
+        assert_eq!(node.empty, None);
+        assert!(tracker.empty_before(Local::now()).is_empty());
+    }
+
+    #[test]
+    fn test_node_log() {
+        let mut tracker = BatteryTracker::new();
+        for line in [
+            "Oct 10 12:00:00 DEBUG TSM:READY:ID=21,PAR=0,DIS=1",
+            "Oct 10 12:00:00 DEBUG TSF:MSG:SEND,21-21-0-0,s=255,c=3,t=0,pt=1,l=2,sg=0,ft=0,st=OK:90",
+            "Oct 10 12:00:00 DEBUG MCO:SLP:MS=600000,SMS=0,I1=255,M1=255,I2=255,M2=255",
+            "Oct 10 12:10:00 DEBUG MCO:SLP:WUP=-1",
+            "Oct 10 12:10:00 DEBUG TSF:MSG:SEND,21-21-0-0,s=255,c=3,t=0,pt=1,l=2,sg=0,ft=0,st=OK:89",
+            "Oct 10 12:10:00 DEBUG MCO:SLP:MS=600000,SMS=0,I1=255,M1=255,I2=255,M2=255",
+        ] {
+            tracker.feed(&parse_log_line(line));
+        }
+        let nodes = tracker.nodes();
+        assert_eq!(nodes[0].node, 21);
+        assert_eq!(nodes[0].sleeps, 2);
+        assert_eq!(nodes[0].mean_sleep, Some(Duration::minutes(10)));
+        assert_eq!(nodes[0].per_wake_up, Some(0.5));
+    }
+
+    #[test]
+    fn test_simulated() {
+        let config = SimConfig::default();
+        let battery_nodes = config.battery_nodes as usize;
+        let mut tracker = BatteryTracker::new();
+        for line in Simulator::new(config) {
+            tracker.feed(&parse_log_line(&line));
+        }
+        let nodes = tracker.nodes();
+        assert_eq!(nodes.len(), battery_nodes);
+        for node in nodes {
+            assert!(node.rate.unwrap() <= 0.0);
+            assert!(node.sleeps > 0);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+// inventory of the deployment: what each node says it is when it presents
+//
+// after a start a node presents itself and its children:
+//   s=255,c=0,t=17 (S_ARDUINO_NODE, S_ARDUINO_REPEATER_NODE for repeaters)
+//                  with the library version as payload
+//   s=255,c=3,t=11 (I_SKETCH_NAME) and s=255,c=3,t=12 (I_SKETCH_VERSION)
+//   s=<child>,c=0,t=<S_* type> with the description as payload
+// the latest presentation wins, a child presented with another type replaces
+// the old one. MQTT gateways do not log the payload of what they publish, an
+// empty payload keeps what is known
+//
+// read from the gateway's READs, the lines passed on to the controller and
+// the SENDs to the gateway in a node's own log
+use crate::protocol::{
+    type_name, MsgKind, TransportMsg, C_INTERNAL, C_PRESENTATION, I_SKETCH_NAME, I_SKETCH_VERSION,
+};
+use crate::{BoxError, LogLine, MsgFields};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+const NODE_SENSOR: u8 = 255;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ChildSensor {
+    pub id: u8,
+    pub sensor_type: String, //S_* name, or the number if unknown
+    pub description: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct NodeInventory {
+    pub node: u8,
+    pub node_type: Option<String>, //S_ARDUINO_NODE or S_ARDUINO_REPEATER_NODE
+    pub sketch_name: Option<String>,
+    pub sketch_version: Option<String>,
+    pub library_version: Option<String>,
+    pub children: Vec<ChildSensor>, //ordered by id
+}
+
+impl NodeInventory {
+    fn child(&mut self, mut child: ChildSensor) {
+        match self.children.binary_search_by_key(&child.id, |c| c.id) {
+            Ok(i) => {
+                if child.description.is_empty() && child.sensor_type == self.children[i].sensor_type
+                {
+                    child.description = self.children[i].description.clone();
+                }
+                self.children[i] = child
+            }
+            Err(i) => self.children.insert(i, child),
+        }
+    }
+}
+
+// quote a CSV field if it needs it (RFC 4180)
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Inventory {
+    nodes: BTreeMap<u8, NodeInventory>,
+}
+
+// a node's presentation, wherever in the log it shows up
+fn presentation(line: &LogLine) -> Option<&TransportMsg> {
+    match &line.fields {
+        Some(MsgFields::Msg(m)) if m.sender != 0 => match m.kind {
+            MsgKind::Read | MsgKind::Serial | MsgKind::MqttOut => Some(m),
+            MsgKind::Send if m.destination == 0 => Some(m),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl Inventory {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn feed(&mut self, line: &LogLine) {
+        let m = match presentation(line) {
+            Some(m) => m,
+            None => return,
+        };
+        let node = self.nodes.entry(m.sender).or_insert_with(|| NodeInventory {
+            node: m.sender,
+            ..Default::default()
+        });
+        let payload = Some(m.payload.clone()).filter(|p| !p.is_empty());
+        match (m.command, m.msg_type) {
+            (C_PRESENTATION, _) if m.sensor == NODE_SENSOR => {
+                node.node_type = Some(m.type_str());
+                node.library_version = payload.or(node.library_version.take());
+            }
+            (C_PRESENTATION, _) => node.child(ChildSensor {
+                id: m.sensor,
+                sensor_type: type_name(C_PRESENTATION, m.msg_type)
+                    .unwrap_or_else(|| m.msg_type.to_string()),
+                description: m.payload.clone(),
+            }),
+            (C_INTERNAL, I_SKETCH_NAME) if payload.is_some() => node.sketch_name = payload,
+            (C_INTERNAL, I_SKETCH_VERSION) if payload.is_some() => node.sketch_version = payload,
+            _ => {}
+        }
+    }
+
+    // nodes that presented anything, heard from without presenting are left out
+    pub fn nodes(&self) -> Vec<&NodeInventory> {
+        self.nodes
+            .values()
+            .filter(|n| {
+                n.node_type.is_some()
+                    || n.sketch_name.is_some()
+                    || n.sketch_version.is_some()
+                    || !n.children.is_empty()
+            })
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Result<String, BoxError> {
+        Ok(serde_json::to_string_pretty(&self.nodes())?)
+    }
+
+    // one row per child, nodes without children get a row of their own
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "node,node_type,sketch_name,sketch_version,library_version,child,sensor_type,description\n",
+        );
+        for node in self.nodes() {
+            let opt = |v: &Option<String>| csv_field(v.as_deref().unwrap_or(""));
+            let head = format!(
+                "{},{},{},{},{}",
+                node.node,
+                opt(&node.node_type),
+                opt(&node.sketch_name),
+                opt(&node.sketch_version),
+                opt(&node.library_version)
+            );
+            if node.children.is_empty() {
+                csv.push_str(&format!("{},,,\n", head));
+            }
+            for child in &node.children {
+                csv.push_str(&format!(
+                    "{},{},{},{}\n",
+                    head,
+                    child.id,
+                    csv_field(&child.sensor_type),
+                    csv_field(&child.description)
+                ));
+            }
+        }
+        csv
+    }
+}
+
+impl fmt::Display for Inventory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let opt = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_string());
+        for node in self.nodes() {
+            writeln!(
+                f,
+                "{:>4} {} {} (library {})",
+                node.node,
+                opt(&node.sketch_name),
+                opt(&node.sketch_version),
+                opt(&node.library_version)
+            )?;
+            for child in &node.children {
+                writeln!(
+                    f,
+                    "     {:>4} {:24} {}",
+                    child.id, child.sensor_type, child.description
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+
+    fn inventory() -> Inventory {
+        let mut inventory = Inventory::new();
+        for line in [
+            "Oct 18 13:00:00 DEBUG TSF:MSG:READ,12-12-0,s=255,c=0,t=17,pt=0,l=5,sg=0:2.3.2",
+            "Oct 18 13:00:00 DEBUG TSF:MSG:READ,12-12-0,s=255,c=3,t=11,pt=0,l=7,sg=0:Outside",
+            "Oct 18 13:00:00 DEBUG TSF:MSG:READ,12-12-0,s=255,c=3,t=12,pt=0,l=3,sg=0:1.1",
+            "Oct 18 13:00:00 DEBUG TSF:MSG:READ,12-12-0,s=2,c=0,t=7,pt=0,l=8,sg=0:Humidity",
+            "Oct 18 13:00:00 DEBUG TSF:MSG:READ,12-12-0,s=1,c=0,t=6,pt=0,l=11,sg=0:Temp, north",
+            // passed on to the controller, the same again
+            "Oct 18 13:00:00 DEBUG GWT:TPS:TOPIC=mygateway1-out/12/1/0/0/6,MSG SENT",
+            "Oct 18 13:00:01 DEBUG TSF:MSG:READ,7-7-0,s=1,c=1,t=0,pt=7,l=4,sg=0:19.0",
+            "Oct 18 13:00:02 DEBUG TSF:MSG:READ,5-5-0,s=255,c=0,t=18,pt=0,l=5,sg=0:2.3.2",
+        ] {
+            inventory.feed(&parse_log_line(line));
+        }
+        inventory
+    }
+
+    #[test]
+    fn test_inventory() {
+        let inventory = inventory();
+        let nodes = inventory.nodes();
+        // node 7 never presented
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].node, 5);
+        assert_eq!(
+            nodes[0].node_type.as_deref(),
+            Some("S_ARDUINO_REPEATER_NODE")
+        );
+        let twelve = nodes[1];
+        assert_eq!(twelve.sketch_name.as_deref(), Some("Outside"));
+        assert_eq!(twelve.sketch_version.as_deref(), Some("1.1"));
+        assert_eq!(twelve.library_version.as_deref(), Some("2.3.2"));
+        let children: Vec<(u8, &str)> = twelve
+            .children
+            .iter()
+            .map(|c| (c.id, c.sensor_type.as_str()))
+            .collect();
+        assert_eq!(children, vec![(1, "S_TEMP"), (2, "S_HUM")]);
+        assert_eq!(twelve.children[0].description, "Temp, north");
+    }
+
+    #[test]
+    fn test_export() {
+        let inventory = inventory();
+        let csv = inventory.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1], "5,S_ARDUINO_REPEATER_NODE,,,2.3.2,,,");
+        assert_eq!(
+            lines[2],
+            "12,S_ARDUINO_NODE,Outside,1.1,2.3.2,1,S_TEMP,\"Temp, north\""
+        );
+
+        let json = inventory.to_json().unwrap();
+        assert!(json.starts_with('['));
+        assert!(json.contains("\"sketch_name\": \"Outside\""));
+        assert!(json.contains("\"description\": \"Temp, north\""));
+    }
+}
@@ -0,0 +1,150 @@
+// analyses built on top of parsed log lines
+// each analyzer is fed lines in order so it works on a whole file as well as
+// on lines arriving live
+pub mod acks;
+pub mod battery;
+pub mod inventory;
+pub mod node_ids;
+pub mod rates;
+pub mod signal;
+pub mod timeline;
+pub mod watchdog;
+
+use crate::protocol::{MsgFields, MsgKind, TransportMsg, C_INTERNAL, I_LOG_MESSAGE};
+use crate::{LogLine, SendStatus};
+use chrono::Duration;
+
+// compact human readable duration, e.g. 1h02m03s
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.num_seconds();
+    let sign = if secs < 0 { "-" } else { "" };
+    let secs = secs.abs();
+    let (h, m, s) = (secs / 3600, (secs % 3600) / 60, secs % 60);
+    if h > 0 {
+        format!("{}{}h{:02}m{:02}s", sign, h, m, s)
+    } else if m > 0 {
+        format!("{}{}m{:02}s", sign, m, s)
+    } else {
+        format!("{}{}s", sign, s)
+    }
+}
+
+// node a line was heard from: frames read by the gateway and what the
+// gateway passed on to the controller for a node
+pub(crate) fn heard_from(line: &LogLine) -> Option<u8> {
+    match &line.fields {
+        Some(MsgFields::Msg(m)) => match m.kind {
+            MsgKind::Read | MsgKind::Serial | MsgKind::MqttOut if m.sender != 0 => Some(m.sender),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// node a message was received from, counted once: an MQTT gateway publishes
+// every frame it reads, the copy is not another message
+pub(crate) fn received_from(line: &LogLine) -> Option<u8> {
+    match &line.fields {
+        Some(MsgFields::Msg(m)) if matches!(m.kind, MsgKind::Read | MsgKind::Serial) => {
+            heard_from(line)
+        }
+        _ => None,
+    }
+}
+
+// a serial gateway sends its debug messages to the controller as I_LOG_MESSAGE
+// with the milliseconds since the gateway started:
+//   0;255;3;0;9;1234 TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5
+// returns the milliseconds and the message parsed as if it was logged on its
+// own (without time and level), None for any other line
+pub fn serial_debug(line: &LogLine) -> Option<(i64, LogLine)> {
+    let msg = match &line.fields {
+        Some(MsgFields::Msg(m)) => m.clone(),
+        Some(_) => return None,
+        None => TransportMsg::from_serial(&line.msg)?,
+    };
+    if (msg.command, msg.msg_type) != (C_INTERNAL, I_LOG_MESSAGE) {
+        return None;
+    }
+    let (millis, text) = msg.payload.split_once(' ')?;
+    let millis = millis.parse().ok()?;
+    let (status, message) = match text.chars().next() {
+        Some('!') => (SendStatus::ERROR, &text[1..]),
+        Some('?') => (SendStatus::UNKNOWN, &text[1..]),
+        _ => (SendStatus::OK, text),
+    };
+    let inner = match crate::parsers::parse_builtin(message) {
+        Some(parsed) => LogLine {
+            datetime: None,
+            level: None,
+            msg: parsed.to_string(),
+            system: parsed.system,
+            subsystem: parsed.subsystem,
+            fields: parsed.fields,
+            status,
+        },
+        None => LogLine {
+            datetime: None,
+            level: None,
+            system: None,
+            subsystem: None,
+            msg: message.to_string(),
+            fields: None,
+            status,
+        },
+    };
+    Some((millis, inner))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+
+    #[test]
+    fn test_serial_debug() {
+        let line = parse_log_line(
+            "0;255;3;0;9;1234 !TSF:MSG:SEND,0-0-12-12,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=2,st=NACK:1",
+        );
+        let (millis, inner) = serial_debug(&line).unwrap();
+        assert_eq!(millis, 1234);
+        assert_eq!(inner.status, SendStatus::ERROR);
+        match inner.fields {
+            Some(MsgFields::Msg(m)) => assert_eq!((m.destination, m.send_ok), (12, Some(false))),
+            other => panic!("not a message: {:?}", other),
+        }
+        let (_, inner) = serial_debug(&parse_log_line("0;255;3;0;9;25 hello")).unwrap();
+        assert_eq!(inner.msg, "hello");
+        assert!(serial_debug(&parse_log_line("12;1;1;0;0;21.5")).is_none());
+        assert!(serial_debug(&parse_log_line("Oct 18 13:00:00 DEBUG TSM:INIT")).is_none());
+    }
+
+    #[test]
+    fn test_heard_from() {
+        let read = parse_log_line(
+            "Oct 18 13:00:01 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5",
+        );
+        let published = parse_log_line(
+            "Oct 18 13:00:01 DEBUG GWT:TPS:TOPIC=mygateway1-out/12/1/1/0/0,MSG SENT",
+        );
+        assert_eq!(
+            (heard_from(&read), received_from(&read)),
+            (Some(12), Some(12))
+        );
+        assert_eq!(
+            (heard_from(&published), received_from(&published)),
+            (Some(12), None)
+        );
+        let sent = parse_log_line(
+            "Oct 18 13:00:02 DEBUG TSF:MSG:SEND,0-0-12-12,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=0,st=OK:1",
+        );
+        assert_eq!(heard_from(&sent), None);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::seconds(5)), "5s");
+        assert_eq!(format_duration(Duration::seconds(65)), "1m05s");
+        assert_eq!(format_duration(Duration::seconds(3723)), "1h02m03s");
+    }
+}
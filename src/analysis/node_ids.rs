@@ -0,0 +1,443 @@
+// audit of the node IDs handed out, to catch two nodes sharing an ID
+//
+// a node without an ID asks for one as node 255 (AUTO), the controller answers
+// through the gateway:
+//   TSF:MSG:READ,255-255-0,s=255,c=3,t=3,pt=0,l=0,sg=0:       I_ID_REQUEST
+//   TSF:MSG:SEND,0-0-255-255,s=255,c=3,t=4,pt=0,l=2,sg=0,ft=0,st=OK:12   I_ID_RESPONSE
+// and the node logs TSF:SID:OK,ID=12 and, once it checked the ID,
+// TSM:ID:FAIL,ID= if it is not usable
+//
+// the log does not tell which node asked, requests are told apart by the hop
+// they came through (255 when the node is in range of the gateway); an answer
+// goes back to the hop in the next field of its SEND
+//
+// flagged are
+// - IDs handed out while a node is still heard using them, i.e. was heard
+//   within the in use window (a day by default); an ID freed by a node that
+//   was removed long ago may well be handed out again
+// - IDs presenting with a second sketch name, two nodes using one ID or a node
+//   flashed with another sketch
+// - requests that keep coming without an answer
+// - IDs that failed verification on the node
+use super::{heard_from, received_from};
+use crate::protocol::{MsgKind, C_INTERNAL, I_ID_REQUEST, I_ID_RESPONSE, I_SKETCH_NAME};
+use crate::{LogLine, MsgFields};
+use chrono::{DateTime, Duration, Local};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+const AUTO: u8 = 255;
+
+// what is known about one node ID
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeRecord {
+    pub id: u8,
+    pub assigned: Vec<DateTime<Local>>, //each time the ID was handed out
+    pub static_id: bool,                //the node reported a fixed ID (TSM:ID:STATID)
+    pub first_seen: Option<DateTime<Local>>,
+    pub last_seen: Option<DateTime<Local>>,
+    pub messages: usize,
+    pub sketches: Vec<String>, //sketch names presented, in order of appearance
+}
+
+impl fmt::Display for NodeRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = |t: Option<DateTime<Local>>| {
+            t.map_or("-".to_string(), |t| t.format("%F %H:%M:%S").to_string())
+        };
+        write!(
+            f,
+            "{:>4} {:>8} {:>19} {:>19} {:>8}  {}",
+            self.id,
+            if self.static_id {
+                "static".to_string()
+            } else {
+                self.assigned.len().to_string()
+            },
+            time(self.first_seen),
+            time(self.last_seen),
+            self.messages,
+            self.sketches.join(", ")
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IdIssue {
+    Reassigned {
+        id: u8,
+        datetime: DateTime<Local>,
+        last_seen: DateTime<Local>, //when the node already using it was last heard
+    },
+    SketchConflict {
+        id: u8,
+        datetime: DateTime<Local>,
+        known: String,
+        new: String,
+    },
+    RepeatedRequests {
+        via: u8, //hop the requests came through, 255 for direct
+        datetime: DateTime<Local>,
+        count: usize,
+    },
+    VerificationFailed {
+        id: u8,
+        datetime: DateTime<Local>,
+    },
+}
+
+impl fmt::Display for IdIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = |t: &DateTime<Local>| t.format("%F %H:%M:%S").to_string();
+        match self {
+            IdIssue::Reassigned {
+                id,
+                datetime,
+                last_seen,
+            } => write!(
+                f,
+                "{} ID {} handed out again, still in use at {}",
+                time(datetime),
+                id,
+                time(last_seen)
+            ),
+            IdIssue::SketchConflict {
+                id,
+                datetime,
+                known,
+                new,
+            } => write!(
+                f,
+                "{} ID {} presented as ({}) and ({})",
+                time(datetime),
+                id,
+                known,
+                new
+            ),
+            IdIssue::RepeatedRequests {
+                via,
+                datetime,
+                count,
+            } => write!(
+                f,
+                "{} {} ID requests without an answer via {}",
+                time(datetime),
+                count,
+                if *via == AUTO {
+                    "the gateway".to_string()
+                } else {
+                    format!("node {}", via)
+                }
+            ),
+            IdIssue::VerificationFailed { id, datetime } => {
+                write!(f, "{} ID {} failed verification", time(datetime), id)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct IdAudit {
+    repeat_limit: usize,
+    in_use: Duration,  //a node heard within this is still using its ID
+    local: Option<u8>, //ID from the node's own log
+    unanswered: HashMap<u8, (usize, bool)>, //per hop: requests, already flagged
+    nodes: BTreeMap<u8, NodeRecord>,
+    issues: Vec<IdIssue>,
+}
+
+impl Default for IdAudit {
+    fn default() -> Self {
+        IdAudit::with_repeat_limit(3)
+    }
+}
+
+impl IdAudit {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // flag a hop after this many requests in a row without an answer
+    pub fn with_repeat_limit(repeat_limit: usize) -> Self {
+        IdAudit::with_limits(repeat_limit, Duration::days(1))
+    }
+
+    // as with_repeat_limit, and flag an ID handed out again if its node was
+    // heard within in_use before
+    pub fn with_limits(repeat_limit: usize, in_use: Duration) -> Self {
+        IdAudit {
+            repeat_limit: repeat_limit.max(1),
+            in_use,
+            local: None,
+            unanswered: HashMap::new(),
+            nodes: BTreeMap::new(),
+            issues: Vec::new(),
+        }
+    }
+
+    fn node(&mut self, id: u8) -> &mut NodeRecord {
+        self.nodes.entry(id).or_insert_with(|| NodeRecord {
+            id,
+            ..Default::default()
+        })
+    }
+
+    fn requested(&mut self, via: u8, datetime: DateTime<Local>) {
+        let limit = self.repeat_limit;
+        let (count, flagged) = self.unanswered.entry(via).or_default();
+        *count += 1;
+        if *count >= limit && !*flagged {
+            *flagged = true;
+            self.issues.push(IdIssue::RepeatedRequests {
+                via,
+                datetime,
+                count: *count,
+            });
+        }
+    }
+
+    fn assigned(&mut self, id: u8, datetime: DateTime<Local>) {
+        let in_use = self.in_use;
+        let node = self.node(id);
+        let last_seen = node.last_seen;
+        node.assigned.push(datetime);
+        // whatever presents from now on is the new node
+        node.sketches.clear();
+        if let Some(last_seen) = last_seen.filter(|seen| datetime - *seen <= in_use) {
+            self.issues.push(IdIssue::Reassigned {
+                id,
+                datetime,
+                last_seen,
+            });
+        }
+    }
+
+    // lines without a time (e.g. serial gateway logs) are ignored
+    pub fn feed(&mut self, line: &LogLine) {
+        let datetime = match line.datetime {
+            Some(dt) => dt,
+            None => return,
+        };
+        match &line.fields {
+            Some(MsgFields::Ready { id, .. }) => self.local = Some(*id),
+            Some(MsgFields::StaticId { id }) => {
+                self.local = Some(*id);
+                self.node(*id).static_id = true;
+            }
+            Some(MsgFields::AssignNodeId { ok: true, id }) => {
+                self.unanswered.remove(&AUTO);
+                self.local = Some(*id);
+                self.assigned(*id, datetime);
+            }
+            Some(MsgFields::IdVerificationFailed { id }) => {
+                self.issues
+                    .push(IdIssue::VerificationFailed { id: *id, datetime });
+            }
+            Some(MsgFields::Msg(m)) if m.command == C_INTERNAL => match (m.msg_type, m.kind) {
+                // read by the gateway, or sent by a node without ID in its own log
+                (I_ID_REQUEST, MsgKind::Read) if m.destination == 0 => {
+                    self.requested(m.last, datetime)
+                }
+                (I_ID_REQUEST, MsgKind::Send) if m.sender == AUTO => self.requested(AUTO, datetime),
+                (I_ID_RESPONSE, MsgKind::Send) if m.sender == 0 => {
+                    if let Ok(id) = m.payload.parse::<u8>() {
+                        self.unanswered.remove(&m.next.unwrap_or(AUTO));
+                        self.assigned(id, datetime);
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+
+        // a node asking for an ID is not a node yet
+        if let Some(id) = heard_from(line).filter(|id| *id != AUTO) {
+            let counted = received_from(line).is_some();
+            let node = self.node(id);
+            node.first_seen.get_or_insert(datetime);
+            node.last_seen = Some(datetime);
+            if counted {
+                node.messages += 1;
+            }
+            if let Some(MsgFields::Msg(m)) = &line.fields {
+                if m.command == C_INTERNAL && m.msg_type == I_SKETCH_NAME {
+                    self.presented(id, &m.payload, datetime);
+                }
+            }
+        }
+    }
+
+    fn presented(&mut self, id: u8, name: &str, datetime: DateTime<Local>) {
+        let node = self.node(id);
+        if node.sketches.iter().any(|s| s == name) {
+            return; //e.g. read and then forwarded to the controller
+        }
+        let known = node.sketches.last().cloned();
+        node.sketches.push(name.to_string());
+        if let Some(known) = known {
+            self.issues.push(IdIssue::SketchConflict {
+                id,
+                datetime,
+                known,
+                new: name.to_string(),
+            });
+        }
+    }
+
+    // ID of the node whose own log this is, if it logged one
+    pub fn local(&self) -> Option<u8> {
+        self.local
+    }
+
+    // every node ID seen, handed out or used
+    pub fn nodes(&self) -> &BTreeMap<u8, NodeRecord> {
+        &self.nodes
+    }
+
+    // problems in the order they were found
+    pub fn issues(&self) -> &[IdIssue] {
+        &self.issues
+    }
+
+    // IDs that look like they are used by more than one node
+    pub fn duplicates(&self) -> Vec<u8> {
+        let mut ids: Vec<u8> = self
+            .issues
+            .iter()
+            .filter_map(|issue| match issue {
+                IdIssue::Reassigned { id, .. } | IdIssue::SketchConflict { id, .. } => Some(*id),
+                _ => None,
+            })
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+}
+
+impl fmt::Display for IdAudit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>4} {:>8} {:>19} {:>19} {:>8}  sketch",
+            "id", "assigned", "first seen", "last seen", "messages"
+        )?;
+        for node in self.nodes.values() {
+            writeln!(f, "{}", node)?;
+        }
+        for issue in &self.issues {
+            writeln!(f, "{}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+
+    #[test]
+    fn test_gateway_log() {
+        let mut audit = IdAudit::new();
+        for line in [
+            "Oct 18 13:00:00 DEBUG TSF:MSG:READ,12-12-0,s=255,c=3,t=11,pt=0,l=7,sg=0:Outside",
+            "Oct 18 13:00:01 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5",
+            "Oct 18 13:05:00 DEBUG TSF:MSG:READ,255-255-0,s=255,c=3,t=3,pt=0,l=0,sg=0:",
+            "Oct 18 13:05:00 DEBUG TSF:MSG:SEND,0-0-255-255,s=255,c=3,t=4,pt=0,l=2,sg=0,ft=0,st=OK:12",
+            "Oct 18 13:05:02 DEBUG TSF:MSG:READ,12-12-0,s=255,c=3,t=11,pt=0,l=6,sg=0:Cellar",
+            "Oct 18 13:10:00 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.6",
+            // another sketch name on the same ID without an assignment
+            "Oct 18 13:11:00 DEBUG TSF:MSG:READ,12-12-0,s=255,c=3,t=11,pt=0,l=7,sg=0:Outside",
+            // a node behind repeater 5 that never gets an answer
+            "Oct 18 14:00:00 DEBUG TSF:MSG:READ,255-5-0,s=255,c=3,t=3,pt=0,l=0,sg=0:",
+            "Oct 18 14:00:02 DEBUG TSF:MSG:READ,255-5-0,s=255,c=3,t=3,pt=0,l=0,sg=0:",
+            "Oct 18 14:00:04 DEBUG TSF:MSG:READ,255-5-0,s=255,c=3,t=3,pt=0,l=0,sg=0:",
+            "Oct 18 14:00:06 DEBUG TSF:MSG:READ,255-5-0,s=255,c=3,t=3,pt=0,l=0,sg=0:",
+        ] {
+            audit.feed(&parse_log_line(line));
+        }
+
+        let node = &audit.nodes()[&12];
+        assert_eq!(node.assigned.len(), 1);
+        assert_eq!(node.messages, 5);
+
+        // the copy an MQTT gateway publishes is not another message
+        audit.feed(&parse_log_line(
+            "Oct 18 14:01:00 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.7",
+        ));
+        audit.feed(&parse_log_line(
+            "Oct 18 14:01:00 DEBUG GWT:TPS:TOPIC=mygateway1-out/12/1/1/0/0,MSG SENT",
+        ));
+        let node = &audit.nodes()[&12];
+        assert_eq!(node.messages, 6);
+        assert_eq!(node.sketches, vec!["Cellar", "Outside"]);
+        assert!(!audit.nodes().contains_key(&255));
+
+        let issues = audit.issues();
+        assert_eq!(issues.len(), 3);
+        assert!(matches!(issues[0], IdIssue::Reassigned { id: 12, .. }));
+        assert!(
+            matches!(&issues[1], IdIssue::SketchConflict { id: 12, known, .. } if known == "Cellar")
+        );
+        assert!(matches!(
+            issues[2],
+            IdIssue::RepeatedRequests {
+                via: 5,
+                count: 3,
+                ..
+            }
+        ));
+        assert_eq!(audit.duplicates(), vec![12]);
+        assert!(issues[2].to_string().ends_with("via node 5"));
+    }
+
+    #[test]
+    fn test_in_use() {
+        let lines = [
+            "Oct 10 13:00:00 DEBUG TSF:MSG:READ,7-7-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5",
+            "Oct 18 13:05:00 DEBUG TSF:MSG:READ,255-255-0,s=255,c=3,t=3,pt=0,l=0,sg=0:",
+            "Oct 18 13:05:00 DEBUG TSF:MSG:SEND,0-0-255-255,s=255,c=3,t=4,pt=0,l=2,sg=0,ft=0,st=OK:7",
+        ];
+        // node 7 was last heard a week before
+        let mut audit = IdAudit::new();
+        let mut month = IdAudit::with_limits(3, Duration::days(30));
+        for line in lines {
+            audit.feed(&parse_log_line(line));
+            month.feed(&parse_log_line(line));
+        }
+        assert_eq!(audit.nodes()[&7].assigned.len(), 1);
+        assert!(audit.issues().is_empty());
+        assert!(matches!(
+            month.issues(),
+            [IdIssue::Reassigned { id: 7, .. }]
+        ));
+    }
+
+    #[test]
+    fn test_node_log() {
+        let mut audit = IdAudit::new();
+        for line in [
+            "Oct 18 13:00:00 DEBUG TSM:ID",
+            "Oct 18 13:00:00 DEBUG TSF:MSG:SEND,255-255-0-0,s=255,c=3,t=3,pt=0,l=0,sg=0,ft=0,st=OK:",
+            "Oct 18 13:00:01 DEBUG TSF:MSG:READ,0-0-255,s=255,c=3,t=4,pt=0,l=2,sg=0:12",
+            "Oct 18 13:00:01 DEBUG TSF:SID:OK,ID=12",
+            "Oct 18 13:00:01 DEBUG TSM:ID:FAIL,ID=12",
+            "Oct 18 13:00:02 DEBUG TSM:READY:ID=12,PAR=0,DIS=1",
+        ] {
+            audit.feed(&parse_log_line(line));
+        }
+        assert_eq!(audit.local(), Some(12));
+        assert_eq!(audit.nodes()[&12].assigned.len(), 1);
+        assert_eq!(
+            audit.issues(),
+            &[IdIssue::VerificationFailed {
+                id: 12,
+                datetime: parse_log_line("Oct 18 13:00:01 DEBUG TSM:ID")
+                    .datetime
+                    .unwrap()
+            }]
+        );
+        assert!(audit.duplicates().is_empty());
+    }
+}
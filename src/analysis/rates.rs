@@ -0,0 +1,431 @@
+// message rates per node and per node and type, to catch sketches spamming
+// the network and nodes that suddenly go quiet
+//
+// messages are counted per minute; each rate learns a baseline (a moving
+// average of its messages per minute) and once it has seen warmup minutes
+// a closed minute is checked for
+// - a burst: one minute far above the baseline
+// - a flood: the last window of minutes far above the baseline
+// - a drop: the last window far below the baseline
+// minutes of a flood are not learned from, so a flood that goes on stays one.
+// burst and flood alerts carry the first lines of the offending minute; the
+// latest MAX_ALERTS alerts are kept
+//
+// counted are the messages the gateway reads from nodes (and serial lines of
+// controller logs with a time), so a message forwarded to the controller is
+// not counted twice. minutes are closed as later lines arrive, or by check
+// with the current time when following a log
+use crate::protocol::{command_name, type_name, MsgKind};
+use crate::{LogLine, MsgFields};
+use chrono::{DateTime, Local, TimeZone};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+
+const MAX_LINES: usize = 10; //offending lines kept per minute
+const MAX_ALERTS: usize = 1000;
+
+// a node's messages, all of them or those of one command and type
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RateKey {
+    pub node: u8,
+    pub command_type: Option<(u8, u8)>,
+}
+
+impl fmt::Display for RateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "node {}", self.node)?;
+        if let Some((command, msg_type)) = self.command_type {
+            write!(
+                f,
+                " {} {}",
+                command_name(command).unwrap_or_else(|| command.to_string()),
+                type_name(command, msg_type).unwrap_or_else(|| msg_type.to_string())
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateConfig {
+    pub window: usize,      //minutes a flood or drop is judged over
+    pub warmup: usize,      //minutes learned before anything is flagged
+    pub smoothing: usize,   //minutes the moving average roughly spans
+    pub burst_factor: f64,  //one minute this many times the baseline
+    pub flood_factor: f64,  //the window this many times the baseline
+    pub drop_fraction: f64, //the window below this fraction of the baseline
+    pub min_count: usize,   //fewer messages than this are never a burst, flood or drop
+}
+
+impl Default for RateConfig {
+    fn default() -> Self {
+        RateConfig {
+            window: 5,
+            warmup: 30,
+            smoothing: 60,
+            burst_factor: 5.0,
+            flood_factor: 3.0,
+            drop_fraction: 0.2,
+            min_count: 10,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anomaly {
+    Burst,
+    Flood,
+    Drop,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateAlert {
+    pub key: RateKey,
+    pub anomaly: Anomaly,
+    pub minute: DateTime<Local>, //start of the minute that raised it
+    pub count: usize,            //messages in that minute (burst) or the window
+    pub baseline: f64,           //messages per minute before
+    pub lines: Vec<String>,
+}
+
+impl fmt::Display for RateAlert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self.anomaly {
+            Anomaly::Burst => "burst",
+            Anomaly::Flood => "flood",
+            Anomaly::Drop => "drop",
+        };
+        write!(
+            f,
+            "{} {} {}: {} messages, usually {:.1}/min",
+            self.minute.format("%F %H:%M"),
+            self.key,
+            what,
+            self.count,
+            self.baseline
+        )?;
+        for line in &self.lines {
+            write!(f, "\n    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Rate {
+    minute: i64, //minute being counted, minutes since the epoch
+    count: usize,
+    lines: Vec<String>,
+    window: VecDeque<usize>, //counts of the last closed minutes
+    baseline: f64,
+    learned: usize,
+    flooding: bool,
+    dropped: bool,
+}
+
+fn minute_start(minute: i64) -> DateTime<Local> {
+    Local.timestamp_opt(minute * 60, 0).unwrap()
+}
+
+impl Rate {
+    fn window_count(&self) -> usize {
+        self.window.iter().sum()
+    }
+
+    // close the minute being counted and the empty ones up to minute
+    fn advance(
+        &mut self,
+        key: RateKey,
+        minute: i64,
+        config: &RateConfig,
+        alerts: &mut Vec<RateAlert>,
+    ) {
+        let alpha = 2.0 / (config.smoothing as f64 + 1.0);
+        // empty minutes after a window do not change the outcome, only the baseline
+        let checked = (minute - self.minute).min(config.window as i64 + 1);
+        for step in 0..checked {
+            let count = if step == 0 { self.count } else { 0 };
+            let lines = if step == 0 {
+                std::mem::take(&mut self.lines)
+            } else {
+                Vec::new()
+            };
+            self.close(key, self.minute + step, count, lines, alpha, config, alerts);
+        }
+        let skipped = minute - self.minute - checked;
+        if skipped > 0 && !self.flooding {
+            self.baseline *= (1.0 - alpha).powi(skipped.min(i32::MAX as i64) as i32);
+            self.learned += skipped as usize;
+        }
+        self.minute = minute;
+        self.count = 0;
+        self.lines.clear();
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn close(
+        &mut self,
+        key: RateKey,
+        minute: i64,
+        count: usize,
+        lines: Vec<String>,
+        alpha: f64,
+        config: &RateConfig,
+        alerts: &mut Vec<RateAlert>,
+    ) {
+        self.window.push_back(count);
+        if self.window.len() > config.window {
+            self.window.pop_front();
+        }
+        let baseline = self.baseline;
+        let mut alert = |anomaly, count, lines| {
+            alerts.push(RateAlert {
+                key,
+                anomaly,
+                minute: minute_start(minute),
+                count,
+                baseline,
+                lines,
+            })
+        };
+
+        if self.learned >= config.warmup && self.window.len() == config.window {
+            let window = self.window_count();
+            let expected = baseline * config.window as f64;
+            let flooding =
+                window >= config.min_count && window as f64 >= config.flood_factor * expected;
+            if flooding && !self.flooding {
+                alert(Anomaly::Flood, window, lines);
+            } else if !flooding
+                && count >= config.min_count
+                && count as f64 >= config.burst_factor * baseline
+            {
+                alert(Anomaly::Burst, count, lines);
+            }
+            self.flooding = flooding;
+
+            let dropped = expected >= config.min_count as f64
+                && (window as f64) <= config.drop_fraction * expected;
+            if dropped && !self.dropped {
+                alert(Anomaly::Drop, window, Vec::new());
+            }
+            self.dropped = dropped;
+        }
+
+        if !self.flooding {
+            self.baseline = if self.learned == 0 {
+                count as f64
+            } else {
+                alpha * count as f64 + (1.0 - alpha) * self.baseline
+            };
+            self.learned += 1;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RateMonitor {
+    config: RateConfig,
+    rates: BTreeMap<RateKey, Rate>,
+    alerts: Vec<RateAlert>,
+    reported: usize, //alerts already returned by check
+}
+
+impl RateMonitor {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_config(config: RateConfig) -> Self {
+        RateMonitor {
+            config,
+            ..Default::default()
+        }
+    }
+
+    // lines without a time (e.g. serial gateway logs) are ignored
+    pub fn feed(&mut self, line: &LogLine) {
+        let datetime = match line.datetime {
+            Some(dt) => dt,
+            None => return,
+        };
+        let m = match &line.fields {
+            Some(MsgFields::Msg(m)) if m.sender != 0 => match m.kind {
+                MsgKind::Read | MsgKind::Serial => m,
+                _ => return,
+            },
+            _ => return,
+        };
+        let minute = datetime.timestamp().div_euclid(60);
+        for command_type in [None, Some((m.command, m.msg_type))] {
+            let key = RateKey {
+                node: m.sender,
+                command_type,
+            };
+            let rate = self.rates.entry(key).or_insert_with(|| Rate {
+                minute,
+                ..Default::default()
+            });
+            if minute > rate.minute {
+                rate.advance(key, minute, &self.config, &mut self.alerts);
+            }
+            rate.count += 1;
+            if rate.lines.len() < MAX_LINES {
+                rate.lines.push(line.to_string());
+            }
+        }
+        self.forget();
+    }
+
+    // drop the oldest alerts, so a followed log does not grow them for good
+    fn forget(&mut self) {
+        if let Some(excess) = self.alerts.len().checked_sub(MAX_ALERTS) {
+            self.alerts.drain(..excess);
+            self.reported = self.reported.saturating_sub(excess);
+        }
+    }
+
+    // close the minutes before now, for rates that have not seen a line since
+    pub fn advance(&mut self, now: DateTime<Local>) {
+        let minute = now.timestamp().div_euclid(60);
+        for (key, rate) in self.rates.iter_mut() {
+            if minute > rate.minute {
+                rate.advance(*key, minute, &self.config, &mut self.alerts);
+            }
+        }
+        self.forget();
+    }
+
+    // alerts raised since the last check, for live alerts
+    pub fn check(&mut self, now: DateTime<Local>) -> Vec<RateAlert> {
+        self.advance(now);
+        let new = self.alerts[self.reported..].to_vec();
+        self.reported = self.alerts.len();
+        new
+    }
+
+    // the latest alerts raised
+    pub fn alerts(&self) -> &[RateAlert] {
+        &self.alerts
+    }
+
+    // learned messages per minute of each rate
+    pub fn baselines(&self) -> Vec<(RateKey, f64)> {
+        self.rates.iter().map(|(k, r)| (*k, r.baseline)).collect()
+    }
+}
+
+impl fmt::Display for RateMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (key, baseline) in self.baselines() {
+            writeln!(f, "{:40} {:>8.2}/min", key.to_string(), baseline)?;
+        }
+        for alert in &self.alerts {
+            writeln!(f, "{}", alert)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+    use chrono::Duration;
+
+    fn read(monitor: &mut RateMonitor, at: DateTime<Local>, sensor: u8, msg_type: u8) {
+        let line = format!(
+            "{} DEBUG TSF:MSG:READ,12-12-0,s={},c=1,t={},pt=7,l=4,sg=0:21.5",
+            at.format("%b %d %H:%M:%S"),
+            sensor,
+            msg_type
+        );
+        monitor.feed(&parse_log_line(&line));
+    }
+
+    #[test]
+    fn test_rates() {
+        let start = parse_log_line("Oct 18 12:00:00 DEBUG TSM:READY:ID=0,PAR=0,DIS=0")
+            .datetime
+            .unwrap();
+        let minute = |m: i64| start + Duration::minutes(m);
+        let mut monitor = RateMonitor::new();
+        // 4 messages a minute for an hour
+        for m in 0..60 {
+            for s in 0..4 {
+                read(&mut monitor, minute(m) + Duration::seconds(10 * s), 1, 0);
+            }
+        }
+        assert!(monitor.check(minute(60)).is_empty());
+
+        // one bad minute
+        for s in 0..40 {
+            read(&mut monitor, minute(60) + Duration::seconds(s), 1, 0);
+        }
+        for m in 61..66 {
+            for s in 0..4 {
+                read(&mut monitor, minute(m) + Duration::seconds(10 * s), 1, 0);
+            }
+        }
+        let alerts = monitor.check(minute(66));
+        // once for the node and once for its C_SET V_TEMP
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].anomaly, Anomaly::Burst);
+        assert_eq!(alerts[0].minute, minute(60));
+        assert_eq!(alerts[0].count, 40);
+        assert_eq!(alerts[0].lines.len(), MAX_LINES);
+        assert_eq!(alerts[1].key.to_string(), "node 12 C_SET V_TEMP");
+
+        // a sketch stuck in a loop, V_TRIPPED has no baseline yet
+        for m in 66..76 {
+            for s in 0..30 {
+                read(&mut monitor, minute(m) + Duration::seconds(s), 2, 16);
+            }
+        }
+        let alerts = monitor.check(minute(76));
+        let floods: Vec<&RateAlert> = alerts
+            .iter()
+            .filter(|a| a.anomaly == Anomaly::Flood)
+            .collect();
+        assert_eq!(floods.len(), 1);
+        assert_eq!(floods[0].key.command_type, None);
+
+        // and then nothing at all
+        let alerts = monitor.check(minute(120));
+        assert!(alerts.iter().any(|a| a.anomaly == Anomaly::Drop
+            && a.key.command_type.is_none()
+            && a.minute < minute(90)));
+        // a drop is raised once
+        assert!(!monitor
+            .check(minute(121))
+            .iter()
+            .any(|a| a.key.command_type.is_none()));
+        assert!(monitor.to_string().contains("drop"));
+    }
+
+    #[test]
+    fn test_forget() {
+        let start = parse_log_line("Oct 18 12:00:00 DEBUG TSM:READY:ID=0,PAR=0,DIS=0")
+            .datetime
+            .unwrap();
+        let mut monitor = RateMonitor::new();
+        for m in 0..60 {
+            read(&mut monitor, start + Duration::minutes(m), 1, 0);
+        }
+        for s in 0..40 {
+            read(
+                &mut monitor,
+                start + Duration::minutes(60) + Duration::seconds(s),
+                1,
+                0,
+            );
+        }
+        let alert = monitor.check(start + Duration::minutes(61))[0].clone();
+        // a long followed log: most alerts already reported, a few new ones
+        monitor.alerts = vec![alert; MAX_ALERTS + 10];
+        monitor.reported = MAX_ALERTS;
+        monitor.advance(start + Duration::minutes(61));
+        assert_eq!(monitor.alerts().len(), MAX_ALERTS);
+        assert_eq!(monitor.check(start + Duration::minutes(61)).len(), 10);
+    }
+}
@@ -0,0 +1,378 @@
+// signal quality per radio link, to decide where a repeater would help
+//
+// signal reports come from two places:
+// - TSF:SIR lines, logged by a node (or the gateway) answering a signal report
+//   request; the link is between the node TSM:READY reports and the node that
+//   asked, or its parent
+// - I_SIGNAL_REPORT_RESPONSE (c=3,t=31) read by the gateway from a node; what
+//   the value is follows from the command character of the
+//   I_SIGNAL_REPORT_REQUEST (c=3,t=29) sent to that node before, the link is
+//   between the node and the hop that passed its response on
+//
+// the values of each link and kind are summed up as min/median/max, and a link
+// is flagged when its recent values got worse than its first ones or its RSSI
+// is weak
+use crate::protocol::{
+    MsgKind, SignalKind, C_INTERNAL, I_SIGNAL_REPORT_REQUEST, I_SIGNAL_REPORT_RESPONSE,
+};
+use crate::{LogLine, MsgFields};
+use chrono::{DateTime, Duration, Local};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+// (node, peer): the node that reported and the other end of the link
+pub type Link = (u8, u8);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SignalSample {
+    pub datetime: DateTime<Local>,
+    pub value: i16,
+}
+
+fn median(values: &[i16]) -> Option<i16> {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    sorted.get(sorted.len() / 2).copied()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LinkStats {
+    pub link: Link,
+    pub kind: SignalKind,
+    pub count: usize,
+    pub min: i16,
+    pub median: i16,
+    pub max: i16,
+    pub last: SignalSample,
+}
+
+impl fmt::Display for LinkStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>4} {:>4} {:16} {:>6} {:>6} {:>6} {:>6}",
+            self.link.0,
+            self.link.1,
+            self.kind.to_string(),
+            self.count,
+            self.min,
+            self.median,
+            self.max
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AlertReason {
+    Degraded { baseline: i16 }, //median of the first values
+    Weak,                       //RSSI below the weak limit
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignalAlert {
+    pub link: Link,
+    pub kind: SignalKind,
+    pub recent: i16, //median of the latest values
+    pub datetime: DateTime<Local>,
+    pub reason: AlertReason,
+}
+
+impl fmt::Display for SignalAlert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} link {}-{} {} ",
+            self.datetime.format("%F %H:%M:%S"),
+            self.link.0,
+            self.link.1,
+            self.kind
+        )?;
+        match self.reason {
+            AlertReason::Degraded { baseline } => {
+                write!(f, "degraded from ({}) to ({})", baseline, self.recent)
+            }
+            AlertReason::Weak => write!(f, "weak ({})", self.recent),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SignalQuality {
+    window: usize,
+    degradation: i16,
+    weak_rssi: i16,
+    local: Option<(u8, u8)>, //node and parent from TSM:READY
+    requester: Option<u8>,   //node whose request the next TSF:SIR answers
+    requests: HashMap<u8, SignalKind>,
+    samples: BTreeMap<(Link, SignalKind), Vec<SignalSample>>,
+}
+
+impl Default for SignalQuality {
+    fn default() -> Self {
+        SignalQuality::with_limits(10, 10, -90)
+    }
+}
+
+impl SignalQuality {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // window: values taken as the first and the recent ones; degradation: how
+    // much worse the recent median may get; weak_rssi: dBm below which a link
+    // is weak
+    pub fn with_limits(window: usize, degradation: i16, weak_rssi: i16) -> Self {
+        SignalQuality {
+            window: window.max(1),
+            degradation,
+            weak_rssi,
+            local: None,
+            requester: None,
+            requests: HashMap::new(),
+            samples: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, link: Link, kind: SignalKind, datetime: DateTime<Local>, value: i16) {
+        self.samples
+            .entry((link, kind))
+            .or_default()
+            .push(SignalSample { datetime, value });
+    }
+
+    // lines without a time (e.g. serial gateway logs) are ignored
+    pub fn feed(&mut self, line: &LogLine) {
+        let datetime = match line.datetime {
+            Some(dt) => dt,
+            None => return,
+        };
+        match &line.fields {
+            Some(MsgFields::Ready { id, parent, .. }) => self.local = Some((*id, *parent)),
+            Some(MsgFields::SignalReport { cmd, value }) => {
+                if let Some((id, parent)) = self.local {
+                    let peer = self.requester.take().unwrap_or(parent);
+                    self.add((id, peer), SignalKind::from_command(*cmd), datetime, *value);
+                }
+            }
+            Some(MsgFields::Msg(m)) if m.command == C_INTERNAL => match m.msg_type {
+                I_SIGNAL_REPORT_REQUEST => {
+                    let kind = SignalKind::from_command(m.payload.bytes().next().unwrap_or(0));
+                    match m.kind {
+                        // a request to this node, answered by the next TSF:SIR
+                        MsgKind::Read => self.requester = Some(m.sender),
+                        MsgKind::Send => {
+                            self.requests.insert(m.destination, kind);
+                        }
+                        // from the controller to the node
+                        MsgKind::MqttIn | MsgKind::SerialIn => {
+                            self.requests.insert(m.destination, kind);
+                        }
+                        MsgKind::Serial | MsgKind::MqttOut => {}
+                    }
+                }
+                I_SIGNAL_REPORT_RESPONSE if m.kind == MsgKind::Read => {
+                    if let Ok(value) = m.payload.parse::<i16>() {
+                        let kind = self
+                            .requests
+                            .remove(&m.sender)
+                            .unwrap_or(SignalKind::Other(0));
+                        let peer = if m.last == m.sender { 0 } else { m.last };
+                        self.add((m.sender, peer), kind, datetime, value);
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    // all values of a link and kind, in log order
+    pub fn samples(&self, link: Link, kind: SignalKind) -> &[SignalSample] {
+        self.samples
+            .get(&(link, kind))
+            .map_or(&[], |s| s.as_slice())
+    }
+
+    pub fn links(&self) -> Vec<LinkStats> {
+        self.samples
+            .iter()
+            .filter_map(|((link, kind), samples)| {
+                let values: Vec<i16> = samples.iter().map(|s| s.value).collect();
+                Some(LinkStats {
+                    link: *link,
+                    kind: *kind,
+                    count: values.len(),
+                    min: *values.iter().min()?,
+                    median: median(&values)?,
+                    max: *values.iter().max()?,
+                    last: *samples.last()?,
+                })
+            })
+            .collect()
+    }
+
+    // min, median and max of a link per period, e.g. per day
+    pub fn series(
+        &self,
+        link: Link,
+        kind: SignalKind,
+        period: Duration,
+    ) -> Vec<(DateTime<Local>, i16, i16, i16)> {
+        let samples = self.samples(link, kind);
+        let first = match samples.first() {
+            Some(s) => s.datetime,
+            None => return Vec::new(),
+        };
+        let period_ms = period.num_milliseconds().max(1);
+        let mut periods: BTreeMap<i64, Vec<i16>> = BTreeMap::new();
+        for sample in samples {
+            let index = (sample.datetime - first).num_milliseconds() / period_ms;
+            periods.entry(index).or_default().push(sample.value);
+        }
+        periods
+            .into_iter()
+            .filter_map(|(index, values)| {
+                Some((
+                    first + Duration::milliseconds(index * period_ms),
+                    *values.iter().min()?,
+                    median(&values)?,
+                    *values.iter().max()?,
+                ))
+            })
+            .collect()
+    }
+
+    // links that got worse or are weak, as of their last value
+    pub fn alerts(&self) -> Vec<SignalAlert> {
+        let mut alerts = Vec::new();
+        for ((link, kind), samples) in &self.samples {
+            let values: Vec<i16> = samples.iter().map(|s| s.value).collect();
+            let recent = match median(&values[values.len().saturating_sub(self.window)..]) {
+                Some(recent) => recent,
+                None => continue,
+            };
+            let datetime = samples.last().unwrap().datetime;
+            let mut alert = |reason| {
+                alerts.push(SignalAlert {
+                    link: *link,
+                    kind: *kind,
+                    recent,
+                    datetime,
+                    reason,
+                })
+            };
+            if values.len() >= 2 * self.window {
+                let baseline = median(&values[..self.window]).unwrap();
+                let worse = match kind.higher_is_better() {
+                    Some(true) => baseline - recent,
+                    Some(false) => recent - baseline,
+                    None => 0,
+                };
+                if worse >= self.degradation {
+                    alert(AlertReason::Degraded { baseline });
+                }
+            }
+            if matches!(kind, SignalKind::SendingRssi | SignalKind::ReceivingRssi)
+                && recent < self.weak_rssi
+            {
+                alert(AlertReason::Weak);
+            }
+        }
+        alerts
+    }
+}
+
+impl fmt::Display for SignalQuality {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>4} {:>4} {:16} {:>6} {:>6} {:>6} {:>6}",
+            "node", "peer", "kind", "count", "min", "median", "max"
+        )?;
+        for stats in self.links() {
+            writeln!(f, "{}", stats)?;
+        }
+        for alert in self.alerts() {
+            writeln!(f, "{}", alert)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+
+    #[test]
+    fn test_node_log() {
+        let mut signal = SignalQuality::with_limits(2, 10, -90);
+        for line in [
+            "Oct 18 13:00:00 DEBUG TSM:READY:ID=12,PAR=3,DIS=2",
+            "Oct 18 13:00:01 DEBUG TSF:SIR:CMD=83,RSSI=-60",
+            "Oct 18 13:10:01 DEBUG TSF:SIR:CMD=83,RSSI=-62",
+            "Oct 18 13:20:01 DEBUG TSF:SIR:CMD=115,REP=7",
+            // asked by node 5
+            "Oct 18 13:30:00 DEBUG TSF:MSG:READ,5-5-12,s=255,c=3,t=29,pt=0,l=1,sg=0:R",
+            "Oct 18 13:30:00 DEBUG TSF:SIR:CMD=82,RSSI=-95",
+            "Oct 18 14:00:01 DEBUG TSF:SIR:CMD=83,RSSI=-75",
+            "Oct 18 14:10:01 DEBUG TSF:SIR:CMD=83,RSSI=-77",
+        ] {
+            signal.feed(&parse_log_line(line));
+        }
+        let links = signal.links();
+        let summary: Vec<(Link, SignalKind, usize)> =
+            links.iter().map(|s| (s.link, s.kind, s.count)).collect();
+        assert_eq!(
+            summary,
+            vec![
+                ((12, 3), SignalKind::SendingRssi, 4),
+                ((12, 3), SignalKind::SendingSnr, 1),
+                ((12, 5), SignalKind::ReceivingRssi, 1),
+            ]
+        );
+        assert_eq!(
+            (links[0].min, links[0].median, links[0].max),
+            (-77, -62, -60)
+        );
+
+        let alerts = signal.alerts();
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].reason, AlertReason::Degraded { baseline: -60 });
+        assert_eq!(alerts[0].recent, -75);
+        assert_eq!(
+            (alerts[1].link, &alerts[1].reason),
+            ((12, 5), &AlertReason::Weak)
+        );
+
+        let hourly = signal.series((12, 3), SignalKind::SendingRssi, Duration::hours(1));
+        let medians: Vec<i16> = hourly.iter().map(|p| p.2).collect();
+        assert_eq!(medians, vec![-60, -75]);
+    }
+
+    #[test]
+    fn test_gateway_log() {
+        let mut signal = SignalQuality::new();
+        for line in [
+            "Oct 18 13:00:00 DEBUG TSM:READY:ID=0,PAR=0,DIS=0",
+            "Oct 18 13:00:01 DEBUG TSF:MSG:SEND,0-0-3-12,s=255,c=3,t=29,pt=0,l=1,sg=0,ft=0,st=OK:S",
+            "Oct 18 13:00:01 DEBUG TSF:MSG:READ,12-3-0,s=255,c=3,t=31,pt=3,l=2,sg=0:-71",
+            "Oct 18 13:00:02 DEBUG GWT:IMQ:TOPIC=mygateway1-in/7/255/3/0/29, MSG RECEIVED",
+            "Oct 18 13:00:02 DEBUG TSF:MSG:READ,7-7-0,s=255,c=3,t=31,pt=3,l=2,sg=0:42",
+        ] {
+            signal.feed(&parse_log_line(line));
+        }
+        let links: Vec<(Link, SignalKind, i16)> = signal
+            .links()
+            .iter()
+            .map(|s| (s.link, s.kind, s.median))
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                ((7, 0), SignalKind::Other(0), 42),
+                ((12, 3), SignalKind::SendingRssi, -71),
+            ]
+        );
+    }
+}
@@ -0,0 +1,174 @@
+// timeline of transport state machine (TSM) states per gateway session
+// shows how long the gateway spent in each state, e.g. in FAIL or FPAR after
+// radio trouble
+use super::format_duration;
+use crate::protocol::XportState;
+use crate::session::SessionTracker;
+use crate::LogLine;
+use chrono::{DateTime, Duration, Local};
+use std::collections::HashMap;
+use std::fmt;
+
+// one stay in a state
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateSpan {
+    pub state: XportState,
+    pub start: DateTime<Local>,
+    pub end: Option<DateTime<Local>>, //None while still in this state
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SessionTimeline {
+    pub start: Option<DateTime<Local>>,
+    pub last_seen: Option<DateTime<Local>>,
+    pub spans: Vec<StateSpan>,
+}
+
+impl SessionTimeline {
+    // duration of a span, an open span lasts until the last line of the session
+    pub fn span_duration(&self, span: &StateSpan) -> Duration {
+        match span.end.or(self.last_seen) {
+            Some(end) => end - span.start,
+            None => Duration::zero(),
+        }
+    }
+
+    // total time spent in each state during the session
+    pub fn time_in_states(&self) -> HashMap<XportState, Duration> {
+        let mut totals = HashMap::new();
+        for span in &self.spans {
+            *totals.entry(span.state).or_insert_with(Duration::zero) += self.span_duration(span);
+        }
+        totals
+    }
+
+    pub fn current_state(&self) -> Option<XportState> {
+        self.spans.last().map(|s| s.state)
+    }
+
+    fn enter(&mut self, state: XportState, when: DateTime<Local>) {
+        if self.current_state() == Some(state) {
+            return; //e.g. TSM:READY logged again without leaving READY
+        }
+        if let Some(last) = self.spans.last_mut() {
+            last.end = Some(when);
+        }
+        self.spans.push(StateSpan {
+            state,
+            start: when,
+            end: None,
+        });
+    }
+}
+
+impl fmt::Display for SessionTimeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.start {
+            Some(start) => writeln!(f, "Session started {}", start.format("%F %H:%M:%S"))?,
+            None => writeln!(f, "Session (start not in log)")?,
+        }
+        for span in &self.spans {
+            writeln!(
+                f,
+                "  {} {:10} {}{}",
+                span.start.format("%F %H:%M:%S"),
+                span.state.to_string(),
+                format_duration(self.span_duration(span)),
+                if span.end.is_none() { "+" } else { "" }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Timeline {
+    tracker: SessionTracker,
+    sessions: Vec<SessionTimeline>,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn feed(&mut self, line: &LogLine) {
+        let new_session = self.tracker.feed(line);
+        if new_session || self.sessions.is_empty() {
+            // close the previous session at its last line
+            if let Some(previous) = self.sessions.last_mut() {
+                if let (Some(span), Some(last)) = (previous.spans.last_mut(), previous.last_seen) {
+                    span.end = Some(last);
+                }
+            }
+            self.sessions.push(SessionTimeline {
+                start: if new_session { line.datetime } else { None },
+                ..Default::default()
+            });
+        }
+
+        let session = self.sessions.last_mut().unwrap();
+        let when = match line.datetime {
+            Some(dt) => dt,
+            None => return, //unparsed line, nothing to place on the timeline
+        };
+        session.last_seen = Some(when);
+        if let Some(state) = line.fields.as_ref().and_then(|f| f.xport_state()) {
+            session.enter(state, when);
+        }
+    }
+
+    pub fn sessions(&self) -> &[SessionTimeline] {
+        &self.sessions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+
+    #[test]
+    fn test_timeline() {
+        let mut timeline = Timeline::new();
+        for line in [
+            "Oct 18 13:00:00 INFO  Starting gateway...",
+            "Oct 18 13:00:00 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2",
+            "Oct 18 13:00:00 DEBUG TSM:INIT",
+            "Oct 18 13:00:01 DEBUG TSM:INIT:TSP OK",
+            "Oct 18 13:00:01 DEBUG TSM:READY:ID=0,PAR=0,DIS=0",
+            "Oct 18 13:10:01 DEBUG !TSM:FAIL:CNT=1",
+            "Oct 18 13:10:01 DEBUG TSM:FAIL:DIS",
+            "Oct 18 13:10:11 DEBUG TSM:FAIL:RE-INIT",
+            "Oct 18 13:10:11 DEBUG TSM:INIT",
+            "Oct 18 13:10:12 DEBUG TSM:READY:ID=0,PAR=0,DIS=0",
+            "Oct 18 13:20:12 DEBUG GWT:TSA:C=0,CONNECTED",
+            "Oct 18 14:00:00 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2",
+            "Oct 18 14:00:00 DEBUG TSM:INIT",
+        ] {
+            timeline.feed(&parse_log_line(line));
+        }
+
+        let sessions = timeline.sessions();
+        assert_eq!(sessions.len(), 2);
+
+        let states: Vec<XportState> = sessions[0].spans.iter().map(|s| s.state).collect();
+        assert_eq!(
+            states,
+            vec![
+                XportState::Init,
+                XportState::Ready,
+                XportState::Failure,
+                XportState::Init,
+                XportState::Ready
+            ]
+        );
+        let totals = sessions[0].time_in_states();
+        assert_eq!(totals[&XportState::Failure], Duration::seconds(10));
+        assert_eq!(totals[&XportState::Init], Duration::seconds(2));
+        // open READY span is closed at the last line of the session
+        assert_eq!(totals[&XportState::Ready], Duration::seconds(600 + 600));
+
+        assert_eq!(sessions[1].current_state(), Some(XportState::Init));
+    }
+}
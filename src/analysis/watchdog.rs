@@ -0,0 +1,254 @@
+// finds nodes that went silent, usually because of a dead battery
+//
+// learns how often each node is heard from (values, heartbeats, battery
+// levels, ...) and flags a node once its silence is much longer than that.
+// messages a node sends within a few seconds of each other are one report,
+// the interval is the median time between the starts of its last reports
+//
+// works on a whole log, checked at the time of its last line, and live, with
+// check called now and then with the current time
+use super::{format_duration, heard_from};
+use crate::LogLine;
+use chrono::{DateTime, Duration, Local};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+
+const BURST_SECS: i64 = 5; //messages closer than this are one report
+const INTERVALS: usize = 16; //intervals kept to learn from
+
+#[derive(Clone, Debug)]
+struct NodeTraffic {
+    last_seen: DateTime<Local>,
+    last_message: String,
+    report_start: DateTime<Local>,
+    intervals: VecDeque<Duration>,
+    alerted: bool,
+}
+
+impl NodeTraffic {
+    fn expected_interval(&self) -> Option<Duration> {
+        let mut sorted: Vec<Duration> = self.intervals.iter().copied().collect();
+        sorted.sort();
+        sorted.get(sorted.len() / 2).copied()
+    }
+}
+
+// what is known about one node at a point in time
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeStatus {
+    pub node: u8,
+    pub last_seen: DateTime<Local>,
+    pub expected: Option<Duration>, //None until two reports have been seen
+    pub silent: Duration,
+    pub missing: bool,
+    pub last_message: String,
+}
+
+impl fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>4} {:8} {} silent {:>9} expected {:>9}  {}",
+            self.node,
+            if self.missing { "MISSING" } else { "ok" },
+            self.last_seen.format("%F %H:%M:%S"),
+            format_duration(self.silent),
+            self.expected.map_or("-".to_string(), format_duration),
+            self.last_message
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct Watchdog {
+    factor: i32,
+    minimum: Duration,
+    nodes: BTreeMap<u8, NodeTraffic>,
+    last_time: Option<DateTime<Local>>,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog::with_threshold(3, Duration::minutes(5))
+    }
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // a node is missing once it has been silent for factor times its interval,
+    // and for at least minimum
+    pub fn with_threshold(factor: i32, minimum: Duration) -> Self {
+        Watchdog {
+            factor,
+            minimum,
+            nodes: BTreeMap::new(),
+            last_time: None,
+        }
+    }
+
+    // lines without a time (e.g. serial gateway logs) are ignored
+    pub fn feed(&mut self, line: &LogLine) {
+        let when = match line.datetime {
+            Some(dt) => dt,
+            None => return,
+        };
+        self.last_time = Some(when);
+        let id = match heard_from(line) {
+            Some(id) => id,
+            None => return,
+        };
+        let message = line.msg.clone();
+        match self.nodes.get_mut(&id) {
+            Some(node) => {
+                if when - node.last_seen > Duration::seconds(BURST_SECS) {
+                    node.intervals.push_back(when - node.report_start);
+                    if node.intervals.len() > INTERVALS {
+                        node.intervals.pop_front();
+                    }
+                    node.report_start = when;
+                }
+                node.last_seen = when;
+                node.last_message = message;
+                node.alerted = false;
+            }
+            None => {
+                self.nodes.insert(
+                    id,
+                    NodeTraffic {
+                        last_seen: when,
+                        last_message: message,
+                        report_start: when,
+                        intervals: VecDeque::new(),
+                        alerted: false,
+                    },
+                );
+            }
+        }
+    }
+
+    // time of the last line fed, "now" for a log that is not live
+    pub fn last_time(&self) -> Option<DateTime<Local>> {
+        self.last_time
+    }
+
+    fn status(&self, id: u8, node: &NodeTraffic, now: DateTime<Local>) -> NodeStatus {
+        let expected = node.expected_interval();
+        let silent = now - node.last_seen;
+        let missing = match expected {
+            Some(interval) => silent > (interval * self.factor).max(self.minimum),
+            None => false, //no idea how often it reports
+        };
+        NodeStatus {
+            node: id,
+            last_seen: node.last_seen,
+            expected,
+            silent,
+            missing,
+            last_message: node.last_message.clone(),
+        }
+    }
+
+    // all nodes heard from, as of now
+    pub fn nodes(&self, now: DateTime<Local>) -> Vec<NodeStatus> {
+        self.nodes
+            .iter()
+            .map(|(id, node)| self.status(*id, node, now))
+            .collect()
+    }
+
+    // nodes that are missing as of now
+    pub fn missing(&self, now: DateTime<Local>) -> Vec<NodeStatus> {
+        self.nodes(now).into_iter().filter(|s| s.missing).collect()
+    }
+
+    // nodes that went missing since the last check, for live alerts: a node
+    // is reported once until it is heard from again
+    pub fn check(&mut self, now: DateTime<Local>) -> Vec<NodeStatus> {
+        let missing = self.missing(now);
+        missing
+            .into_iter()
+            .filter(|status| {
+                let node = self.nodes.get_mut(&status.node).unwrap();
+                !std::mem::replace(&mut node.alerted, true)
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Watchdog {
+    // the nodes as of the last line
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(now) = self.last_time {
+            for status in self.nodes(now) {
+                writeln!(f, "{}", status)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+    use crate::simulator::{SimConfig, Simulator};
+
+    #[test]
+    fn test_watchdog() {
+        let mut watchdog = Watchdog::new();
+        for line in [
+            "Oct 18 13:00:00 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5",
+            "Oct 18 13:00:00 DEBUG TSF:MSG:READ,12-12-0,s=255,c=3,t=0,pt=1,l=2,sg=0:87",
+            "Oct 18 13:00:30 DEBUG TSF:MSG:READ,7-7-0,s=1,c=1,t=0,pt=7,l=4,sg=0:19.0",
+            "Oct 18 13:10:00 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.6",
+            "Oct 18 13:10:30 DEBUG TSF:MSG:READ,7-7-0,s=1,c=1,t=0,pt=7,l=4,sg=0:19.1",
+            "Oct 18 13:20:00 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.7",
+            "Oct 18 13:20:00 DEBUG TSF:MSG:SEND,0-0-7-7,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=0,st=OK:1",
+            "Oct 18 13:40:00 DEBUG GWT:IMQ:TOPIC=mysensors-in/7/2/1/0/2, MSG RECEIVED",
+            "Oct 18 13:30:00 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.8",
+            "Oct 18 13:40:00 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.9",
+            "Oct 18 13:50:00 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:22.0",
+        ] {
+            watchdog.feed(&parse_log_line(line));
+        }
+        let now = watchdog.last_time().unwrap();
+        let nodes = watchdog.nodes(now);
+        assert_eq!(nodes.len(), 2);
+        // sends to a node do not count as hearing from it
+        let seven = &nodes[0];
+        assert_eq!(seven.node, 7);
+        assert_eq!(seven.expected, Some(Duration::minutes(10)));
+        assert_eq!(seven.silent, Duration::minutes(39) + Duration::seconds(30));
+        assert!(seven.missing);
+        assert!(seven.last_message.ends_with("payload (19.1)"));
+        assert!(!nodes[1].missing);
+
+        assert_eq!(watchdog.check(now).len(), 1);
+        assert_eq!(watchdog.check(now + Duration::minutes(1)).len(), 0);
+        watchdog.feed(&parse_log_line(
+            "Oct 18 13:51:00 DEBUG TSF:MSG:READ,7-7-0,s=1,c=1,t=0,pt=7,l=4,sg=0:19.2",
+        ));
+        assert_eq!(watchdog.check(now + Duration::hours(3)).len(), 2);
+    }
+
+    #[test]
+    fn test_simulated() {
+        let config = SimConfig::default();
+        let nodes = config.nodes as usize;
+        let mut watchdog = Watchdog::new();
+        // the lines are read back with an inferred year, not that of the start
+        let mut end = None;
+        for line in Simulator::new(config) {
+            let line = parse_log_line(&line);
+            end = line.datetime.or(end);
+            watchdog.feed(&line);
+        }
+        let end = end.unwrap();
+        assert_eq!(watchdog.nodes(end).len(), nodes);
+        assert!(watchdog.missing(end).is_empty());
+        assert_eq!(watchdog.missing(end + Duration::days(1)).len(), nodes);
+    }
+}
@@ -0,0 +1,532 @@
+// SQLite archive of parsed logs, to keep months of gateway history queryable
+//
+// every line goes into lines, with its decoded transport message (if any) in
+// messages; sessions are the gateway runs (see session.rs) and nodes sums up
+// what was heard from each node. lines are kept per source (the file name) at
+// their position in it, so the same text in two logs is archived twice. a log
+// can be imported again after it grew: sources remembers how many lines were
+// read and the first of them, and if the log still starts with that line the
+// lines already archived are skipped; otherwise it was rotated and replaced by
+// a new file, whose lines are all added
+//
+// syslog dates have no year, the timestamps are stored with the year from
+// merge::infer_year
+//
+// the stored QUERIES answer the usual questions; anything else is plain SQL
+// against the schema below
+use crate::merge::infer_year;
+use crate::protocol::{
+    command_name, type_name, MsgKind, TransportMsg, C_INTERNAL, C_PRESENTATION, I_SKETCH_NAME,
+    I_SKETCH_VERSION,
+};
+use crate::session::SessionTracker;
+use crate::{BoxError, LogLine, MsgFields};
+use chrono::Local;
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::fmt;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sources (
+    name TEXT PRIMARY KEY,
+    lines INTEGER NOT NULL, -- read so far, over all files of this name
+    first_raw TEXT NOT NULL, -- first line of the file last imported
+    start INTEGER NOT NULL -- position of that line
+);
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY,
+    source TEXT NOT NULL,
+    start_line INTEGER NOT NULL UNIQUE,
+    started INTEGER -- unix time, NULL for logs without time
+);
+CREATE TABLE IF NOT EXISTS lines (
+    id INTEGER PRIMARY KEY,
+    source TEXT NOT NULL,
+    session INTEGER REFERENCES sessions(id),
+    timestamp INTEGER, -- unix time, NULL for logs without time
+    level TEXT,
+    system TEXT,
+    subsystem TEXT,
+    status TEXT NOT NULL, -- OK, ERROR (!) or UNKNOWN (?)
+    msg TEXT NOT NULL,
+    raw TEXT NOT NULL,
+    position INTEGER NOT NULL, -- in the source, counted over its imports
+    UNIQUE (source, position)
+);
+CREATE INDEX IF NOT EXISTS lines_timestamp ON lines (timestamp);
+CREATE TABLE IF NOT EXISTS messages (
+    line INTEGER PRIMARY KEY REFERENCES lines(id),
+    kind TEXT NOT NULL,
+    sender INTEGER NOT NULL,
+    last INTEGER NOT NULL,
+    next INTEGER,
+    destination INTEGER NOT NULL,
+    sensor INTEGER NOT NULL,
+    command INTEGER NOT NULL,
+    command_name TEXT,
+    type INTEGER NOT NULL,
+    type_name TEXT,
+    ack INTEGER,
+    signed INTEGER NOT NULL,
+    failures INTEGER,
+    send_ok INTEGER,
+    payload TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_sender ON messages (sender);
+CREATE INDEX IF NOT EXISTS messages_destination ON messages (destination);
+CREATE TABLE IF NOT EXISTS nodes (
+    id INTEGER PRIMARY KEY,
+    first_seen INTEGER,
+    last_seen INTEGER,
+    messages INTEGER NOT NULL DEFAULT 0,
+    sketch_name TEXT,
+    sketch_version TEXT,
+    library_version TEXT
+);
+";
+
+// name, description and SQL of the stored queries
+pub const QUERIES: &[(&str, &str, &str)] = &[
+    (
+        "nodes",
+        "nodes with what they presented and when they were heard from",
+        "SELECT id AS node, sketch_name, sketch_version, library_version, messages,
+                datetime(first_seen, 'unixepoch', 'localtime') AS first_seen,
+                datetime(last_seen, 'unixepoch', 'localtime') AS last_seen
+         FROM nodes ORDER BY id",
+    ),
+    (
+        "daily",
+        "messages received per node and day",
+        "SELECT date(l.timestamp, 'unixepoch', 'localtime') AS day, m.sender AS node,
+                count(*) AS messages
+         FROM messages m JOIN lines l ON l.id = m.line
+         WHERE m.kind = 'Read' AND l.timestamp IS NOT NULL
+         GROUP BY day, node ORDER BY day, node",
+    ),
+    (
+        "nacks",
+        "sends per destination and how many were not acked by the next hop",
+        "SELECT destination AS node, count(*) AS sent,
+                sum(send_ok = 0) AS nacks,
+                round(100.0 * sum(send_ok = 0) / count(*), 1) AS nack_percent
+         FROM messages WHERE kind = 'Send'
+         GROUP BY destination ORDER BY nacks DESC, node",
+    ),
+    (
+        "sessions",
+        "gateway runs with their length and number of lines",
+        "SELECT s.id AS session, s.source,
+                datetime(s.started, 'unixepoch', 'localtime') AS started,
+                count(l.id) AS lines,
+                max(l.timestamp) - min(l.timestamp) AS seconds
+         FROM sessions s LEFT JOIN lines l ON l.session = s.id
+         GROUP BY s.id ORDER BY s.id",
+    ),
+    (
+        "errors",
+        "failed and unknown status messages per system and subsystem",
+        "SELECT system, subsystem, status, count(*) AS lines
+         FROM lines WHERE status != 'OK'
+         GROUP BY system, subsystem, status ORDER BY lines DESC",
+    ),
+    (
+        "silent",
+        "nodes not heard from during the last day of the archive",
+        "SELECT id AS node, sketch_name,
+                datetime(last_seen, 'unixepoch', 'localtime') AS last_seen
+         FROM nodes
+         WHERE last_seen < (SELECT max(timestamp) FROM lines) - 86400
+         ORDER BY last_seen",
+    ),
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImportStats {
+    pub lines: usize,
+    pub inserted: usize,
+    pub duplicates: usize,
+}
+
+impl fmt::Display for ImportStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} lines, {} new, {} already archived",
+            self.lines, self.inserted, self.duplicates
+        )
+    }
+}
+
+// columns and rows of a query, values as text
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl fmt::Display for QueryResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut widths: Vec<usize> = self.columns.iter().map(|c| c.len()).collect();
+        for row in &self.rows {
+            for (width, value) in widths.iter_mut().zip(row) {
+                *width = (*width).max(value.chars().count());
+            }
+        }
+        let line = |f: &mut fmt::Formatter, values: &[String]| -> fmt::Result {
+            let cells: Vec<String> = values
+                .iter()
+                .zip(&widths)
+                .map(|(v, w)| format!("{:w$}", v, w = *w))
+                .collect();
+            writeln!(f, "{}", cells.join("  ").trim_end())
+        };
+        line(f, &self.columns)?;
+        for row in &self.rows {
+            line(f, row)?;
+        }
+        Ok(())
+    }
+}
+
+fn text(value: ValueRef) -> String {
+    match value {
+        ValueRef::Null => String::new(),
+        ValueRef::Integer(i) => i.to_string(),
+        ValueRef::Real(r) => r.to_string(),
+        ValueRef::Text(t) | ValueRef::Blob(t) => String::from_utf8_lossy(t).into_owned(),
+    }
+}
+
+pub struct Archive {
+    conn: Connection,
+}
+
+impl Archive {
+    // open or create the archive at path
+    pub fn open(path: &str) -> Result<Archive, BoxError> {
+        let conn = Connection::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        Archive::with_connection(conn)
+    }
+
+    pub fn open_in_memory() -> Result<Archive, BoxError> {
+        Archive::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Archive, BoxError> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Archive { conn })
+    }
+
+    // archive the lines of one log (raw text and parsed line, in order);
+    // source names the log, e.g. its file name
+    pub fn import(
+        &mut self,
+        source: &str,
+        lines: impl IntoIterator<Item = Result<(String, LogLine), BoxError>>,
+    ) -> Result<ImportStats, BoxError> {
+        let tx = self.conn.transaction()?;
+        let now = Local::now();
+        let mut stats = ImportStats::default();
+        let mut sessions = SessionTracker::new();
+        let known: Option<(i64, String, i64)> = tx
+            .query_row(
+                "SELECT lines, first_raw, start FROM sources WHERE name = ?1",
+                [source],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        // position of the first line of this import, and the lines to skip
+        // if it is the same log
+        let (mut start, mut skip) = match &known {
+            Some((lines, _, start)) => (*start, lines - start),
+            None => (0, 0),
+        };
+        // lines continue the last session of an earlier import of the log
+        let mut session: Option<i64> = tx
+            .query_row(
+                "SELECT max(id) FROM sessions WHERE source = ?1",
+                [source],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+
+        for (index, line) in lines.into_iter().enumerate() {
+            let (raw, line) = line?;
+            stats.lines += 1;
+            if index == 0 {
+                match &known {
+                    Some((_, first_raw, _)) if *first_raw == raw => {}
+                    // a new file: after everything read from the source so far
+                    Some((lines, _, _)) => (start, skip) = (*lines, 0),
+                    None => {}
+                }
+                tx.execute(
+                    "INSERT INTO sources (name, lines, first_raw, start) VALUES (?1, ?2, ?3, ?2)
+                     ON CONFLICT (name) DO UPDATE SET first_raw = ?3, start = ?2",
+                    params![source, start, raw],
+                )?;
+            }
+            let new_session = sessions.feed(&line);
+            if (index as i64) < skip {
+                stats.duplicates += 1;
+                continue;
+            }
+            let position = start + index as i64;
+            let timestamp = line.datetime.map(|dt| infer_year(dt, now).timestamp());
+            tx.execute(
+                "INSERT INTO lines
+                 (source, session, timestamp, level, system, subsystem, status, msg, raw, position)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    source,
+                    session,
+                    timestamp,
+                    line.level,
+                    line.system,
+                    line.subsystem,
+                    format!("{:?}", line.status),
+                    line.msg,
+                    raw,
+                    position
+                ],
+            )?;
+            let id = tx.last_insert_rowid();
+
+            if new_session {
+                tx.execute(
+                    "INSERT INTO sessions (source, start_line, started) VALUES (?1, ?2, ?3)",
+                    params![source, id, timestamp],
+                )?;
+                session = Some(tx.last_insert_rowid());
+                tx.execute(
+                    "UPDATE lines SET session = ?1 WHERE id = ?2",
+                    params![session, id],
+                )?;
+            }
+            stats.inserted += 1;
+            if let Some(MsgFields::Msg(m)) = &line.fields {
+                insert_message(&tx, id, m)?;
+                update_node(&tx, m, timestamp)?;
+            }
+        }
+        tx.execute(
+            "UPDATE sources SET lines = max(lines, ?1) WHERE name = ?2",
+            params![start + stats.lines as i64, source],
+        )?;
+        tx.commit()?;
+        Ok(stats)
+    }
+
+    pub fn query(&self, sql: &str) -> Result<QueryResult, BoxError> {
+        let mut statement = self.conn.prepare(sql)?;
+        let columns: Vec<String> = statement
+            .column_names()
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        let count = columns.len();
+        let rows = statement
+            .query_map([], |row| {
+                (0..count)
+                    .map(|i| row.get_ref(i).map(text))
+                    .collect::<Result<Vec<String>, _>>()
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(QueryResult { columns, rows })
+    }
+
+    // one of the QUERIES by name
+    pub fn stored_query(&self, name: &str) -> Result<QueryResult, BoxError> {
+        match QUERIES.iter().find(|(n, _, _)| *n == name) {
+            Some((_, _, sql)) => self.query(sql),
+            None => Err(format!(
+                "unknown query {}, one of {}",
+                name,
+                QUERIES
+                    .iter()
+                    .map(|(n, _, _)| *n)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .into()),
+        }
+    }
+}
+
+fn insert_message(tx: &Transaction, line: i64, m: &TransportMsg) -> rusqlite::Result<usize> {
+    tx.execute(
+        "INSERT INTO messages
+         (line, kind, sender, last, next, destination, sensor, command, command_name,
+          type, type_name, ack, signed, failures, send_ok, payload)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+        params![
+            line,
+            format!("{:?}", m.kind),
+            m.sender,
+            m.last,
+            m.next,
+            m.destination,
+            m.sensor,
+            m.command,
+            command_name(m.command),
+            m.msg_type,
+            type_name(m.command, m.msg_type),
+            m.ack,
+            m.signed,
+            m.failures,
+            m.send_ok,
+            m.payload
+        ],
+    )
+}
+
+// messages from a node, as counted for the watchdog
+fn update_node(tx: &Transaction, m: &TransportMsg, timestamp: Option<i64>) -> rusqlite::Result<()> {
+    let from_node = matches!(m.kind, MsgKind::Read | MsgKind::Serial) && m.sender != 0;
+    if !from_node {
+        return Ok(());
+    }
+    tx.execute(
+        "INSERT INTO nodes (id, first_seen, last_seen, messages) VALUES (?1, ?2, ?2, 1)
+         ON CONFLICT (id) DO UPDATE SET
+             first_seen = min(coalesce(first_seen, excluded.first_seen), coalesce(excluded.first_seen, first_seen)),
+             last_seen = max(coalesce(last_seen, excluded.last_seen), coalesce(excluded.last_seen, last_seen)),
+             messages = messages + 1",
+        params![m.sender, timestamp],
+    )?;
+    let column = match (m.command, m.msg_type) {
+        (C_INTERNAL, I_SKETCH_NAME) => "sketch_name",
+        (C_INTERNAL, I_SKETCH_VERSION) => "sketch_version",
+        (C_PRESENTATION, _) if m.sensor == 255 => "library_version",
+        _ => return Ok(()),
+    };
+    if !m.payload.is_empty() {
+        tx.execute(
+            &format!("UPDATE nodes SET {} = ?1 WHERE id = ?2", column),
+            params![m.payload, m.sender],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+
+    const LOG: &str = "\
+Oct 18 13:00:00 INFO  Starting gateway...
+Oct 18 13:00:00 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2
+Oct 18 13:00:01 DEBUG TSF:MSG:READ,12-12-0,s=255,c=0,t=17,pt=0,l=5,sg=0:2.3.2
+Oct 18 13:00:01 DEBUG TSF:MSG:READ,12-12-0,s=255,c=3,t=11,pt=0,l=7,sg=0:Outside
+Oct 18 13:00:02 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5
+Oct 18 13:00:02 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5
+Oct 18 13:00:03 DEBUG !TSF:MSG:SEND,0-0-12-12,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=3,st=NACK:1
+Oct 18 13:00:04 DEBUG TSF:MSG:SEND,0-0-12-12,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=0,st=OK:1
+Oct 18 13:05:00 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2
+Oct 18 13:05:01 DEBUG TSF:MSG:READ,7-7-0,s=1,c=1,t=0,pt=7,l=4,sg=0:19.0
+";
+
+    fn lines(log: &str) -> Vec<Result<(String, LogLine), BoxError>> {
+        log.lines()
+            .map(|raw| Ok((raw.to_string(), parse_log_line(raw))))
+            .collect()
+    }
+
+    #[test]
+    fn test_import() {
+        let mut archive = Archive::open_in_memory().unwrap();
+        // the first part of the log, then all of it once it grew
+        let head: String = LOG.lines().take(6).map(|l| format!("{}\n", l)).collect();
+        let stats = archive.import("gateway.log", lines(&head)).unwrap();
+        assert_eq!((stats.inserted, stats.duplicates), (6, 0));
+        let stats = archive.import("gateway.log", lines(LOG)).unwrap();
+        assert_eq!((stats.lines, stats.inserted, stats.duplicates), (10, 4, 6));
+
+        let count = |sql: &str| archive.query(sql).unwrap().rows[0][0].clone();
+        assert_eq!(count("SELECT count(*) FROM lines"), "10");
+        // the identical READs are both kept
+        assert_eq!(
+            count("SELECT count(*) FROM messages WHERE sender = 12"),
+            "4"
+        );
+        assert_eq!(count("SELECT count(DISTINCT session) FROM lines"), "2");
+        assert_eq!(
+            count("SELECT count(*) FROM lines WHERE session IS NULL"),
+            "0"
+        );
+
+        let nodes = archive.stored_query("nodes").unwrap();
+        assert_eq!(nodes.columns[0], "node");
+        assert_eq!(nodes.rows.len(), 2);
+        assert_eq!(nodes.rows[1][..5], ["12", "Outside", "", "2.3.2", "4"]);
+
+        let nacks = archive.stored_query("nacks").unwrap();
+        assert_eq!(nacks.rows, vec![vec!["12", "2", "1", "50"]]);
+        let sessions = archive.stored_query("sessions").unwrap();
+        assert_eq!(sessions.rows.len(), 2);
+        assert_eq!(sessions.rows[0][3], "8");
+        assert!(archive.stored_query("bogus").is_err());
+        assert!(nodes.to_string().starts_with("node  sketch_name"));
+    }
+
+    #[test]
+    fn test_sources() {
+        let mut archive = Archive::open_in_memory().unwrap();
+        let count = |archive: &Archive, sql: &str| archive.query(sql).unwrap().rows[0][0].clone();
+        // two gateways that logged some of the same lines
+        let other: String = LOG.lines().skip(4).map(|l| format!("{}\n", l)).collect();
+        archive.import("gateway.log", lines(LOG)).unwrap();
+        let stats = archive.import("other.log", lines(&other)).unwrap();
+        assert_eq!((stats.inserted, stats.duplicates), (6, 0));
+        assert_eq!(count(&archive, "SELECT count(*) FROM lines"), "16");
+
+        // gateway.log rotated: a new file with lines the old one had, then
+        // grown and imported again
+        let rotated: String = LOG.lines().skip(8).map(|l| format!("{}\n", l)).collect();
+        let stats = archive.import("gateway.log", lines(&rotated)).unwrap();
+        assert_eq!((stats.inserted, stats.duplicates), (2, 0));
+        let grown = format!("{}Oct 18 13:06:00 DEBUG TSM:INIT\n", rotated);
+        let stats = archive.import("gateway.log", lines(&grown)).unwrap();
+        assert_eq!((stats.inserted, stats.duplicates), (1, 2));
+        assert_eq!(
+            count(
+                &archive,
+                "SELECT count(*) FROM lines WHERE source = 'gateway.log'"
+            ),
+            "13"
+        );
+        assert_eq!(
+            count(
+                &archive,
+                "SELECT max(position) FROM lines WHERE source = 'gateway.log'"
+            ),
+            "12"
+        );
+    }
+
+    #[test]
+    fn test_year() {
+        // a line later in the year than today is from last year
+        let now = Local::now();
+        let later = now + chrono::Duration::days(3);
+        let raw = format!("{} DEBUG TSM:INIT", later.format("%b %e %H:%M:%S"));
+        let mut archive = Archive::open_in_memory().unwrap();
+        archive.import("gateway.log", lines(&raw)).unwrap();
+        let stored: i64 = archive.query("SELECT timestamp FROM lines").unwrap().rows[0][0]
+            .parse()
+            .unwrap();
+        assert!(stored <= now.timestamp() + 86400);
+    }
+
+    #[test]
+    fn test_stored_queries() {
+        let mut archive = Archive::open_in_memory().unwrap();
+        archive.import("gateway.log", lines(LOG)).unwrap();
+        for (name, _, _) in QUERIES {
+            archive.stored_query(name).unwrap();
+        }
+    }
+}
@@ -0,0 +1,360 @@
+// dictionary of human readable names for the codes found in log lines
+//
+// the built-in default (dictionaries/default.toml) is compiled in; a site
+// specific dictionary can be loaded at runtime and is merged on top of it so
+// newer library codes can be added without recompiling
+//
+// entries under [versions."<release>"] only apply to logs of that release,
+// e.g. [versions."3".systems.TSF.messages] for all 3.x releases
+use crate::protocol::Version;
+use crate::{BoxError, LogParseError};
+use nom::error::ErrorKind;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+const BUILTIN: &str = include_str!("../dictionaries/default.toml");
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct SystemDictionary {
+    pub name: Option<String>, //None in an overlay keeps the existing name
+    pub subsystems: HashMap<String, String>,
+    pub messages: HashMap<String, String>,
+}
+
+impl SystemDictionary {
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn subsystem_name(&self, code: &str) -> Option<&str> {
+        self.subsystems.get(code).map(|s| s.as_str())
+    }
+
+    // "<subsystem>:<message>" keys win over plain message keys
+    pub fn message(&self, subsystem: &str, msg: &str) -> Option<&str> {
+        self.messages
+            .get(&format!("{}:{}", subsystem, msg))
+            .or_else(|| self.messages.get(msg))
+            .map(|s| s.as_str())
+    }
+
+    // matcher built from the subsystem keys: the longest key at the start of
+    // the input that is followed by ':' or the end of the input
+    pub fn parse_subsystem<'a>(&self, i: &'a str) -> nom::IResult<&'a str, &'a str, LogParseError> {
+        let found = self
+            .subsystems
+            .keys()
+            .filter(|k| i.starts_with(k.as_str()))
+            .filter(|k| i[k.len()..].is_empty() || i[k.len()..].starts_with(':'))
+            .map(|k| k.len())
+            .max();
+        match found {
+            Some(len) => Ok((&i[len..], &i[..len])),
+            None => Err(nom::Err::Error(LogParseError::Nom(
+                i.to_string(),
+                ErrorKind::Tag,
+            ))),
+        }
+    }
+
+    fn merge(&mut self, other: SystemDictionary) {
+        if other.name.is_some() {
+            self.name = other.name;
+        }
+        self.subsystems.extend(other.subsystems);
+        self.messages.extend(other.messages);
+    }
+}
+
+// names of protocol numbers, keyed by the number as text
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ProtocolDictionary {
+    pub commands: HashMap<String, String>,
+    pub presentation: HashMap<String, String>,
+    pub variables: HashMap<String, String>,
+    pub internal: HashMap<String, String>,
+    pub stream: HashMap<String, String>,
+}
+
+impl ProtocolDictionary {
+    fn merge(&mut self, other: ProtocolDictionary) {
+        self.commands.extend(other.commands);
+        self.presentation.extend(other.presentation);
+        self.variables.extend(other.variables);
+        self.internal.extend(other.internal);
+        self.stream.extend(other.stream);
+    }
+}
+
+// topic prefixes of an MQTT gateway (MY_MQTT_PUBLISH/SUBSCRIBE_TOPIC_PREFIX)
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct MqttDictionary {
+    pub publish_prefix: Option<String>, //gateway to controller, e.g. mygateway1-out
+    pub subscribe_prefix: Option<String>, //controller to gateway, e.g. mygateway1-in
+}
+
+impl MqttDictionary {
+    fn merge(&mut self, other: MqttDictionary) {
+        if other.publish_prefix.is_some() {
+            self.publish_prefix = other.publish_prefix;
+        }
+        if other.subscribe_prefix.is_some() {
+            self.subscribe_prefix = other.subscribe_prefix;
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct Dictionary {
+    pub systems: HashMap<String, SystemDictionary>,
+    pub protocol: ProtocolDictionary,
+    pub mqtt: MqttDictionary,
+    pub versions: HashMap<String, Dictionary>, //release specific overlays
+}
+
+impl Dictionary {
+    pub fn builtin() -> Dictionary {
+        Dictionary::from_toml(BUILTIN).expect("built-in dictionary is valid")
+    }
+
+    pub fn from_toml(s: &str) -> Result<Dictionary, BoxError> {
+        Ok(toml::from_str(s)?)
+    }
+
+    // built-in default with the given file merged on top
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Dictionary, BoxError> {
+        let mut dictionary = Dictionary::builtin();
+        dictionary.merge(Dictionary::from_toml(&std::fs::read_to_string(path)?)?);
+        Ok(dictionary)
+    }
+
+    // entries of other are added to or replace entries of self
+    pub fn merge(&mut self, other: Dictionary) {
+        for (code, system) in other.systems {
+            self.systems.entry(code).or_default().merge(system);
+        }
+        self.protocol.merge(other.protocol);
+        self.mqtt.merge(other.mqtt);
+        for (key, overlay) in other.versions {
+            self.versions.entry(key).or_default().merge(overlay);
+        }
+    }
+
+    // dictionary with the overlays matching the release applied, least
+    // specific first so "2.3" entries win over "2" entries
+    pub fn for_version(&self, version: &Version) -> Dictionary {
+        let mut keys: Vec<&String> = self
+            .versions
+            .keys()
+            .filter(|k| version.matches(k))
+            .collect();
+        keys.sort_by_key(|k| k.split('.').count());
+
+        let mut dictionary = Dictionary {
+            systems: self.systems.clone(),
+            protocol: self.protocol.clone(),
+            mqtt: self.mqtt.clone(),
+            versions: HashMap::new(),
+        };
+        for key in keys {
+            let mut overlay = self.versions[key].clone();
+            overlay.versions.clear(); //no nesting of release overlays
+            dictionary.merge(overlay);
+        }
+        dictionary
+    }
+
+    pub fn system(&self, code: &str) -> Option<&SystemDictionary> {
+        self.systems.get(code)
+    }
+
+    // name of a command number, e.g. 1 -> "C_SET"
+    pub fn command_name(&self, command: u8) -> Option<&str> {
+        self.protocol
+            .commands
+            .get(&command.to_string())
+            .map(|s| s.as_str())
+    }
+
+    // name of a type number - the meaning of type depends on the command
+    pub fn type_name(&self, command: u8, msg_type: u8) -> Option<&str> {
+        let names = match self.command_name(command)? {
+            "C_PRESENTATION" => &self.protocol.presentation,
+            "C_SET" | "C_REQ" => &self.protocol.variables,
+            "C_INTERNAL" => &self.protocol.internal,
+            "C_STREAM" => &self.protocol.stream,
+            _ => return None,
+        };
+        names.get(&msg_type.to_string()).map(|s| s.as_str())
+    }
+}
+
+//the dictionary used by the parsers
+lazy_static! {
+    static ref ACTIVE: RwLock<Arc<Dictionary>> = RwLock::new(Arc::new(Dictionary::builtin()));
+}
+
+//dictionary and release of the log line currently being parsed on this thread
+thread_local! {
+    static SCOPE: RefCell<Option<(Arc<Dictionary>, Option<Version>)>> = const { RefCell::new(None) };
+}
+
+// dictionary for the line being parsed: the release specific one while a
+// LogParser is parsing a line, otherwise the active one
+pub fn dictionary() -> Arc<Dictionary> {
+    match SCOPE.with(|s| s.borrow().as_ref().map(|(d, _)| d.clone())) {
+        Some(d) => d,
+        None => active_dictionary(),
+    }
+}
+
+// release of the log line being parsed, if known
+pub fn protocol_version() -> Option<Version> {
+    SCOPE.with(|s| s.borrow().as_ref().and_then(|(_, v)| *v))
+}
+
+pub fn active_dictionary() -> Arc<Dictionary> {
+    ACTIVE.read().unwrap().clone()
+}
+
+// run f with the given dictionary and release as the parsing context
+pub(crate) fn with_scope<R>(
+    dictionary: Arc<Dictionary>,
+    version: Option<Version>,
+    f: impl FnOnce() -> R,
+) -> R {
+    // puts the previous scope back, also when f panics
+    struct Restore(Option<(Arc<Dictionary>, Option<Version>)>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            SCOPE.with(|s| *s.borrow_mut() = previous);
+        }
+    }
+
+    let _restore = Restore(SCOPE.with(|s| s.replace(Some((dictionary, version)))));
+    f()
+}
+
+pub fn set_dictionary(dictionary: Dictionary) {
+    *ACTIVE.write().unwrap() = Arc::new(dictionary);
+}
+
+// load a dictionary file on top of the built-in default and use it from now on
+pub fn load_dictionary(path: impl AsRef<Path>) -> Result<(), BoxError> {
+    set_dictionary(Dictionary::from_file(path)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin() {
+        let d = Dictionary::builtin();
+        let core = d.system("MCO").unwrap();
+        assert_eq!(core.name(), Some("Core"));
+        assert_eq!(core.subsystem_name("SLP"), Some("Sleep"));
+        assert_eq!(
+            core.message("SLP", "NTL"),
+            Some("Can't Sleep - no time left")
+        );
+        let tsm = d.system("TSM").unwrap();
+        assert_eq!(tsm.message("FPAR", "OK"), Some("Parent found"));
+        assert_eq!(tsm.message("UPL", "OK"), Some("UL OK, GW returned ping"));
+        assert_eq!(d.command_name(1), Some("C_SET"));
+        assert_eq!(d.type_name(1, 38), Some("V_VOLTAGE"));
+        assert_eq!(d.type_name(3, 0), Some("I_BATTERY_LEVEL"));
+        assert_eq!(d.type_name(9, 0), None);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut d = Dictionary::builtin();
+        d.merge(
+            Dictionary::from_toml(
+                r#"
+                [systems.MCO.subsystems]
+                SLP = "Nap"
+                [systems.APP]
+                name = "App"
+                [systems.APP.subsystems]
+                BAT = "Battery"
+                [protocol.internal]
+                99 = "I_SITE_SPECIFIC"
+                [mqtt]
+                publish_prefix = "home/mysensors-out"
+                "#,
+            )
+            .unwrap(),
+        );
+        let core = d.system("MCO").unwrap();
+        assert_eq!(core.name(), Some("Core"));
+        assert_eq!(core.subsystem_name("SLP"), Some("Nap"));
+        assert_eq!(core.subsystem_name("BGN"), Some("Begin"));
+        assert_eq!(
+            d.system("APP").unwrap().subsystem_name("BAT"),
+            Some("Battery")
+        );
+        assert_eq!(d.type_name(3, 99), Some("I_SITE_SPECIFIC"));
+        assert_eq!(d.type_name(3, 2), Some("I_VERSION"));
+        assert_eq!(d.mqtt.publish_prefix.as_deref(), Some("home/mysensors-out"));
+        assert_eq!(d.mqtt.subscribe_prefix.as_deref(), Some("mygateway1-in"));
+    }
+
+    #[test]
+    fn test_for_version() {
+        let d = Dictionary::from_toml(
+            r#"
+            [systems.TSF.messages]
+            ACK = "ACK received"
+            [versions."3".systems.TSF.messages]
+            ACK = "v3"
+            [versions."3.1".systems.TSF.messages]
+            ACK = "v3.1"
+            "#,
+        )
+        .unwrap();
+        let msg = |v: Version| {
+            d.for_version(&v)
+                .system("TSF")
+                .unwrap()
+                .message("MSG", "ACK")
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(msg(Version::new(2, 3, 2)), "ACK received");
+        assert_eq!(msg(Version::new(3, 0, 0)), "v3");
+        assert_eq!(msg(Version::new(3, 1, 1)), "v3.1");
+    }
+
+    #[test]
+    fn test_scope_restored() {
+        let version = Some(Version::new(2, 2, 0));
+        let result = std::panic::catch_unwind(|| {
+            with_scope(Arc::new(Dictionary::builtin()), version, || {
+                assert_eq!(protocol_version(), version);
+                panic!("parser bug");
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(protocol_version(), None);
+    }
+
+    #[test]
+    fn test_parse_subsystem() {
+        let d = Dictionary::builtin();
+        let tsm = d.system("TSM").unwrap();
+        assert_eq!(tsm.parse_subsystem("FPAR:OK"), Ok((":OK", "FPAR")));
+        assert_eq!(tsm.parse_subsystem("READY"), Ok(("", "READY")));
+        assert!(tsm.parse_subsystem("IDX:OK").is_err());
+    }
+}
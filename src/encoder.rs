@@ -0,0 +1,522 @@
+// renders structured log lines back into the raw text the gateway writes, the
+// reverse of the parsers - used by tests, simulators and to write anonymized
+// logs
+//
+// messages with typed fields are rendered from the fields so
+// parse_log_line(&render_line(&line)) == line; messages without fields are
+// mapped back through the dictionary, which is best effort as names are not
+// unique (e.g. RRT and RTE are both "Route")
+use crate::dictionary::{dictionary, SystemDictionary};
+use crate::protocol::{MsgFields, MsgKind, TransportMsg};
+use crate::{LogLine, ParsedMessage, SendStatus};
+use chrono::{DateTime, Local};
+
+// system and subsystem code a message with these fields is logged with
+// None for serial lines (no prefix) and custom fields (format unknown)
+pub fn codes(fields: &MsgFields) -> Option<(&'static str, &'static str)> {
+    let codes = match fields {
+        MsgFields::Msg(m) => match m.kind {
+            MsgKind::Read | MsgKind::Send => ("TSF", "MSG"),
+            MsgKind::MqttOut => ("GWT", "TPS"),
+            MsgKind::MqttIn => ("GWT", "IMQ"),
+            MsgKind::SerialIn => ("GWT", "RFC"),
+            MsgKind::Serial => return None,
+        },
+        MsgFields::BadLength { .. } | MsgFields::BadProtocolVersion { .. } => ("TSF", "MSG"),
+        MsgFields::AssignNodeId { .. } => ("TSF", "SID"),
+        MsgFields::PingSend { .. } => ("TSF", "PNG"),
+        MsgFields::SignalReport { .. } => ("TSF", "SIR"),
+        MsgFields::LoadRoutingTable { .. } => ("TSF", "LRT"),
+        MsgFields::SaveRoutingTable { .. } => ("TSF", "SRT"),
+        MsgFields::SanityCheck { .. } => ("TSF", "SAN"),
+        MsgFields::UplinkCheck { .. } | MsgFields::GatewayDistanceChanged { .. } => ("TSF", "CKU"),
+        MsgFields::NodeToNodeRoute { .. } => ("TSF", "RTE"),
+        MsgFields::StateTransition(state) => ("TSM", state.code()),
+        MsgFields::Ready { .. } => ("TSM", "READY"),
+        MsgFields::FailCount { .. } => ("TSM", "FAIL"),
+        MsgFields::IdVerificationFailed { .. } | MsgFields::StaticId { .. } => ("TSM", "ID"),
+        MsgFields::CoreInit { .. } => ("MCO", "BGN"),
+        MsgFields::Sleep { .. } | MsgFields::WakeUp { .. } => ("MCO", "SLP"),
+        MsgFields::Custom(_) => return None,
+    };
+    Some(codes)
+}
+
+fn ok_or_fail(ok: bool) -> &'static str {
+    if ok {
+        "OK"
+    } else {
+        "FAIL"
+    }
+}
+
+// node-id;child-sensor-id;command;ack;type;payload
+pub fn render_serial(m: &TransportMsg) -> String {
+    format!(
+        "{};{};{};{};{};{}",
+        m.node(),
+        m.sensor,
+        m.command,
+        u8::from(m.ack.unwrap_or(false)),
+        m.msg_type,
+        m.payload
+    )
+}
+
+// <prefix>/node-id/child-sensor-id/command/ack/type, None if the dictionary has
+// no prefix for the direction of the message
+pub fn render_mqtt_topic(m: &TransportMsg) -> Option<String> {
+    let dictionary = dictionary();
+    let prefix = match m.kind {
+        MsgKind::MqttOut => dictionary.mqtt.publish_prefix.clone()?,
+        MsgKind::MqttIn => dictionary.mqtt.subscribe_prefix.clone()?,
+        _ => return None,
+    };
+    Some(format!(
+        "{}/{}/{}/{}/{}/{}",
+        prefix,
+        m.node(),
+        m.sensor,
+        m.command,
+        u8::from(m.ack.unwrap_or(false)),
+        m.msg_type
+    ))
+}
+
+// READ,%d-%d-%d,s=%d,c=%d,t=%d,pt=%d,l=%d,sg=%d:%s
+// SEND,%d-%d-%d-%d,s=%d,c=%d,t=%d,pt=%d,l=%d,sg=%d,ft=%d,st=%s:%s
+fn render_frame(m: &TransportMsg) -> String {
+    let route = match m.kind {
+        MsgKind::Send => format!(
+            "SEND,{}-{}-{}-{}",
+            m.sender,
+            m.last,
+            m.next.unwrap_or(m.destination),
+            m.destination
+        ),
+        _ => format!("READ,{}-{}-{}", m.sender, m.last, m.destination),
+    };
+    let status = match m.kind {
+        MsgKind::Send => format!(
+            ",ft={},st={}",
+            m.failures.unwrap_or(0),
+            if m.send_ok.unwrap_or(true) {
+                "OK"
+            } else {
+                "NACK"
+            }
+        ),
+        _ => String::new(),
+    };
+    format!(
+        "{},s={},c={},t={},pt={},l={},sg={}{}:{}",
+        route,
+        m.sensor,
+        m.command,
+        m.msg_type,
+        m.payload_type,
+        m.length,
+        u8::from(m.signed),
+        status,
+        m.payload
+    )
+}
+
+// message text (without system and subsystem) the fields are logged as
+fn render_fields_msg(fields: &MsgFields) -> Option<String> {
+    let msg = match fields {
+        MsgFields::Msg(m) => match m.kind {
+            MsgKind::Read | MsgKind::Send => render_frame(m),
+            MsgKind::MqttOut => format!("TOPIC={},MSG SENT", render_mqtt_topic(m)?),
+            MsgKind::MqttIn => format!("TOPIC={}, MSG RECEIVED", render_mqtt_topic(m)?),
+            MsgKind::Serial => render_serial(m),
+            MsgKind::SerialIn => format!("MSG={}", render_serial(m)),
+        },
+        // 2.3 format, the older "LEN,%d!=%d" is still accepted by the parser
+        MsgFields::BadLength { length, expected } => format!("LEN={},EXP={}", length, expected),
+        MsgFields::BadProtocolVersion { received, expected } => {
+            format!("PVER,{}={}", received, expected)
+        }
+        MsgFields::AssignNodeId { ok, id } => format!("{},ID={}", ok_or_fail(*ok), id),
+        MsgFields::PingSend { to } => format!("SEND,TO={}", to),
+        MsgFields::SignalReport { cmd, value } => format!("CMD={},RSSI={}", cmd, value),
+        MsgFields::LoadRoutingTable { ok }
+        | MsgFields::SaveRoutingTable { ok }
+        | MsgFields::SanityCheck { ok }
+        | MsgFields::UplinkCheck { ok } => ok_or_fail(*ok).to_string(),
+        MsgFields::GatewayDistanceChanged { old, new } => format!("DGWC,O={},N={}", old, new),
+        MsgFields::NodeToNodeRoute { ok } => format!("N2N {}", ok_or_fail(*ok)),
+        MsgFields::StateTransition(_) => String::new(),
+        MsgFields::Ready {
+            id,
+            parent,
+            distance,
+        } => format!("ID={},PAR={},DIS={}", id, parent, distance),
+        MsgFields::FailCount { count } => format!("CNT={}", count),
+        MsgFields::IdVerificationFailed { id } => format!("FAIL,ID={}", id),
+        MsgFields::StaticId { id } => format!("STATID={}", id),
+        MsgFields::CoreInit { .. } => fields.to_string(), //already in its original form
+        MsgFields::Sleep {
+            ms,
+            smart,
+            interrupt1,
+            mode1,
+            interrupt2,
+            mode2,
+        } => format!(
+            "MS={},SMS={},I1={},M1={},I2={},M2={}",
+            ms, smart, interrupt1, mode1, interrupt2, mode2
+        ),
+        MsgFields::WakeUp { reason } => format!("WUP={}", reason),
+        MsgFields::Custom(_) => return None,
+    };
+    Some(msg)
+}
+
+// raw message of typed fields, e.g. TSF:SID:OK,ID=12
+pub fn render_fields(fields: &MsgFields) -> Option<String> {
+    let msg = render_fields_msg(fields)?;
+    match codes(fields) {
+        Some((system, subsystem)) if msg.is_empty() => Some(format!("{}:{}", system, subsystem)),
+        Some((system, subsystem)) => Some(format!("{}:{}:{}", system, subsystem, msg)),
+        None => Some(msg),
+    }
+}
+
+// codes of the dictionary entries with the name, sorted as a name can be used
+// more than once and the result must not depend on hash order
+fn code_of<'a>(
+    entries: impl Iterator<Item = (&'a String, &'a String)>,
+    name: &str,
+) -> Vec<&'a str> {
+    let mut codes: Vec<&str> = entries
+        .filter(|(_, n)| n.as_str() == name)
+        .map(|(c, _)| c.as_str())
+        .collect();
+    codes.sort_unstable();
+    codes
+}
+
+// raw message of a looked up message, "<subsystem>:<message>" keys first
+fn unlookup(dict: &SystemDictionary, subsystem: &str, msg: &str) -> Option<String> {
+    let keys = code_of(dict.messages.iter(), msg);
+    let qualified = format!("{}:", subsystem);
+    keys.iter()
+        .find_map(|k| k.strip_prefix(qualified.as_str()))
+        .or_else(|| {
+            keys.iter()
+                .find(|k| !k.contains(':') && dict.message(subsystem, k) == Some(msg))
+                .copied()
+        })
+        .map(|k| k.to_string())
+}
+
+// "System:Subsystem:message" back to "SYS:SS:msg" through the dictionary
+fn render_named_msg(line: &LogLine) -> Option<String> {
+    let (system, subsystem) = (line.system.as_deref()?, line.subsystem.as_deref()?);
+    let text = line
+        .msg
+        .strip_prefix(&format!("{}:{}:", system, subsystem))?;
+    let dictionary = dictionary();
+    let (code, dict) = dictionary
+        .systems
+        .iter()
+        .filter(|(_, d)| d.name() == Some(system))
+        .min_by_key(|(c, _)| c.as_str())?;
+    let subsystems = code_of(dict.subsystems.iter(), subsystem);
+    // prefer the subsystem the message is defined for
+    let (ss, raw) = subsystems
+        .iter()
+        .find_map(|ss| Some((*ss, unlookup(dict, ss, text)?)))
+        .unwrap_or((subsystems.first()?, text.to_string()));
+    Some(format!("{}:{}:{}", code, ss, raw))
+}
+
+// prefix of the message for the send status
+pub fn status_prefix(status: SendStatus) -> &'static str {
+    match status {
+        SendStatus::OK => "",
+        SendStatus::ERROR => "!",
+        SendStatus::UNKNOWN => "?",
+    }
+}
+
+// message part of a log line including the '!' / '?' prefix
+pub fn render_message(line: &LogLine) -> String {
+    let msg = match line.fields.as_ref().and_then(render_fields) {
+        Some(msg) => msg,
+        None => render_named_msg(line).unwrap_or_else(|| line.msg.clone()),
+    };
+    format!("{}{}", status_prefix(line.status), msg)
+}
+
+// syslog style prefix and message, e.g.
+// Oct 18 13:36:52 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=5,sg=0:21.5
+pub fn render_syslog(datetime: &DateTime<Local>, level: &str, message: &str) -> String {
+    format!(
+        "{} {:5} {}",
+        datetime.format("%b %e %H:%M:%S"),
+        level,
+        message
+    )
+}
+
+// whole log line, lines that could not be parsed are echoed as they were read
+pub fn render_line(line: &LogLine) -> String {
+    match (&line.datetime, &line.level) {
+        (Some(datetime), Some(level)) => render_syslog(datetime, level, &render_message(line)),
+        _ => line.msg.clone(),
+    }
+}
+
+// log line as the parser would return it for the fields, for building lines
+// to render without going through text
+pub fn line_from_fields(
+    datetime: DateTime<Local>,
+    level: &str,
+    status: SendStatus,
+    fields: MsgFields,
+) -> LogLine {
+    let dictionary = dictionary();
+    let (system, subsystem) = match (&fields, codes(&fields)) {
+        (_, Some((system, subsystem))) => {
+            let dict = dictionary.system(system);
+            (
+                dict.and_then(|d| d.name()).map(|n| n.to_string()),
+                dict.and_then(|d| d.subsystem_name(subsystem))
+                    .map(|n| n.to_string()),
+            )
+        }
+        (MsgFields::Msg(m), None) => (
+            Some("Serial".to_string()),
+            dictionary.command_name(m.command).map(|n| n.to_string()),
+        ),
+        _ => (None, None),
+    };
+    let message = ParsedMessage {
+        send_status: status,
+        system,
+        subsystem,
+        msg: fields.to_string(),
+        fields: Some(fields),
+    };
+    LogLine {
+        datetime: Some(datetime),
+        level: Some(level.to_string()),
+        system: message.system.clone(),
+        subsystem: message.subsystem.clone(),
+        msg: message.to_string(),
+        fields: message.fields,
+        status,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+    use crate::protocol::XportState;
+    use chrono::{Datelike, NaiveDate, TimeZone};
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_render_real_lines() {
+        for raw in [
+            "Oct 18 13:36:52 INFO  Protocol version - 2.3.2",
+            "Oct 18 13:36:52 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2",
+            "Oct 18 13:36:52 DEBUG TSM:INIT",
+            "Oct 18 13:36:52 DEBUG TSM:INIT:TSP OK",
+            "Oct 18 13:36:52 DEBUG TSM:INIT:GW MODE",
+            "Oct 18 13:36:52 DEBUG TSM:READY:ID=0,PAR=0,DIS=0",
+            "Oct 18 13:36:52 DEBUG MCO:REG:NOT NEEDED",
+            "Oct  8 13:36:53 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=5,sg=0:21.5",
+            "Oct 18 13:36:53 DEBUG !TSF:MSG:SEND,0-0-12-12,s=255,c=3,t=6,pt=0,l=1,sg=0,ft=1,st=NACK:M",
+            "Oct 18 13:36:53 DEBUG TSF:SID:OK,ID=12",
+            "Oct 18 13:36:53 DEBUG TSF:CKU:DGWC,O=1,N=2",
+            "Oct 18 13:36:53 DEBUG GWT:TPS:TOPIC=mygateway1-out/12/1/1/0/0,MSG SENT",
+            "Oct 18 13:36:53 DEBUG GWT:IMQ:TOPIC=mygateway1-in/12/1/1/0/2, MSG RECEIVED",
+            "Oct 18 13:36:53 DEBUG 12;1;1;0;0;21.5",
+            "not a log line",
+        ] {
+            assert_eq!(render_line(&parse_log_line(raw)), raw);
+        }
+    }
+
+    fn datetime() -> impl Strategy<Value = DateTime<Local>> {
+        (1u32..=12, 1u32..=28, 0u32..24, 0u32..60, 0u32..60).prop_filter_map(
+            "not a local time",
+            |(month, day, h, m, s)| {
+                // the log has no year, the parser infers it
+                let date = NaiveDate::from_ymd_opt(Local::now().year(), month, day)?;
+                Local
+                    .from_local_datetime(&date.and_hms_opt(h, m, s)?)
+                    .earliest()
+                    .map(|t| crate::merge::infer_year(t, Local::now()))
+            },
+        )
+    }
+
+    fn ok() -> impl Strategy<Value = bool> {
+        any::<bool>()
+    }
+
+    fn frame() -> impl Strategy<Value = TransportMsg> {
+        (
+            any::<bool>(),
+            any::<[u8; 8]>(),
+            any::<bool>(),
+            any::<bool>(),
+            "[ -~]{0,25}",
+        )
+            .prop_map(|(send, n, signed, send_ok, payload)| TransportMsg {
+                kind: if send { MsgKind::Send } else { MsgKind::Read },
+                sender: n[0],
+                last: n[1],
+                next: if send { Some(n[2]) } else { None },
+                destination: n[3],
+                sensor: n[4],
+                command: n[5] % 5,
+                ack: None,
+                msg_type: n[6],
+                payload_type: n[7] % 8,
+                length: payload.len() as u8,
+                signed,
+                failures: if send { Some(n[2] % 4) } else { None },
+                send_ok: if send { Some(send_ok) } else { None },
+                payload,
+            })
+    }
+
+    // serial lines and MQTT topics: one node, text payload
+    fn node_msg() -> impl Strategy<Value = TransportMsg> {
+        (0u8..4, any::<[u8; 4]>(), any::<bool>(), "[ -~]{0,25}").prop_map(
+            |(kind, n, ack, payload)| {
+                let kind = [
+                    MsgKind::Serial,
+                    MsgKind::SerialIn,
+                    MsgKind::MqttOut,
+                    MsgKind::MqttIn,
+                ][kind as usize];
+                // the payload of MQTT messages is not logged
+                let payload = if matches!(kind, MsgKind::Serial | MsgKind::SerialIn) {
+                    payload
+                } else {
+                    String::new()
+                };
+                // messages to a node name it in destination
+                let (sender, destination) = match kind {
+                    MsgKind::SerialIn | MsgKind::MqttIn => (0, n[0]),
+                    _ => (n[0], 0),
+                };
+                TransportMsg {
+                    kind,
+                    sender,
+                    last: 0,
+                    next: None,
+                    destination,
+                    sensor: n[1],
+                    command: n[2] % 6,
+                    ack: Some(ack),
+                    msg_type: n[3],
+                    payload_type: 0,
+                    length: payload.len() as u8,
+                    signed: false,
+                    failures: None,
+                    send_ok: None,
+                    payload,
+                }
+            },
+        )
+    }
+
+    fn state() -> impl Strategy<Value = XportState> {
+        prop_oneof![
+            Just(XportState::Init),
+            Just(XportState::FindParent),
+            Just(XportState::Id),
+            Just(XportState::Uplink),
+            Just(XportState::Ready),
+            Just(XportState::Failure),
+        ]
+    }
+
+    fn fields() -> impl Strategy<Value = MsgFields> {
+        prop_oneof![
+            frame().prop_map(MsgFields::Msg),
+            node_msg().prop_map(MsgFields::Msg),
+            any::<(u8, u8)>()
+                .prop_map(|(length, expected)| MsgFields::BadLength { length, expected }),
+            any::<(u8, u8)>().prop_map(|(received, expected)| MsgFields::BadProtocolVersion {
+                received,
+                expected
+            }),
+            (ok(), any::<u8>()).prop_map(|(ok, id)| MsgFields::AssignNodeId { ok, id }),
+            any::<u8>().prop_map(|to| MsgFields::PingSend { to }),
+            any::<(u8, i16)>().prop_map(|(cmd, value)| MsgFields::SignalReport { cmd, value }),
+            ok().prop_map(|ok| MsgFields::LoadRoutingTable { ok }),
+            ok().prop_map(|ok| MsgFields::SaveRoutingTable { ok }),
+            ok().prop_map(|ok| MsgFields::SanityCheck { ok }),
+            ok().prop_map(|ok| MsgFields::UplinkCheck { ok }),
+            any::<(u8, u8)>().prop_map(|(old, new)| MsgFields::GatewayDistanceChanged { old, new }),
+            ok().prop_map(|ok| MsgFields::NodeToNodeRoute { ok }),
+            state().prop_map(MsgFields::StateTransition),
+            any::<(u8, u8, u8)>().prop_map(|(id, parent, distance)| MsgFields::Ready {
+                id,
+                parent,
+                distance
+            }),
+            any::<u8>().prop_map(|count| MsgFields::FailCount { count }),
+            any::<u8>().prop_map(|id| MsgFields::IdVerificationFailed { id }),
+            any::<u8>().prop_map(|id| MsgFields::StaticId { id }),
+            (
+                "[A-Z]{1,4}",
+                "[A-Z-]{1,8}",
+                proptest::option::of("[A-Z0-9]{1,4}"),
+                proptest::option::of(any::<u8>()),
+                "[0-9.a-z-]{1,10}"
+            )
+                .prop_map(|(node, capabilities, frequency, release, version)| {
+                    MsgFields::CoreInit {
+                        node,
+                        capabilities,
+                        frequency,
+                        release,
+                        version,
+                    }
+                }),
+            (any::<u32>(), any::<u8>(), any::<(u8, u8, u8, u8)>()).prop_map(
+                |(ms, smart, (interrupt1, mode1, interrupt2, mode2))| MsgFields::Sleep {
+                    ms,
+                    smart,
+                    interrupt1,
+                    mode1,
+                    interrupt2,
+                    mode2,
+                }
+            ),
+            any::<i8>().prop_map(|reason| MsgFields::WakeUp { reason }),
+        ]
+    }
+
+    fn status() -> impl Strategy<Value = SendStatus> {
+        prop_oneof![
+            Just(SendStatus::OK),
+            Just(SendStatus::ERROR),
+            Just(SendStatus::UNKNOWN),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(
+            datetime in datetime(),
+            level in prop_oneof![Just("DEBUG"), Just("INFO")],
+            status in status(),
+            fields in fields(),
+        ) {
+            let line = line_from_fields(datetime, level, status, fields);
+            let raw = render_line(&line);
+            prop_assert_eq!(parse_log_line(&raw), line, "rendered as {}", raw);
+        }
+    }
+}
@@ -0,0 +1,495 @@
+// filter expressions evaluated against parsed lines, as grep does not work on
+// humanized lines
+//
+//   node=12 and cmd=C_SET and status=ERROR and time>"Oct 18 13:00"
+//   (system=TSM or subsystem=Msg) and not level=INFO
+//   payload~"door"
+//
+// comparisons are <field><op><value> with the operators = != < <= > >= and ~
+// (contains). values are compared as numbers if both sides are numbers and
+// otherwise as text ignoring case; quote values with spaces. a line without the
+// field never matches, also not for !=
+//
+// fields:
+//   level, status (OK, ERROR, UNKNOWN), msg, time
+//   system, subsystem - the name or the code, e.g. system=TSF or system=Xport
+//   node - any node a message is from, to or about
+//   transport messages: sender, dest, last, next, sensor, cmd, type (name or
+//   number), pt, length, signed, ack, ft, st (OK, NACK), payload, kind
+//   other decoded fields by their name: id, parent, distance, count, value,
+//   rssi, snr, tx (signal reports), cmd, to, old, new, expected, received,
+//   ok, state, version, release, ms (sleep), reason (wake up) and the names
+//   of custom fields
+use crate::dictionary::dictionary;
+use crate::merge::infer_year;
+use crate::protocol::{MsgFields, MsgKind, SignalKind};
+use crate::{BoxError, LogLine, SendStatus};
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, TimeZone};
+use nom::branch::alt;
+use nom::bytes::complete::{is_not, tag, tag_no_case, take_while1};
+use nom::character::complete::{char, multispace0, multispace1};
+use nom::combinator::{all_consuming, map, opt, peek, value};
+use nom::multi::many0;
+use nom::sequence::{delimited, preceded, terminated};
+use nom::Finish;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self {
+            Op::Eq => "=",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Contains => "~",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    Compare {
+        field: String,
+        op: Op,
+        value: String,
+        time: Option<DateTime<Local>>, //value parsed once if field is time
+    },
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+fn parse_op(i: &str) -> nom::IResult<&str, Op> {
+    alt((
+        value(Op::Ge, tag(">=")),
+        value(Op::Le, tag("<=")),
+        value(Op::Ne, tag("!=")),
+        value(Op::Eq, tag("=")),
+        value(Op::Gt, tag(">")),
+        value(Op::Lt, tag("<")),
+        value(Op::Contains, tag("~")),
+    ))(i)
+}
+
+fn parse_value(i: &str) -> nom::IResult<&str, String> {
+    alt((
+        map(
+            delimited(char('"'), opt(is_not("\"")), char('"')),
+            |v: Option<&str>| v.unwrap_or_default().to_string(),
+        ),
+        map(is_not(" \t\r\n()\""), |v: &str| v.to_string()),
+    ))(i)
+}
+
+fn parse_compare(i: &str) -> nom::IResult<&str, Filter> {
+    let (remaining, field) = take_while1(|c: char| c.is_alphanumeric() || c == '_')(i)?;
+    let (remaining, op) = delimited(multispace0, parse_op, multispace0)(remaining)?;
+    let (remaining, value) = parse_value(remaining)?;
+    let field = field.to_lowercase();
+    let time = if field == "time" {
+        parse_time(&value)
+    } else {
+        None
+    };
+    Ok((
+        remaining,
+        Filter::Compare {
+            field,
+            op,
+            value,
+            time,
+        },
+    ))
+}
+
+// keyword followed by a space or an opening parenthesis
+fn keyword<'a>(word: &'static str) -> impl FnMut(&'a str) -> nom::IResult<&'a str, &'a str> {
+    terminated(tag_no_case(word), alt((multispace1, peek(tag("(")))))
+}
+
+fn parse_unary(i: &str) -> nom::IResult<&str, Filter> {
+    delimited(
+        multispace0,
+        alt((
+            parse_compare,
+            map(preceded(keyword("not"), parse_unary), |f| {
+                Filter::Not(Box::new(f))
+            }),
+            delimited(char('('), parse_or, char(')')),
+        )),
+        multispace0,
+    )(i)
+}
+
+fn parse_and(i: &str) -> nom::IResult<&str, Filter> {
+    let (remaining, first) = parse_unary(i)?;
+    let (remaining, rest) = many0(preceded(keyword("and"), parse_unary))(remaining)?;
+    Ok((
+        remaining,
+        rest.into_iter()
+            .fold(first, |a, b| Filter::And(Box::new(a), Box::new(b))),
+    ))
+}
+
+fn parse_or(i: &str) -> nom::IResult<&str, Filter> {
+    let (remaining, first) = parse_and(i)?;
+    let (remaining, rest) = many0(preceded(keyword("or"), parse_and))(remaining)?;
+    Ok((
+        remaining,
+        rest.into_iter()
+            .fold(first, |a, b| Filter::Or(Box::new(a), Box::new(b))),
+    ))
+}
+
+// time in a filter: "Oct 18 13:00[:00]" (the year inferred as for the lines
+// of the log, see merge::infer_year) or "2021-10-18 13:00[:00]"
+pub fn parse_time(value: &str) -> Option<DateTime<Local>> {
+    parse_time_at(value, Local::now())
+}
+
+fn parse_time_at(value: &str, now: DateTime<Local>) -> Option<DateTime<Local>> {
+    let local = |t: NaiveDateTime| Local.from_local_datetime(&t).earliest();
+    let with_year = format!("{} {}", now.year(), value);
+    let without_year = ["%Y %b %d %H:%M:%S", "%Y %b %d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(&with_year, format).ok());
+    if let Some(t) = without_year {
+        return local(t).map(|t| infer_year(t, now));
+    }
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .and_then(local)
+}
+
+impl std::str::FromStr for Filter {
+    type Err = BoxError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (_, filter) = all_consuming(parse_or)(s)
+            .finish()
+            .map_err(|e| format!("bad filter expression at '{}'", e.input))?;
+        filter.check()?;
+        Ok(filter)
+    }
+}
+
+fn status_name(status: SendStatus) -> &'static str {
+    match status {
+        SendStatus::OK => "OK",
+        SendStatus::ERROR => "ERROR",
+        SendStatus::UNKNOWN => "UNKNOWN",
+    }
+}
+
+fn kind_name(kind: MsgKind) -> &'static str {
+    match kind {
+        MsgKind::Read => "read",
+        MsgKind::Send => "send",
+        MsgKind::Serial => "serial",
+        MsgKind::SerialIn => "serial-in",
+        MsgKind::MqttOut => "mqtt-out",
+        MsgKind::MqttIn => "mqtt-in",
+    }
+}
+
+// values of a field in a line, empty if the line does not have it
+fn values(line: &LogLine, field: &str) -> Vec<String> {
+    let n = |v: &u8| v.to_string();
+    let mut values: Vec<String> = match field {
+        "level" => line.level.iter().cloned().collect(),
+        "status" => vec![status_name(line.status).to_string()],
+        "msg" | "message" => vec![line.msg.clone()],
+        "system" => {
+            let mut v: Vec<String> = line.system.iter().cloned().collect();
+            if let Some(name) = &line.system {
+                v.extend(
+                    dictionary()
+                        .systems
+                        .iter()
+                        .filter(|(_, d)| d.name() == Some(name))
+                        .map(|(code, _)| code.clone()),
+                );
+            }
+            v
+        }
+        "subsystem" => {
+            let mut v: Vec<String> = line.subsystem.iter().cloned().collect();
+            if let (Some(system), Some(name)) = (&line.system, &line.subsystem) {
+                for d in dictionary().systems.values() {
+                    if d.name() == Some(system) {
+                        v.extend(
+                            d.subsystems
+                                .iter()
+                                .filter(|(_, n)| *n == name)
+                                .map(|(code, _)| code.clone()),
+                        );
+                    }
+                }
+            }
+            v
+        }
+        _ => Vec::new(),
+    };
+
+    match &line.fields {
+        Some(MsgFields::Msg(m)) => values.extend(match field {
+            "node" => vec![n(&m.sender), n(&m.destination)],
+            "sender" | "from" => vec![n(&m.sender)],
+            "dest" | "destination" => vec![n(&m.destination)],
+            "last" => vec![n(&m.last)],
+            "next" => m.next.iter().map(n).collect(),
+            "sensor" | "child" => vec![n(&m.sensor)],
+            "cmd" | "command" => vec![n(&m.command), m.command_str()],
+            "type" => vec![n(&m.msg_type), m.type_str()],
+            "pt" => vec![n(&m.payload_type)],
+            "length" => vec![n(&m.length)],
+            "signed" => vec![m.signed.to_string()],
+            "ack" => m.ack.iter().map(|a| a.to_string()).collect(),
+            "ft" | "failures" => m.failures.iter().map(n).collect(),
+            "st" => m
+                .send_ok
+                .iter()
+                .map(|ok| if *ok { "OK" } else { "NACK" }.to_string())
+                .collect(),
+            "payload" => vec![m.payload.clone()],
+            "kind" => vec![kind_name(m.kind).to_string()],
+            _ => Vec::new(),
+        }),
+        Some(MsgFields::Custom(pairs)) => values.extend(
+            pairs
+                .iter()
+                .filter(|(name, _)| name.to_lowercase() == field)
+                .map(|(_, v)| v.clone()),
+        ),
+        Some(fields) => values.extend(other_field(fields, field)),
+        None => (),
+    }
+    values
+}
+
+// fields of the decoded messages other than transport messages
+fn other_field(fields: &MsgFields, field: &str) -> Option<String> {
+    let value = match (fields, field) {
+        (MsgFields::BadLength { length, .. }, "length") => length.to_string(),
+        (MsgFields::BadLength { expected, .. }, "expected") => expected.to_string(),
+        (MsgFields::BadProtocolVersion { received, .. }, "received") => received.to_string(),
+        (MsgFields::BadProtocolVersion { expected, .. }, "expected") => expected.to_string(),
+        (MsgFields::AssignNodeId { id, .. }, "id" | "node") => id.to_string(),
+        (MsgFields::AssignNodeId { ok, .. }, "ok") => ok.to_string(),
+        (MsgFields::PingSend { to }, "to" | "node") => to.to_string(),
+        (MsgFields::SignalReport { cmd, .. }, "cmd") => cmd.to_string(),
+        (MsgFields::SignalReport { value, .. }, "value") => value.to_string(),
+        (MsgFields::SignalReport { cmd, value }, "rssi" | "snr" | "tx") => {
+            let wanted = match SignalKind::from_command(*cmd) {
+                SignalKind::SendingRssi | SignalKind::ReceivingRssi => "rssi",
+                SignalKind::SendingSnr | SignalKind::ReceivingSnr => "snr",
+                SignalKind::TxPowerLevel | SignalKind::TxPowerPercent => "tx",
+                _ => return None,
+            };
+            if field != wanted {
+                return None;
+            }
+            value.to_string()
+        }
+        (MsgFields::LoadRoutingTable { ok }, "ok")
+        | (MsgFields::SaveRoutingTable { ok }, "ok")
+        | (MsgFields::SanityCheck { ok }, "ok")
+        | (MsgFields::UplinkCheck { ok }, "ok")
+        | (MsgFields::NodeToNodeRoute { ok }, "ok") => ok.to_string(),
+        (MsgFields::GatewayDistanceChanged { old, .. }, "old") => old.to_string(),
+        (MsgFields::GatewayDistanceChanged { new, .. }, "new") => new.to_string(),
+        (MsgFields::Ready { id, .. }, "id" | "node") => id.to_string(),
+        (MsgFields::Ready { parent, .. }, "parent") => parent.to_string(),
+        (MsgFields::Ready { distance, .. }, "distance") => distance.to_string(),
+        (MsgFields::FailCount { count }, "count") => count.to_string(),
+        (MsgFields::IdVerificationFailed { id }, "id" | "node") => id.to_string(),
+        (MsgFields::StaticId { id }, "id" | "node") => id.to_string(),
+        (MsgFields::CoreInit { version, .. }, "version") => version.clone(),
+        (MsgFields::CoreInit { release, .. }, "release") => release.as_ref()?.to_string(),
+        (MsgFields::Sleep { ms, .. }, "ms") => ms.to_string(),
+        (MsgFields::WakeUp { reason }, "reason") => reason.to_string(),
+        _ => match (fields.xport_state(), field) {
+            (Some(state), "state") => state.to_string(),
+            _ => return None,
+        },
+    };
+    Some(value)
+}
+
+fn compare(a: &str, op: Op, b: &str) -> bool {
+    let ordering = match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b),
+        _ => Some(a.to_lowercase().cmp(&b.to_lowercase())),
+    };
+    match op {
+        Op::Contains => a.to_lowercase().contains(&b.to_lowercase()),
+        Op::Eq | Op::Ne => ordering == Some(std::cmp::Ordering::Equal),
+        Op::Lt => ordering == Some(std::cmp::Ordering::Less),
+        Op::Le => matches!(ordering, Some(o) if o.is_le()),
+        Op::Gt => ordering == Some(std::cmp::Ordering::Greater),
+        Op::Ge => matches!(ordering, Some(o) if o.is_ge()),
+    }
+}
+
+impl Filter {
+    pub fn parse(s: &str) -> Result<Filter, BoxError> {
+        s.parse()
+    }
+
+    // values that can never match, e.g. a time that is not a time
+    fn check(&self) -> Result<(), BoxError> {
+        match self {
+            Filter::Compare {
+                field, value, time, ..
+            } if field == "time" => match time {
+                Some(_) => Ok(()),
+                None => Err(format!("'{}' is not a time", value).into()),
+            },
+            Filter::Compare { .. } => Ok(()),
+            Filter::Not(f) => f.check(),
+            Filter::And(a, b) | Filter::Or(a, b) => a.check().and(b.check()),
+        }
+    }
+
+    pub fn matches(&self, line: &LogLine) -> bool {
+        match self {
+            Filter::Compare {
+                field, op, time, ..
+            } if field == "time" => match (line.datetime, *time) {
+                (Some(t), Some(v)) => match op {
+                    Op::Eq => t == v,
+                    Op::Ne => t != v,
+                    Op::Lt => t < v,
+                    Op::Le => t <= v,
+                    Op::Gt => t > v,
+                    Op::Ge => t >= v,
+                    Op::Contains => false,
+                },
+                _ => false,
+            },
+            Filter::Compare {
+                field, op, value, ..
+            } => {
+                let values = values(line, field);
+                match op {
+                    Op::Ne => !values.is_empty() && !values.iter().any(|v| compare(v, *op, value)),
+                    _ => values.iter().any(|v| compare(v, *op, value)),
+                }
+            }
+            Filter::Not(f) => !f.matches(line),
+            Filter::And(a, b) => a.matches(line) && b.matches(line),
+            Filter::Or(a, b) => a.matches(line) || b.matches(line),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Filter::Compare {
+                field, op, value, ..
+            } => write!(f, "{}{}\"{}\"", field, op, value),
+            Filter::Not(a) => write!(f, "not ({})", a),
+            Filter::And(a, b) => write!(f, "({} and {})", a, b),
+            Filter::Or(a, b) => write!(f, "({} or {})", a, b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+
+    fn matches(filter: &str, line: &str) -> bool {
+        Filter::parse(filter)
+            .unwrap()
+            .matches(&parse_log_line(line))
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        let filter =
+            Filter::parse(r#"node=12 and (cmd = C_SET or not level=INFO) or msg~"a b""#).unwrap();
+        assert_eq!(
+            filter.to_string(),
+            r#"((node="12" and (cmd="C_SET" or not (level="INFO"))) or msg~"a b")"#
+        );
+        assert_eq!(Filter::parse(&filter.to_string()).unwrap(), filter);
+        assert!(Filter::parse("node=").is_err());
+        assert!(Filter::parse("node=12 and").is_err());
+        assert!(Filter::parse("(node=12").is_err());
+        assert!(Filter::parse("time>yesterday").is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let at = |s: &str| {
+            let t = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+            Local.from_local_datetime(&t).unwrap()
+        };
+        let january = at("2022-01-05 09:00");
+        // in January an October time is last year's, as the lines of the log
+        assert_eq!(
+            parse_time_at("Oct 18 13:00", january),
+            Some(at("2021-10-18 13:00"))
+        );
+        assert_eq!(
+            parse_time_at("Jan 4 13:00:00", january),
+            Some(at("2022-01-04 13:00"))
+        );
+        assert_eq!(
+            parse_time_at("2020-10-18 13:00", january),
+            Some(at("2020-10-18 13:00"))
+        );
+        assert_eq!(parse_time_at("Oct 32 13:00", january), None);
+        let Filter::Compare { time, .. } = Filter::parse(r#"time>"2021-10-18 13:00""#).unwrap()
+        else {
+            panic!("not a comparison");
+        };
+        assert_eq!(time, Some(at("2021-10-18 13:00")));
+    }
+
+    #[test]
+    fn test_matches() {
+        let send = "Oct 18 13:36:53 DEBUG !TSF:MSG:SEND,0-0-12-12,s=1,c=1,t=2,pt=0,l=1,sg=0,ft=2,st=NACK:1";
+        assert!(matches(
+            r#"node=12 and cmd=C_SET and status=ERROR and time>"Oct 18 13:00""#,
+            send
+        ));
+        assert!(matches(
+            "cmd=1 and type=V_STATUS and st=NACK and ft>=2",
+            send
+        ));
+        assert!(matches("system=TSF and subsystem=MSG", send));
+        assert!(matches("system=xport and subsystem=Msg", send));
+        assert!(!matches(r#"time<"Oct 18 13:00""#, send));
+        assert!(!matches("node=7", send));
+        assert!(!matches("not node=12", send));
+        assert!(matches("node!=7", send));
+
+        let ready = "Oct 18 13:36:52 DEBUG TSM:READY:ID=12,PAR=0,DIS=1";
+        assert!(matches("node=12 and parent=0 and state=READY", ready));
+        assert!(!matches("payload=1", ready));
+        assert!(!matches("payload!=1", ready));
+        assert!(matches("level=debug and msg~\"parent ID (0)\"", ready));
+
+        let banner = "Oct 18 13:36:52 INFO  Protocol version - 2.3.2";
+        assert!(matches("level=INFO and status=OK", banner));
+        assert!(!matches("system=TSF", banner));
+    }
+}
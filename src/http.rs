@@ -0,0 +1,138 @@
+// just enough of an HTTP/1.1 client to POST to services on the local network
+// (InfluxDB, webhooks) without pulling in an HTTP stack; plain http:// only
+use crate::BoxError;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// how long connecting, sending and waiting for the answer may each take, so
+// a service that is down or hangs does not stall the log being read
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpUrl {
+    pub host: String, //host:port
+    pub path: String, //with the query, if any
+}
+
+impl HttpUrl {
+    pub fn parse(url: &str) -> Result<HttpUrl, BoxError> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("{}: only http:// URLs are supported", url))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(format!("{}: no host", url).into());
+        }
+        let host = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(HttpUrl {
+            host,
+            path: path.to_string(),
+        })
+    }
+}
+
+// POST body to url, anything but a 2xx answer is an error
+pub fn post(
+    url: &HttpUrl,
+    content_type: &str,
+    headers: &[(&str, String)],
+    body: &str,
+) -> Result<(), BoxError> {
+    post_timeout(url, content_type, headers, body, TIMEOUT)
+}
+
+fn connect(host: &str, timeout: Duration) -> std::io::Result<TcpStream> {
+    let mut error = None;
+    for addr in host.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error.unwrap_or_else(|| std::io::Error::other("no address")))
+}
+
+fn post_timeout(
+    url: &HttpUrl,
+    content_type: &str,
+    headers: &[(&str, String)],
+    body: &str,
+    timeout: Duration,
+) -> Result<(), BoxError> {
+    let mut stream = connect(&url.host, timeout).map_err(|e| format!("{}: {}", url.host, e))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.host,
+        content_type,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body.as_bytes())?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader
+        .read_line(&mut status)
+        .map_err(|e| format!("{}{}: {}", url.host, url.path, e))?;
+    let code = status.split_whitespace().nth(1).unwrap_or("");
+    if code.starts_with('2') {
+        return Ok(());
+    }
+    let mut response = String::new();
+    let _ = reader.read_to_string(&mut response);
+    let message = response.split("\r\n\r\n").nth(1).unwrap_or("").trim();
+    Err(format!("{}{}: {} {}", url.host, url.path, status.trim(), message).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            HttpUrl::parse("http://localhost:8086/api/v2/write?bucket=b").unwrap(),
+            HttpUrl {
+                host: "localhost:8086".to_string(),
+                path: "/api/v2/write?bucket=b".to_string()
+            }
+        );
+        assert_eq!(
+            HttpUrl::parse("http://hooks.local").unwrap(),
+            HttpUrl {
+                host: "hooks.local:80".to_string(),
+                path: "/".to_string()
+            }
+        );
+        assert!(HttpUrl::parse("https://example.com/").is_err());
+        assert!(HttpUrl::parse("http:///path").is_err());
+    }
+
+    #[test]
+    fn test_timeout() {
+        // a service that takes the request but never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = HttpUrl::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let start = std::time::Instant::now();
+        let result = post_timeout(&url, "text/plain", &[], "x", Duration::from_millis(200));
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(listener);
+    }
+}
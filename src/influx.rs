@@ -0,0 +1,466 @@
+// InfluxDB line protocol export of sensor values and transport statistics
+//
+// every value a node reports (C_SET read by the gateway) becomes a point
+//   mysensors,node=12,sensor=1,type=V_TEMP value=21.5 1697634001000000000
+// values that are not numbers go into a field of their own, as InfluxDB
+// rejects a field that is a float in one point and a string in another
+//   mysensors,node=12,sensor=3,type=V_TEXT text="hello" 1697634002000000000
+// and the messages each node sent, received and lost are summed up per
+// interval
+//   mysensors_transport,node=12 received=4i,sent=2i,nacks=1i 1697634000000000000
+// names of the measurements, tags and field come from an InfluxConfig, which
+// can be read from TOML. lines without a time (serial gateway logs) cannot be
+// placed and are skipped
+//
+// the lines are written to a file or stdout, or POSTed in batches to an HTTP
+// endpoint such as http://localhost:8086/api/v2/write?org=home&bucket=sensors
+use crate::http::{post, HttpUrl};
+use crate::protocol::{type_name, MsgKind, C_SET};
+use crate::{BoxError, LogLine, MsgFields};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct InfluxConfig {
+    pub sensor_measurement: String,
+    pub transport_measurement: String,
+    pub node_tag: String,
+    pub sensor_tag: String,
+    pub type_tag: String,
+    pub value_field: String,            //numbers, as floats
+    pub text_field: String,             //anything else, as strings
+    pub tags: BTreeMap<String, String>, //added to every point, e.g. site = "home"
+    pub interval_secs: i64,             //of the transport statistics
+    pub batch_size: usize,              //lines per POST
+    pub token: Option<String>,          //sent as Authorization: Token <token>
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        InfluxConfig {
+            sensor_measurement: "mysensors".to_string(),
+            transport_measurement: "mysensors_transport".to_string(),
+            node_tag: "node".to_string(),
+            sensor_tag: "sensor".to_string(),
+            type_tag: "type".to_string(),
+            value_field: "value".to_string(),
+            text_field: "text".to_string(),
+            tags: BTreeMap::new(),
+            interval_secs: 60,
+            batch_size: 5000,
+            token: None,
+        }
+    }
+}
+
+impl InfluxConfig {
+    pub fn from_toml(s: &str) -> Result<InfluxConfig, BoxError> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn load(path: &str) -> Result<InfluxConfig, BoxError> {
+        let s = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        InfluxConfig::from_toml(&s).map_err(|e| format!("{}: {}", path, e).into())
+    }
+}
+
+// escaping of the line protocol: measurements escape commas and spaces, tag
+// keys, tag values and field keys also equal signs
+fn escape_measurement(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(' ', "\\ ")
+}
+
+fn escape_key(s: &str) -> String {
+    escape_measurement(s).replace('=', "\\=")
+}
+
+// numbers as floats in the value field, anything else as a string in the
+// text field
+fn field(config: &InfluxConfig, payload: &str) -> String {
+    match payload.trim().parse::<f64>() {
+        Ok(v) if v.is_finite() => format!("{}={}", escape_key(&config.value_field), v),
+        _ => format!(
+            "{}=\"{}\"",
+            escape_key(&config.text_field),
+            payload.replace('\\', "\\\\").replace('"', "\\\"")
+        ),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct NodeCounts {
+    received: u64,
+    sent: u64,
+    nacks: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct InfluxExporter {
+    config: InfluxConfig,
+    tags: String, //the static tags, rendered
+    interval: Option<i64>,
+    counts: BTreeMap<u8, NodeCounts>,
+}
+
+impl InfluxExporter {
+    pub fn new(config: InfluxConfig) -> Self {
+        let tags = config
+            .tags
+            .iter()
+            .map(|(k, v)| format!(",{}={}", escape_key(k), escape_key(v)))
+            .collect();
+        InfluxExporter {
+            config,
+            tags,
+            ..Default::default()
+        }
+    }
+
+    fn point(&self, measurement: &str, tags: &[(&str, String)], fields: &str, at: i64) -> String {
+        let mut line = escape_measurement(measurement);
+        for (key, value) in tags {
+            line.push_str(&format!(",{}={}", escape_key(key), escape_key(value)));
+        }
+        line.push_str(&self.tags);
+        format!("{} {} {}", line, fields, at)
+    }
+
+    // lines for the statistics of the interval that ended
+    fn flush_interval(&mut self) -> Vec<String> {
+        let start = match self.interval.take() {
+            Some(start) => start,
+            None => return Vec::new(),
+        };
+        let at = start * self.config.interval_secs.max(1) * 1_000_000_000;
+        let counts = std::mem::take(&mut self.counts);
+        counts
+            .into_iter()
+            .map(|(node, c)| {
+                self.point(
+                    &self.config.transport_measurement,
+                    &[(&self.config.node_tag, node.to_string())],
+                    &format!(
+                        "received={}i,sent={}i,nacks={}i",
+                        c.received, c.sent, c.nacks
+                    ),
+                    at,
+                )
+            })
+            .collect()
+    }
+
+    fn nanos(datetime: DateTime<Local>) -> i64 {
+        datetime.timestamp_nanos_opt().unwrap_or_default()
+    }
+
+    // lines a log line adds, statistics are returned once their interval is over
+    pub fn feed(&mut self, line: &LogLine) -> Vec<String> {
+        let (datetime, m) = match (line.datetime, &line.fields) {
+            (Some(dt), Some(MsgFields::Msg(m))) => (dt, m),
+            _ => return Vec::new(),
+        };
+        let interval = dt_interval(datetime, self.config.interval_secs);
+        let mut lines = match self.interval {
+            Some(current) if current != interval => self.flush_interval(),
+            _ => Vec::new(),
+        };
+        self.interval = Some(interval);
+
+        match m.kind {
+            MsgKind::Read | MsgKind::Serial if m.sender != 0 => {
+                self.counts.entry(m.sender).or_default().received += 1;
+                if m.command == C_SET {
+                    let value_type =
+                        type_name(m.command, m.msg_type).unwrap_or_else(|| m.msg_type.to_string());
+                    lines.push(self.point(
+                        &self.config.sensor_measurement,
+                        &[
+                            (&self.config.node_tag, m.sender.to_string()),
+                            (&self.config.sensor_tag, m.sensor.to_string()),
+                            (&self.config.type_tag, value_type),
+                        ],
+                        &field(&self.config, &m.payload),
+                        Self::nanos(datetime),
+                    ));
+                }
+            }
+            MsgKind::Send => {
+                let counts = self.counts.entry(m.destination).or_default();
+                counts.sent += 1;
+                if m.send_ok == Some(false) {
+                    counts.nacks += 1;
+                }
+            }
+            _ => {}
+        }
+        lines
+    }
+
+    // statistics of the last interval, at the end of the log
+    pub fn finish(&mut self) -> Vec<String> {
+        self.flush_interval()
+    }
+}
+
+fn dt_interval(datetime: DateTime<Local>, interval_secs: i64) -> i64 {
+    datetime.timestamp().div_euclid(interval_secs.max(1))
+}
+
+// batches kept for an InfluxDB that cannot be reached, older lines are dropped
+const PENDING_BATCHES: usize = 100;
+
+// where the lines go
+pub enum InfluxSink {
+    Writer(Box<dyn Write + Send>),
+    Http {
+        url: HttpUrl,
+        token: Option<String>,
+        batch_size: usize,
+        pending: Vec<String>,
+    },
+}
+
+impl InfluxSink {
+    // "-" for stdout, an http:// URL to POST to or a file to create
+    pub fn open(dest: &str, config: &InfluxConfig) -> Result<InfluxSink, BoxError> {
+        if dest == "-" {
+            return Ok(InfluxSink::Writer(Box::new(std::io::stdout())));
+        }
+        if dest.contains("://") {
+            return Ok(InfluxSink::Http {
+                url: HttpUrl::parse(dest)?,
+                token: config.token.clone(),
+                batch_size: config.batch_size.max(1),
+                pending: Vec::new(),
+            });
+        }
+        let file = std::fs::File::create(dest).map_err(|e| format!("{}: {}", dest, e))?;
+        Ok(InfluxSink::Writer(Box::new(std::io::BufWriter::new(file))))
+    }
+
+    pub fn write(&mut self, lines: &[String]) -> Result<(), BoxError> {
+        match self {
+            InfluxSink::Writer(writer) => {
+                for line in lines {
+                    writeln!(writer, "{}", line)?;
+                }
+                Ok(())
+            }
+            InfluxSink::Http {
+                pending,
+                batch_size,
+                ..
+            } => {
+                // while InfluxDB cannot be reached it is tried again once a
+                // batch more is waiting, not for every line
+                let before = pending.len() / *batch_size;
+                pending.extend_from_slice(lines);
+                if pending.len() / *batch_size > before {
+                    self.flush()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // write out what is buffered, e.g. at the end of the log
+    pub fn flush(&mut self) -> Result<(), BoxError> {
+        match self {
+            InfluxSink::Writer(writer) => Ok(writer.flush()?),
+            InfluxSink::Http {
+                url,
+                token,
+                batch_size,
+                pending,
+            } => {
+                if pending.is_empty() {
+                    return Ok(());
+                }
+                let mut body = pending.join("\n");
+                body.push('\n');
+                let headers: Vec<(&str, String)> = token
+                    .iter()
+                    .map(|t| ("Authorization", format!("Token {}", t)))
+                    .collect();
+                if let Err(e) = post(url, "text/plain; charset=utf-8", &headers, &body) {
+                    // sent with the next batch, unless the outage lasts long
+                    let keep = *batch_size * PENDING_BATCHES;
+                    if pending.len() > keep {
+                        pending.drain(..pending.len() - keep);
+                    }
+                    return Err(e);
+                }
+                pending.clear();
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+
+    const LOG: [&str; 6] = [
+        "Oct 18 13:00:01 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5",
+        "Oct 18 13:00:02 DEBUG TSF:MSG:READ,12-12-0,s=3,c=1,t=47,pt=0,l=9,sg=0:say \"hi\"",
+        "Oct 18 13:00:03 DEBUG !TSF:MSG:SEND,0-0-12-12,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=3,st=NACK:1",
+        "Oct 18 13:00:03 DEBUG TSF:MSG:READ,12-12-0,s=255,c=3,t=0,pt=1,l=2,sg=0:87",
+        "Oct 18 13:01:00 DEBUG TSF:MSG:READ,7-7-0,s=1,c=1,t=0,pt=7,l=4,sg=0:19.0",
+        "0;255;3;0;9;1234 TSF:MSG:READ,9-9-0,s=1,c=1,t=0,pt=7,l=4,sg=0:18.0",
+    ];
+
+    fn export(config: InfluxConfig) -> Vec<String> {
+        let mut exporter = InfluxExporter::new(config);
+        let mut lines = Vec::new();
+        for line in LOG {
+            lines.extend(exporter.feed(&parse_log_line(line)));
+        }
+        lines.extend(exporter.finish());
+        lines
+    }
+
+    fn nanos(line: &str) -> i64 {
+        InfluxExporter::nanos(parse_log_line(line).datetime.unwrap())
+    }
+
+    #[test]
+    fn test_export() {
+        let lines = export(InfluxConfig::default());
+        assert_eq!(
+            lines,
+            vec![
+                format!(
+                    "mysensors,node=12,sensor=1,type=V_TEMP value=21.5 {}",
+                    nanos(LOG[0])
+                ),
+                format!(
+                    "mysensors,node=12,sensor=3,type=V_TEXT text=\"say \\\"hi\\\"\" {}",
+                    nanos(LOG[1])
+                ),
+                format!(
+                    "mysensors_transport,node=12 received=3i,sent=1i,nacks=1i {}",
+                    nanos("Oct 18 13:00:00 DEBUG TSM:INIT")
+                ),
+                format!(
+                    "mysensors,node=7,sensor=1,type=V_TEMP value=19 {}",
+                    nanos(LOG[4])
+                ),
+                format!(
+                    "mysensors_transport,node=7 received=1i,sent=0i,nacks=0i {}",
+                    nanos("Oct 18 13:01:00 DEBUG TSM:INIT")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config() {
+        let config = InfluxConfig::from_toml(
+            r#"
+            sensor_measurement = "sensor values"
+            node_tag = "node_id"
+            value_field = "v"
+            text_field = "t"
+            [tags]
+            site = "home,garden"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.transport_measurement, "mysensors_transport");
+        let lines = export(config);
+        assert!(lines[0].starts_with(
+            "sensor\\ values,node_id=12,sensor=1,type=V_TEMP,site=home\\,garden v=21.5 "
+        ));
+        assert!(lines[1].contains(",site=home\\,garden t=\"say "));
+        assert!(lines[2].starts_with("mysensors_transport,node_id=12,site=home\\,garden "));
+    }
+
+    #[test]
+    fn test_post() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in ["204 No Content", "400 Bad Request"] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(l) = line.strip_prefix("Content-Length: ") {
+                        length = l.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                requests.push((head, String::from_utf8(body).unwrap()));
+                let mut stream = stream;
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: 5\r\n\r\nnope\n",
+                    status
+                )
+                .unwrap();
+            }
+            requests
+        });
+
+        let config = InfluxConfig {
+            batch_size: 2,
+            token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let url = format!("http://{}/api/v2/write?bucket=sensors", addr);
+        let mut sink = InfluxSink::open(&url, &config).unwrap();
+        let lines = export(config);
+        // the first two lines make a batch
+        sink.write(&lines[..1]).unwrap();
+        sink.write(&lines[1..2]).unwrap();
+        sink.write(&lines[2..3]).unwrap();
+        let error = sink.flush().unwrap_err().to_string();
+        assert!(error.contains("400 Bad Request nope"), "{}", error);
+
+        let requests = server.join().unwrap();
+        let (head, body) = &requests[0];
+        assert!(head.starts_with("POST /api/v2/write?bucket=sensors HTTP/1.1\r\n"));
+        assert!(head.contains("Authorization: Token secret\r\n"));
+        assert_eq!(body, &format!("{}\n{}\n", lines[0], lines[1]));
+        assert_eq!(requests[1].1, format!("{}\n", lines[2]));
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = InfluxConfig {
+            batch_size: 1,
+            ..Default::default()
+        };
+        let mut sink = InfluxSink::open(&format!("http://{}/", addr), &config).unwrap();
+        let lines = export(config);
+        for i in 0..PENDING_BATCHES + 10 {
+            assert!(sink.write(&lines[i % lines.len()..][..1]).is_err());
+        }
+        let InfluxSink::Http { pending, .. } = &sink else {
+            panic!("not posting");
+        };
+        assert_eq!(pending.len(), PENDING_BATCHES);
+    }
+}
@@ -1,14 +1,36 @@
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Duration, Local};
 use nom::error::{ErrorKind, ParseError};
 use nom::Finish;
+use std::sync::Arc;
 
 #[macro_use]
 extern crate lazy_static;
 
+pub mod alerts;
+pub mod analysis;
+#[cfg(feature = "sqlite")]
+pub mod archive;
+pub mod dictionary;
+pub mod encoder;
+pub mod filter;
+pub mod http;
+pub mod influx;
+pub mod merge;
+pub mod metrics;
+pub mod protocol;
+pub mod reader;
+pub mod redact;
+pub mod registry;
+pub mod report;
+pub mod session;
+pub mod simulator;
+pub use protocol::{MsgFields, TransportMsg};
+pub use registry::{ParserRegistry, SubsystemParser};
+
 // so that we can use ? to pass up errors
 pub type BoxError = std::boxed::Box<dyn std::error::Error + std::marker::Send + std::marker::Sync>;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SendStatus {
     OK,      //if no prepended char
     ERROR,   //if '!'
@@ -32,7 +54,11 @@ impl std::str::FromStr for SendStatus {
 pub struct LogLine {
     pub datetime: Option<DateTime<Local>>,
     pub level: Option<std::string::String>,
+    pub system: Option<String>, //name of the system, e.g. "Xport" for TSF
+    pub subsystem: Option<String>, //name of the subsystem, e.g. "Msg" for MSG
     pub msg: std::string::String, //TODO: break down message further if possible
+    pub fields: Option<MsgFields>, //decoded fields, if the message has any
+    pub status: SendStatus,     //from the '!' / '?' prefix of the message
 }
 
 impl Default for LogLine {
@@ -40,7 +66,11 @@ impl Default for LogLine {
         LogLine {
             datetime: Some(Local::now()),
             level: Some("INFO".to_string()),
+            system: None,
+            subsystem: None,
             msg: "".to_string(),
+            fields: None,
+            status: SendStatus::OK,
         }
     }
 }
@@ -57,7 +87,7 @@ impl std::fmt::Display for LogLine {
             None => "".to_string(),
         };
 
-        if self.datetime == None || self.level == None {
+        if self.datetime.is_none() || self.level.is_none() {
             //just write the message - as there was an error in parsing the input
             write!(f, "{}", self.msg)
         } else {
@@ -88,7 +118,7 @@ use std::fmt;
 impl fmt::Display for LogParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            LogParseError::DateTimeError => write!(f, "{}", "LogParseError: bad date/time"),
+            LogParseError::DateTimeError => write!(f, "LogParseError: bad date/time"),
             LogParseError::SubSystemError(found, expected) => write!(
                 f,
                 "LogParseError: bad subsystem found ({}) expected ({})",
@@ -108,27 +138,169 @@ impl std::error::Error for LogParseError {
     //FIXME: add other Error trait fn's
 }
 
+// message part of a log line as broken down by one of the subsystem parsers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParsedMessage {
+    pub send_status: SendStatus, //set from the '!'/'?' prefix after parsing
+    pub system: Option<String>,
+    pub subsystem: Option<String>,
+    pub msg: String,
+    pub fields: Option<MsgFields>,
+}
+
+impl std::fmt::Display for ParsedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.system, &self.subsystem) {
+            (Some(system), Some(subsystem)) => {
+                write!(f, "{}:{}:{}", system, subsystem, self.msg)
+            }
+            _ => write!(f, "{}", self.msg),
+        }
+    }
+}
+
+// parses log lines with the built-in subsystem parsers plus any registered
+// custom ones
+//
+// lines are expected in order: the parser keeps track of the gateway session
+// and of the MySensors release that wrote it (from the "Protocol version" banner
+// or VER= of MCO:BGN:INIT) to pick the matching dictionary and message formats
+//
+// syslog dates have no year: lines that would be in the future as the current
+// year are moved back a year, see merge::infer_year
+#[derive(Default)]
+pub struct LogParser {
+    registry: ParserRegistry,
+    sessions: session::SessionTracker,
+    forced_version: Option<protocol::Version>,
+    detected_version: Option<protocol::Version>,
+    now: Option<DateTime<Local>>, //for infer_year, only read again when a line is later
+    //release specific dictionary: built from, release, dictionary
+    versioned: Option<(
+        Arc<dictionary::Dictionary>,
+        protocol::Version,
+        Arc<dictionary::Dictionary>,
+    )>,
+}
+
+impl LogParser {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // add a parser for custom messages, see ParserRegistry::register
+    pub fn register(&mut self, priority: i32, parser: impl SubsystemParser + 'static) {
+        self.registry.register(priority, parser);
+    }
+
+    // decode as the given release regardless of what the log says, for logs
+    // without the banner; None goes back to detecting the release
+    pub fn force_version(&mut self, version: Option<protocol::Version>) {
+        self.forced_version = version;
+    }
+
+    // release used for the next line
+    pub fn version(&self) -> Option<protocol::Version> {
+        self.forced_version.or(self.detected_version)
+    }
+
+    fn dictionary_for(
+        &mut self,
+        version: Option<protocol::Version>,
+    ) -> Arc<dictionary::Dictionary> {
+        let active = dictionary::active_dictionary();
+        let version = match version {
+            Some(v) => v,
+            None => return active,
+        };
+        match &self.versioned {
+            Some((base, v, d)) if Arc::ptr_eq(base, &active) && *v == version => d.clone(),
+            _ => {
+                let d = Arc::new(active.for_version(&version));
+                self.versioned = Some((active, version, d.clone()));
+                d
+            }
+        }
+    }
+
+    pub fn parse_line(&mut self, i: &str) -> LogLine {
+        let version = self.version();
+        let dictionary = self.dictionary_for(version);
+        let result = dictionary::with_scope(dictionary, version, || {
+            parsers::parse_log_with(i, &self.registry).finish()
+        });
+        let mut line = match result {
+            Ok((_, ll)) => ll,
+            Err(_) => LogLine {
+                datetime: None,
+                level: None,
+                system: None,
+                subsystem: None,
+                msg: i.to_string(),
+                fields: None,
+                status: SendStatus::OK,
+            },
+        };
+
+        if let Some(datetime) = line.datetime {
+            let now = match self.now {
+                Some(now) if datetime <= now + Duration::days(1) => now,
+                _ => *self.now.insert(Local::now()),
+            };
+            line.datetime = Some(merge::infer_year(datetime, now));
+        }
+
+        if self.sessions.feed(&line) {
+            self.detected_version = None;
+        }
+        if let Some(v) = reported_version(&line) {
+            self.detected_version = Some(v);
+        }
+        line
+    }
+}
+
+// release a line reports, from the banner or MCO:BGN:INIT
+fn reported_version(line: &LogLine) -> Option<protocol::Version> {
+    if let Some(MsgFields::CoreInit { version, .. }) = &line.fields {
+        return version.parse().ok();
+    }
+    line.msg
+        .strip_prefix("Protocol version - ")
+        .and_then(|v| v.parse().ok())
+}
+
+// a line on its own, its year inferred as LogParser does
 pub fn parse_log_line(i: &str) -> LogLine {
     let result = parsers::parse_log(i).finish();
     match result {
-        Ok((_, ll)) => return ll,
+        Ok((_, mut ll)) => {
+            ll.datetime = ll
+                .datetime
+                .map(|datetime| merge::infer_year(datetime, Local::now()));
+            ll
+        }
         Err(_) => {
             //THINK: return error or default LogLine swallowing parse error to caller??
-            return LogLine {
+            LogLine {
                 datetime: None,
                 level: None,
+                system: None,
+                subsystem: None,
                 msg: i.to_string(),
-            };
+                fields: None,
+                status: SendStatus::OK,
+            }
         } //was Err(e) => return Err(BoxError::from(e)),
-    };
+    }
 
     // enum `nom::Err<LogParseError<&str>>`
 }
 
-// pub(self) allows other methods within this file to use this module but not externally
-pub(self) mod parsers {
+// private module allows other methods within this file to use this module but not externally
+mod parsers {
     use super::*;
-    use chrono::{Datelike, NaiveTime};
+    use chrono::{Datelike, NaiveDate, NaiveTime, TimeZone};
     use nom::branch::alt;
     use nom::bytes::complete::tag;
     use nom::character::complete::alpha1;
@@ -137,6 +309,9 @@ pub(self) mod parsers {
 
     mod core_parsers;
     mod gateway_parsers;
+    mod serial_parsers;
+    pub use gateway_parsers::parse_mqtt_topic;
+    pub use serial_parsers::parse_serial_msg;
     mod xport_function_parsers;
     mod xport_machine_parsers;
 
@@ -174,19 +349,14 @@ pub(self) mod parsers {
         };
         let (remaining, _) = nom::character::complete::one_of(" ")(remaining)?;
         let (remaining, daystr) = nom::bytes::complete::take(2usize)(remaining)?;
-        let day = match daystr.parse::<u32>() {
-            Ok(d) => d,
-            Err(_) => 0u32, //Let the date function fail as out of bounds
-        };
+        // syslog pads single digit days with a space ("Oct  8")
+        let day = daystr.trim_start().parse::<u32>().unwrap_or_default(); //Let the date function fail as out of bounds
+
         // create a Date from month, day and current year
-        let date = chrono::offset::Local::today();
-        let date = match date.with_month(month) {
-            Some(d) => d,
-            None => chrono::offset::Local::today(), //FIXME: not the right thing to do!
-        };
-        let date = match date.with_day(day) {
+        let today = Local::now().date_naive();
+        let date = match NaiveDate::from_ymd_opt(today.year(), month, day) {
             Some(d) => d,
-            None => chrono::offset::Local::today(), //FIXME: not the right thing to do!
+            None => today, //FIXME: not the right thing to do!
         };
 
         //get the time
@@ -200,7 +370,7 @@ pub(self) mod parsers {
         };
 
         //finally add time to the date
-        let datetime = match date.and_time(time) {
+        let datetime = match Local.from_local_datetime(&date.and_time(time)).earliest() {
             Some(dt) => dt,
             None => return Err(nom::Err::Error(LogParseError::DateTimeError)),
         };
@@ -211,48 +381,25 @@ pub(self) mod parsers {
         Ok((remaining, datetime))
     }
 
-    //look up table helper for converting system/subsystem into human readable name
-    lazy_static! {
-        static ref S_SS_LUT: HashMap<&'static str, &'static str> = {
-            let mut m = HashMap::new();
-            m.insert("GWT:RFC", "Gway:Rcv");
-            m.insert("GWT:TSA", "Gway:Xport Avail");
-            m.insert("MCO:BGN", "Core:Begin");
-            m.insert("TSF:LRT", "Xport:LoadRoutingTable");
-            m.insert("TSF:MSG", "Xport:TxMsg");
-            m.insert("TSF:SAN", "Xport:Sanity");
-            m.insert("TSF:WUR", "Xport:WaitUntilReady");
-            m.insert("TSM:READY", "XportSM:Ready");
-            m
-        };
-    }
-
-    #[derive(Debug, PartialEq)]
-    pub struct ParsedMessage {
-        send_status: SendStatus,
-        system: Option<String>,
-        subsystem: Option<String>,
-        msg: String,
-    }
-
-    impl std::fmt::Display for ParsedMessage {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            if self.system == None || self.subsystem == None {
-                write!(f, "{}", self.msg)
-            } else {
-                // let s_ss = S_SS_LUT.get(&s_ss[..]).unwrap();
-                write!(
-                    f,
-                    "{}:{}:{}",
-                    self.system.as_ref().unwrap(),
-                    self.subsystem.as_ref().unwrap(),
-                    self.msg
-                )
-            }
+    // all of the built-in subsystem parsers
+    pub fn parse_builtin(i: &str) -> Option<ParsedMessage> {
+        match alt((
+            core_parsers::parse_core,
+            gateway_parsers::parse_gateway,
+            xport_function_parsers::parse_xport_function,
+            xport_machine_parsers::parse_xport_machine,
+            serial_parsers::parse_serial,
+        ))(i)
+        {
+            Ok((_, parsed)) => Some(parsed),
+            Err(_) => None,
         }
     }
 
-    fn parse_message(i: &str) -> nom::IResult<&str, ParsedMessage, LogParseError> {
+    fn parse_message<'a>(
+        i: &'a str,
+        registry: &ParserRegistry,
+    ) -> nom::IResult<&'a str, ParsedMessage, LogParseError> {
         // for now just parse this as remaining string
         // parse optional first char of message into SendStatus   nom::IResult<&str, &str>
         let result: nom::IResult<&str, &str> = alt((tag("!"), tag("?")))(i);
@@ -261,25 +408,21 @@ pub(self) mod parsers {
             Err(_) => (i, SendStatus::OK), //return a SendStatus::OK
         };
 
-        //alternate of system parsers or remaining as message
-        let result = match alt((
-            core_parsers::parse_core,
-            gateway_parsers::parse_gateway,
-            xport_function_parsers::parse_xport_function,
-            xport_machine_parsers::parse_xport_machine,
-        ))(remaining)
-        {
-            Ok((_, parsed)) => ParsedMessage {
+        //registered parsers in priority order or remaining as message
+        let result = match registry.parse(remaining) {
+            Some(parsed) => ParsedMessage {
                 send_status: status,
                 system: parsed.system,
                 subsystem: parsed.subsystem,
                 msg: parsed.msg,
+                fields: parsed.fields,
             },
-            Err(_) => ParsedMessage {
+            None => ParsedMessage {
                 send_status: status,
                 system: None,
                 subsystem: None,
                 msg: remaining.to_string(),
+                fields: None,
             },
         };
 
@@ -287,6 +430,17 @@ pub(self) mod parsers {
     }
 
     pub fn parse_log(i: &str) -> nom::IResult<&str, LogLine, LogParseError> {
+        parse_log_with(i, &DEFAULT_REGISTRY)
+    }
+
+    lazy_static! {
+        static ref DEFAULT_REGISTRY: ParserRegistry = ParserRegistry::new();
+    }
+
+    pub fn parse_log_with<'a>(
+        i: &'a str,
+        registry: &ParserRegistry,
+    ) -> nom::IResult<&'a str, LogLine, LogParseError> {
         // parse the whole log line
         //Oct 18 13:36:52 INFO  Protocol version - 2.3.2
         //Oct 18 13:36:52 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2
@@ -300,14 +454,18 @@ pub(self) mod parsers {
         let (remaining, level) = alpha1(remaining)?;
         let (remaining, _) = space1(remaining)?;
         // finally parse the message
-        let (_, message) = parse_message(remaining)?; //FIXME: if can't parse message just pass up remaining
+        let (_, message) = parse_message(remaining, registry)?; //FIXME: if can't parse message just pass up remaining
 
         Ok((
             "",
             LogLine {
                 datetime: Some(datetime),
                 level: Some(level.to_string()),
+                system: message.system.clone(),
+                subsystem: message.subsystem.clone(),
                 msg: message.to_string(),
+                fields: message.fields,
+                status: message.send_status,
             },
         ))
     }
@@ -325,7 +483,12 @@ pub(self) mod parsers {
                 Ok((
                     "",
                     Local
-                        .from_local_datetime(&NaiveDate::from_ymd(2021, 10, 18).and_hms(13, 36, 52))
+                        .from_local_datetime(
+                            &NaiveDate::from_ymd_opt(Local::now().year(), 10, 18)
+                                .unwrap()
+                                .and_hms_opt(13, 36, 52)
+                                .unwrap()
+                        )
                         .unwrap(),
                 ))
             );
@@ -352,12 +515,19 @@ pub(self) mod parsers {
                         datetime: Some(
                             Local
                                 .from_local_datetime(
-                                    &NaiveDate::from_ymd(2021, 10, 18).and_hms(13, 36, 52)
+                                    &NaiveDate::from_ymd_opt(Local::now().year(), 10, 18)
+                                        .unwrap()
+                                        .and_hms_opt(13, 36, 52)
+                                        .unwrap()
                                 )
                                 .unwrap()
                         ),
                         level: Some("INFO".to_string()),
+                        system: None,
+                        subsystem: None,
                         msg: "Protocol version - 2.3.2".to_string(),
+                        fields: None,
+                        status: SendStatus::OK,
                     }
                 ))
             );
@@ -371,12 +541,25 @@ pub(self) mod parsers {
                         datetime: Some(
                             Local
                                 .from_local_datetime(
-                                    &NaiveDate::from_ymd(2021, 10, 18).and_hms(13, 36, 52)
+                                    &NaiveDate::from_ymd_opt(Local::now().year(), 10, 18)
+                                        .unwrap()
+                                        .and_hms_opt(13, 36, 52)
+                                        .unwrap()
                                 )
                                 .unwrap()
                         ),
                         level: Some("DEBUG".to_string()),
+                        system: Some("Core".to_string()),
+                        subsystem: Some("Begin".to_string()),
                         msg: "Core:Begin:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2".to_string(),
+                        fields: Some(MsgFields::CoreInit {
+                            node: "GW".to_string(),
+                            capabilities: "RNNGL---".to_string(),
+                            frequency: Some("NA".to_string()),
+                            release: Some(255),
+                            version: "2.3.2".to_string(),
+                        }),
+                        status: SendStatus::OK,
                     }
                 ))
             );
@@ -386,9 +569,157 @@ pub(self) mod parsers {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn test_parse_serial_line() {
+        let line = parse_log_line("Oct 18 13:36:52 DEBUG 12;1;1;0;0;21.5");
+        match line.fields {
+            Some(MsgFields::Msg(m)) => {
+                assert_eq!((m.sender, m.sensor, m.command, m.msg_type), (12, 1, 1, 0));
+                assert_eq!(m.payload, "21.5");
+            }
+            other => panic!("serial line not decoded: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_version_tracking() {
+        let mut parser = LogParser::new();
+        assert_eq!(parser.version(), None);
+
+        parser.parse_line("Oct 18 13:36:52 INFO  Starting gateway...");
+        parser.parse_line("Oct 18 13:36:52 INFO  Protocol version - 3.0.0");
+        assert_eq!(parser.version(), Some(protocol::Version::new(3, 0, 0)));
+        let line = parser.parse_line("Oct 18 13:36:53 DEBUG TSF:MSG:ECHO REQ");
+        assert_eq!(line.msg, "Xport:Msg:ECHO requested");
+        // the pre 2.3 LEN format is not accepted in a 3.x log
+        let line = parser.parse_line("Oct 18 13:36:53 DEBUG !TSF:MSG:LEN,9!=8");
+        assert_eq!(line.fields, None);
+
+        // restart with an older release
+        parser.parse_line("Oct 18 14:00:00 DEBUG MCO:BGN:INIT GW,CP=RNNGA---,REL=255,VER=2.2.0");
+        assert_eq!(parser.version(), Some(protocol::Version::new(2, 2, 0)));
+        let line = parser.parse_line("Oct 18 14:00:01 DEBUG TSF:MSG:ECHO REQ");
+        assert_eq!(line.msg, "Xport:Msg:ECHO REQ");
+        let line = parser.parse_line("Oct 18 14:00:01 DEBUG !TSF:MSG:LEN,9!=8");
+        assert_eq!(
+            line.fields,
+            Some(MsgFields::BadLength {
+                length: 9,
+                expected: 8
+            })
+        );
+
+        // banner starts a new session that does not know its release yet
+        parser.parse_line("Oct 18 15:00:00 INFO  Starting gateway...");
+        assert_eq!(parser.version(), None);
+
+        parser.force_version(Some(protocol::Version::new(3, 1, 0)));
+        let line = parser.parse_line("Oct 18 15:00:01 DEBUG TSF:MSG:ECHO");
+        assert_eq!(line.msg, "Xport:Msg:ECHO received");
+    }
+
+    #[test]
+    fn test_infer_year() {
+        use chrono::Datelike;
+        let now = Local::now();
+        let mut parser = LogParser::new();
+        for (datetime, years_back) in [(now, 0), (now + Duration::days(3), 1)] {
+            let raw = format!(
+                "{} DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5",
+                datetime.format("%b %e %H:%M:%S")
+            );
+            let parsed = parser.parse_line(&raw).datetime.unwrap();
+            // a line on its own gets the same year
+            assert_eq!(parse_log_line(&raw).datetime, Some(parsed));
+            assert!(parsed <= now + Duration::days(1), "{} in the future", raw);
+            // unless it is early next year, read as this year
+            let years_back = if datetime.year() == now.year() {
+                years_back
+            } else {
+                0
+            };
+            assert_eq!(parsed.year(), now.year() - years_back);
+        }
+    }
+
+    // the differences between releases listed in dictionaries/default.toml
+    #[test]
+    fn test_releases() {
+        let releases = [
+            // release, its MCO:BGN:INIT, its LEN line, the other LEN line, echo
+            (
+                (2, 1, 1),
+                "MCO:BGN:INIT GW,CP=RNNGA---,VER=2.1.1",
+                "!TSF:MSG:LEN,9!=8",
+                "!TSF:MSG:LEN=9,EXP=8",
+                "ACK received",
+            ),
+            (
+                (2, 2, 0),
+                "MCO:BGN:INIT GW,CP=RNNGA---,REL=255,VER=2.2.0",
+                "!TSF:MSG:LEN,9!=8",
+                "!TSF:MSG:LEN=9,EXP=8",
+                "ACK received",
+            ),
+            (
+                (2, 3, 2),
+                "MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2",
+                "!TSF:MSG:LEN=9,EXP=8",
+                "!TSF:MSG:LEN,9!=8",
+                "ACK received",
+            ),
+            (
+                (3, 0, 0),
+                "MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=3.0.0",
+                "!TSF:MSG:LEN=9,EXP=8",
+                "!TSF:MSG:LEN,9!=8",
+                "ECHO received",
+            ),
+        ];
+        for ((major, minor, patch), init, len, other_len, echo) in releases {
+            let version = protocol::Version::new(major, minor, patch);
+            let mut parser = LogParser::new();
+            let line = parser.parse_line(&format!("Oct 18 13:00:00 DEBUG {}", init));
+            assert_eq!(parser.version(), Some(version), "{}", init);
+            match line.fields {
+                Some(MsgFields::CoreInit {
+                    frequency, release, ..
+                }) => {
+                    assert_eq!(
+                        frequency.is_some(),
+                        version >= protocol::Version::new(2, 3, 0)
+                    );
+                    assert_eq!(
+                        release.is_some(),
+                        version >= protocol::Version::new(2, 2, 0)
+                    );
+                }
+                other => panic!("{}: {:?}", init, other),
+            }
+            let line = parser.parse_line(&format!("Oct 18 13:00:01 DEBUG {}", len));
+            assert_eq!(
+                line.fields,
+                Some(MsgFields::BadLength {
+                    length: 9,
+                    expected: 8
+                }),
+                "{} in {}",
+                len,
+                version
+            );
+            let line = parser.parse_line(&format!("Oct 18 13:00:01 DEBUG {}", other_len));
+            assert_eq!(line.fields, None, "{} in {}", other_len, version);
+            let code = echo.split(' ').next().unwrap();
+            let line = parser.parse_line(&format!("Oct 18 13:00:02 DEBUG TSF:MSG:{}", code));
+            assert_eq!(line.msg, format!("Xport:Msg:{}", echo));
+        }
+    }
 }
@@ -0,0 +1,690 @@
+// command line front end: reads gateway logs and prints them humanized
+//
+//   mysensors-logparser [OPTIONS] [FILE...]
+//
+// reads stdin if no file is given, several files are merged in time order;
+// gzip, bzip2 and xz compressed files are decompressed on the fly
+//
+// with --analyze the lines are not printed but fed to the analyses, which
+// print their report at the end of the input; when following a log the
+// analyses that can raise alerts print them as they happen
+use chrono::Local;
+use mysensors_logparser::alerts::{AlertConfig, AlertEngine};
+use mysensors_logparser::analysis::acks::AckCorrelator;
+use mysensors_logparser::analysis::battery::BatteryTracker;
+use mysensors_logparser::analysis::inventory::Inventory;
+use mysensors_logparser::analysis::node_ids::IdAudit;
+use mysensors_logparser::analysis::rates::RateMonitor;
+use mysensors_logparser::analysis::signal::SignalQuality;
+use mysensors_logparser::analysis::timeline::Timeline;
+use mysensors_logparser::analysis::watchdog::Watchdog;
+#[cfg(feature = "sqlite")]
+use mysensors_logparser::archive::Archive;
+use mysensors_logparser::filter::Filter;
+use mysensors_logparser::influx::{InfluxConfig, InfluxExporter, InfluxSink};
+use mysensors_logparser::merge::MergeReader;
+use mysensors_logparser::metrics::{self, Metrics};
+use mysensors_logparser::protocol::Version;
+use mysensors_logparser::reader::{lines, open, Follow};
+use mysensors_logparser::redact::Redactor;
+use mysensors_logparser::report::Report;
+use mysensors_logparser::{dictionary, BoxError, LogLine, LogParser};
+use std::io::{self, Write};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage: mysensors-logparser [OPTIONS] [FILE...]
+
+options:
+  -f, --filter EXPR      only print lines matching EXPR, e.g.
+                         'node=12 and cmd=C_SET and status=ERROR'
+      --raw              print matching lines as they were read
+      --redact SEED      print matching lines with node IDs, IP addresses and
+                         text payloads replaced, for attaching logs to public
+                         issues; the same SEED gives the same replacements
+      --redact-map FILE  with --redact write the replacements to FILE as TOML
+  -a, --analyze NAME     instead of printing the lines run an analysis on them:
+                         acks      round trip times and lost sends per node
+                         battery   battery levels, discharge rates and when
+                                   they will be empty
+                         inventory sketches and sensors each node presented
+                         ids       node IDs handed out and IDs used by more
+                                   than one node
+                         rates     bursts, floods and drops of the messages per
+                                   minute of each node
+                         signal    signal quality per link and links that
+                                   got worse
+                         timeline  transport states per gateway session
+                         watchdog  nodes that went silent
+                         can be given more than once
+      --inventory FILE   write the inventory to FILE as CSV if it ends in
+                         .csv, JSON otherwise; implies -a inventory
+      --html FILE        instead of printing the lines write a self-contained
+                         HTML report of them to FILE: sessions, nodes,
+                         delivery, topology, sensor charts and errors
+  -F, --follow           keep reading the file as it grows, like tail -f,
+                         starting at its end
+      --from-start       with --follow start at the beginning of the file
+      --influx DEST      instead of printing the lines export sensor values and
+                         transport statistics as InfluxDB line protocol to
+                         DEST, a file, - for stdout or an http:// URL to POST
+                         to, e.g. http://localhost:8086/api/v2/write?bucket=b
+      --influx-config FILE
+                         TOML file with the measurement, tag and field names
+      --archive DB       import the files (or stdin) into the SQLite database DB
+                         instead of printing them; lines already in it are
+                         skipped; all lines are imported, --filter cannot be
+                         used with it
+      --query NAME       with --archive run a stored query on DB afterwards:
+                         nodes, daily, nacks, sessions, errors or silent
+                         can be given more than once
+      --metrics ADDR     with --follow serve Prometheus metrics on
+                         http://ADDR/metrics, e.g. 0.0.0.0:9101
+      --alerts FILE      evaluate the alert rules in the TOML file FILE over
+                         the lines, print the alerts to stderr and take their
+                         actions (webhook, command or file)
+  -d, --dictionary FILE  load names from FILE on top of the built-in ones
+  -r, --release VERSION  decode as MySensors release VERSION, e.g. 2.3.2
+  -h, --help             show this help";
+
+const ANALYSES: &[&str] = &[
+    "acks",
+    "battery",
+    "ids",
+    "inventory",
+    "rates",
+    "signal",
+    "timeline",
+    "watchdog",
+];
+
+#[derive(Debug, Default)]
+struct Options {
+    filter: Option<Filter>,
+    raw: bool,
+    redact: Option<u64>,
+    redact_map: Option<String>,
+    analyses: Vec<String>,
+    inventory: Option<String>,
+    html: Option<String>,
+    follow: bool,
+    from_start: bool,
+    help: bool,
+    metrics: Option<String>,
+    alerts: Option<String>,
+    influx: Option<String>,
+    influx_config: Option<String>,
+    archive: Option<String>,
+    queries: Vec<String>,
+    dictionary: Option<String>,
+    release: Option<Version>,
+    files: Vec<String>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, BoxError> {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| BoxError::from(format!("{} needs a value", name)))
+        };
+        match arg.as_str() {
+            "-f" | "--filter" => options.filter = Some(value(&arg)?.parse()?),
+            "--raw" => options.raw = true,
+            "--redact" => options.redact = Some(value(&arg)?.parse()?),
+            "--redact-map" => options.redact_map = Some(value(&arg)?),
+            "-a" | "--analyze" => {
+                let name = value(&arg)?;
+                if !ANALYSES.contains(&name.as_str()) {
+                    return Err(format!(
+                        "unknown analysis {}, one of {}",
+                        name,
+                        ANALYSES.join(", ")
+                    )
+                    .into());
+                }
+                options.analyses.push(name);
+            }
+            "--inventory" => {
+                options.inventory = Some(value(&arg)?);
+                options.analyses.push("inventory".to_string());
+            }
+            "--html" => options.html = Some(value(&arg)?),
+            "-F" | "--follow" => options.follow = true,
+            "--from-start" => options.from_start = true,
+            "--metrics" => options.metrics = Some(value(&arg)?),
+            "--alerts" => options.alerts = Some(value(&arg)?),
+            "--influx" => options.influx = Some(value(&arg)?),
+            "--influx-config" => options.influx_config = Some(value(&arg)?),
+            "--archive" => options.archive = Some(value(&arg)?),
+            "--query" => options.queries.push(value(&arg)?),
+            "-d" | "--dictionary" => options.dictionary = Some(value(&arg)?),
+            "-r" | "--release" => options.release = Some(value(&arg)?.parse()?),
+            "-h" | "--help" => {
+                options.help = true;
+                return Ok(options);
+            }
+            "-" => options.files.push(arg),
+            _ if arg.starts_with('-') => {
+                return Err(format!("unknown option {}\n\n{}", arg, USAGE).into())
+            }
+            _ => options.files.push(arg),
+        }
+    }
+    if options.follow && options.files.len() != 1 {
+        return Err("--follow needs exactly one file".into());
+    }
+    if options.from_start && !options.follow {
+        return Err("--from-start needs --follow".into());
+    }
+    if options.influx_config.is_some() && options.influx.is_none() {
+        return Err("--influx-config needs --influx".into());
+    }
+    if !options.queries.is_empty() && options.archive.is_none() {
+        return Err("--query needs --archive".into());
+    }
+    if options.archive.is_some() && options.follow {
+        return Err("--archive cannot be used with --follow".into());
+    }
+    // positions in the source count every line, a filtered import would
+    // resume at the wrong one
+    if options.archive.is_some() && options.filter.is_some() {
+        return Err("--archive cannot be used with --filter".into());
+    }
+    if options.html.is_some() && options.follow {
+        return Err("--html cannot be used with --follow".into());
+    }
+    if options.redact_map.is_some() && options.redact.is_none() {
+        return Err("--redact-map needs --redact".into());
+    }
+    // only printed lines are redacted, not what the analyses or exports write
+    if options.redact.is_some()
+        && (!options.analyses.is_empty()
+            || options.html.is_some()
+            || options.influx.is_some()
+            || options.archive.is_some())
+    {
+        return Err("--redact cannot be used with --analyze, --html, --influx or --archive".into());
+    }
+    if options.redact_map.is_some() && options.follow {
+        return Err("--redact-map cannot be used with --follow".into());
+    }
+    if options.metrics.is_some() && !options.follow {
+        return Err("--metrics needs --follow".into());
+    }
+    Ok(options)
+}
+
+// the analyses asked for on the command line
+#[derive(Default)]
+struct Analyses {
+    acks: Option<AckCorrelator>,
+    battery: Option<BatteryTracker>,
+    ids: Option<IdAudit>,
+    inventory: Option<Inventory>,
+    rates: Option<RateMonitor>,
+    signal: Option<SignalQuality>,
+    timeline: Option<Timeline>,
+    watchdog: Option<Watchdog>,
+    influx: Option<(InfluxExporter, InfluxSink)>,
+}
+
+impl Analyses {
+    fn new(names: &[String]) -> Self {
+        let wanted = |name: &str| names.iter().any(|n| n == name);
+        Analyses {
+            acks: wanted("acks").then(AckCorrelator::new),
+            battery: wanted("battery").then(BatteryTracker::new),
+            ids: wanted("ids").then(IdAudit::new),
+            inventory: wanted("inventory").then(Inventory::new),
+            rates: wanted("rates").then(RateMonitor::new),
+            signal: wanted("signal").then(SignalQuality::new),
+            timeline: wanted("timeline").then(Timeline::new),
+            watchdog: wanted("watchdog").then(Watchdog::new),
+            influx: None,
+        }
+    }
+
+    fn feed(&mut self, line: &LogLine) -> io::Result<()> {
+        if let Some((exporter, sink)) = &mut self.influx {
+            // InfluxDB being down must not stop the parsing, the lines are
+            // sent with the next batch
+            if let Err(e) = sink.write(&exporter.feed(line)) {
+                eprintln!("influx: {}", e);
+            }
+        }
+        if let Some(acks) = &mut self.acks {
+            acks.feed(line);
+        }
+        if let Some(battery) = &mut self.battery {
+            battery.feed(line);
+        }
+        if let Some(ids) = &mut self.ids {
+            ids.feed(line);
+        }
+        if let Some(inventory) = &mut self.inventory {
+            inventory.feed(line);
+        }
+        if let Some(rates) = &mut self.rates {
+            rates.feed(line);
+        }
+        if let Some(signal) = &mut self.signal {
+            signal.feed(line);
+        }
+        if let Some(timeline) = &mut self.timeline {
+            timeline.feed(line);
+        }
+        if let Some(watchdog) = &mut self.watchdog {
+            watchdog.feed(line);
+        }
+        Ok(())
+    }
+
+    // live alerts while following a log
+    fn check(&mut self, out: &mut impl Write) -> io::Result<()> {
+        if let Some(watchdog) = &mut self.watchdog {
+            for status in watchdog.check(Local::now()) {
+                writeln!(out, "{}", status)?;
+            }
+        }
+        if let Some(rates) = &mut self.rates {
+            for alert in rates.check(Local::now()) {
+                writeln!(out, "{}", alert)?;
+            }
+        }
+        if let Some((_, sink)) = &mut self.influx {
+            if let Err(e) = sink.flush() {
+                eprintln!("influx: {}", e);
+            }
+        }
+        out.flush()
+    }
+
+    fn report(&mut self, out: &mut impl Write) -> io::Result<()> {
+        if let Some((exporter, sink)) = &mut self.influx {
+            sink.write(&exporter.finish())
+                .and_then(|_| sink.flush())
+                .map_err(io::Error::other)?;
+        }
+        if let Some(acks) = &self.acks {
+            writeln!(out, "Acks\n{}", acks)?;
+        }
+        if let Some(battery) = &self.battery {
+            writeln!(out, "Battery\n{}", battery)?;
+        }
+        if let Some(ids) = &self.ids {
+            writeln!(out, "Node IDs\n{}", ids)?;
+        }
+        if let Some(inventory) = &self.inventory {
+            writeln!(out, "Inventory\n{}", inventory)?;
+        }
+        if let Some(rates) = &self.rates {
+            writeln!(out, "Rates\n{}", rates)?;
+        }
+        if let Some(signal) = &self.signal {
+            writeln!(out, "Signal\n{}", signal)?;
+        }
+        if let Some(timeline) = &self.timeline {
+            writeln!(out, "Timeline")?;
+            for session in timeline.sessions() {
+                write!(out, "{}", session)?;
+            }
+            writeln!(out)?;
+        }
+        if let Some(watchdog) = &self.watchdog {
+            writeln!(out, "Watchdog\n{}", watchdog)?;
+        }
+        Ok(())
+    }
+}
+
+// import the files into the archive and run the queries asked for
+#[cfg(feature = "sqlite")]
+fn archive(options: &Options, path: &str, parser: impl Fn() -> LogParser) -> Result<(), BoxError> {
+    let mut archive = Archive::open(path)?;
+    let files: Vec<&str> = if options.files.is_empty() && options.queries.is_empty() {
+        vec!["-"]
+    } else {
+        options.files.iter().map(|f| f.as_str()).collect()
+    };
+    for file in files {
+        let mut parser = parser();
+        let lines = lines(open(file)?).map(|raw| -> Result<(String, LogLine), BoxError> {
+            let raw = raw?;
+            let line = parser.parse_line(&raw);
+            Ok((raw, line))
+        });
+        let source = if file == "-" { "stdin" } else { file };
+        let stats = archive.import(source, lines)?;
+        eprintln!("{}: {}", source, stats);
+    }
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for name in &options.queries {
+        writeln!(out, "{}\n{}", name, archive.stored_query(name)?)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn archive(_: &Options, _: &str, _: impl Fn() -> LogParser) -> Result<(), BoxError> {
+    Err("--archive: built without sqlite support".into())
+}
+
+fn run(options: Options) -> Result<(), BoxError> {
+    if options.help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    if let Some(path) = &options.dictionary {
+        dictionary::load_dictionary(path).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    let parser = || {
+        let mut parser = LogParser::new();
+        parser.force_version(options.release);
+        parser
+    };
+    if let Some(path) = &options.archive {
+        return archive(&options, path, parser);
+    }
+    let mut analyses = Analyses::new(&options.analyses);
+    if let Some(dest) = &options.influx {
+        let config = match &options.influx_config {
+            Some(path) => InfluxConfig::load(path)?,
+            None => InfluxConfig::default(),
+        };
+        let sink = InfluxSink::open(dest, &config)?;
+        analyses.influx = Some((InfluxExporter::new(config), sink));
+    }
+    let mut report = options.html.as_ref().map(|_| {
+        let title = match options.files.as_slice() {
+            [] => "stdin".to_string(),
+            files => files.join(", "),
+        };
+        Report::new(&title)
+    });
+    let analyze =
+        !options.analyses.is_empty() || options.influx.is_some() || options.html.is_some();
+    let metrics = match &options.metrics {
+        Some(addr) => {
+            let metrics = Arc::new(Mutex::new(Metrics::new()));
+            let (local, _) = metrics::serve(addr, metrics.clone())?;
+            eprintln!("serving metrics on http://{}/metrics", local);
+            Some(metrics)
+        }
+        None => None,
+    };
+    let mut alerts = match &options.alerts {
+        Some(path) => Some(AlertEngine::new(AlertConfig::load(path)?)?),
+        None => None,
+    };
+    // after the dictionary is loaded, its MQTT prefixes are replaced too
+    let mut redactor = options.redact.map(Redactor::new);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut output = |analyses: &mut Analyses,
+                      out: &mut io::StdoutLock,
+                      source: Option<&str>,
+                      raw: &str,
+                      line: &LogLine|
+     -> io::Result<()> {
+        // metrics are of the whole log, not just the lines printed
+        if let Some(metrics) = &metrics {
+            metrics.lock().unwrap().feed(line);
+        }
+        if let Some(engine) = &mut alerts {
+            for alert in engine.feed(line) {
+                eprintln!("alert: {}", alert);
+                // a webhook being down must not stop the parsing
+                if let Err(e) = engine.dispatch(&alert) {
+                    eprintln!("alert {}: {}", alert.rule, e);
+                }
+            }
+        }
+        if !options.filter.as_ref().is_none_or(|f| f.matches(line)) {
+            return Ok(());
+        }
+        if let Some(report) = &mut report {
+            report.feed(raw, line);
+        }
+        if analyze {
+            analyses.feed(line)
+        } else if let Some(redactor) = &mut redactor {
+            writeln!(out, "{}", redactor.redact_line(line))
+        } else if options.raw {
+            writeln!(out, "{}", raw)
+        } else if let Some(source) = source {
+            writeln!(out, "{}: {}", source, line)
+        } else {
+            writeln!(out, "{}", line)
+        }
+    };
+
+    if options.follow {
+        let mut parser = parser();
+        let (sender, receiver) = channel();
+        let follow = if options.from_start {
+            Follow::from_start(&options.files[0])?
+        } else {
+            Follow::new(&options.files[0])?
+        };
+        thread::spawn(move || {
+            for raw in follow {
+                if sender.send(raw).is_err() {
+                    return;
+                }
+            }
+        });
+        // silent nodes are looked for every second, also while a busy log
+        // keeps the channel full
+        let interval = Duration::from_secs(1);
+        let mut last_check = Instant::now();
+        loop {
+            let wait = interval.saturating_sub(last_check.elapsed());
+            match receiver.recv_timeout(wait) {
+                Ok(raw) => {
+                    let raw = raw?;
+                    output(
+                        &mut analyses,
+                        &mut out,
+                        None,
+                        &raw,
+                        &parser.parse_line(&raw),
+                    )?;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            if last_check.elapsed() >= interval {
+                analyses.check(&mut out)?;
+                last_check = Instant::now();
+            }
+        }
+    }
+
+    if options.files.len() > 1 {
+        let mut merge = MergeReader::new();
+        for file in &options.files {
+            merge.add_with_parser(file, open(file)?, parser());
+        }
+        for merged in merge {
+            let merged = merged?;
+            output(
+                &mut analyses,
+                &mut out,
+                Some(&merged.source),
+                &merged.raw,
+                &merged.line,
+            )?;
+        }
+    } else {
+        let file = options.files.first().map_or("-", |f| f.as_str());
+        let mut parser = parser();
+        for raw in lines(open(file)?) {
+            let raw = raw?;
+            output(
+                &mut analyses,
+                &mut out,
+                None,
+                &raw,
+                &parser.parse_line(&raw),
+            )?;
+        }
+    }
+    analyses.report(&mut out)?;
+    if let (Some(path), Some(inventory)) = (&options.inventory, &analyses.inventory) {
+        let export = if path.ends_with(".csv") {
+            inventory.to_csv()
+        } else {
+            inventory.to_json()?
+        };
+        std::fs::write(path, export).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if let (Some(path), Some(report)) = (&options.html, &report) {
+        std::fs::write(path, report.render()).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if let (Some(path), Some(redactor)) = (&options.redact_map, &redactor) {
+        let mapping = redactor.mapping().to_toml()?;
+        std::fs::write(path, mapping).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let result = parse_args(std::env::args().skip(1)).and_then(run);
+    if let Err(e) = result {
+        // output piped into head etc. that stops reading is not an error
+        if let Some(io_error) = e.downcast_ref::<io::Error>() {
+            if io_error.kind() == io::ErrorKind::BrokenPipe {
+                return;
+            }
+        }
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(args("--raw -r 2.2.0 -f status=ERROR a.log b.log")).unwrap();
+        assert!(options.raw);
+        assert_eq!(options.release, Some(Version::new(2, 2, 0)));
+        assert!(options.filter.is_some());
+        assert_eq!(options.files, vec!["a.log", "b.log"]);
+        assert!(parse_args(args("--filter")).is_err());
+        assert!(parse_args(args("--bogus")).is_err());
+        assert!(parse_args(args("-f node=")).is_err());
+    }
+
+    #[test]
+    fn test_help_args() {
+        assert!(parse_args(args("-a bogus --help")).is_err());
+        assert!(parse_args(args("a.log --help -F")).unwrap().help);
+    }
+
+    #[test]
+    fn test_analysis_args() {
+        let options = parse_args(args("-F -a watchdog -a acks syslog")).unwrap();
+        assert_eq!(options.analyses, vec!["watchdog", "acks"]);
+        assert!(parse_args(args("-a bogus")).is_err());
+        let options = parse_args(args("--inventory nodes.csv gateway.log")).unwrap();
+        assert_eq!(options.inventory.as_deref(), Some("nodes.csv"));
+        assert_eq!(options.analyses, vec!["inventory"]);
+    }
+
+    #[test]
+    fn test_analyses() {
+        let mut analyses = Analyses::new(&["rates".to_string()]);
+        analyses
+            .feed(&mysensors_logparser::parse_log_line(
+                "Oct 18 13:00:01 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5",
+            ))
+            .unwrap();
+        let mut report = Vec::new();
+        analyses.report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("Rates\n"));
+        assert!(report.contains("node 12 C_SET V_TEMP"));
+    }
+
+    #[test]
+    fn test_follow_args() {
+        assert!(parse_args(args("-F syslog")).unwrap().follow);
+        assert!(parse_args(args("--follow a.log b.log")).is_err());
+        assert!(
+            parse_args(args("-F --from-start a.log"))
+                .unwrap()
+                .from_start
+        );
+        assert!(parse_args(args("--from-start a.log")).is_err());
+    }
+
+    #[test]
+    fn test_metrics_args() {
+        let options = parse_args(args("-F --metrics 127.0.0.1:9101 gateway.log")).unwrap();
+        assert_eq!(options.metrics.as_deref(), Some("127.0.0.1:9101"));
+        assert!(parse_args(args("--metrics 127.0.0.1:9101 gateway.log")).is_err());
+    }
+
+    #[test]
+    fn test_html_args() {
+        let options = parse_args(args("--html report.html a.log b.log")).unwrap();
+        assert_eq!(options.html.as_deref(), Some("report.html"));
+        assert!(parse_args(args("-F --html report.html a.log")).is_err());
+    }
+
+    #[test]
+    fn test_alerts_args() {
+        let options = parse_args(args("-F --alerts alerts.toml gateway.log")).unwrap();
+        assert_eq!(options.alerts.as_deref(), Some("alerts.toml"));
+        assert!(parse_args(args("--alerts")).is_err());
+    }
+
+    #[test]
+    fn test_influx_args() {
+        let options = parse_args(args("--influx - --influx-config influx.toml a.log")).unwrap();
+        assert_eq!(options.influx.as_deref(), Some("-"));
+        assert_eq!(options.influx_config.as_deref(), Some("influx.toml"));
+        assert!(parse_args(args("--influx-config influx.toml a.log")).is_err());
+    }
+
+    #[test]
+    fn test_archive_args() {
+        let options = parse_args(args("--archive gw.db --query nodes --query nacks")).unwrap();
+        assert_eq!(options.archive.as_deref(), Some("gw.db"));
+        assert_eq!(options.queries, vec!["nodes", "nacks"]);
+        assert!(parse_args(args("--query nodes a.log")).is_err());
+        assert!(parse_args(args("-F --archive gw.db a.log")).is_err());
+        assert!(parse_args(args("--archive gw.db -f node=12 a.log")).is_err());
+    }
+
+    #[test]
+    fn test_redact_args() {
+        let options = parse_args(args("--redact 42 --redact-map map.toml a.log")).unwrap();
+        assert_eq!(options.redact, Some(42));
+        assert_eq!(options.redact_map.as_deref(), Some("map.toml"));
+        assert!(parse_args(args("--redact x a.log")).is_err());
+        assert!(parse_args(args("--redact-map map.toml a.log")).is_err());
+        for other in [
+            "-a acks",
+            "--inventory nodes.csv",
+            "--html report.html",
+            "--influx -",
+            "--archive gw.db",
+        ] {
+            let line = format!("--redact 42 {} a.log", other);
+            assert!(parse_args(args(&line)).is_err(), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+// merges the logs of several gateways or rotated files (syslog, syslog.1, ...)
+// into one stream ordered by time
+//
+// every input is read and parsed on its own thread with its own LogParser, so
+// sessions and releases are tracked per input. lines with the same time keep
+// the order of the inputs and of the lines within an input; lines without a
+// time (not parsed) stay behind the line before them
+//
+// syslog lines have no year and are parsed as the current year, which puts
+// the december lines of a log read in january into the future: LogParser moves
+// those back a year with infer_year, per line, so a log across new year is in
+// order again
+use crate::{BoxError, LogLine, LogParser};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::BufRead;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
+
+const CHANNEL_LINES: usize = 1024; //lines buffered per input
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergedLine {
+    pub source: String, //name of the input the line came from
+    pub raw: String,
+    pub line: LogLine,
+}
+
+// time of a line assumed to be of the current year, moved to the year before if
+// that would be later than now (allowing a day for clocks that are off)
+pub fn infer_year(datetime: DateTime<Local>, now: DateTime<Local>) -> DateTime<Local> {
+    if datetime <= now + Duration::days(1) {
+        return datetime;
+    }
+    let naive = datetime.naive_local();
+    // 29th of february moves to the 28th in a year that is not a leap year
+    NaiveDate::from_ymd_opt(naive.year() - 1, naive.month(), naive.day())
+        .or_else(|| NaiveDate::from_ymd_opt(naive.year() - 1, naive.month(), naive.day() - 1))
+        .and_then(|d| {
+            Local
+                .from_local_datetime(&d.and_time(naive.time()))
+                .earliest()
+        })
+        .unwrap_or(datetime)
+}
+
+type Received = Result<(String, LogLine), String>;
+
+struct Input {
+    source: String,
+    receiver: Receiver<Received>,
+    last_time: Option<DateTime<Local>>,
+    sequence: u64,
+}
+
+// ordering key of a line: time, then input, then position in the input
+type Key = (Option<DateTime<Local>>, usize, u64);
+
+#[derive(Default)]
+pub struct MergeReader {
+    inputs: Vec<Input>,
+    heap: BinaryHeap<Reverse<(Key, usize)>>,
+    heads: Vec<Option<MergedLine>>, //next line of each input, waiting in the heap
+    started: bool,
+}
+
+impl MergeReader {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // add an input parsed with a default LogParser
+    pub fn add(&mut self, source: &str, reader: impl BufRead + Send + 'static) {
+        self.add_with_parser(source, reader, LogParser::new());
+    }
+
+    // add an input parsed with the given parser, e.g. one with custom parsers
+    // registered or a forced release
+    pub fn add_with_parser(
+        &mut self,
+        source: &str,
+        reader: impl BufRead + Send + 'static,
+        mut parser: LogParser,
+    ) {
+        let (sender, receiver) = sync_channel(CHANNEL_LINES);
+        let name = source.to_string();
+        thread::spawn(move || {
+            for raw in crate::reader::lines(reader) {
+                let message = match raw {
+                    Ok(raw) => {
+                        let line = parser.parse_line(&raw);
+                        Ok((raw, line))
+                    }
+                    Err(e) => Err(format!("{}: {}", name, e)),
+                };
+                let failed = message.is_err();
+                if sender.send(message).is_err() || failed {
+                    return; //merge reader dropped or input broken
+                }
+            }
+        });
+        self.inputs.push(Input {
+            source: source.to_string(),
+            receiver,
+            last_time: None,
+            sequence: 0,
+        });
+        self.heads.push(None);
+    }
+
+    // read the next line of an input into the heap
+    fn fill(&mut self, index: usize) -> Result<(), BoxError> {
+        let input = &mut self.inputs[index];
+        let (raw, line) = match input.receiver.recv() {
+            Ok(received) => received?,
+            Err(_) => return Ok(()), //input finished
+        };
+        if line.datetime.is_some() {
+            input.last_time = line.datetime;
+        }
+        input.sequence += 1;
+        let key = (input.last_time, index, input.sequence);
+        self.heads[index] = Some(MergedLine {
+            source: input.source.clone(),
+            raw,
+            line,
+        });
+        self.heap.push(Reverse((key, index)));
+        Ok(())
+    }
+}
+
+impl Iterator for MergeReader {
+    type Item = Result<MergedLine, BoxError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            for index in 0..self.inputs.len() {
+                if let Err(e) = self.fill(index) {
+                    return Some(Err(e));
+                }
+            }
+        }
+        let Reverse((_, index)) = self.heap.pop()?;
+        let line = self.heads[index].take()?;
+        if let Err(e) = self.fill(index) {
+            return Some(Err(e));
+        }
+        Some(Ok(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn input(lines: &[&str]) -> Cursor<String> {
+        Cursor::new(lines.join("\n"))
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut merge = MergeReader::new();
+        merge.add(
+            "gw1",
+            input(&[
+                "Oct 18 13:00:00 DEBUG TSM:INIT",
+                "Oct 18 13:00:02 DEBUG TSM:READY:ID=0,PAR=0,DIS=0",
+                "not a log line",
+                "Oct 18 13:00:05 DEBUG TSF:SID:OK,ID=12",
+            ]),
+        );
+        merge.add(
+            "gw2",
+            input(&[
+                "Oct 18 13:00:01 DEBUG TSM:INIT",
+                "Oct 18 13:00:02 DEBUG TSM:READY:ID=0,PAR=0,DIS=0",
+                "Oct 18 13:00:02 DEBUG TSF:SID:OK,ID=7",
+                "Oct 18 13:00:03 DEBUG TSF:SID:OK,ID=8",
+            ]),
+        );
+        let merged: Vec<(String, String)> = merge
+            .map(|l| l.unwrap())
+            .map(|l| (l.source, l.raw))
+            .collect();
+        let expected = [
+            ("gw1", "Oct 18 13:00:00 DEBUG TSM:INIT"),
+            ("gw2", "Oct 18 13:00:01 DEBUG TSM:INIT"),
+            ("gw1", "Oct 18 13:00:02 DEBUG TSM:READY:ID=0,PAR=0,DIS=0"),
+            ("gw1", "not a log line"), //stays behind the line before it
+            ("gw2", "Oct 18 13:00:02 DEBUG TSM:READY:ID=0,PAR=0,DIS=0"),
+            ("gw2", "Oct 18 13:00:02 DEBUG TSF:SID:OK,ID=7"),
+            ("gw2", "Oct 18 13:00:03 DEBUG TSF:SID:OK,ID=8"),
+            ("gw1", "Oct 18 13:00:05 DEBUG TSF:SID:OK,ID=12"),
+        ];
+        let expected: Vec<(String, String)> = expected
+            .iter()
+            .map(|(s, l)| (s.to_string(), l.to_string()))
+            .collect();
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn test_infer_year() {
+        let at = |y, m, d| {
+            Local
+                .from_local_datetime(
+                    &NaiveDate::from_ymd_opt(y, m, d)
+                        .unwrap()
+                        .and_hms_opt(12, 0, 0)
+                        .unwrap(),
+                )
+                .unwrap()
+        };
+        let now = at(2026, 1, 5);
+        assert_eq!(infer_year(at(2026, 1, 4), now), at(2026, 1, 4));
+        assert_eq!(infer_year(at(2026, 12, 30), now), at(2025, 12, 30));
+        assert_eq!(infer_year(at(2028, 2, 29), at(2028, 1, 1)), at(2027, 2, 28));
+    }
+}
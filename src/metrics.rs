@@ -0,0 +1,343 @@
+// Prometheus metrics of a gateway log, served on /metrics while following it
+//
+// counters and gauges are kept up to date as lines are fed and rendered in
+// the Prometheus text format on each scrape. the server is a plain
+// std::net listener answering one request at a time, enough for a scraper
+// polling every few seconds
+//
+// messages from nodes are counted from the READs of the gateway (and serial
+// lines of controller logs), not again from what it passes on over MQTT
+use crate::analysis::signal::SignalQuality;
+use crate::dictionary::dictionary;
+use crate::http;
+use crate::protocol::{MsgKind, XportState};
+use crate::session::SessionTracker;
+use crate::{BoxError, LogLine, MsgFields};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+const STATES: [XportState; 6] = [
+    XportState::Init,
+    XportState::FindParent,
+    XportState::Id,
+    XportState::Uplink,
+    XportState::Ready,
+    XportState::Failure,
+];
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    lines: u64,
+    received: BTreeMap<u8, u64>, //messages from a node
+    sent: BTreeMap<u8, u64>,     //messages sent to a node
+    nacks: BTreeMap<u8, u64>,    //sends to a node the next hop did not ack
+    last_seen: BTreeMap<u8, i64>,
+    state: Option<XportState>,
+    uplink_failures: u64,
+    sessions: SessionTracker,
+    starts: u64,
+    signal: SignalQuality,
+}
+
+// label values escaped as the text format wants them
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// one metric family: its HELP and TYPE lines and a sample per label set
+fn family<V: Display>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, V)>,
+) {
+    use std::fmt::Write;
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+// TSM:UPL:FAIL, a node's ping to the gateway went unanswered. the message is
+// not decoded, so it is told by its text as the dictionary names it
+fn uplink_failed(line: &LogLine) -> bool {
+    let dictionary = dictionary();
+    let tsm = match dictionary.system("TSM") {
+        Some(tsm) => tsm,
+        None => return false,
+    };
+    let (system, subsystem) = match (&line.system, &line.subsystem) {
+        (Some(system), Some(subsystem)) => (system, subsystem),
+        _ => return false,
+    };
+    if tsm.name() != Some(system) || tsm.subsystem_name("UPL") != Some(subsystem) {
+        return false;
+    }
+    let msg = line
+        .msg
+        .strip_prefix(&format!("{}:{}:", system, subsystem))
+        .unwrap_or(&line.msg);
+    msg == "FAIL" || tsm.message("UPL", "FAIL") == Some(msg)
+}
+
+fn per_node<V: Copy>(values: &BTreeMap<u8, V>) -> Vec<(String, V)> {
+    values
+        .iter()
+        .map(|(node, v)| (format!("node=\"{}\"", node), *v))
+        .collect()
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn feed(&mut self, line: &LogLine) {
+        self.lines += 1;
+        if self.sessions.feed(line) {
+            self.starts += 1;
+        }
+        self.signal.feed(line);
+        match &line.fields {
+            Some(MsgFields::Msg(m)) => match m.kind {
+                MsgKind::Read | MsgKind::Serial if m.sender != 0 => {
+                    *self.received.entry(m.sender).or_default() += 1;
+                    if let Some(dt) = line.datetime {
+                        self.last_seen.insert(m.sender, dt.timestamp());
+                    }
+                }
+                MsgKind::Send => {
+                    *self.sent.entry(m.destination).or_default() += 1;
+                    if m.send_ok == Some(false) {
+                        *self.nacks.entry(m.destination).or_default() += 1;
+                    }
+                }
+                _ => {}
+            },
+            Some(MsgFields::UplinkCheck { ok: false }) => self.uplink_failures += 1,
+            Some(fields) => {
+                if let Some(state) = fields.xport_state() {
+                    self.state = Some(state);
+                }
+            }
+            None if uplink_failed(line) => self.uplink_failures += 1,
+            None => {}
+        }
+    }
+
+    // the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        family(
+            &mut out,
+            "mysensors_lines_total",
+            "counter",
+            "Log lines read.",
+            [(String::new(), self.lines)],
+        );
+        family(
+            &mut out,
+            "mysensors_messages_received_total",
+            "counter",
+            "Messages received from a node.",
+            per_node(&self.received),
+        );
+        family(
+            &mut out,
+            "mysensors_messages_sent_total",
+            "counter",
+            "Messages sent to a node.",
+            per_node(&self.sent),
+        );
+        family(
+            &mut out,
+            "mysensors_nacks_total",
+            "counter",
+            "Messages sent to a node that the next hop did not acknowledge.",
+            per_node(&self.nacks),
+        );
+        family(
+            &mut out,
+            "mysensors_node_last_seen_timestamp_seconds",
+            "gauge",
+            "When a message from a node was last received.",
+            per_node(&self.last_seen),
+        );
+        family(
+            &mut out,
+            "mysensors_transport_state",
+            "gauge",
+            "1 for the state the transport state machine is in.",
+            STATES.iter().map(|state| {
+                (
+                    format!("state=\"{}\"", state.code()),
+                    u8::from(self.state == Some(*state)),
+                )
+            }),
+        );
+        family(
+            &mut out,
+            "mysensors_uplink_failures_total",
+            "counter",
+            "Failed uplink checks.",
+            [(String::new(), self.uplink_failures)],
+        );
+        family(
+            &mut out,
+            "mysensors_gateway_restarts_total",
+            "counter",
+            "Gateway restarts, the first start in the log not counted.",
+            [(String::new(), self.starts.saturating_sub(1))],
+        );
+        family(
+            &mut out,
+            "mysensors_signal_report",
+            "gauge",
+            "Last signal report of a link, e.g. an RSSI in dBm.",
+            self.signal.links().into_iter().map(|stats| {
+                (
+                    format!(
+                        "node=\"{}\",peer=\"{}\",kind=\"{}\"",
+                        stats.link.0,
+                        stats.link.1,
+                        escape(&stats.kind.to_string())
+                    ),
+                    stats.last.value,
+                )
+            }),
+        );
+        out
+    }
+}
+
+fn respond(stream: TcpStream, metrics: &Mutex<Metrics>) -> Result<(), BoxError> {
+    // a client that connects and sends nothing is given up on
+    stream.set_read_timeout(Some(http::TIMEOUT))?;
+    stream.set_write_timeout(Some(http::TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // the headers are not needed, but read them so the client is not reset
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.lock().unwrap().render()),
+        (Some("GET"), _) => ("404 Not Found", "not found, try /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+// serve the metrics on addr, e.g. "0.0.0.0:9101" or "127.0.0.1:0" for any free
+// port; returns the address bound to and the thread answering requests
+pub fn serve(
+    addr: &str,
+    metrics: Arc<Mutex<Metrics>>,
+) -> Result<(SocketAddr, JoinHandle<()>), BoxError> {
+    let listener = TcpListener::bind(addr).map_err(|e| format!("{}: {}", addr, e))?;
+    let local = listener.local_addr()?;
+    let handle = thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // each on its own thread, so a slow client does not hold up the
+            // next; a client going away is its problem
+            let metrics = metrics.clone();
+            thread::spawn(move || {
+                let _ = respond(stream, &metrics);
+            });
+        }
+    });
+    Ok((local, handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+    use std::io::Read;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Arc::new(Mutex::new(Metrics::new()));
+        let (addr, _) = serve("127.0.0.1:0", metrics.clone()).unwrap();
+        for line in [
+            "Oct 18 13:00:00 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2",
+            "Oct 18 13:00:00 DEBUG TSM:READY:ID=0,PAR=0,DIS=0",
+            "Oct 18 13:00:01 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5",
+            "Oct 18 13:00:01 DEBUG GWT:TPS:TOPIC=mygateway1-out/12/1/1/0/0,MSG SENT",
+            "Oct 18 13:00:02 DEBUG !TSF:MSG:SEND,0-0-12-12,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=3,st=NACK:1",
+            "Oct 18 13:00:03 DEBUG TSF:MSG:SEND,0-0-3-12,s=255,c=3,t=29,pt=0,l=1,sg=0,ft=0,st=OK:S",
+            "Oct 18 13:00:03 DEBUG TSF:MSG:READ,12-3-0,s=255,c=3,t=31,pt=3,l=2,sg=0:-71",
+            "Oct 18 13:05:00 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2",
+            "Oct 18 13:05:00 DEBUG TSM:INIT",
+            // failed uplink checks of a node's own log
+            "Oct 18 13:05:01 DEBUG !TSF:CKU:FAIL",
+            "Oct 18 13:05:02 DEBUG !TSM:UPL:FAIL",
+            "Oct 18 13:05:03 DEBUG TSM:UPL:OK",
+        ] {
+            metrics.lock().unwrap().feed(&parse_log_line(line));
+        }
+
+        // a client that sends nothing does not keep the others waiting
+        let _silent = TcpStream::connect(addr).unwrap();
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let last_seen = parse_log_line("Oct 18 13:00:03 DEBUG TSM:INIT")
+            .datetime
+            .unwrap()
+            .timestamp();
+        for sample in [
+            "mysensors_lines_total 12".to_string(),
+            "mysensors_messages_received_total{node=\"12\"} 2".to_string(),
+            "mysensors_messages_sent_total{node=\"12\"} 2".to_string(),
+            "mysensors_nacks_total{node=\"12\"} 1".to_string(),
+            format!(
+                "mysensors_node_last_seen_timestamp_seconds{{node=\"12\"}} {}",
+                last_seen
+            ),
+            "mysensors_transport_state{state=\"INIT\"} 1".to_string(),
+            "mysensors_transport_state{state=\"READY\"} 0".to_string(),
+            "mysensors_gateway_restarts_total 1".to_string(),
+            "mysensors_uplink_failures_total 2".to_string(),
+            "mysensors_signal_report{node=\"12\",peer=\"3\",kind=\"sending RSSI\"} -71".to_string(),
+        ] {
+            assert!(body.lines().any(|l| l == sample), "{} missing", sample);
+        }
+        assert!(body.contains("# TYPE mysensors_nacks_total counter"));
+
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404"));
+    }
+}
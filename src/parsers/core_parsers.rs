@@ -1,78 +1,111 @@
-use crate::{parsers::ParsedMessage, LogParseError, SendStatus};
+use crate::dictionary::{dictionary, SystemDictionary};
+use crate::protocol::MsgFields;
+use crate::{LogParseError, ParsedMessage, SendStatus};
 use nom::branch::alt;
-use nom::bytes::complete::tag;
+use nom::bytes::complete::{tag, take_until};
+use nom::character::complete::{i8, u32, u8};
+use nom::combinator::{all_consuming, opt, rest};
 use nom::error::ErrorKind;
-use std::collections::HashMap;
+use nom::sequence::preceded;
 
-//look up table helper for converting subsystem into human readable name
-lazy_static! {
-    static ref SS_LUT: HashMap<&'static str, &'static str> = {
-        let mut m = HashMap::new();
-        m.insert("BGN", "Begin");
-        m.insert("NLK", "NodeLock");
-        m.insert("PIM", "InternalMsg");
-        m.insert("REG", "RegisterNode");
-        m.insert("SLP", "Sleep");
-        m.insert("SND", "Send");
-        m.insert("WAI", "Wait");
-        m
-    };
+// lookup of a message in the dictionary
+fn parse_msg_by_lookup<'a>(
+    dict: &SystemDictionary,
+    subsystem: &str,
+    i: &'a str,
+) -> nom::IResult<&'a str, String, crate::LogParseError> {
+    match dict.message(subsystem, i) {
+        Some(new_msg) => Ok(("", new_msg.to_string())),
+        None => Err(nom::Err::Error(crate::LogParseError::Nom(
+            "Msg not in LUT".to_string(),
+            ErrorKind::Tag,
+        ))),
+    }
 }
 
-//look up table helper for converting message into human readable name
-lazy_static! {
-    static ref MSG_LUT: HashMap<&'static str, &'static str> = {
-        let mut m = HashMap::new();
-        m.insert("BFR", "Callback before()");
-        m.insert("STP", "Callback setup()");
-        m.insert("TSP FAIL", "Xport Init Failed");
-        m.insert("TSL", "Xport Sleep");
-        m.insert("REQ", "Registration Request");
-        m.insert("NTL", "Can't Sleep - no time left");
-        m.insert("FWUPD", "Can't Sleep - FW updating");
-        m.insert("REP", "Can't Sleep - repeater node");
-        m.insert("TNR", "Xport Not Ready - attempting reconnect");
-        m
-    };
+fn parse_begin_init(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
+    // INIT %s,CP=%s,FQ=%s,REL=%d,VER=%s
+    // this line tells which release wrote the log, so it is parsed the same for
+    // all releases: FQ= only exists since 2.3 and REL= since 2.2
+    let (remaining, _) = tag("INIT ")(i)?;
+    let (remaining, node) = take_until(",")(remaining)?;
+    let (remaining, _) = tag(",CP=")(remaining)?;
+    let (remaining, capabilities) = take_until(",")(remaining)?;
+    let (remaining, frequency) = opt(preceded(tag(",FQ="), take_until(",")))(remaining)?;
+    let (remaining, release) = opt(preceded(tag(",REL="), u8))(remaining)?;
+    let (remaining, _) = tag(",VER=")(remaining)?;
+    let (remaining, version) = rest(remaining)?;
+
+    Ok((
+        remaining,
+        MsgFields::CoreInit {
+            node: node.to_string(),
+            capabilities: capabilities.to_string(),
+            frequency: frequency.map(|f| f.to_string()),
+            release,
+            version: version.to_string(),
+        },
+    ))
 }
 
-fn parse_msg_by_lookup(i: &str) -> nom::IResult<&str, &str, crate::LogParseError> {
-    match MSG_LUT.get(i) {
-        Some(new_msg) => return Ok(("", new_msg)),
-        None => {
-            return Err(nom::Err::Error(crate::LogParseError::Nom(
-                "Msg not in LUT".to_string(),
-                ErrorKind::Tag,
-            )))
-        }
-    };
+fn parse_sleep(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
+    // MS=%lu,SMS=%d,I1=%d,M1=%d,I2=%d,M2=%d
+    let (remaining, _) = tag("MS=")(i)?;
+    let (remaining, ms) = u32(remaining)?;
+    let (remaining, _) = tag(",SMS=")(remaining)?;
+    let (remaining, smart) = u8(remaining)?;
+    let (remaining, _) = tag(",I1=")(remaining)?;
+    let (remaining, interrupt1) = u8(remaining)?;
+    let (remaining, _) = tag(",M1=")(remaining)?;
+    let (remaining, mode1) = u8(remaining)?;
+    let (remaining, _) = tag(",I2=")(remaining)?;
+    let (remaining, interrupt2) = u8(remaining)?;
+    let (remaining, _) = tag(",M2=")(remaining)?;
+    let (remaining, mode2) = u8(remaining)?;
+
+    Ok((
+        remaining,
+        MsgFields::Sleep {
+            ms,
+            smart,
+            interrupt1,
+            mode1,
+            interrupt2,
+            mode2,
+        },
+    ))
 }
 
-fn parse_msg_into_human(i: &str) -> nom::IResult<&str, &str, LogParseError> {
-    //FIXME: finish imple
-    Err(nom::Err::Error(crate::LogParseError::Nom(
-        "JUNK".to_string(),
-        ErrorKind::Tag,
-    )))
+fn parse_wake_up(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
+    // WUP=%d
+    let (remaining, _) = tag("WUP=")(i)?;
+    let (remaining, reason) = i8(remaining)?;
+    Ok((remaining, MsgFields::WakeUp { reason }))
+}
+
+fn parse_msg_into_human<'a>(
+    subsystem: &str,
+    i: &'a str,
+) -> nom::IResult<&'a str, MsgFields, LogParseError> {
+    match subsystem {
+        "BGN" => parse_begin_init(i),
+        "SLP" => all_consuming(alt((parse_sleep, parse_wake_up)))(i),
+        _ => Err(nom::Err::Error(crate::LogParseError::Nom(
+            i.to_string(),
+            ErrorKind::Tag,
+        ))),
+    }
 }
 
 fn parse_subsystem(i: &str) -> nom::IResult<&str, &str, LogParseError> {
-    // ugh wanted something to extract keys of the LUT to create tag parsers
-    // let mut ss_parser:List = SS_LUT
-    //     .keys()
-    //     .map(|x| tag::<&str, &str, crate::LogParseError>(*x))
-    //     .collect();
-    // but need something to convert iter into tuple?
-    let (remaining, subsystem) = alt((
-        tag("BGN"),
-        tag("NLK"),
-        tag("PIM"),
-        tag("REG"),
-        tag("SLP"),
-        tag("SND"),
-        tag("WAI"),
-    ))(i)?;
-    Ok((remaining, subsystem))
+    // matcher is built from the subsystem keys of the dictionary
+    match dictionary().system("MCO") {
+        Some(dict) => dict.parse_subsystem(i),
+        None => Err(nom::Err::Error(LogParseError::Nom(
+            i.to_string(),
+            ErrorKind::Tag,
+        ))),
+    }
 }
 
 //top level core parser
@@ -80,28 +113,28 @@ fn parse_subsystem(i: &str) -> nom::IResult<&str, &str, LogParseError> {
 pub fn parse_core(i: &str) -> nom::IResult<&str, ParsedMessage, LogParseError> {
     //must start with "MCO:"
     let (remaining, _) = tag("MCO:")(i)?;
-    let (remaining, subsystem) = parse_subsystem(remaining)?; //FIXME: use newer method
+    let (remaining, subsystem) = parse_subsystem(remaining)?;
     let (remaining, _) = tag(":")(remaining)?;
+    let dictionary = dictionary();
+    let dict = dictionary.system("MCO").unwrap(); //parse_subsystem succeeded so it exists
 
     //either try to parse as human readable message (most strict)
     //or try to parse via simple message convert via lookup
     //or just expand system/subsystem and leave message as is
-    let result = match alt((parse_msg_into_human, parse_msg_by_lookup))(remaining) {
-        Ok((_, converted)) => ParsedMessage {
-            send_status: SendStatus::UNKNOWN,
-            system: Some("Core".to_string()),
-            subsystem: Some(SS_LUT.get(&subsystem[..]).unwrap().to_string()),
-            msg: converted.to_string(),
-        },
-        Err(_) => ParsedMessage {
-            send_status: SendStatus::UNKNOWN,
-            system: Some("Core".to_string()),
-            subsystem: Some(SS_LUT.get(&subsystem[..]).unwrap().to_string()),
-            msg: remaining.to_string(),
+    let (msg, fields) = match parse_msg_into_human(subsystem, remaining) {
+        Ok((_, fields)) => (fields.to_string(), Some(fields)),
+        Err(_) => match parse_msg_by_lookup(dict, subsystem, remaining) {
+            Ok((_, converted)) => (converted, None),
+            Err(_) => (remaining.to_string(), None),
         },
     };
-
-    // let result = format!("Core:{}:{}", SS_LUT.get(&subsystem[..]).unwrap(), remaining);
+    let result = ParsedMessage {
+        send_status: SendStatus::UNKNOWN,
+        system: dict.name().map(|n| n.to_string()),
+        subsystem: dict.subsystem_name(subsystem).map(|n| n.to_string()),
+        msg,
+        fields,
+    };
 
     Ok(("", result)) //consume rest and pass input to output as default
 }
@@ -136,6 +169,7 @@ mod tests {
                     system: Some("Core".to_string()),
                     subsystem: Some("InternalMsg".to_string()),
                     msg: "some message".to_string(),
+                    fields: None,
                 }
             ))
         );
@@ -148,8 +182,31 @@ mod tests {
                     system: Some("Core".to_string()),
                     subsystem: Some("Wait".to_string()),
                     msg: "another message".to_string(),
+                    fields: None,
                 }
             ))
         );
     }
+    #[test]
+    fn test_parse_sleep() {
+        let (_, parsed) =
+            parse_core("MCO:SLP:MS=300000,SMS=0,I1=255,M1=255,I2=255,M2=255").unwrap();
+        assert_eq!(
+            parsed.fields,
+            Some(MsgFields::Sleep {
+                ms: 300000,
+                smart: 0,
+                interrupt1: 255,
+                mode1: 255,
+                interrupt2: 255,
+                mode2: 255,
+            })
+        );
+        let (_, parsed) = parse_core("MCO:SLP:WUP=-1").unwrap();
+        assert_eq!(parsed.fields, Some(MsgFields::WakeUp { reason: -1 }));
+        assert_eq!(parsed.msg, "Woke up by timer");
+        // other sleep messages are left as they are
+        let (_, parsed) = parse_core("MCO:SLP:TPD").unwrap();
+        assert_eq!((parsed.msg.as_str(), parsed.fields), ("TPD", None));
+    }
 }
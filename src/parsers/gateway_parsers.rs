@@ -1,42 +1,117 @@
-use crate::{parsers::ParsedMessage, LogParseError, SendStatus};
+use super::serial_parsers::{parse_serial_msg, to_node};
+use crate::dictionary::dictionary;
+use crate::protocol::{MsgFields, MsgKind, TransportMsg};
+use crate::{LogParseError, ParsedMessage, SendStatus};
 use nom::branch::alt;
-use nom::bytes::complete::tag;
-use std::collections::HashMap;
-
-//look up table helper for converting subsystem into human readable name
-lazy_static! {
-    static ref SS_LUT: HashMap<&'static str, &'static str> = {
-        let mut m = HashMap::new();
-        m.insert("IMQ", "MQTT-In");
-        m.insert("RFC", "Rcv");
-        m.insert("RMQ", "MQTT-Reconnect");
-        m.insert("TIN", "XportInit");
-        m.insert("TPC", "XportConnect");
-        m.insert("TPS", "XportSend");
-        m.insert("TRC", "ReadFromClient");
-        m.insert("TSA", "XportAvail");
-        m
+use nom::bytes::complete::{is_not, tag};
+use nom::character::complete::{one_of, u8};
+use nom::combinator::{all_consuming, opt};
+use nom::error::ErrorKind;
+use nom::sequence::tuple;
+
+// <prefix>/node-id/child-sensor-id/command/ack/type with the prefixes taken
+// from the [mqtt] section of the dictionary
+pub fn parse_mqtt_topic<'a>(
+    i: &'a str,
+    payload: &str,
+) -> nom::IResult<&'a str, TransportMsg, LogParseError> {
+    let dictionary = dictionary();
+    let prefixes = [
+        (&dictionary.mqtt.publish_prefix, MsgKind::MqttOut),
+        (&dictionary.mqtt.subscribe_prefix, MsgKind::MqttIn),
+    ];
+    let (remaining, kind) = match prefixes.iter().find_map(|(prefix, kind)| {
+        let prefix = prefix.as_ref()?;
+        i.strip_prefix(prefix.as_str())?
+            .strip_prefix('/')
+            .map(|r| (r, *kind))
+    }) {
+        Some(found) => found,
+        None => {
+            return Err(nom::Err::Error(LogParseError::Nom(
+                i.to_string(),
+                ErrorKind::Tag,
+            )))
+        }
+    };
+    let (remaining, node) = u8(remaining)?;
+    let (remaining, _) = tag("/")(remaining)?;
+    let (remaining, sensor) = u8(remaining)?;
+    let (remaining, _) = tag("/")(remaining)?;
+    let (remaining, command) = u8(remaining)?;
+    let (remaining, _) = tag("/")(remaining)?;
+    let (remaining, ack) = one_of("01")(remaining)?;
+    let (remaining, _) = tag("/")(remaining)?;
+    let (remaining, msg_type) = u8(remaining)?;
+    // published topics come from the node, subscribed ones go to it
+    let (sender, destination) = match kind {
+        MsgKind::MqttIn => (0, node),
+        _ => (node, 0),
     };
+
+    Ok((
+        remaining,
+        TransportMsg {
+            kind,
+            sender,
+            last: 0,
+            next: None,
+            destination,
+            sensor,
+            command,
+            ack: Some(ack == '1'),
+            msg_type,
+            payload_type: 0, //P_STRING, MQTT payloads are text
+            length: payload.len().min(u8::MAX as usize) as u8,
+            signed: false,
+            failures: None,
+            send_ok: None,
+            payload: payload.to_string(),
+        },
+    ))
+}
+
+fn parse_topic_msg(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
+    // TOPIC=%s,MSG SENT or TOPIC=%s, MSG RECEIVED - the payload is not logged
+    let (remaining, _) = tag("TOPIC=")(i)?;
+    let (remaining, topic) = is_not(",")(remaining)?;
+    let (remaining, _) = alt((tag(",MSG SENT"), tag(", MSG RECEIVED")))(remaining)?;
+    let (_, msg) = all_consuming(|t| parse_mqtt_topic(t, ""))(topic)?;
+    Ok((remaining, MsgFields::Msg(msg)))
+}
+
+fn parse_client_msg(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
+    // C=%d,MSG=%s or MSG=%s for gateways with a single client; the client
+    // number is not kept
+    let (remaining, _) = opt(tuple((tag("C="), u8, tag(","))))(i)?;
+    let (remaining, _) = tag("MSG=")(remaining)?;
+    let (remaining, msg) = parse_serial_msg(remaining)?;
+    Ok((remaining, MsgFields::Msg(to_node(msg))))
+}
+
+fn parse_msg_into_human<'a>(
+    subsystem: &str,
+    i: &'a str,
+) -> nom::IResult<&'a str, MsgFields, LogParseError> {
+    match subsystem {
+        "TPS" | "IMQ" => all_consuming(parse_topic_msg)(i),
+        "RFC" => all_consuming(parse_client_msg)(i),
+        _ => Err(nom::Err::Error(crate::LogParseError::Nom(
+            i.to_string(),
+            ErrorKind::Tag,
+        ))),
+    }
 }
 
 fn parse_subsystem(i: &str) -> nom::IResult<&str, &str, LogParseError> {
-    // ugh wanted something to extract keys of the LUT to create tag parsers
-    // let mut ss_parser:List = SS_LUT
-    //     .keys()
-    //     .map(|x| tag::<&str, &str, crate::LogParseError>(*x))
-    //     .collect();
-    // but need something to convert iter into tuple?
-    let (remaining, subsystem) = alt((
-        tag("IMQ"),
-        tag("RFC"),
-        tag("RMQ"),
-        tag("TIN"),
-        tag("TPC"),
-        tag("TPS"),
-        tag("TRC"),
-        tag("TSA"),
-    ))(i)?;
-    Ok((remaining, subsystem))
+    // matcher is built from the subsystem keys of the dictionary
+    match dictionary().system("GWT") {
+        Some(dict) => dict.parse_subsystem(i),
+        None => Err(nom::Err::Error(LogParseError::Nom(
+            i.to_string(),
+            ErrorKind::Tag,
+        ))),
+    }
 }
 
 //top level gateway parser
@@ -44,17 +119,24 @@ fn parse_subsystem(i: &str) -> nom::IResult<&str, &str, LogParseError> {
 pub fn parse_gateway(i: &str) -> nom::IResult<&str, ParsedMessage, LogParseError> {
     //must start with "GWT:"
     let (remaining, _) = tag("GWT:")(i)?;
-    let (remaining, subsystem) = parse_subsystem(remaining)?; //FIXME: use newer method
+    let (remaining, subsystem) = parse_subsystem(remaining)?;
     let (remaining, _) = tag(":")(remaining)?;
+    let dictionary = dictionary();
+    let dict = dictionary.system("GWT").unwrap(); //parse_subsystem succeeded so it exists
 
-    // currently all gateway messages are just passed up as is
+    // MQTT traffic is decoded, other gateway messages are just passed up as is
+    let (msg, fields) = match parse_msg_into_human(subsystem, remaining) {
+        Ok((_, fields)) => (fields.to_string(), Some(fields)),
+        Err(_) => (remaining.to_string(), None),
+    };
     Ok((
         "",
         ParsedMessage {
             send_status: SendStatus::UNKNOWN, //overwritten later with correct status
-            system: Some("Gway".to_string()),
-            subsystem: Some(SS_LUT.get(&subsystem[..]).unwrap().to_string()),
-            msg: remaining.to_string(),
+            system: dict.name().map(|n| n.to_string()),
+            subsystem: dict.subsystem_name(subsystem).map(|n| n.to_string()),
+            msg,
+            fields,
         },
     )) //consume rest and pass input to output as default
 }
@@ -62,7 +144,6 @@ pub fn parse_gateway(i: &str) -> nom::IResult<&str, ParsedMessage, LogParseError
 #[cfg(test)]
 mod tests {
     use super::*;
-    use nom::error::ErrorKind;
 
     #[test]
     fn test_parse_subsystem() {
@@ -89,6 +170,7 @@ mod tests {
                     system: Some("Gway".to_string()),
                     subsystem: Some("Rcv".to_string()),
                     msg: "some message".to_string(),
+                    fields: None,
                 }
             ))
         );
@@ -101,8 +183,82 @@ mod tests {
                     system: Some("Gway".to_string()),
                     subsystem: Some("XportAvail".to_string()),
                     msg: "another message".to_string(),
+                    fields: None,
                 }
             ))
         );
     }
+
+    #[test]
+    fn test_parse_mqtt() {
+        let (_, parsed) =
+            parse_gateway("GWT:TPS:TOPIC=mygateway1-out/12/1/1/0/0,MSG SENT").unwrap();
+        assert_eq!(
+            parsed.fields,
+            Some(MsgFields::Msg(TransportMsg {
+                kind: MsgKind::MqttOut,
+                sender: 12,
+                last: 0,
+                next: None,
+                destination: 0,
+                sensor: 1,
+                command: 1,
+                ack: Some(false),
+                msg_type: 0,
+                payload_type: 0,
+                length: 0,
+                signed: false,
+                failures: None,
+                send_ok: None,
+                payload: "".to_string(),
+            }))
+        );
+        assert_eq!(
+            parsed.msg,
+            "MQTT publish node (12): sensor (1) C_SET V_TEMP payload ()"
+        );
+
+        let (_, parsed) =
+            parse_gateway("GWT:IMQ:TOPIC=mygateway1-in/12/2/1/1/2, MSG RECEIVED").unwrap();
+        match parsed.fields {
+            Some(MsgFields::Msg(m)) => {
+                assert_eq!(m.kind, MsgKind::MqttIn);
+                assert_eq!(m.ack, Some(true));
+                // from the controller to node 12
+                assert_eq!((m.sender, m.destination, m.node()), (0, 12, 12));
+            }
+            other => panic!("topic not decoded: {:?}", other),
+        }
+        assert_eq!(
+            parsed.msg,
+            "MQTT subscribe node (12): sensor (2) C_SET V_STATUS payload () ack"
+        );
+
+        // serial lines from a controller client
+        for line in ["GWT:RFC:C=1,MSG=12;2;1;1;2;1", "GWT:RFC:MSG=12;2;1;1;2;1"] {
+            let (_, parsed) = parse_gateway(line).unwrap();
+            match parsed.fields {
+                Some(MsgFields::Msg(m)) => {
+                    assert_eq!(m.kind, MsgKind::SerialIn);
+                    assert_eq!((m.sender, m.destination, m.node()), (0, 12, 12));
+                    assert_eq!((m.sensor, m.command, m.msg_type), (2, 1, 2));
+                    assert_eq!(m.payload, "1");
+                }
+                other => panic!("{} not decoded: {:?}", line, other),
+            }
+            assert_eq!(
+                parsed.msg,
+                "Serial to node (12): sensor (2) C_SET V_STATUS payload (1) ack"
+            );
+        }
+
+        // unknown prefixes are left alone
+        let (_, parsed) = parse_gateway("GWT:TPS:TOPIC=othergw-out/12/1/1/0/0,MSG SENT").unwrap();
+        assert_eq!(parsed.fields, None);
+        assert_eq!(parsed.msg, "TOPIC=othergw-out/12/1/1/0/0,MSG SENT");
+
+        let (_, msg) = parse_mqtt_topic("mygateway1-out/5/0/1/0/38", "3.02").unwrap();
+        assert_eq!((msg.sender, msg.msg_type), (5, 38));
+        assert_eq!(msg.payload, "3.02");
+    }
 }
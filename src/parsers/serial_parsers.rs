@@ -0,0 +1,134 @@
+use crate::dictionary::dictionary;
+use crate::protocol::{MsgFields, MsgKind, TransportMsg};
+use crate::{LogParseError, ParsedMessage, SendStatus};
+use nom::bytes::complete::tag;
+use nom::character::complete::{one_of, u8};
+use nom::combinator::rest;
+
+//serial protocol lines as sent between gateway and controller
+//node-id;child-sensor-id;command;ack;type;payload
+
+pub fn parse_serial_msg(i: &str) -> nom::IResult<&str, TransportMsg, LogParseError> {
+    let (remaining, node) = u8(i)?;
+    let (remaining, _) = tag(";")(remaining)?;
+    let (remaining, sensor) = u8(remaining)?;
+    let (remaining, _) = tag(";")(remaining)?;
+    let (remaining, command) = u8(remaining)?;
+    let (remaining, _) = tag(";")(remaining)?;
+    let (remaining, ack) = one_of("01")(remaining)?;
+    let (remaining, _) = tag(";")(remaining)?;
+    let (remaining, msg_type) = u8(remaining)?;
+    let (remaining, _) = tag(";")(remaining)?;
+    let (remaining, payload) = rest(remaining)?;
+    let payload = payload.trim_end_matches(['\r', '\n']);
+
+    Ok((
+        remaining,
+        TransportMsg {
+            kind: MsgKind::Serial,
+            sender: node,
+            last: 0,
+            next: None,
+            destination: 0,
+            sensor,
+            command,
+            ack: Some(ack == '1'),
+            msg_type,
+            payload_type: 0, //P_STRING, serial payloads are text
+            length: payload.len().min(u8::MAX as usize) as u8,
+            signed: false,
+            failures: None,
+            send_ok: None,
+            payload: payload.to_string(),
+        },
+    ))
+}
+
+// a serial line the controller sent: it names the node it goes to
+pub fn to_node(mut msg: TransportMsg) -> TransportMsg {
+    msg.kind = MsgKind::SerialIn;
+    msg.destination = msg.sender;
+    msg.sender = 0;
+    msg
+}
+
+//top level serial protocol parser
+
+pub fn parse_serial(i: &str) -> nom::IResult<&str, ParsedMessage, LogParseError> {
+    let (remaining, msg) = parse_serial_msg(i)?;
+    let command = dictionary()
+        .command_name(msg.command)
+        .map(|n| n.to_string());
+    let fields = MsgFields::Msg(msg);
+
+    Ok((
+        remaining,
+        ParsedMessage {
+            send_status: SendStatus::UNKNOWN, //overwritten later with correct status
+            system: Some("Serial".to_string()),
+            subsystem: command,
+            msg: fields.to_string(),
+            fields: Some(fields),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom::error::ErrorKind;
+
+    #[test]
+    fn test_parse_serial() {
+        assert_eq!(
+            parse_serial("12;1;1;0;0;21.5\n"),
+            Ok((
+                "",
+                ParsedMessage {
+                    send_status: SendStatus::UNKNOWN,
+                    system: Some("Serial".to_string()),
+                    subsystem: Some("C_SET".to_string()),
+                    msg: "Serial node (12): sensor (1) C_SET V_TEMP payload (21.5)".to_string(),
+                    fields: Some(MsgFields::Msg(TransportMsg {
+                        kind: MsgKind::Serial,
+                        sender: 12,
+                        last: 0,
+                        next: None,
+                        destination: 0,
+                        sensor: 1,
+                        command: 1,
+                        ack: Some(false),
+                        msg_type: 0,
+                        payload_type: 0,
+                        length: 4,
+                        signed: false,
+                        failures: None,
+                        send_ok: None,
+                        payload: "21.5".to_string(),
+                    })),
+                }
+            ))
+        );
+
+        let (_, parsed) = parse_serial("0;255;3;1;14;Gateway startup complete.").unwrap();
+        assert_eq!(
+            parsed.msg,
+            "Serial node (0): sensor (255) C_INTERNAL I_GATEWAY_READY payload (Gateway startup complete.) ack"
+        );
+
+        // what the gateway writes comes from the node it names
+        let (_, msg) = parse_serial_msg("12;1;1;0;0;21.5").unwrap();
+        assert_eq!((msg.sender, msg.destination, msg.node()), (12, 0, 12));
+        let msg = to_node(msg);
+        assert_eq!(msg.kind, MsgKind::SerialIn);
+        assert_eq!((msg.sender, msg.destination, msg.node()), (0, 12, 12));
+
+        // empty payload is fine, missing fields are not
+        assert!(parse_serial("12;255;3;0;6;").is_ok());
+        let result_error = parse_serial("12;255;3;0").unwrap_err();
+        assert_eq!(
+            result_error,
+            nom::Err::Error(crate::LogParseError::Nom("".to_string(), ErrorKind::Tag)),
+        );
+    }
+}
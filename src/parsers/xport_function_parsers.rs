@@ -1,77 +1,216 @@
-use crate::{parsers::ParsedMessage, LogParseError, SendStatus};
+use crate::dictionary::{dictionary, protocol_version, SystemDictionary};
+use crate::protocol::{MsgFields, MsgKind, TransportMsg, Version};
+use crate::{LogParseError, ParsedMessage, SendStatus};
 use nom::branch::alt;
 use nom::bytes::complete::tag;
-use nom::bytes::complete::take;
+use nom::character::complete::{i16, u8};
+use nom::combinator::{all_consuming, rest, value};
 use nom::error::ErrorKind;
-use std::collections::HashMap;
-
-//look up table helper for converting subsystem into human readable name
-lazy_static! {
-    static ref SS_LUT: HashMap<&'static str, &'static str> = {
-        let mut m = HashMap::new();
-        m.insert("CKU", "CheckUL");
-        m.insert("LRT", "LoadRoutingTable");
-        m.insert("MSG", "Msg");
-        m.insert("PNG", "Ping");
-        m.insert("RRT", "Route");
-        m.insert("RTE", "Route");
-        m.insert("SAN", "Sanity");
-        m.insert("SID", "AssignNode");
-        m.insert("SIR", "SignalReport");
-        m.insert("SND", "RouteSend");
-        m.insert("SRT", "SaveRoutingTable");
-        m.insert("TDI", "Disable");
-        m.insert("TRI", "ReInit");
-        m.insert("UPL", "PingGW");
-        m.insert("WUR", "WaitUntilReady");
-        m
+
+fn parse_ok_fail(i: &str) -> nom::IResult<&str, bool, LogParseError> {
+    alt((value(true, tag("OK")), value(false, tag("FAIL"))))(i)
+}
+
+fn parse_msg_frame(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
+    // READ,%d-%d-%d,s=%d,c=%d,t=%d,pt=%d,l=%d,sg=%d:%s
+    // SEND,%d-%d-%d-%d,s=%d,c=%d,t=%d,pt=%d,l=%d,sg=%d,ft=%d,st=%s:%s
+    let (remaining, kind) = alt((
+        value(MsgKind::Read, tag("READ,")),
+        value(MsgKind::Send, tag("SEND,")),
+    ))(i)?;
+    let (remaining, sender) = u8(remaining)?;
+    let (remaining, _) = tag("-")(remaining)?;
+    let (remaining, last) = u8(remaining)?;
+    let (remaining, _) = tag("-")(remaining)?;
+    let (remaining, next) = match kind {
+        MsgKind::Send => {
+            let (remaining, next) = u8(remaining)?;
+            let (remaining, _) = tag("-")(remaining)?;
+            (remaining, Some(next))
+        }
+        _ => (remaining, None),
     };
+    let (remaining, destination) = u8(remaining)?;
+    let (remaining, _) = tag(",s=")(remaining)?;
+    let (remaining, sensor) = u8(remaining)?;
+    let (remaining, _) = tag(",c=")(remaining)?;
+    let (remaining, command) = u8(remaining)?;
+    let (remaining, _) = tag(",t=")(remaining)?;
+    let (remaining, msg_type) = u8(remaining)?;
+    let (remaining, _) = tag(",pt=")(remaining)?;
+    let (remaining, payload_type) = u8(remaining)?;
+    let (remaining, _) = tag(",l=")(remaining)?;
+    let (remaining, length) = u8(remaining)?;
+    let (remaining, _) = tag(",sg=")(remaining)?;
+    let (remaining, signed) = u8(remaining)?;
+    let (remaining, failures, send_ok) = match kind {
+        MsgKind::Send => {
+            let (remaining, _) = tag(",ft=")(remaining)?;
+            let (remaining, failures) = u8(remaining)?;
+            let (remaining, _) = tag(",st=")(remaining)?;
+            let (remaining, send_ok) =
+                alt((value(true, tag("OK")), value(false, tag("NACK"))))(remaining)?;
+            (remaining, Some(failures), Some(send_ok))
+        }
+        _ => (remaining, None, None),
+    };
+    let (remaining, _) = tag(":")(remaining)?;
+    let (remaining, payload) = rest(remaining)?;
+
+    Ok((
+        remaining,
+        MsgFields::Msg(TransportMsg {
+            kind,
+            sender,
+            last,
+            next,
+            destination,
+            sensor,
+            command,
+            ack: None,
+            msg_type,
+            payload_type,
+            length,
+            signed: signed != 0,
+            failures,
+            send_ok,
+            payload: payload.to_string(),
+        }),
+    ))
+}
+
+fn parse_msg_length_since_2_3(i: &str) -> nom::IResult<&str, (u8, u8), LogParseError> {
+    // LEN=%d,EXP=%d
+    let (remaining, _) = tag("LEN=")(i)?;
+    let (remaining, length) = u8(remaining)?;
+    let (remaining, _) = tag(",EXP=")(remaining)?;
+    let (remaining, expected) = u8(remaining)?;
+    Ok((remaining, (length, expected)))
 }
 
-lazy_static! {
-    static ref MSG_LUT: HashMap<&'static str, &'static str> = {
-        let mut m = HashMap::new();
-        m.insert("OK,FCTRL", "UL OK - ping filtered, interval too short");
-        m.insert("FAIL", "No Reply recieved");
-        m.insert("FPAR PREF FOUND", "Found Preferred parent - static ID");
-        m.insert("FPAR INACTIVE", "rvd FindParent Response but no request");
-        m.insert("BC", "Broadcast Message Recieved");
-        m.insert("GWL OK", "Link to GW OK");
-        m.insert("FWD BC MSG", "Controlled Broadcast Msg Fowarding");
-        m.insert("RCV CB", "call Receive Callback()");
-        m.insert("REL MSG", "Relay Message");
-        m.insert("REL MSG,NORP", "Relay Message but NOT a repeater");
-        m.insert("SIGN FAIL", "Signing Message Failed");
-        m.insert("GWL FAIL", "GW UL Failed");
-        m.insert("ID TK INVALID", "Token for ID Request Invalid");
-        m.insert("FPAR ACTIVE", "Finding Parent Active, message not sent");
-        m.insert("TNR", "Xport Not Ready, message not sent");
-        m.insert("TSL", "Xport Sleep");
-        m.insert("TPD", "Xport PowerDown");
-        m.insert("TRI", "Xport ReInit");
-        m.insert("TSB", "Xport Standby");
-        m
+fn parse_msg_length_before_2_3(i: &str) -> nom::IResult<&str, (u8, u8), LogParseError> {
+    // LEN,%d!=%d
+    let (remaining, _) = tag("LEN,")(i)?;
+    let (remaining, length) = u8(remaining)?;
+    let (remaining, _) = tag("!=")(remaining)?;
+    let (remaining, expected) = u8(remaining)?;
+    Ok((remaining, (length, expected)))
+}
+
+fn parse_msg_length(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
+    // the format changed with 2.3, accept either if the release is not known
+    let (remaining, (length, expected)) = match protocol_version() {
+        Some(v) if v < Version::new(2, 3, 0) => parse_msg_length_before_2_3(i)?,
+        Some(_) => parse_msg_length_since_2_3(i)?,
+        None => alt((parse_msg_length_since_2_3, parse_msg_length_before_2_3))(i)?,
     };
+    Ok((remaining, MsgFields::BadLength { length, expected }))
 }
 
-fn parse_msg_into_human(i: &str) -> nom::IResult<&str, &str, crate::LogParseError> {
-    //FIXME: finish imple
-    Err(nom::Err::Error(crate::LogParseError::Nom(
-        "Not Implemented Yet".to_string(),
-        ErrorKind::Tag,
-    )))
+fn parse_msg_protocol_version(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
+    // PVER,%d=%d
+    let (remaining, _) = tag("PVER,")(i)?;
+    let (remaining, received) = u8(remaining)?;
+    let (remaining, _) = tag("=")(remaining)?;
+    let (remaining, expected) = u8(remaining)?;
+    Ok((
+        remaining,
+        MsgFields::BadProtocolVersion { received, expected },
+    ))
 }
 
-fn parse_msg_by_lookup(i: &str) -> nom::IResult<&str, &str, crate::LogParseError> {
-    match MSG_LUT.get(i) {
-        Some(new_msg) => return Ok(("", new_msg)),
-        None => {
-            return Err(nom::Err::Error(crate::LogParseError::Nom(
-                "Msg not in LUT".to_string(),
-                ErrorKind::Tag,
-            )))
+fn parse_assign_node_id(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
+    // OK,ID=%d or FAIL,ID=%d
+    let (remaining, ok) = parse_ok_fail(i)?;
+    let (remaining, _) = tag(",ID=")(remaining)?;
+    let (remaining, id) = u8(remaining)?;
+    Ok((remaining, MsgFields::AssignNodeId { ok, id }))
+}
+
+fn parse_ping_send(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
+    // SEND,TO=%d
+    let (remaining, _) = tag("SEND,TO=")(i)?;
+    let (remaining, to) = u8(remaining)?;
+    Ok((remaining, MsgFields::PingSend { to }))
+}
+
+fn parse_signal_report(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
+    // CMD=%d,RSSI=%d (older releases print REP= for the reported value)
+    let (remaining, _) = tag("CMD=")(i)?;
+    let (remaining, cmd) = u8(remaining)?;
+    let (remaining, _) = alt((tag(",RSSI="), tag(",REP=")))(remaining)?;
+    let (remaining, value) = i16(remaining)?;
+    Ok((remaining, MsgFields::SignalReport { cmd, value }))
+}
+
+fn parse_uplink_check(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
+    // OK, FAIL or DGWC,O=%d,N=%d
+    alt((
+        |i| {
+            let (remaining, _) = tag("DGWC,O=")(i)?;
+            let (remaining, old) = u8(remaining)?;
+            let (remaining, _) = tag(",N=")(remaining)?;
+            let (remaining, new) = u8(remaining)?;
+            Ok((remaining, MsgFields::GatewayDistanceChanged { old, new }))
+        },
+        |i| {
+            let (remaining, ok) = parse_ok_fail(i)?;
+            Ok((remaining, MsgFields::UplinkCheck { ok }))
+        },
+    ))(i)
+}
+
+fn parse_node_to_node_route(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
+    // N2N OK or N2N FAIL
+    let (remaining, _) = tag("N2N ")(i)?;
+    let (remaining, ok) = parse_ok_fail(remaining)?;
+    Ok((remaining, MsgFields::NodeToNodeRoute { ok }))
+}
+
+// the meaning of a message depends on the subsystem it was logged by
+// the whole message must be consumed, otherwise it is left for the lookup
+fn parse_msg_into_human<'a>(
+    subsystem: &str,
+    i: &'a str,
+) -> nom::IResult<&'a str, MsgFields, LogParseError> {
+    match subsystem {
+        "MSG" => all_consuming(alt((
+            parse_msg_frame,
+            parse_msg_length,
+            parse_msg_protocol_version,
+        )))(i),
+        "SID" => all_consuming(parse_assign_node_id)(i),
+        "PNG" => all_consuming(parse_ping_send)(i),
+        "SIR" => all_consuming(parse_signal_report)(i),
+        "LRT" => {
+            all_consuming(parse_ok_fail)(i).map(|(r, ok)| (r, MsgFields::LoadRoutingTable { ok }))
         }
-    };
+        "SRT" => {
+            all_consuming(parse_ok_fail)(i).map(|(r, ok)| (r, MsgFields::SaveRoutingTable { ok }))
+        }
+        "SAN" => all_consuming(parse_ok_fail)(i).map(|(r, ok)| (r, MsgFields::SanityCheck { ok })),
+        "CKU" => all_consuming(parse_uplink_check)(i),
+        "RTE" => all_consuming(parse_node_to_node_route)(i),
+        _ => Err(nom::Err::Error(crate::LogParseError::Nom(
+            i.to_string(),
+            ErrorKind::Tag,
+        ))),
+    }
+}
+
+// lookup of a message in the dictionary
+fn parse_msg_by_lookup<'a>(
+    dict: &SystemDictionary,
+    subsystem: &str,
+    i: &'a str,
+) -> nom::IResult<&'a str, String, crate::LogParseError> {
+    match dict.message(subsystem, i) {
+        Some(new_msg) => Ok(("", new_msg.to_string())),
+        None => Err(nom::Err::Error(crate::LogParseError::Nom(
+            "Msg not in LUT".to_string(),
+            ErrorKind::Tag,
+        ))),
+    }
 }
 
 //top level trasnport function parser
@@ -79,36 +218,48 @@ fn parse_msg_by_lookup(i: &str) -> nom::IResult<&str, &str, crate::LogParseError
 pub fn parse_xport_function(i: &str) -> nom::IResult<&str, ParsedMessage, LogParseError> {
     //must start with "TSF:"
     let (remaining, _) = tag("TSF:")(i)?;
-    //get the subsystem - always 3 chars
-    let (remaining, subsystem) = take(3u32)(remaining)?;
-    //now check to see if subsystem is one of the keys above
-    if !SS_LUT.contains_key(subsystem) {
-        // subsystem is NOT defined - return an error by wrapping a LogParseError in a nom error type:
-        return Err(nom::Err::Error(crate::LogParseError::SubSystemError(
-            subsystem.to_string(),
-            format!("{:?}", SS_LUT.keys()),
-        )));
-    }
+    let dictionary = dictionary();
+    let dict = match dictionary.system("TSF") {
+        Some(dict) => dict,
+        None => {
+            return Err(nom::Err::Error(LogParseError::Nom(
+                i.to_string(),
+                ErrorKind::Tag,
+            )))
+        }
+    };
+    //get the subsystem - matched against the subsystem keys of the dictionary
+    let (remaining, subsystem) = match dict.parse_subsystem(remaining) {
+        Ok(parsed) => parsed,
+        Err(_) => {
+            // subsystem is NOT defined - return an error by wrapping a LogParseError in a nom error type:
+            let found = remaining.split(':').next().unwrap_or_default();
+            return Err(nom::Err::Error(LogParseError::SubSystemError(
+                found.to_string(),
+                format!("{:?}", dict.subsystems.keys()),
+            )));
+        }
+    };
 
     let (remaining, _) = tag(":")(remaining)?;
 
     //either try to parse as human readable message (most strict)
     //or try to parse via simple message convert via lookup
     //or just expand system/subsystem and leave message as is
-    let result = match alt((parse_msg_into_human, parse_msg_by_lookup))(remaining) {
-        Ok((_, converted)) => ParsedMessage {
-            send_status: SendStatus::UNKNOWN,
-            system: Some("Xport".to_string()),
-            subsystem: Some(SS_LUT.get(&subsystem[..]).unwrap().to_string()),
-            msg: converted.to_string(),
-        },
-        Err(_) => ParsedMessage {
-            send_status: SendStatus::UNKNOWN,
-            system: Some("Xport".to_string()),
-            subsystem: Some(SS_LUT.get(&subsystem[..]).unwrap().to_string()),
-            msg: remaining.to_string(),
+    let (msg, fields) = match parse_msg_into_human(subsystem, remaining) {
+        Ok((_, fields)) => (fields.to_string(), Some(fields)),
+        Err(_) => match parse_msg_by_lookup(dict, subsystem, remaining) {
+            Ok((_, converted)) => (converted, None),
+            Err(_) => (remaining.to_string(), None),
         },
     };
+    let result = ParsedMessage {
+        send_status: SendStatus::UNKNOWN,
+        system: dict.name().map(|n| n.to_string()),
+        subsystem: dict.subsystem_name(subsystem).map(|n| n.to_string()),
+        msg,
+        fields,
+    };
 
     Ok(("", result)) //consume rest and pass input to output as default
 }
@@ -129,6 +280,7 @@ mod tests {
                     system: Some("Xport".to_string()),
                     subsystem: Some("PingGW".to_string()),
                     msg: "some message".to_string(),
+                    fields: None,
                 }
             ))
         );
@@ -141,6 +293,7 @@ mod tests {
                     system: Some("Xport".to_string()),
                     subsystem: Some("Route".to_string()),
                     msg: "another message".to_string(),
+                    fields: None,
                 }
             ))
         );
@@ -154,4 +307,153 @@ mod tests {
             )),
         );
     }
+
+    #[test]
+    fn test_parse_msg_into_human() {
+        assert_eq!(
+            parse_msg_into_human("SID", "OK,ID=12"),
+            Ok(("", MsgFields::AssignNodeId { ok: true, id: 12 }))
+        );
+        assert_eq!(
+            parse_msg_into_human("PNG", "SEND,TO=0"),
+            Ok(("", MsgFields::PingSend { to: 0 }))
+        );
+        assert_eq!(
+            parse_msg_into_human("SIR", "CMD=1,RSSI=-72"),
+            Ok(("", MsgFields::SignalReport { cmd: 1, value: -72 }))
+        );
+        assert_eq!(
+            parse_msg_into_human("LRT", "OK"),
+            Ok(("", MsgFields::LoadRoutingTable { ok: true }))
+        );
+        assert_eq!(
+            parse_msg_into_human("SRT", "OK"),
+            Ok(("", MsgFields::SaveRoutingTable { ok: true }))
+        );
+        assert_eq!(
+            parse_msg_into_human("SAN", "OK"),
+            Ok(("", MsgFields::SanityCheck { ok: true }))
+        );
+        assert_eq!(
+            parse_msg_into_human("CKU", "OK"),
+            Ok(("", MsgFields::UplinkCheck { ok: true }))
+        );
+        assert_eq!(
+            parse_msg_into_human("CKU", "DGWC,O=2,N=1"),
+            Ok(("", MsgFields::GatewayDistanceChanged { old: 2, new: 1 }))
+        );
+        assert_eq!(
+            parse_msg_into_human("RTE", "N2N OK"),
+            Ok(("", MsgFields::NodeToNodeRoute { ok: true }))
+        );
+        assert_eq!(
+            parse_msg_into_human("MSG", "LEN=9,EXP=8"),
+            Ok((
+                "",
+                MsgFields::BadLength {
+                    length: 9,
+                    expected: 8
+                }
+            ))
+        );
+        assert_eq!(
+            parse_msg_into_human("MSG", "LEN,9!=8"),
+            Ok((
+                "",
+                MsgFields::BadLength {
+                    length: 9,
+                    expected: 8
+                }
+            ))
+        );
+        assert_eq!(
+            parse_msg_into_human("MSG", "PVER,1=2"),
+            Ok((
+                "",
+                MsgFields::BadProtocolVersion {
+                    received: 1,
+                    expected: 2
+                }
+            ))
+        );
+        // once the release is known only its format is accepted
+        let v2_3 = Some(Version::new(2, 3, 2));
+        let dict = dictionary();
+        assert!(crate::dictionary::with_scope(dict.clone(), v2_3, || {
+            parse_msg_into_human("MSG", "LEN,9!=8")
+        })
+        .is_err());
+        let v2_2 = Some(Version::new(2, 2, 0));
+        assert!(crate::dictionary::with_scope(dict, v2_2, || {
+            parse_msg_into_human("MSG", "LEN,9!=8")
+        })
+        .is_ok());
+        // trailing text is not silently dropped
+        assert!(parse_msg_into_human("CKU", "OK,FCTRL").is_err());
+        assert!(parse_msg_into_human("WUR", "MS=0").is_err());
+    }
+
+    #[test]
+    fn test_parse_xport_function_msg() {
+        let send = TransportMsg {
+            kind: MsgKind::Send,
+            sender: 1,
+            last: 1,
+            next: Some(0),
+            destination: 0,
+            sensor: 255,
+            command: 3,
+            ack: None,
+            msg_type: 15,
+            payload_type: 6,
+            length: 2,
+            signed: false,
+            failures: Some(0),
+            send_ok: Some(true),
+            payload: "0100".to_string(),
+        };
+        assert_eq!(
+            parse_xport_function("TSF:MSG:SEND,1-1-0-0,s=255,c=3,t=15,pt=6,l=2,sg=0,ft=0,st=OK:0100"),
+            Ok((
+                "",
+                ParsedMessage {
+                    send_status: SendStatus::UNKNOWN,
+                    system: Some("Xport".to_string()),
+                    subsystem: Some("Msg".to_string()),
+                    msg: "Send from node (1) via (1) next (0) to (0): sensor (255) C_INTERNAL I_SIGNING_PRESENTATION payload (0100) - OK".to_string(),
+                    fields: Some(MsgFields::Msg(send)),
+                }
+            ))
+        );
+
+        let read = TransportMsg {
+            kind: MsgKind::Read,
+            sender: 12,
+            last: 12,
+            next: None,
+            destination: 0,
+            sensor: 1,
+            command: 1,
+            ack: None,
+            msg_type: 0,
+            payload_type: 7,
+            length: 5,
+            signed: false,
+            failures: None,
+            send_ok: None,
+            payload: "21.5".to_string(),
+        };
+        let (_, parsed) =
+            parse_xport_function("TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=5,sg=0:21.5").unwrap();
+        assert_eq!(parsed.fields, Some(MsgFields::Msg(read)));
+        assert_eq!(
+            parsed.msg,
+            "Read from node (12) via (12) to (0): sensor (1) C_SET V_TEMP payload (21.5)"
+        );
+
+        // lookups still apply when there is nothing to decode
+        let (_, parsed) = parse_xport_function("TSF:MSG:ACK REQ").unwrap();
+        assert_eq!(parsed.msg, "ACK requested");
+        assert_eq!(parsed.fields, None);
+    }
 }
@@ -1,103 +1,76 @@
-use crate::{parsers::ParsedMessage, LogParseError, SendStatus};
+use crate::dictionary::{dictionary, SystemDictionary};
+use crate::protocol::{MsgFields, XportState};
+use crate::{LogParseError, ParsedMessage, SendStatus};
 use nom::branch::alt;
 use nom::bytes::complete::tag;
-use nom::bytes::complete::{take_until, take_while};
-use nom::character::complete::digit1;
-use nom::character::is_alphabetic;
+use nom::character::complete::u8;
 use nom::error::ErrorKind;
-use std::collections::HashMap;
-
-//look up table helper for converting subsystem into human readable name
-lazy_static! {
-    static ref SS_LUT: HashMap<&'static str, &'static str> = {
-        let mut m = HashMap::new();
-        m.insert("FAIL", "FAIL");
-        m.insert("FPAR", "FindParent");
-        m.insert("ID", "ID");
-        m.insert("INIT", "INIT");
-        m.insert("READY", "READY");
-        m.insert("UPL", "UPLINK");
-        m
-    };
-}
-
-lazy_static! {
-    static ref MSG_LUT: HashMap<&'static str, &'static str> = {
-        let mut m = HashMap::new();
-        m.insert("DIS", "Xport Disable");
-        m.insert("TSP OK", "Xport Configured & Fully Operational");
-        m.insert("TSP PSM", "Xport PassiveMode set");
-        m.insert("TSP FAIL", "Xport Init Failed");
-        m.insert("SRT", "Save Routing Table");
-        m.insert("UPL FAIL,SNP", "Fail count exceeded - search new parent");
-        m.insert("FAIL,STATP", "Fail count exceeded - static parent enforced");
-        m.insert("OK", "UL OK, GW returned ping");
-        m.insert("FAIL", "UL Check FAILED - GW Ping Failed");
-        m.insert("NWD REQ", "Send xport network discovery request");
-        m
-    };
-}
 
-fn parse_id_verification_failed(i: &str) -> nom::IResult<&str, String, LogParseError> {
+fn parse_id_verification_failed(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
     // FAIL,ID=%d
     let (remaining, _) = tag("FAIL,ID=")(i)?;
-    let (remaining, id) = digit1(remaining)?;
-    let msg = format!(
-        "ID ({}) invalid / verification failed / no ID received from controller",
-        id
-    );
+    let (remaining, id) = u8(remaining)?;
 
-    Ok((remaining, msg))
+    Ok((remaining, MsgFields::IdVerificationFailed { id }))
 }
 
-fn parse_static_id(i: &str) -> nom::IResult<&str, String, LogParseError> {
+fn parse_static_id(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
     // STATID=%d
     let (remaining, _) = tag("STATID=")(i)?;
-    let (remaining, id) = digit1(remaining)?;
-    let msg = format!("Static ID ({})", id);
+    let (remaining, id) = u8(remaining)?;
 
-    Ok((remaining, msg))
+    Ok((remaining, MsgFields::StaticId { id }))
 }
 
-fn parse_transition_ready(i: &str) -> nom::IResult<&str, String, LogParseError> {
+fn parse_transition_ready(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
     // ID=%d,PAR=%d,DIS=%d
     let (remaining, _) = tag("ID=")(i)?;
-    let (remaining, id) = digit1(remaining)?;
+    let (remaining, id) = u8(remaining)?;
     let (remaining, _) = tag(",PAR=")(remaining)?;
-    let (remaining, parent) = digit1(remaining)?;
+    let (remaining, parent) = u8(remaining)?;
     let (remaining, _) = tag(",DIS=")(remaining)?;
-    let (remaining, distance) = digit1(remaining)?;
+    let (remaining, distance) = u8(remaining)?;
+
+    Ok((
+        remaining,
+        MsgFields::Ready {
+            id,
+            parent,
+            distance,
+        },
+    ))
+}
 
-    let msg = format!(
-        "READY: node ID ({}) parent ID ({}) GW distance ({})",
-        id, parent, distance
-    );
+fn parse_fail_count(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
+    // CNT=%d
+    let (remaining, _) = tag("CNT=")(i)?;
+    let (remaining, count) = u8(remaining)?;
 
-    Ok((remaining, msg))
+    Ok((remaining, MsgFields::FailCount { count }))
 }
 
-fn parse_msg_into_human(i: &str) -> nom::IResult<&str, String, LogParseError> {
+fn parse_msg_into_human(i: &str) -> nom::IResult<&str, MsgFields, LogParseError> {
     alt((
         parse_id_verification_failed,
         parse_static_id,
         parse_transition_ready,
+        parse_fail_count,
     ))(i)
 }
 
-fn parse_msg_by_lookup(i: &str) -> nom::IResult<&str, String, crate::LogParseError> {
-    match MSG_LUT.get(i) {
-        Some(new_msg) => return Ok(("", new_msg.to_string())),
-        None => {
-            return Err(nom::Err::Error(crate::LogParseError::Nom(
-                "Msg not in LUT".to_string(),
-                ErrorKind::Tag,
-            )))
-        }
-    };
-}
-
-fn is_char_alphabetic(chr: char) -> bool {
-    return chr.is_ascii() && is_alphabetic(chr as u8);
+// lookup of a message in the dictionary
+fn parse_msg_by_lookup<'a>(
+    dict: &SystemDictionary,
+    subsystem: &str,
+    i: &'a str,
+) -> nom::IResult<&'a str, String, crate::LogParseError> {
+    match dict.message(subsystem, i) {
+        Some(new_msg) => Ok(("", new_msg.to_string())),
+        None => Err(nom::Err::Error(crate::LogParseError::Nom(
+            "Msg not in LUT".to_string(),
+            ErrorKind::Tag,
+        ))),
+    }
 }
 
 //top level trasnport state machine parser
@@ -105,43 +78,58 @@ fn is_char_alphabetic(chr: char) -> bool {
 pub fn parse_xport_machine(i: &str) -> nom::IResult<&str, ParsedMessage, LogParseError> {
     //must start with "TSF:"
     let (remaining, _) = tag("TSM:")(i)?;
-    //get the subsystem - in this case, read until the ':'
-    // let (remaining, subsystem) = take_until(":")(remaining)?;
-    let (remaining, subsystem) = alt((take_until(":"), take_while(is_char_alphabetic)))(remaining)?;
-    //now check to see if subsystem is one of the keys above
-    if !SS_LUT.contains_key(subsystem) {
-        // subsystem is NOT defined - return an error by wrapping a LogParseError in a nom error type:
-        return Err(nom::Err::Error(LogParseError::SubSystemError(
-            subsystem.to_string(),
-            format!("{:?}", SS_LUT.keys()),
-        )));
-    }
+    let dictionary = dictionary();
+    let dict = match dictionary.system("TSM") {
+        Some(dict) => dict,
+        None => {
+            return Err(nom::Err::Error(LogParseError::Nom(
+                i.to_string(),
+                ErrorKind::Tag,
+            )))
+        }
+    };
+    //get the subsystem - matched against the subsystem keys of the dictionary
+    let (remaining, subsystem) = match dict.parse_subsystem(remaining) {
+        Ok(parsed) => parsed,
+        Err(_) => {
+            // subsystem is NOT defined - return an error by wrapping a LogParseError in a nom error type:
+            let found = remaining.split(':').next().unwrap_or_default();
+            return Err(nom::Err::Error(LogParseError::SubSystemError(
+                found.to_string(),
+                format!("{:?}", dict.subsystems.keys()),
+            )));
+        }
+    };
 
     // handle the case if there is no message remaining
-    let final_msg: &str;
-    if !remaining.is_empty() {
+    let final_msg: &str = if !remaining.is_empty() {
         let (remaining, _) = tag(":")(remaining)?;
-        final_msg = remaining;
+        remaining
     } else {
-        final_msg = "State Transition";
-    }
+        "State Transition"
+    };
 
     //either try to parse as human readable message (most strict)
     //or try to parse via simple message convert via lookup
     //or just expand system/subsystem and leave message as is
-    let result = match alt((parse_msg_into_human, parse_msg_by_lookup))(final_msg) {
-        Ok((_, converted)) => ParsedMessage {
-            send_status: SendStatus::UNKNOWN,
-            system: Some("XportSM".to_string()),
-            subsystem: Some(SS_LUT.get(&subsystem[..]).unwrap().to_string()),
-            msg: converted.to_string(),
-        },
-        Err(_) => ParsedMessage {
-            send_status: SendStatus::UNKNOWN,
-            system: Some("XportSM".to_string()),
-            subsystem: Some(SS_LUT.get(&subsystem[..]).unwrap().to_string()),
-            msg: final_msg.to_string(),
-        },
+    let (msg, fields) = if remaining.is_empty() {
+        let fields = XportState::from_code(subsystem).map(MsgFields::StateTransition);
+        (final_msg.to_string(), fields)
+    } else {
+        match parse_msg_into_human(final_msg) {
+            Ok((_, fields)) => (fields.to_string(), Some(fields)),
+            Err(_) => match parse_msg_by_lookup(dict, subsystem, final_msg) {
+                Ok((_, converted)) => (converted, None),
+                Err(_) => (final_msg.to_string(), None),
+            },
+        }
+    };
+    let result = ParsedMessage {
+        send_status: SendStatus::UNKNOWN,
+        system: dict.name().map(|n| n.to_string()),
+        subsystem: dict.subsystem_name(subsystem).map(|n| n.to_string()),
+        msg,
+        fields,
     };
 
     Ok(("", result)) //consume rest and pass input to output as default
@@ -163,6 +151,7 @@ mod tests {
                     system: Some("XportSM".to_string()),
                     subsystem: Some("UPLINK".to_string()),
                     msg: "some message".to_string(),
+                    fields: None,
                 }
             ))
         );
@@ -175,6 +164,7 @@ mod tests {
                     system: Some("XportSM".to_string()),
                     subsystem: Some("INIT".to_string()),
                     msg: "another message".to_string(),
+                    fields: None,
                 }
             ))
         );
@@ -187,7 +177,8 @@ mod tests {
                     send_status: SendStatus::UNKNOWN,
                     system: Some("XportSM".to_string()),
                     subsystem: Some("INIT".to_string()),
-                    msg: "".to_string(),
+                    msg: "State Transition".to_string(),
+                    fields: Some(MsgFields::StateTransition(XportState::Init)),
                 }
             ))
         );
@@ -201,6 +192,7 @@ mod tests {
                     system: Some("XportSM".to_string()),
                     subsystem: Some("INIT".to_string()),
                     msg: "Xport Init Failed".to_string(),
+                    fields: None,
                 }
             ))
         );
@@ -214,4 +206,40 @@ mod tests {
             )),
         );
     }
+
+    #[test]
+    fn test_parse_xport_machine_transitions() {
+        let msg_of = |i| parse_xport_machine(i).unwrap().1.msg;
+        let fields_of = |i| parse_xport_machine(i).unwrap().1.fields;
+
+        assert_eq!(msg_of("TSM:FPAR:OK"), "Parent found");
+        assert_eq!(
+            msg_of("TSM:FPAR:NO REPLY"),
+            "No reply to find parent request"
+        );
+        assert_eq!(msg_of("TSM:FAIL:DIS"), "Xport Disable after failures");
+        assert_eq!(msg_of("TSM:FAIL:RE-INIT"), "Xport ReInit after failures");
+        assert_eq!(msg_of("TSM:ID:REQ"), "Requesting node ID from controller");
+        assert_eq!(msg_of("TSM:UPL:OK"), "UL OK, GW returned ping");
+        assert_eq!(msg_of("TSM:UPL:FAIL"), "UL Check FAILED - GW Ping Failed");
+
+        assert_eq!(msg_of("TSM:FAIL:CNT=3"), "Failure counter (3)");
+        assert_eq!(
+            fields_of("TSM:FAIL:CNT=3"),
+            Some(MsgFields::FailCount { count: 3 })
+        );
+        assert_eq!(
+            fields_of("TSM:READY:ID=0,PAR=0,DIS=0"),
+            Some(MsgFields::Ready {
+                id: 0,
+                parent: 0,
+                distance: 0
+            })
+        );
+        assert_eq!(
+            fields_of("TSM:FPAR"),
+            Some(MsgFields::StateTransition(XportState::FindParent))
+        );
+        assert_eq!(fields_of("TSM:FPAR:OK"), None);
+    }
 }
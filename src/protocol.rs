@@ -0,0 +1,575 @@
+// MySensors protocol helpers: typed message fields and names for the numeric
+// command / type values that show up in transport messages
+use crate::dictionary::dictionary;
+use std::fmt;
+
+pub const C_PRESENTATION: u8 = 0;
+pub const C_SET: u8 = 1;
+pub const C_REQ: u8 = 2;
+pub const C_INTERNAL: u8 = 3;
+pub const C_STREAM: u8 = 4;
+
+// internal (C_INTERNAL) message types
+pub const I_BATTERY_LEVEL: u8 = 0;
+pub const I_ID_REQUEST: u8 = 3;
+pub const I_ID_RESPONSE: u8 = 4;
+pub const I_LOG_MESSAGE: u8 = 9;
+pub const I_SKETCH_NAME: u8 = 11;
+pub const I_SKETCH_VERSION: u8 = 12;
+pub const I_HEARTBEAT_RESPONSE: u8 = 22;
+pub const I_SIGNAL_REPORT_REQUEST: u8 = 29;
+pub const I_SIGNAL_REPORT_RESPONSE: u8 = 31;
+pub const I_PRE_SLEEP_NOTIFICATION: u8 = 32;
+pub const I_POST_SLEEP_NOTIFICATION: u8 = 33;
+
+// name of a command number from the dictionary, e.g. 1 -> "C_SET"
+pub fn command_name(command: u8) -> Option<String> {
+    dictionary().command_name(command).map(|n| n.to_string())
+}
+
+// name of a type number - the meaning of type depends on the command
+pub fn type_name(command: u8, msg_type: u8) -> Option<String> {
+    dictionary()
+        .type_name(command, msg_type)
+        .map(|n| n.to_string())
+}
+
+// MySensors library release, e.g. 2.3.2
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl Version {
+    pub fn new(major: u8, minor: u8, patch: u8) -> Self {
+        Version {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    // true if a version key like "2", "2.3" or "2.3.2" covers this version
+    pub fn matches(&self, key: &str) -> bool {
+        let parts = [self.major, self.minor, self.patch];
+        let key_parts: Vec<&str> = key.split('.').collect();
+        key_parts.len() <= parts.len()
+            && key_parts
+                .iter()
+                .zip(parts)
+                .all(|(k, p)| k.parse::<u8>() == Ok(p))
+    }
+}
+
+impl std::str::FromStr for Version {
+    type Err = String;
+    // "2.3.2", "2.3" or "3.0.0-beta" (pre-release suffix is ignored)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let numbers = s.trim().split('-').next().unwrap_or_default();
+        let mut parts = numbers.split('.').map(|p| p.parse::<u8>());
+        let mut next = |required: bool| match parts.next() {
+            Some(Ok(n)) => Ok(n),
+            None if !required => Ok(0),
+            _ => Err(format!("'{}' is not a valid version", s)),
+        };
+        let version = Version::new(next(true)?, next(true)?, next(false)?);
+        match parts.next() {
+            None => Ok(version),
+            Some(_) => Err(format!("'{}' is not a valid version", s)),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+// where a transport message was seen
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MsgKind {
+    Read,     // TSF:MSG:READ
+    Send,     // TSF:MSG:SEND
+    Serial,   // serial protocol line node-id;child-sensor-id;command;ack;type;payload
+    SerialIn, // serial protocol line the gateway received from the controller (GWT:RFC)
+    MqttOut,  // MQTT topic published by the gateway <prefix>/node/sensor/command/ack/type
+    MqttIn,   // MQTT topic the gateway subscribed to
+}
+
+// a single MySensors message as decoded from a TSF:MSG frame or a serial
+// protocol line, fields not present in every format are Options
+//
+// serial protocol lines and MQTT topics only name one node: for messages from
+// a node (Serial, MqttOut) it is kept in sender, with last and destination set
+// to the gateway (0); for messages the controller sends to a node (SerialIn,
+// MqttIn) it is kept in destination, with sender and last set to the gateway.
+// their payload is always text (pt=0)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransportMsg {
+    pub kind: MsgKind,
+    pub sender: u8,
+    pub last: u8,
+    pub next: Option<u8>, //only SEND reports the next hop
+    pub destination: u8,
+    pub sensor: u8,
+    pub command: u8,
+    pub ack: Option<bool>, //only serial lines carry the ack flag
+    pub msg_type: u8,
+    pub payload_type: u8,
+    pub length: u8,
+    pub signed: bool,
+    pub failures: Option<u8>,  //SEND only: ft=
+    pub send_ok: Option<bool>, //SEND only: st=OK / st=NACK
+    pub payload: String,
+}
+
+impl TransportMsg {
+    // decode an MQTT topic (with the prefixes from the dictionary) and payload
+    pub fn from_mqtt(topic: &str, payload: &str) -> Option<TransportMsg> {
+        match crate::parsers::parse_mqtt_topic(topic, payload) {
+            Ok(("", msg)) => Some(msg),
+            _ => None,
+        }
+    }
+
+    // the node a serial line or MQTT topic names, see above
+    pub fn node(&self) -> u8 {
+        match self.kind {
+            MsgKind::SerialIn | MsgKind::MqttIn => self.destination,
+            _ => self.sender,
+        }
+    }
+
+    // decode a serial protocol line node-id;child-sensor-id;command;ack;type;payload
+    // as written by the gateway, i.e. from the node it names
+    pub fn from_serial(line: &str) -> Option<TransportMsg> {
+        match crate::parsers::parse_serial_msg(line) {
+            Ok(("", msg)) => Some(msg),
+            _ => None,
+        }
+    }
+
+    // "C_SET" or the number if unknown
+    pub fn command_str(&self) -> String {
+        command_name(self.command).unwrap_or_else(|| self.command.to_string())
+    }
+
+    // "V_TEMP" or the number if unknown
+    pub fn type_str(&self) -> String {
+        type_name(self.command, self.msg_type).unwrap_or_else(|| self.msg_type.to_string())
+    }
+}
+
+impl fmt::Display for TransportMsg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            MsgKind::Read => write!(
+                f,
+                "Read from node ({}) via ({}) to ({})",
+                self.sender, self.last, self.destination
+            )?,
+            MsgKind::Send => write!(
+                f,
+                "Send from node ({}) via ({}) next ({}) to ({})",
+                self.sender,
+                self.last,
+                self.next.unwrap_or(self.destination),
+                self.destination
+            )?,
+            MsgKind::Serial => write!(f, "Serial node ({})", self.sender)?,
+            MsgKind::SerialIn => write!(f, "Serial to node ({})", self.destination)?,
+            MsgKind::MqttOut => write!(f, "MQTT publish node ({})", self.sender)?,
+            MsgKind::MqttIn => write!(f, "MQTT subscribe node ({})", self.destination)?,
+        }
+        write!(
+            f,
+            ": sensor ({}) {} {} payload ({})",
+            self.sensor,
+            self.command_str(),
+            self.type_str(),
+            self.payload
+        )?;
+        if self.signed {
+            write!(f, " signed")?;
+        }
+        if self.ack == Some(true) {
+            write!(f, " ack")?;
+        }
+        match self.send_ok {
+            Some(true) => write!(f, " - OK"),
+            Some(false) => write!(f, " - NACK after ({}) tries", self.failures.unwrap_or(0)),
+            None => Ok(()),
+        }
+    }
+}
+
+// states of the transport state machine (TSM)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum XportState {
+    Init,
+    FindParent,
+    Id,
+    Uplink,
+    Ready,
+    Failure,
+}
+
+impl XportState {
+    // state from the subsystem code used in TSM:<code> lines
+    pub fn from_code(code: &str) -> Option<XportState> {
+        match code {
+            "INIT" => Some(XportState::Init),
+            "FPAR" => Some(XportState::FindParent),
+            "ID" => Some(XportState::Id),
+            "UPL" => Some(XportState::Uplink),
+            "READY" => Some(XportState::Ready),
+            "FAIL" => Some(XportState::Failure),
+            _ => None,
+        }
+    }
+
+    // subsystem code of the state, the reverse of from_code
+    pub fn code(&self) -> &'static str {
+        match self {
+            XportState::Init => "INIT",
+            XportState::FindParent => "FPAR",
+            XportState::Id => "ID",
+            XportState::Uplink => "UPL",
+            XportState::Ready => "READY",
+            XportState::Failure => "FAIL",
+        }
+    }
+}
+
+impl fmt::Display for XportState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            XportState::Init => "INIT",
+            XportState::FindParent => "FindParent",
+            XportState::Id => "ID",
+            XportState::Uplink => "UPLINK",
+            XportState::Ready => "READY",
+            XportState::Failure => "FAIL",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// what a signal report (TSF:SIR or I_SIGNAL_REPORT_RESPONSE) is about, from
+// the command character of the request
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SignalKind {
+    SendingRssi,    // 'S' dBm, of the node's messages at its parent
+    ReceivingRssi,  // 'R' dBm, of messages received by the node
+    SendingSnr,     // 's' dB
+    ReceivingSnr,   // 'r' dB
+    TxPowerLevel,   // 'P' dBm
+    TxPowerPercent, // 'T' %
+    UplinkQuality,  // 'U'
+    Other(u8),
+}
+
+impl SignalKind {
+    pub fn from_command(command: u8) -> SignalKind {
+        match command {
+            b'S' => SignalKind::SendingRssi,
+            b'R' => SignalKind::ReceivingRssi,
+            b's' => SignalKind::SendingSnr,
+            b'r' => SignalKind::ReceivingSnr,
+            b'P' => SignalKind::TxPowerLevel,
+            b'T' => SignalKind::TxPowerPercent,
+            b'U' => SignalKind::UplinkQuality,
+            other => SignalKind::Other(other),
+        }
+    }
+
+    // Some(true) if a higher value means a better link; a node with automatic
+    // transmit power control turns up its power when the link gets worse
+    pub fn higher_is_better(&self) -> Option<bool> {
+        match self {
+            SignalKind::SendingRssi
+            | SignalKind::ReceivingRssi
+            | SignalKind::SendingSnr
+            | SignalKind::ReceivingSnr
+            | SignalKind::UplinkQuality => Some(true),
+            SignalKind::TxPowerLevel | SignalKind::TxPowerPercent => Some(false),
+            SignalKind::Other(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for SignalKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignalKind::SendingRssi => write!(f, "sending RSSI"),
+            SignalKind::ReceivingRssi => write!(f, "receiving RSSI"),
+            SignalKind::SendingSnr => write!(f, "sending SNR"),
+            SignalKind::ReceivingSnr => write!(f, "receiving SNR"),
+            SignalKind::TxPowerLevel => write!(f, "TX power level"),
+            SignalKind::TxPowerPercent => write!(f, "TX power percent"),
+            SignalKind::UplinkQuality => write!(f, "uplink quality"),
+            SignalKind::Other(command) => write!(f, "command ({})", command),
+        }
+    }
+}
+
+// decoded fields of log messages that carry more than fixed text
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MsgFields {
+    Msg(TransportMsg), // TSF:MSG:READ / TSF:MSG:SEND
+    BadLength {
+        length: u8,
+        expected: u8,
+    }, // TSF:MSG:LEN
+    BadProtocolVersion {
+        received: u8,
+        expected: u8,
+    }, // TSF:MSG:PVER
+    AssignNodeId {
+        ok: bool,
+        id: u8,
+    }, // TSF:SID:OK,ID= / FAIL,ID=
+    PingSend {
+        to: u8,
+    }, // TSF:PNG:SEND,TO=
+    SignalReport {
+        cmd: u8,    //what is reported, see SignalKind
+        value: i16, //the reported value, e.g. an RSSI in dBm
+    }, // TSF:SIR:CMD=,RSSI=
+    LoadRoutingTable {
+        ok: bool,
+    }, // TSF:LRT:OK
+    SaveRoutingTable {
+        ok: bool,
+    }, // TSF:SRT:OK
+    SanityCheck {
+        ok: bool,
+    }, // TSF:SAN:OK / FAIL
+    UplinkCheck {
+        ok: bool,
+    }, // TSF:CKU:OK / FAIL
+    GatewayDistanceChanged {
+        old: u8,
+        new: u8,
+    }, // TSF:CKU:DGWC,O=,N=
+    NodeToNodeRoute {
+        ok: bool,
+    }, // TSF:RTE:N2N OK / FAIL
+    StateTransition(XportState), // TSM:<state> without message
+    Ready {
+        id: u8,
+        parent: u8,
+        distance: u8,
+    }, // TSM:READY:ID=,PAR=,DIS=
+    FailCount {
+        count: u8,
+    }, // TSM:FAIL:CNT=
+    IdVerificationFailed {
+        id: u8,
+    }, // TSM:ID:FAIL,ID=
+    StaticId {
+        id: u8,
+    }, // TSM:ID:STATID=
+    CoreInit {
+        node: String,
+        capabilities: String,
+        frequency: Option<String>, //FQ= since 2.3
+        release: Option<u8>,       //REL= since 2.2
+        version: String,
+    }, // MCO:BGN:INIT <node>,CP=,FQ=,REL=,VER=
+    Sleep {
+        ms: u32,
+        smart: u8,
+        interrupt1: u8,
+        mode1: u8,
+        interrupt2: u8,
+        mode2: u8,
+    }, // MCO:SLP:MS=,SMS=,I1=,M1=,I2=,M2=
+    WakeUp {
+        reason: i8, //interrupt that woke the node, -1 timer, -2 sleep not possible
+    }, // MCO:SLP:WUP=
+    Custom(Vec<(String, String)>), // name/value pairs from a registered custom parser
+}
+
+impl MsgFields {
+    // the TSM state a line reports entering, if any
+    pub fn xport_state(&self) -> Option<XportState> {
+        match self {
+            MsgFields::StateTransition(state) => Some(*state),
+            MsgFields::Ready { .. } => Some(XportState::Ready),
+            MsgFields::FailCount { .. } => Some(XportState::Failure),
+            _ => None,
+        }
+    }
+}
+
+fn ok_or_failed(ok: bool) -> &'static str {
+    if ok {
+        "OK"
+    } else {
+        "FAILED"
+    }
+}
+
+impl fmt::Display for MsgFields {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MsgFields::Msg(m) => write!(f, "{}", m),
+            MsgFields::BadLength { length, expected } => write!(
+                f,
+                "Message length ({}) does not match expected length ({})",
+                length, expected
+            ),
+            MsgFields::BadProtocolVersion { received, expected } => write!(
+                f,
+                "Protocol version mismatch: received ({}) expected ({})",
+                received, expected
+            ),
+            MsgFields::AssignNodeId { ok, id } => {
+                write!(f, "Assign node ID ({}) {}", id, ok_or_failed(*ok))
+            }
+            MsgFields::PingSend { to } => write!(f, "Sending ping to node ({})", to),
+            MsgFields::SignalReport { cmd, value } => match SignalKind::from_command(*cmd) {
+                SignalKind::Other(cmd) => {
+                    write!(f, "Signal report command ({}) value ({})", cmd, value)
+                }
+                kind => write!(f, "Signal report {} ({})", kind, value),
+            },
+            MsgFields::LoadRoutingTable { ok } => {
+                write!(f, "Load routing table {}", ok_or_failed(*ok))
+            }
+            MsgFields::SaveRoutingTable { ok } => {
+                write!(f, "Save routing table {}", ok_or_failed(*ok))
+            }
+            MsgFields::SanityCheck { ok } => write!(f, "Sanity check {}", ok_or_failed(*ok)),
+            MsgFields::UplinkCheck { ok } => write!(f, "Uplink check {}", ok_or_failed(*ok)),
+            MsgFields::GatewayDistanceChanged { old, new } => {
+                write!(f, "GW distance changed from ({}) to ({})", old, new)
+            }
+            MsgFields::NodeToNodeRoute { ok } => {
+                write!(f, "Node to node route {}", ok_or_failed(*ok))
+            }
+            MsgFields::StateTransition(_) => write!(f, "State Transition"),
+            MsgFields::Ready {
+                id,
+                parent,
+                distance,
+            } => write!(
+                f,
+                "READY: node ID ({}) parent ID ({}) GW distance ({})",
+                id, parent, distance
+            ),
+            MsgFields::FailCount { count } => write!(f, "Failure counter ({})", count),
+            MsgFields::IdVerificationFailed { id } => write!(
+                f,
+                "ID ({}) invalid / verification failed / no ID received from controller",
+                id
+            ),
+            MsgFields::StaticId { id } => write!(f, "Static ID ({})", id),
+            // already readable, so echo it in its original form
+            MsgFields::CoreInit {
+                node,
+                capabilities,
+                frequency,
+                release,
+                version,
+            } => {
+                write!(f, "INIT {},CP={}", node, capabilities)?;
+                if let Some(frequency) = frequency {
+                    write!(f, ",FQ={}", frequency)?;
+                }
+                if let Some(release) = release {
+                    write!(f, ",REL={}", release)?;
+                }
+                write!(f, ",VER={}", version)
+            }
+            MsgFields::Sleep {
+                ms,
+                smart,
+                interrupt1,
+                mode1,
+                interrupt2,
+                mode2,
+            } => write!(
+                f,
+                "Sleep ({}) ms smart sleep ({}) interrupt 1 ({}) mode ({}) interrupt 2 ({}) mode ({})",
+                ms, smart, interrupt1, mode1, interrupt2, mode2
+            ),
+            MsgFields::WakeUp { reason } => match reason {
+                -1 => write!(f, "Woke up by timer"),
+                -2 => write!(f, "Sleep not possible"),
+                _ => write!(f, "Woke up by interrupt ({})", reason),
+            },
+            MsgFields::Custom(values) => {
+                let values: Vec<String> = values
+                    .iter()
+                    .map(|(name, value)| format!("{} ({})", name, value))
+                    .collect();
+                write!(f, "{}", values.join(" "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version() {
+        assert_eq!("2.3.2".parse(), Ok(Version::new(2, 3, 2)));
+        assert_eq!("2.2".parse(), Ok(Version::new(2, 2, 0)));
+        assert_eq!("3.0.0-beta".parse(), Ok(Version::new(3, 0, 0)));
+        assert!("2".parse::<Version>().is_err());
+        assert!("2.3.2.1".parse::<Version>().is_err());
+        assert!(Version::new(2, 3, 0) > Version::new(2, 2, 9));
+
+        let v = Version::new(2, 3, 2);
+        assert!(v.matches("2"));
+        assert!(v.matches("2.3"));
+        assert!(v.matches("2.3.2"));
+        assert!(!v.matches("2.2"));
+        assert!(!v.matches("3"));
+    }
+
+    #[test]
+    fn test_from_mqtt() {
+        let msg = TransportMsg::from_mqtt("mygateway1-out/12/1/1/0/0", "21.5").unwrap();
+        assert_eq!(msg.kind, MsgKind::MqttOut);
+        assert_eq!(msg.type_str(), "V_TEMP");
+        assert_eq!(msg.payload, "21.5");
+        assert_eq!(TransportMsg::from_mqtt("mygateway1-out/12/1/1/0", ""), None);
+        assert_eq!(
+            TransportMsg::from_mqtt("mygateway1-out/12/1/1/0/0/9", ""),
+            None
+        );
+    }
+
+    #[test]
+    fn test_from_serial() {
+        let msg = TransportMsg::from_serial("12;1;1;0;0;21.5").unwrap();
+        assert_eq!(msg.kind, MsgKind::Serial);
+        assert_eq!((msg.sender, msg.sensor), (12, 1));
+        assert_eq!(msg.payload, "21.5");
+        assert_eq!(TransportMsg::from_serial("12;1;1;0"), None);
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(command_name(1).as_deref(), Some("C_SET"));
+        assert_eq!(command_name(9), None);
+        assert_eq!(type_name(C_SET, 0).as_deref(), Some("V_TEMP"));
+        assert_eq!(
+            type_name(C_INTERNAL, 22).as_deref(),
+            Some("I_HEARTBEAT_RESPONSE")
+        );
+        assert_eq!(
+            type_name(C_PRESENTATION, 17).as_deref(),
+            Some("S_ARDUINO_NODE")
+        );
+        assert_eq!(type_name(C_STREAM, 99), None);
+    }
+}
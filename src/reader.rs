@@ -0,0 +1,328 @@
+// opens log files, rotated logs compressed by logrotate are decompressed on
+// the fly, and follows logs that are still being written
+//
+// the compression is detected from the magic bytes at the start of the data,
+// not from the file name; each codec is behind a cargo feature (gzip, bzip2,
+// xz), all enabled by default
+//
+// gateways write whatever a node sent into the log, so lines are not always
+// valid UTF-8: invalid bytes are read as U+FFFD instead of ending the input
+use crate::BoxError;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Bzip2,
+    Xz,
+}
+
+impl Compression {
+    // compression of data starting with these bytes
+    pub fn detect(start: &[u8]) -> Compression {
+        if start.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if start.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else if start.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else {
+            Compression::None
+        }
+    }
+}
+
+fn not_built(codec: &str) -> BoxError {
+    format!(
+        "input is {} compressed but support for it was not built (feature {})",
+        codec, codec
+    )
+    .into()
+}
+
+// reader returning the decompressed data of a possibly compressed input
+pub fn decompress(reader: impl Read + Send + 'static) -> Result<Box<dyn BufRead + Send>, BoxError> {
+    let mut reader = BufReader::new(reader);
+    let compression = Compression::detect(reader.fill_buf()?);
+    match compression {
+        Compression::None => Ok(Box::new(reader)),
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => Ok(Box::new(BufReader::new(
+            flate2::bufread::MultiGzDecoder::new(reader),
+        ))),
+        #[cfg(feature = "bzip2")]
+        Compression::Bzip2 => Ok(Box::new(BufReader::new(
+            bzip2::bufread::MultiBzDecoder::new(reader),
+        ))),
+        #[cfg(feature = "xz")]
+        Compression::Xz => Ok(Box::new(BufReader::new(
+            xz2::bufread::XzDecoder::new_multi_decoder(reader),
+        ))),
+        #[allow(unreachable_patterns)]
+        Compression::Gzip => Err(not_built("gzip")),
+        #[allow(unreachable_patterns)]
+        Compression::Bzip2 => Err(not_built("bzip2")),
+        #[allow(unreachable_patterns)]
+        Compression::Xz => Err(not_built("xz")),
+    }
+}
+
+// file or "-" for stdin, decompressed if needed
+pub fn open(path: impl AsRef<Path>) -> Result<Box<dyn BufRead + Send>, BoxError> {
+    let path = path.as_ref();
+    if path == Path::new("-") {
+        return decompress(io::stdin());
+    }
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    decompress(file).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+// a line without its line end, invalid UTF-8 replaced
+fn lossy_line(bytes: &[u8]) -> String {
+    let line = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8_lossy(line).into_owned()
+}
+
+// lines of a reader like BufRead::lines, but with invalid UTF-8 replaced
+pub fn lines(mut reader: impl BufRead) -> impl Iterator<Item = io::Result<String>> {
+    let mut buffer = Vec::new();
+    std::iter::from_fn(move || {
+        buffer.clear();
+        match reader.read_until(b'\n', &mut buffer) {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(lossy_line(&buffer))),
+            Err(e) => Some(Err(e)),
+        }
+    })
+}
+
+// lines of a file as it grows, like tail -f: at the end of the file waits for
+// more to be written. starts at the end of the file, or with from_start at its
+// beginning, and again from the beginning when the file is truncated or
+// replaced, as log rotation does. a line is only returned once its newline has
+// been written
+pub struct Follow {
+    path: PathBuf,
+    reader: BufReader<File>,
+    position: u64,
+    partial: Vec<u8>,
+    skip: bool, //started in the middle of this line
+    poll: Duration,
+}
+
+impl Follow {
+    // the lines written from now on
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, BoxError> {
+        Follow::open(path.as_ref(), true)
+    }
+
+    // the lines already in the file and those written from now on
+    pub fn from_start(path: impl AsRef<Path>) -> Result<Self, BoxError> {
+        Follow::open(path.as_ref(), false)
+    }
+
+    fn open(path: &Path, at_end: bool) -> Result<Self, BoxError> {
+        let error = |e: io::Error| format!("{}: {}", path.display(), e);
+        let mut file = File::open(path).map_err(error)?;
+        let (mut position, mut skip) = (0, false);
+        if at_end {
+            position = file.seek(SeekFrom::End(0)).map_err(error)?;
+            if position > 0 {
+                // the writer may be in the middle of a line
+                let mut last = [0u8];
+                file.seek(SeekFrom::End(-1)).map_err(error)?;
+                file.read_exact(&mut last).map_err(error)?;
+                skip = last[0] != b'\n';
+            }
+        }
+        Ok(Follow {
+            path: path.to_path_buf(),
+            reader: BufReader::new(file),
+            position,
+            partial: Vec::new(),
+            skip,
+            poll: Duration::from_millis(500),
+        })
+    }
+
+    // how often to look for more at the end of the file
+    pub fn with_poll(mut self, poll: Duration) -> Self {
+        self.poll = poll;
+        self
+    }
+
+    // the file at path is no longer the one being read, or got shorter
+    fn rotated(&self) -> bool {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(m) => m,
+            Err(_) => return false, //moved away and not created again yet
+        };
+        if metadata.len() < self.position {
+            return true;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            if let Ok(open) = self.reader.get_ref().metadata() {
+                return (open.dev(), open.ino()) != (metadata.dev(), metadata.ino());
+            }
+        }
+        false
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        let file = File::open(&self.path)?;
+        self.reader = BufReader::new(file);
+        self.position = 0;
+        self.partial.clear();
+        self.skip = false;
+        Ok(())
+    }
+}
+
+impl Iterator for Follow {
+    type Item = Result<String, BoxError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.reader.read_until(b'\n', &mut self.partial) {
+                Ok(0) => {
+                    if self.rotated() {
+                        if let Err(e) = self.reopen() {
+                            return Some(Err(format!("{}: {}", self.path.display(), e).into()));
+                        }
+                        continue;
+                    }
+                    thread::sleep(self.poll);
+                }
+                Ok(n) => {
+                    self.position += n as u64;
+                    if self.partial.ends_with(b"\n") {
+                        let line = lossy_line(&self.partial);
+                        self.partial.clear();
+                        if std::mem::take(&mut self.skip) {
+                            continue;
+                        }
+                        return Some(Ok(line));
+                    }
+                }
+                Err(e) => return Some(Err(format!("{}: {}", self.path.display(), e).into())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const LOG: &str = "Oct 18 13:36:52 INFO  Starting gateway...\n\
+                       Oct 18 13:36:52 DEBUG TSM:INIT\n";
+
+    fn read_all(data: Vec<u8>) -> String {
+        let mut text = String::new();
+        decompress(Cursor::new(data))
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    }
+
+    #[test]
+    fn test_plain() {
+        assert_eq!(Compression::detect(LOG.as_bytes()), Compression::None);
+        assert_eq!(read_all(LOG.as_bytes().to_vec()), LOG);
+        assert_eq!(read_all(Vec::new()), "");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip() {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(LOG.as_bytes()).unwrap();
+        let data = encoder.finish().unwrap();
+        assert_eq!(Compression::detect(&data), Compression::Gzip);
+        assert_eq!(read_all(data), LOG);
+    }
+
+    #[cfg(feature = "bzip2")]
+    #[test]
+    fn test_bzip2() {
+        use std::io::Write;
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        encoder.write_all(LOG.as_bytes()).unwrap();
+        let data = encoder.finish().unwrap();
+        assert_eq!(Compression::detect(&data), Compression::Bzip2);
+        assert_eq!(read_all(data), LOG);
+    }
+
+    #[cfg(feature = "xz")]
+    #[test]
+    fn test_xz() {
+        use std::io::Write;
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(LOG.as_bytes()).unwrap();
+        let data = encoder.finish().unwrap();
+        assert_eq!(Compression::detect(&data), Compression::Xz);
+        assert_eq!(read_all(data), LOG);
+    }
+
+    #[test]
+    fn test_follow() {
+        let path = std::env::temp_dir().join(format!("follow-{}.log", std::process::id()));
+        fs::write(&path, "one\ntwo\nthr").unwrap();
+        let mut follow = Follow::from_start(&path)
+            .unwrap()
+            .with_poll(Duration::from_millis(5));
+        assert_eq!(follow.next().unwrap().unwrap(), "one");
+        assert_eq!(follow.next().unwrap().unwrap(), "two");
+
+        let writer = {
+            let path = path.clone();
+            thread::spawn(move || {
+                use std::io::Write;
+                thread::sleep(Duration::from_millis(20));
+                let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+                file.write_all(b"ee\n").unwrap();
+            })
+        };
+        assert_eq!(follow.next().unwrap().unwrap(), "three");
+        writer.join().unwrap();
+
+        // rotated: replaced by a new, shorter file
+        fs::remove_file(&path).unwrap();
+        fs::write(&path, "new\n").unwrap();
+        assert_eq!(follow.next().unwrap().unwrap(), "new");
+
+        // by default only what is written from now on, from the next whole line
+        let mut tail = Follow::new(&path)
+            .unwrap()
+            .with_poll(Duration::from_millis(5));
+        use std::io::Write;
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"half").unwrap();
+        let mut tail_half = Follow::new(&path)
+            .unwrap()
+            .with_poll(Duration::from_millis(5));
+        file.write_all(b" line\nnext \xff\r\n").unwrap();
+        assert_eq!(tail.next().unwrap().unwrap(), "half line");
+        assert_eq!(tail.next().unwrap().unwrap(), "next \u{fffd}");
+        assert_eq!(tail_half.next().unwrap().unwrap(), "next \u{fffd}");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_lines() {
+        let data: &[u8] = b"one\r\ntw\xc3o\nthree";
+        let lines: Vec<String> = lines(data).map(|l| l.unwrap()).collect();
+        assert_eq!(lines, vec!["one", "tw\u{fffd}o", "three"]);
+    }
+}
@@ -0,0 +1,441 @@
+// scrubs gateway logs before they are attached to public issues
+//
+// works on the parse results so the redacted log can still be parsed: node
+// ids in the decoded fields and serial lines are remapped with a permutation
+// from the seed, IPv4 addresses (GWT:TPC:IP=...) and text payloads get
+// numbered pseudonyms and MQTT topics are written with the library's default
+// prefixes. messages that could not be decoded keep their text with the node
+// ids after ID=, PAR= and TO= and the IPv4 addresses replaced. the same
+// seed and log always give the same result and the mapping can be exported to
+// undo the redaction privately
+use crate::dictionary::{active_dictionary, with_scope, Dictionary};
+use crate::encoder::{line_from_fields, render_fields, render_line, render_serial, status_prefix};
+use crate::protocol::{MsgFields, MsgKind, TransportMsg, C_INTERNAL, I_LOG_MESSAGE};
+use crate::simulator::Rng;
+use crate::{BoxError, LogLine, SendStatus};
+use nom::bytes::complete::tag;
+use nom::character::complete::{char, u8};
+use nom::combinator::recognize;
+use nom::sequence::tuple;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const P_STRING: u8 = 0;
+
+// original value -> pseudonym, as written by Mapping::to_toml
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct Mapping {
+    pub nodes: BTreeMap<String, String>,
+    pub ips: BTreeMap<String, String>,
+    pub strings: BTreeMap<String, String>,
+    pub mqtt: BTreeMap<String, String>,
+}
+
+impl Mapping {
+    pub fn to_toml(&self) -> Result<String, BoxError> {
+        Ok(toml::to_string(self)?)
+    }
+
+    pub fn from_toml(s: &str) -> Result<Mapping, BoxError> {
+        Ok(toml::from_str(s)?)
+    }
+}
+
+fn parse_ipv4(i: &str) -> nom::IResult<&str, &str> {
+    recognize(tuple((u8, char('.'), u8, char('.'), u8, char('.'), u8)))(i)
+}
+
+fn parse_node_key<'a>(i: &'a str, key: &str) -> nom::IResult<&'a str, (&'a str, u8)> {
+    tuple((tag(key), u8))(i)
+}
+
+// keys of the node ids in debug messages the parser does not decode, e.g.
+// TSF:MSG:FPAR RES,ID=0,D=0 or TSF:MSG:PINGED,ID=12,HP=1
+const NODE_KEYS: [&str; 3] = ["ID=", "PAR=", "TO="];
+
+#[derive(Debug)]
+pub struct Redactor {
+    nodes: [u8; 256],
+    mapping: Mapping,
+    output: Arc<Dictionary>, //dictionary the redacted lines are rendered with
+}
+
+impl Redactor {
+    pub fn new(seed: u64) -> Self {
+        // gateway (0) and broadcast (255) keep their meaning, 1..=254 are shuffled
+        let mut nodes: [u8; 256] = std::array::from_fn(|i| i as u8);
+        let mut rng = Rng(seed);
+        for i in (2..=254usize).rev() {
+            let j = 1 + (rng.next_u64() % i as u64) as usize;
+            nodes.swap(i, j);
+        }
+
+        let active = active_dictionary();
+        let mut output = (*active).clone();
+        let defaults = Dictionary::builtin().mqtt;
+        let mut mapping = Mapping::default();
+        for (prefix, default) in [
+            (&active.mqtt.publish_prefix, &defaults.publish_prefix),
+            (&active.mqtt.subscribe_prefix, &defaults.subscribe_prefix),
+        ] {
+            if let (Some(prefix), Some(default)) = (prefix, default) {
+                mapping.mqtt.insert(prefix.clone(), default.clone());
+            }
+        }
+        output.mqtt = defaults;
+
+        Redactor {
+            nodes,
+            mapping,
+            output: Arc::new(output),
+        }
+    }
+
+    pub fn mapping(&self) -> &Mapping {
+        &self.mapping
+    }
+
+    pub fn node(&mut self, id: u8) -> u8 {
+        let pseudonym = self.nodes[id as usize];
+        if (1..=254).contains(&id) {
+            self.mapping
+                .nodes
+                .insert(id.to_string(), pseudonym.to_string());
+        }
+        pseudonym
+    }
+
+    pub fn ip(&mut self, ip: &str) -> String {
+        let next = self.mapping.ips.len() + 1;
+        self.mapping
+            .ips
+            .entry(ip.to_string())
+            .or_insert_with(|| format!("10.0.{}.{}", next / 250, next % 250 + 1))
+            .clone()
+    }
+
+    pub fn string(&mut self, s: &str) -> String {
+        let next = self.mapping.strings.len() + 1;
+        self.mapping
+            .strings
+            .entry(s.to_string())
+            .or_insert_with(|| format!("text{}", next))
+            .clone()
+    }
+
+    // pseudonym for text, numbers and other values without letters are kept
+    fn text(&mut self, value: &str) -> String {
+        if value.chars().any(|c| c.is_alphabetic()) {
+            self.string(value)
+        } else {
+            value.to_string()
+        }
+    }
+
+    // replace every IPv4 address in free text
+    pub fn ips_in(&mut self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        let mut previous: Option<char> = None;
+        while let Some(c) = rest.chars().next() {
+            let boundary = !matches!(previous, Some(p) if p.is_ascii_digit() || p == '.');
+            if boundary && c.is_ascii_digit() {
+                if let Ok((after, ip)) = parse_ipv4(rest) {
+                    if !after.starts_with(|n: char| n.is_ascii_digit() || n == '.') {
+                        out.push_str(&self.ip(ip));
+                        previous = ip.chars().last();
+                        rest = after;
+                        continue;
+                    }
+                }
+            }
+            out.push(c);
+            previous = Some(c);
+            rest = &rest[c.len_utf8()..];
+        }
+        out
+    }
+
+    // replace the node ids after NODE_KEYS in free text
+    pub fn ids_in(&mut self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        let mut previous: Option<char> = None;
+        while let Some(c) = rest.chars().next() {
+            if !matches!(previous, Some(p) if p.is_ascii_alphanumeric()) {
+                let id = NODE_KEYS.iter().find_map(|key| {
+                    let (after, id) = parse_node_key(rest, key).ok()?;
+                    let ended = !after.starts_with(|n: char| n.is_ascii_digit());
+                    ended.then_some((after, id))
+                });
+                if let Some((after, (key, id))) = id {
+                    out.push_str(key);
+                    out.push_str(&self.node(id).to_string());
+                    previous = Some('0');
+                    rest = after;
+                    continue;
+                }
+            }
+            out.push(c);
+            previous = Some(c);
+            rest = &rest[c.len_utf8()..];
+        }
+        out
+    }
+
+    // text of a message that was not decoded
+    fn free_text(&mut self, text: &str) -> String {
+        let text = self.ids_in(text);
+        self.ips_in(&text)
+    }
+
+    fn redact_msg(&mut self, m: &TransportMsg) -> TransportMsg {
+        let mut m = m.clone();
+        m.sender = self.node(m.sender);
+        m.last = self.node(m.last);
+        m.next = m.next.map(|n| self.node(n));
+        m.destination = self.node(m.destination);
+        if m.command == C_INTERNAL && m.msg_type == I_LOG_MESSAGE {
+            m.payload = self.log_message(&m.payload);
+        } else if m.payload_type == P_STRING {
+            m.payload = self.text(&m.payload);
+        }
+        if m.kind != MsgKind::MqttOut && m.kind != MsgKind::MqttIn {
+            m.length = m.payload.len().min(u8::MAX as usize) as u8;
+        }
+        m
+    }
+
+    pub fn redact_fields(&mut self, fields: &MsgFields) -> MsgFields {
+        match fields {
+            MsgFields::Msg(m) => MsgFields::Msg(self.redact_msg(m)),
+            MsgFields::AssignNodeId { ok, id } => MsgFields::AssignNodeId {
+                ok: *ok,
+                id: self.node(*id),
+            },
+            MsgFields::PingSend { to } => MsgFields::PingSend { to: self.node(*to) },
+            MsgFields::Ready {
+                id,
+                parent,
+                distance,
+            } => MsgFields::Ready {
+                id: self.node(*id),
+                parent: self.node(*parent),
+                distance: *distance,
+            },
+            MsgFields::IdVerificationFailed { id } => {
+                MsgFields::IdVerificationFailed { id: self.node(*id) }
+            }
+            MsgFields::StaticId { id } => MsgFields::StaticId { id: self.node(*id) },
+            MsgFields::Custom(values) => MsgFields::Custom(
+                values
+                    .iter()
+                    .map(|(name, value)| (name.clone(), self.text(value)))
+                    .collect(),
+            ),
+            other => other.clone(),
+        }
+    }
+
+    // debug message of a serial gateway: "<ms> <message>"
+    fn log_message(&mut self, payload: &str) -> String {
+        let (ms, text) = match payload.split_once(' ') {
+            Some((ms, text)) if ms.chars().all(|c| c.is_ascii_digit()) => (Some(ms), text),
+            _ => (None, payload),
+        };
+        let (status, message) = match text.chars().next() {
+            Some('!') => (SendStatus::ERROR, &text[1..]),
+            Some('?') => (SendStatus::UNKNOWN, &text[1..]),
+            _ => (SendStatus::OK, text),
+        };
+        let redacted = crate::parsers::parse_builtin(message)
+            .and_then(|parsed| parsed.fields)
+            .and_then(|fields| {
+                let fields = self.redact_fields(&fields);
+                with_scope(self.output.clone(), None, || render_fields(&fields))
+            });
+        let text = match redacted {
+            Some(message) => format!("{}{}", status_prefix(status), message),
+            None => self.free_text(text),
+        };
+        match ms {
+            Some(ms) => format!("{} {}", ms, text),
+            None => text,
+        }
+    }
+
+    // the line with all sensitive values replaced
+    pub fn redact(&mut self, line: &LogLine) -> LogLine {
+        match (&line.fields, line.datetime, &line.level) {
+            // the format of custom messages is unknown, so replace the values
+            // in the message as it was logged
+            (Some(MsgFields::Custom(values)), _, _) => {
+                let mut msg = line.msg.clone();
+                for (_, value) in values {
+                    msg = msg.replace(value.as_str(), &self.text(value));
+                }
+                LogLine {
+                    msg: self.free_text(&msg),
+                    fields: Some(self.redact_fields(&MsgFields::Custom(values.clone()))),
+                    ..line.clone()
+                }
+            }
+            (Some(fields), Some(datetime), Some(level)) => {
+                let fields = self.redact_fields(fields);
+                let output = self.output.clone();
+                with_scope(output, None, || {
+                    line_from_fields(datetime, level, line.status, fields)
+                })
+            }
+            // a serial line as the gateway sends it to the controller
+            (None, None, _) if TransportMsg::from_serial(&line.msg).is_some() => {
+                let m = TransportMsg::from_serial(&line.msg).unwrap();
+                LogLine {
+                    msg: render_serial(&self.redact_msg(&m)),
+                    ..line.clone()
+                }
+            }
+            _ => LogLine {
+                msg: self.free_text(&line.msg),
+                ..line.clone()
+            },
+        }
+    }
+
+    // the redacted line as raw log text
+    pub fn redact_line(&mut self, line: &LogLine) -> String {
+        let redacted = self.redact(line);
+        let output = self.output.clone();
+        with_scope(output, None, || render_line(&redacted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+    use crate::simulator::{OutputFormat, SimConfig, Simulator};
+
+    #[test]
+    fn test_redact() {
+        let raw = [
+            "Oct 18 13:36:52 DEBUG GWT:TPC:IP=192.168.178.20",
+            "Oct 18 13:36:53 DEBUG TSF:MSG:READ,12-7-0,s=255,c=3,t=11,pt=0,l=11,sg=0:Garage Door",
+            "Oct 18 13:36:53 DEBUG !TSF:MSG:SEND,0-0-7-12,s=1,c=1,t=2,pt=0,l=1,sg=0,ft=2,st=NACK:1",
+            "Oct 18 13:36:54 DEBUG GWT:TPS:TOPIC=mygateway1-out/12/1/1/0/0,MSG SENT",
+            "Oct 18 13:36:55 DEBUG TSF:SID:OK,ID=12",
+        ];
+        let mut redactor = Redactor::new(42);
+        let redacted: Vec<String> = raw
+            .iter()
+            .map(|l| redactor.redact_line(&parse_log_line(l)))
+            .collect();
+        let node = redactor.mapping().nodes["12"].clone();
+        let hop = redactor.mapping().nodes["7"].clone();
+        assert_ne!(node, "12");
+        assert_eq!(redacted[0], "Oct 18 13:36:52 DEBUG GWT:TPC:IP=10.0.0.2");
+        assert_eq!(
+            redacted[1],
+            format!(
+                "Oct 18 13:36:53 DEBUG TSF:MSG:READ,{}-{}-0,s=255,c=3,t=11,pt=0,l=5,sg=0:text1",
+                node, hop
+            )
+        );
+        assert!(redacted[2].contains(&format!("SEND,0-0-{}-{},", hop, node)));
+        assert!(redacted[2].starts_with("Oct 18 13:36:53 DEBUG !TSF"));
+        assert!(redacted[3].contains(&format!("mygateway1-out/{}/1/1/0/0", node)));
+        assert!(redacted[4].ends_with(&format!("TSF:SID:OK,ID={}", node)));
+
+        // still parseable, and the same again with the same seed
+        for line in &redacted {
+            assert!(parse_log_line(line).fields.is_some() || line.contains("TPC"));
+        }
+        let mut again = Redactor::new(42);
+        for (l, r) in raw.iter().zip(&redacted) {
+            assert_eq!(&again.redact_line(&parse_log_line(l)), r);
+        }
+        assert_ne!(Redactor::new(43).node(12).to_string(), node);
+
+        let exported = redactor.mapping().to_toml().unwrap();
+        assert_eq!(&Mapping::from_toml(&exported).unwrap(), redactor.mapping());
+        assert_eq!(redactor.mapping().ips["192.168.178.20"], "10.0.0.2");
+        assert_eq!(redactor.mapping().strings["Garage Door"], "text1");
+    }
+
+    #[test]
+    fn test_ips_in() {
+        let mut redactor = Redactor::new(1);
+        assert_eq!(
+            redactor.ips_in("IP=10.1.2.3 and 10.1.2.3, not 1.2.3.4.5 or 1.2.3"),
+            "IP=10.0.0.2 and 10.0.0.2, not 1.2.3.4.5 or 1.2.3"
+        );
+    }
+
+    #[test]
+    fn test_redact_serial() {
+        let config = SimConfig {
+            format: OutputFormat::Serial,
+            ..Default::default()
+        };
+        let mut redactor = Redactor::new(7);
+        for line in Simulator::new(config) {
+            let msg = TransportMsg::from_serial(&line).unwrap();
+            let redacted = redactor.redact_line(&parse_log_line(&line));
+            match TransportMsg::from_serial(&redacted) {
+                Some(r) if msg.sender != 0 => {
+                    assert_eq!(r.sender, redactor.node(msg.sender));
+                    assert_ne!(r.sender, msg.sender);
+                    assert_eq!(
+                        (r.sensor, r.command, r.msg_type),
+                        (msg.sensor, msg.command, msg.msg_type)
+                    );
+                }
+                Some(r) => {
+                    // node ids inside the debug messages are remapped as well
+                    if let Some(read) = msg.payload.split(" TSF:MSG:READ,").nth(1) {
+                        let sender: u8 = read.split('-').next().unwrap().parse().unwrap();
+                        let expected = format!("READ,{}-", redactor.node(sender));
+                        assert!(r.payload.contains(&expected), "{}", r.payload);
+                    }
+                }
+                None => panic!("not a serial line: {}", redacted),
+            }
+        }
+        let mut redactor = Redactor::new(7);
+        assert_eq!(
+            redactor.redact_line(&parse_log_line("12;1;1;0;0;21.5")),
+            format!("{};1;1;0;0;21.5", redactor.node(12))
+        );
+    }
+
+    #[test]
+    fn test_redact_undecoded() {
+        let mut redactor = Redactor::new(42);
+        let (node, parent) = (redactor.node(12), redactor.node(7));
+        for (raw, expected) in [
+            (
+                "Oct 18 13:36:55 DEBUG TSF:MSG:PINGED,ID=12,HP=1",
+                format!("Oct 18 13:36:55 DEBUG TSF:MSG:PINGED,ID={},HP=1", node),
+            ),
+            (
+                "Oct 18 13:36:55 DEBUG TSM:FPAR:OK,ID=7,D=1 PAR=7",
+                format!(
+                    "Oct 18 13:36:55 DEBUG TSM:FPAR:OK,ID={},D=1 PAR={}",
+                    parent, parent
+                ),
+            ),
+            // not node ids
+            (
+                "Oct 18 13:36:55 DEBUG TSF:MSG:PONG RECV,HP=1 TRANSID=12 ID=300",
+                "Oct 18 13:36:55 DEBUG TSF:MSG:PONG RECV,HP=1 TRANSID=12 ID=300".to_string(),
+            ),
+        ] {
+            let line = parse_log_line(raw);
+            assert!(line.fields.is_none(), "{} decoded", raw);
+            assert_eq!(redactor.redact_line(&line), expected);
+        }
+        assert_eq!(redactor.ids_in("TO=0 id=12"), "TO=0 id=12");
+    }
+}
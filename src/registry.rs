@@ -0,0 +1,148 @@
+// registry of subsystem parsers for the message part of a log line
+//
+// parsers are tried from the highest priority down until one handles the
+// message; the built-in parsers are registered at BUILTIN_PRIORITY so custom
+// parsers can run before them (e.g. to decode sketch debug prints such as
+// APP:BAT:...) or after them as a fall back
+use crate::{parsers, ParsedMessage};
+
+pub const BUILTIN_PRIORITY: i32 = 0;
+
+pub trait SubsystemParser: Send + Sync {
+    // None if the message is not one this parser handles
+    // send_status is filled in from the '!'/'?' prefix after parsing
+    fn parse(&self, msg: &str) -> Option<ParsedMessage>;
+}
+
+impl<F> SubsystemParser for F
+where
+    F: Fn(&str) -> Option<ParsedMessage> + Send + Sync,
+{
+    fn parse(&self, msg: &str) -> Option<ParsedMessage> {
+        self(msg)
+    }
+}
+
+struct Entry {
+    priority: i32,
+    parser: Box<dyn SubsystemParser>,
+}
+
+pub struct ParserRegistry {
+    entries: Vec<Entry>, //kept sorted, highest priority first
+}
+
+impl Default for ParserRegistry {
+    fn default() -> Self {
+        let mut registry = ParserRegistry::empty();
+        registry.register(BUILTIN_PRIORITY, parsers::parse_builtin);
+        registry
+    }
+}
+
+impl ParserRegistry {
+    // registry holding the built-in parsers
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // registry without any parsers, not even the built-in ones
+    pub fn empty() -> Self {
+        ParserRegistry {
+            entries: Vec::new(),
+        }
+    }
+
+    // parsers with equal priority are tried in the order they were registered
+    pub fn register(&mut self, priority: i32, parser: impl SubsystemParser + 'static) {
+        let at = self
+            .entries
+            .iter()
+            .position(|e| e.priority < priority)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(
+            at,
+            Entry {
+                priority,
+                parser: Box::new(parser),
+            },
+        );
+    }
+
+    pub fn parse(&self, msg: &str) -> Option<ParsedMessage> {
+        self.entries.iter().find_map(|e| e.parser.parse(msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LogParser, MsgFields, SendStatus};
+
+    // APP:BAT:V=%d.%02d as printed by a custom sketch
+    fn parse_app_battery(i: &str) -> Option<ParsedMessage> {
+        let volts = i.strip_prefix("APP:BAT:V=")?;
+        Some(ParsedMessage {
+            send_status: SendStatus::OK,
+            system: Some("App".to_string()),
+            subsystem: Some("Battery".to_string()),
+            msg: format!("Battery at {}V", volts),
+            fields: Some(MsgFields::Custom(vec![(
+                "V".to_string(),
+                volts.to_string(),
+            )])),
+        })
+    }
+
+    #[test]
+    fn test_custom_parser() {
+        let mut parser = LogParser::new();
+        parser.register(10, parse_app_battery);
+
+        let line = parser.parse_line("Oct 18 13:36:52 DEBUG APP:BAT:V=3.02");
+        assert_eq!(line.msg, "App:Battery:Battery at 3.02V");
+        assert_eq!(
+            line.fields,
+            Some(MsgFields::Custom(vec![(
+                "V".to_string(),
+                "3.02".to_string()
+            )]))
+        );
+
+        // built-ins still handle everything else
+        let line = parser.parse_line("Oct 18 13:36:52 DEBUG TSM:FPAR:OK");
+        assert_eq!(line.msg, "XportSM:FindParent:Parent found");
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut registry = ParserRegistry::new();
+        let tagged = |name: &'static str| {
+            move |i: &str| {
+                Some(ParsedMessage {
+                    send_status: SendStatus::OK,
+                    system: None,
+                    subsystem: None,
+                    msg: format!("{}:{}", name, i),
+                    fields: None,
+                })
+            }
+        };
+        registry.register(-1, tagged("fallback"));
+        registry.register(5, tagged("first"));
+        registry.register(5, tagged("second"));
+
+        assert_eq!(registry.parse("TSM:INIT").unwrap().msg, "first:TSM:INIT");
+
+        let mut registry = ParserRegistry::new();
+        registry.register(-1, tagged("fallback"));
+        // handled by the built-ins, fallback only sees the rest
+        assert_eq!(registry.parse("TSM:INIT").unwrap().msg, "State Transition");
+        assert_eq!(
+            registry.parse("Protocol version - 2.3.2").unwrap().msg,
+            "fallback:Protocol version - 2.3.2"
+        );
+
+        assert_eq!(ParserRegistry::empty().parse("TSM:INIT"), None);
+    }
+}
@@ -0,0 +1,574 @@
+// a self-contained HTML report of a log, for people who do not run the parser
+//
+// one file with its styles, charts and script inline, to be mailed around or
+// opened from a share:
+// - summary: lines, time range, nodes, errors
+// - the gateway sessions and how long the transport spent in READY and FAIL
+// - the inventory of the nodes (see analysis/inventory.rs)
+// - delivery per node: messages received and sent, NACKs and the echoes of
+//   the sends that asked for one (see analysis/acks.rs)
+// - the topology: which node a message reached the gateway from and, for
+//   messages routed through a repeater, whom it came from originally
+// - a chart per sensor of the numeric values it reported
+// - the errors and warnings with the raw lines, searchable
+//
+// charts and the topology are SVG and the search a few lines of script, so
+// the report needs nothing from the network. output only depends on the
+// lines fed, which the snapshot test relies on
+use crate::analysis::acks::AckCorrelator;
+use crate::analysis::format_duration;
+use crate::analysis::inventory::Inventory;
+use crate::analysis::timeline::Timeline;
+use crate::protocol::{type_name, MsgKind, XportState, C_SET};
+use crate::{LogLine, MsgFields, SendStatus};
+use chrono::{DateTime, Local};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+const CHART_WIDTH: f64 = 640.0;
+const CHART_HEIGHT: f64 = 160.0;
+const CHART_MARGIN: f64 = 50.0; //left of the plot, for the value labels
+const NODE_SPACING: f64 = 80.0;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}
+table{border-collapse:collapse;margin-bottom:1em}
+th,td{border:1px solid #ccc;padding:.2em .5em;text-align:left;vertical-align:top}
+th{background:#eee}
+td.num{text-align:right}
+pre{margin:0;white-space:pre-wrap}
+svg text{font-size:12px}
+.chart{margin-bottom:1em}";
+
+const SCRIPT: &str = "document.getElementById('search').addEventListener('input', function () {
+  var q = this.value.toLowerCase();
+  document.querySelectorAll('#errors tbody tr').forEach(function (row) {
+    row.style.display = row.textContent.toLowerCase().indexOf(q) < 0 ? 'none' : '';
+  });
+});";
+
+// values of a sensor over time
+type Series = Vec<(DateTime<Local>, f64)>;
+
+// messages from and to a node
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Delivery {
+    received: usize,
+    sent: usize,
+    nacks: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ErrorLine {
+    datetime: Option<DateTime<Local>>,
+    message: String,
+    raw: String,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    title: String,
+    lines: usize,
+    first: Option<DateTime<Local>>,
+    last: Option<DateTime<Local>>,
+    me: u8, //the node that wrote the log, 0 for a gateway
+    timeline: Timeline,
+    inventory: Inventory,
+    acks: AckCorrelator,
+    delivery: BTreeMap<u8, Delivery>,
+    links: BTreeSet<(u8, u8)>, //(node, node it sent to directly)
+    via: BTreeSet<(u8, u8)>,   //(sender, last hop its messages came from)
+    series: BTreeMap<(u8, u8, u8), Series>, //(node, sensor, type)
+    errors: Vec<ErrorLine>,
+}
+
+// text and attribute values escaped for HTML
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn time(datetime: Option<DateTime<Local>>) -> String {
+    datetime.map_or(String::new(), |dt| dt.format("%F %H:%M:%S").to_string())
+}
+
+// a line chart of values over time
+fn chart(points: &[(DateTime<Local>, f64)]) -> String {
+    let (t0, t1) = (points[0].0, points[points.len() - 1].0);
+    let min = points.iter().map(|p| p.1).fold(f64::INFINITY, f64::min);
+    let max = points.iter().map(|p| p.1).fold(f64::NEG_INFINITY, f64::max);
+    let plot = CHART_WIDTH - CHART_MARGIN - 10.0;
+    let (top, bottom) = (10.0, CHART_HEIGHT - 30.0);
+    let span = (t1 - t0).num_seconds() as f64;
+    let x = |t: DateTime<Local>| {
+        if span > 0.0 {
+            CHART_MARGIN + (t - t0).num_seconds() as f64 / span * plot
+        } else {
+            CHART_MARGIN + plot / 2.0
+        }
+    };
+    let y = |v: f64| {
+        if max > min {
+            top + (max - v) / (max - min) * (bottom - top)
+        } else {
+            (top + bottom) / 2.0
+        }
+    };
+
+    let mut svg = String::new();
+    let _ = write!(
+        svg,
+        "<svg class=\"chart\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">\n\
+         <line x1=\"{m}\" y1=\"{b}\" x2=\"{r}\" y2=\"{b}\" stroke=\"#999\"/>\n\
+         <line x1=\"{m}\" y1=\"{t}\" x2=\"{m}\" y2=\"{b}\" stroke=\"#999\"/>\n\
+         <text x=\"{lx}\" y=\"{ty}\" text-anchor=\"end\">{max}</text>\n\
+         <text x=\"{lx}\" y=\"{b}\" text-anchor=\"end\">{min}</text>\n\
+         <text x=\"{m}\" y=\"{tl}\">{t0}</text>\n\
+         <text x=\"{r}\" y=\"{tl}\" text-anchor=\"end\">{t1}</text>\n",
+        w = CHART_WIDTH,
+        h = CHART_HEIGHT,
+        m = CHART_MARGIN,
+        r = CHART_MARGIN + plot,
+        t = top,
+        b = bottom,
+        lx = CHART_MARGIN - 5.0,
+        ty = top + 10.0,
+        tl = bottom + 20.0,
+        max = max,
+        min = min,
+        t0 = t0.format("%F %H:%M:%S"),
+        t1 = t1.format("%F %H:%M:%S"),
+    );
+    if points.len() == 1 {
+        let _ = writeln!(
+            svg,
+            "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"3\" fill=\"#36c\"/>",
+            x(t0),
+            y(points[0].1)
+        );
+    } else {
+        let coords: Vec<String> = points
+            .iter()
+            .map(|(t, v)| format!("{:.1},{:.1}", x(*t), y(*v)))
+            .collect();
+        let _ = writeln!(
+            svg,
+            "<polyline fill=\"none\" stroke=\"#36c\" stroke-width=\"1.5\" points=\"{}\"/>",
+            coords.join(" ")
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+impl Report {
+    pub fn new(title: &str) -> Self {
+        Report {
+            title: title.to_string(),
+            lines: 0,
+            first: None,
+            last: None,
+            me: 0,
+            timeline: Timeline::new(),
+            inventory: Inventory::new(),
+            acks: AckCorrelator::new(),
+            delivery: BTreeMap::new(),
+            links: BTreeSet::new(),
+            via: BTreeSet::new(),
+            series: BTreeMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    // raw is the line as read, shown next to errors
+    pub fn feed(&mut self, raw: &str, line: &LogLine) {
+        self.lines += 1;
+        if let Some(dt) = line.datetime {
+            self.first.get_or_insert(dt);
+            self.last = Some(dt);
+        }
+        self.timeline.feed(line);
+        self.inventory.feed(line);
+        self.acks.feed(line);
+
+        if line.status != SendStatus::OK || line.level.as_deref() == Some("ERROR") {
+            self.errors.push(ErrorLine {
+                datetime: line.datetime,
+                message: line.msg.clone(),
+                raw: raw.to_string(),
+            });
+        }
+        match &line.fields {
+            Some(MsgFields::Ready { id, parent, .. }) => {
+                self.me = *id;
+                if id != parent {
+                    self.links.insert((*id, *parent));
+                }
+            }
+            Some(MsgFields::Msg(m)) => match m.kind {
+                MsgKind::Read | MsgKind::Serial if m.sender != self.me => {
+                    self.delivery.entry(m.sender).or_default().received += 1;
+                    if m.kind == MsgKind::Read {
+                        self.links.insert((m.last, self.me));
+                        if m.sender != m.last {
+                            self.via.insert((m.sender, m.last));
+                        }
+                    }
+                    // a sensor that failed to read sends nan, which no chart shows
+                    let value = m.payload.trim().parse::<f64>().ok();
+                    let value = value.filter(|v| v.is_finite());
+                    if let (C_SET, Some(dt), Some(value)) = (m.command, line.datetime, value) {
+                        self.series
+                            .entry((m.sender, m.sensor, m.msg_type))
+                            .or_default()
+                            .push((dt, value));
+                    }
+                }
+                MsgKind::Send if m.destination != self.me => {
+                    let delivery = self.delivery.entry(m.destination).or_default();
+                    delivery.sent += 1;
+                    if m.send_ok == Some(false) {
+                        delivery.nacks += 1;
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut html = String::new();
+        let _ = write!(
+            html,
+            "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>{title}</title>\n<style>\n{STYLE}\n</style>\n</head>\n<body>\n\
+             <h1>{title}</h1>\n",
+            title = escape(&self.title)
+        );
+        self.summary(&mut html);
+        self.sessions(&mut html);
+        self.nodes(&mut html);
+        self.delivery(&mut html);
+        self.topology(&mut html);
+        self.charts(&mut html);
+        self.errors(&mut html);
+        let _ = write!(html, "<script>\n{}\n</script>\n</body>\n</html>\n", SCRIPT);
+        html
+    }
+
+    fn summary(&self, html: &mut String) {
+        let nodes: BTreeSet<u8> = self
+            .delivery
+            .keys()
+            .copied()
+            .chain(self.inventory.nodes().iter().map(|n| n.node))
+            .collect();
+        let _ = write!(
+            html,
+            "<h2>Summary</h2>\n<table>\n\
+             <tr><th>Lines</th><td class=\"num\">{}</td></tr>\n\
+             <tr><th>From</th><td>{}</td></tr>\n\
+             <tr><th>To</th><td>{}</td></tr>\n\
+             <tr><th>Sessions</th><td class=\"num\">{}</td></tr>\n\
+             <tr><th>Nodes</th><td class=\"num\">{}</td></tr>\n\
+             <tr><th>Errors</th><td class=\"num\">{}</td></tr>\n</table>\n",
+            self.lines,
+            time(self.first),
+            time(self.last),
+            self.timeline.sessions().len(),
+            nodes.len(),
+            self.errors.len()
+        );
+    }
+
+    fn sessions(&self, html: &mut String) {
+        html.push_str(
+            "<h2>Sessions</h2>\n<table>\n<thead><tr><th>#</th><th>Started</th><th>Last line</th>\
+             <th>Duration</th><th>READY</th><th>FAIL</th><th>State</th></tr></thead>\n<tbody>\n",
+        );
+        for (i, session) in self.timeline.sessions().iter().enumerate() {
+            let states = session.time_in_states();
+            let state_time = |state| {
+                states
+                    .get(&state)
+                    .map_or(String::new(), |d| format_duration(*d))
+            };
+            let duration = match (session.start, session.last_seen) {
+                (Some(start), Some(last)) => format_duration(last - start),
+                _ => String::new(),
+            };
+            let _ = writeln!(
+                html,
+                "<tr><td class=\"num\">{}</td><td>{}</td><td>{}</td><td class=\"num\">{}</td>\
+                 <td class=\"num\">{}</td><td class=\"num\">{}</td><td>{}</td></tr>",
+                i + 1,
+                session
+                    .start
+                    .map_or("(before the log)".to_string(), |s| time(Some(s))),
+                time(session.last_seen),
+                duration,
+                state_time(XportState::Ready),
+                state_time(XportState::Failure),
+                session
+                    .current_state()
+                    .map_or(String::new(), |s| s.to_string())
+            );
+        }
+        html.push_str("</tbody>\n</table>\n");
+    }
+
+    fn nodes(&self, html: &mut String) {
+        html.push_str(
+            "<h2>Nodes</h2>\n<table>\n<thead><tr><th>Node</th><th>Type</th><th>Sketch</th>\
+             <th>Version</th><th>Library</th><th>Sensors</th></tr></thead>\n<tbody>\n",
+        );
+        for node in self.inventory.nodes() {
+            let sensors: Vec<String> = node
+                .children
+                .iter()
+                .map(|c| escape(&format!("{} {} {}", c.id, c.sensor_type, c.description)))
+                .collect();
+            let text = |s: &Option<String>| escape(s.as_deref().unwrap_or(""));
+            let _ = writeln!(
+                html,
+                "<tr><td class=\"num\">{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                node.node,
+                text(&node.node_type),
+                text(&node.sketch_name),
+                text(&node.sketch_version),
+                text(&node.library_version),
+                sensors.join("<br>")
+            );
+        }
+        html.push_str("</tbody>\n</table>\n");
+    }
+
+    fn delivery(&self, html: &mut String) {
+        html.push_str(
+            "<h2>Delivery</h2>\n<table>\n<thead><tr><th>Node</th><th>Received</th><th>Sent</th>\
+             <th>NACKs</th><th>NACK rate</th><th>Echoed</th><th>Lost</th><th>Median round trip</th>\
+             </tr></thead>\n<tbody>\n",
+        );
+        let acks = self.acks.nodes();
+        for (node, delivery) in &self.delivery {
+            let rate = match delivery.sent {
+                0 => String::new(),
+                sent => format!("{:.1}%", 100.0 * delivery.nacks as f64 / sent as f64),
+            };
+            let node_acks = acks.get(node).cloned().unwrap_or_default();
+            let _ = writeln!(
+                html,
+                "<tr><td class=\"num\">{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td>\
+                 <td class=\"num\">{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td>\
+                 <td class=\"num\">{}</td><td class=\"num\">{}</td></tr>",
+                node,
+                delivery.received,
+                delivery.sent,
+                delivery.nacks,
+                rate,
+                node_acks.acked,
+                node_acks.lost,
+                node_acks
+                    .median()
+                    .map_or(String::new(), |ms| format!("{} ms", ms))
+            );
+        }
+        html.push_str("</tbody>\n</table>\n");
+    }
+
+    // nodes in rows by hops from the node that wrote the log
+    fn topology(&self, html: &mut String) {
+        html.push_str("<h2>Topology</h2>\n");
+        let mut hops: BTreeMap<u8, usize> = BTreeMap::from([(self.me, 0)]);
+        // links point towards the gateway, walk them backwards a row at a time
+        for row in 0.. {
+            let next: Vec<u8> = self
+                .links
+                .iter()
+                .chain(&self.via)
+                .filter(|(from, to)| hops.get(to) == Some(&row) && !hops.contains_key(from))
+                .map(|(from, _)| *from)
+                .collect();
+            if next.is_empty() {
+                break;
+            }
+            for node in next {
+                hops.insert(node, row + 1);
+            }
+        }
+        let rows = hops.values().max().map_or(1, |r| r + 1);
+        let mut positions = BTreeMap::new();
+        let mut widest = 1;
+        for row in 0..rows {
+            let nodes: Vec<u8> = hops
+                .iter()
+                .filter(|(_, r)| **r == row)
+                .map(|(n, _)| *n)
+                .collect();
+            widest = widest.max(nodes.len());
+            for (i, node) in nodes.into_iter().enumerate() {
+                let x = NODE_SPACING / 2.0 + i as f64 * NODE_SPACING;
+                let y = NODE_SPACING / 2.0 + row as f64 * NODE_SPACING;
+                positions.insert(node, (x, y));
+            }
+        }
+        let _ = writeln!(
+            html,
+            "<svg width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">",
+            w = widest as f64 * NODE_SPACING,
+            h = rows as f64 * NODE_SPACING
+        );
+        for (edges, dash) in [(&self.links, ""), (&self.via, " stroke-dasharray=\"4 3\"")] {
+            for (from, to) in edges {
+                if let (Some((x1, y1)), Some((x2, y2))) = (positions.get(from), positions.get(to)) {
+                    let _ = writeln!(
+                        html,
+                        "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"#666\"{}/>",
+                        x1, y1, x2, y2, dash
+                    );
+                }
+            }
+        }
+        for (node, (x, y)) in &positions {
+            let fill = if *node == self.me { "#fc6" } else { "#def" };
+            let _ = writeln!(
+                html,
+                "<circle cx=\"{x}\" cy=\"{y}\" r=\"18\" fill=\"{fill}\" stroke=\"#666\"/>\
+                 <text x=\"{x}\" y=\"{ty}\" text-anchor=\"middle\">{node}</text>",
+                ty = y + 4.0
+            );
+        }
+        html.push_str(
+            "</svg>\n<p>Solid lines are direct links, dashed lines lead to the repeater \
+             a node's messages were forwarded by.</p>\n",
+        );
+    }
+
+    fn charts(&self, html: &mut String) {
+        html.push_str("<h2>Sensor values</h2>\n");
+        for ((node, sensor, msg_type), points) in &self.series {
+            let name = type_name(C_SET, *msg_type).unwrap_or_else(|| msg_type.to_string());
+            let _ = write!(
+                html,
+                "<h3>Node {} sensor {} {}</h3>\n{}",
+                node,
+                sensor,
+                escape(&name),
+                chart(points)
+            );
+        }
+    }
+
+    fn errors(&self, html: &mut String) {
+        html.push_str(
+            "<h2>Errors</h2>\n<p><input id=\"search\" type=\"search\" placeholder=\"Search\"></p>\n\
+             <table id=\"errors\">\n<thead><tr><th>Time</th><th>Message</th><th>Line</th></tr></thead>\n<tbody>\n",
+        );
+        for error in &self.errors {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td><pre>{}</pre></td></tr>",
+                time(error.datetime),
+                escape(&error.message),
+                escape(&error.raw)
+            );
+        }
+        html.push_str("</tbody>\n</table>\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+    use chrono::Datelike;
+
+    const LOG: &str = "Oct 18 13:00:00 INFO  Starting gateway...
+Oct 18 13:00:00 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2
+Oct 18 13:00:01 DEBUG TSM:INIT
+Oct 18 13:00:02 DEBUG TSM:READY:ID=0,PAR=0,DIS=0
+Oct 18 13:00:10 DEBUG TSF:MSG:READ,12-12-0,s=255,c=0,t=17,pt=0,l=5,sg=0:2.3.2
+Oct 18 13:00:10 DEBUG TSF:MSG:READ,12-12-0,s=255,c=3,t=11,pt=0,l=7,sg=0:Weather
+Oct 18 13:00:10 DEBUG TSF:MSG:READ,12-12-0,s=1,c=0,t=6,pt=0,l=7,sg=0:Outside
+Oct 18 13:01:00 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5
+Oct 18 13:02:00 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:22.0
+Oct 18 13:03:00 DEBUG TSF:MSG:READ,7-3-0,s=2,c=1,t=1,pt=7,l=4,sg=0:55
+Oct 18 13:04:00 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.0
+Oct 18 13:05:00 DEBUG TSF:MSG:SEND,0-0-12-12,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=0,st=OK:1
+Oct 18 13:05:00 DEBUG TSF:MSG:READ,12-12-0,s=2,c=1,t=2,pt=0,l=1,sg=0:1
+Oct 18 13:05:00 DEBUG TSF:MSG:ACK
+Oct 18 13:06:00 DEBUG !TSF:MSG:SEND,0-0-12-12,s=2,c=1,t=2,pt=0,l=1,sg=0,ft=3,st=NACK:<b>
+Oct 18 13:07:00 DEBUG !TSM:FAIL:CNT=1
+Oct 18 13:08:00 DEBUG TSM:READY:ID=0,PAR=0,DIS=0";
+
+    fn report() -> Report {
+        let mut report = Report::new("gateway.log & more");
+        for raw in LOG.lines() {
+            report.feed(raw, &parse_log_line(raw));
+        }
+        report
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape("<a href=\"x\">'&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let html = report().render();
+        assert!(html.contains("<title>gateway.log &amp; more</title>"));
+        assert!(html.contains("<h3>Node 12 sensor 1 V_TEMP</h3>"));
+        assert!(html.contains("<h3>Node 7 sensor 2 V_HUM</h3>"));
+        // the raw line, escaped
+        assert!(html.contains("st=NACK:&lt;b&gt;</pre>"));
+        assert!(!html.contains("<b>"));
+        // node 7 reached the gateway through repeater 3
+        assert!(html.contains("stroke-dasharray"));
+        assert!(html.contains("points=\"50.0,70.0 243.3,10.0 630.0,130.0\""));
+
+        // values that are not numbers stay out of the charts
+        let mut report = report();
+        for raw in [
+            "Oct 18 13:09:00 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=3,sg=0:nan",
+            "Oct 18 13:10:00 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=3,sg=0:inf",
+        ] {
+            report.feed(raw, &parse_log_line(raw));
+        }
+        let with_nan = report.render();
+        assert!(with_nan.contains("points=\"50.0,70.0 243.3,10.0 630.0,130.0\""));
+        assert!(!with_nan.contains("NaN") && !with_nan.contains("inf"));
+    }
+
+    // the rendered report is compared with src/snapshots/report.html; after
+    // an intended change run the test with UPDATE_SNAPSHOTS=1 and review the
+    // difference. syslog lines have no year, the one inferred is masked
+    #[test]
+    fn test_snapshot() {
+        let first = parse_log_line(LOG.lines().next().unwrap());
+        let year = format!("{}-", first.datetime.unwrap().year());
+        let html = report().render().replace(&year, "YYYY-");
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/snapshots/report.html");
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            std::fs::write(path, &html).unwrap();
+        }
+        let snapshot = std::fs::read_to_string(path).unwrap();
+        for (i, (rendered, expected)) in html.lines().zip(snapshot.lines()).enumerate() {
+            assert_eq!(rendered, expected, "line {} of {}", i + 1, path);
+        }
+        assert_eq!(html.lines().count(), snapshot.lines().count());
+    }
+}
@@ -0,0 +1,58 @@
+// a session is one run of the gateway, from its start up to the next restart
+//
+// the linux gateway starts with a banner before the core begins:
+//   Oct 18 13:36:52 INFO  Starting gateway...
+//   Oct 18 13:36:52 INFO  Protocol version - 2.3.2
+//   Oct 18 13:36:52 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2
+// other gateways only log the MCO:BGN:INIT line
+use crate::{LogLine, MsgFields};
+
+#[derive(Debug, Default)]
+pub struct SessionTracker {
+    banner_seen: bool, //banner started the session but core has not begun yet
+}
+
+impl SessionTracker {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // feed lines in order, returns true if the line starts a new session
+    pub fn feed(&mut self, line: &LogLine) -> bool {
+        if line.msg.starts_with("Starting gateway") {
+            self.banner_seen = true;
+            return true;
+        }
+        if let Some(MsgFields::CoreInit { .. }) = line.fields {
+            if self.banner_seen {
+                // belongs to the session the banner started
+                self.banner_seen = false;
+                return false;
+            }
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_log_line;
+
+    #[test]
+    fn test_session_tracker() {
+        let mut tracker = SessionTracker::new();
+        let starts: Vec<bool> = [
+            "Oct 18 13:36:52 INFO  Starting gateway...",
+            "Oct 18 13:36:52 INFO  Protocol version - 2.3.2",
+            "Oct 18 13:36:52 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2",
+            "Oct 18 13:36:52 DEBUG TSM:INIT",
+            "Oct 18 14:00:00 DEBUG MCO:BGN:INIT GW,CP=RNNGL---,FQ=NA,REL=255,VER=2.3.2",
+        ]
+        .iter()
+        .map(|l| tracker.feed(&parse_log_line(l)))
+        .collect();
+        assert_eq!(starts, vec![true, false, false, false, true]);
+    }
+}
@@ -0,0 +1,575 @@
+// synthetic gateway logs for tests and benchmarks
+//
+// simulates a gateway with a network of nodes: powered nodes report a
+// temperature and a heartbeat and get commands from the controller that they
+// echo back as ack, battery nodes wake up, report their value and battery
+// level and go back to sleep. sends can fail and the gateway can be
+// restarted. the same seed always gives the same log
+//
+// the log is written as the linux gateway writes it to syslog:
+//   Oct 18 13:36:53 DEBUG TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5
+// or as a serial gateway writes it to the controller, debug messages being
+// I_LOG_MESSAGE lines with the milliseconds since the start of the gateway:
+//   0;255;3;0;9;1234 TSF:MSG:READ,12-12-0,s=1,c=1,t=0,pt=7,l=4,sg=0:21.5
+//   12;1;1;0;0;21.5
+use crate::encoder::{
+    render_fields, render_mqtt_topic, render_serial, render_syslog, status_prefix,
+};
+use crate::protocol::{
+    MsgFields, MsgKind, TransportMsg, Version, C_INTERNAL, C_SET, I_BATTERY_LEVEL,
+    I_HEARTBEAT_RESPONSE, I_LOG_MESSAGE, I_POST_SLEEP_NOTIFICATION, I_PRE_SLEEP_NOTIFICATION,
+};
+use crate::SendStatus;
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+
+const V_TEMP: u8 = 0;
+const V_STATUS: u8 = 2;
+const P_STRING: u8 = 0;
+const P_BYTE: u8 = 1;
+const P_ULONG32: u8 = 5;
+const P_FLOAT32: u8 = 7;
+
+// how the nodes are connected to the gateway (node 0)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Topology {
+    Star,     // all nodes talk to the gateway directly
+    Chain,    // node n talks to node n-1, node 1 to the gateway
+    Tree(u8), // every node has up to this many children
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Syslog,
+    Serial,
+}
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub nodes: u8,         //node ids 1..=nodes
+    pub battery_nodes: u8, //the highest node ids sleep between reports
+    pub topology: Topology,
+    pub start: DateTime<Local>,
+    pub duration: Duration,
+    pub report_interval: Duration, //powered nodes
+    pub sleep_interval: Duration,  //battery nodes
+    pub failure_probability: f64,  //per message sent over the radio
+    pub restart_interval: Option<Duration>,
+    pub version: Version,
+    pub format: OutputFormat,
+    pub seed: u64,
+}
+
+// a fixed start, so the same seed gives the same log on any day. syslog dates
+// have no year, lines read back get the one merge::infer_year gives them
+fn default_start() -> DateTime<Local> {
+    let date = NaiveDate::from_ymd_opt(2021, 10, 18).unwrap();
+    Local
+        .from_local_datetime(&date.and_hms_opt(12, 0, 0).unwrap())
+        .unwrap()
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            nodes: 10,
+            battery_nodes: 3,
+            topology: Topology::Star,
+            start: default_start(),
+            duration: Duration::hours(1),
+            report_interval: Duration::seconds(60),
+            sleep_interval: Duration::minutes(5),
+            failure_probability: 0.02,
+            restart_interval: None,
+            version: Version::new(2, 3, 2),
+            format: OutputFormat::Syslog,
+            seed: 1,
+        }
+    }
+}
+
+// small deterministic generator (splitmix64) so a seed gives the same log
+// on every platform
+#[derive(Debug)]
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        self.next_f64() < probability
+    }
+
+    // interval with +-10% jitter
+    fn jitter(&mut self, interval: Duration) -> Duration {
+        let ms = interval.num_milliseconds() as f64;
+        Duration::milliseconds((ms * (0.9 + 0.2 * self.next_f64())) as i64)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Event {
+    GatewayStart,
+    Report(u8),
+    Command(u8),
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    battery: bool,
+    temperature: f64,
+    battery_level: f64,
+    reports: u32,
+}
+
+#[derive(Debug)]
+pub struct Simulator {
+    config: SimConfig,
+    rng: Rng,
+    nodes: Vec<Node>, //index is node id - 1
+    queue: BinaryHeap<Reverse<(DateTime<Local>, u64, Event)>>,
+    sequence: u64, //keeps events at the same time in the order they were queued
+    gateway_start: DateTime<Local>,
+    lines: VecDeque<String>,
+}
+
+impl Simulator {
+    pub fn new(config: SimConfig) -> Self {
+        let mut rng = Rng(config.seed);
+        let nodes = (1..=config.nodes)
+            .map(|id| Node {
+                battery: id > config.nodes.saturating_sub(config.battery_nodes),
+                temperature: 18.0 + 6.0 * rng.next_f64(),
+                battery_level: 100.0 - 30.0 * rng.next_f64(),
+                reports: 0,
+            })
+            .collect();
+        let mut simulator = Simulator {
+            gateway_start: config.start,
+            config,
+            rng,
+            nodes,
+            queue: BinaryHeap::new(),
+            sequence: 0,
+            lines: VecDeque::new(),
+        };
+        let start = simulator.config.start;
+        simulator.schedule(start, Event::GatewayStart);
+        for id in 1..=simulator.config.nodes {
+            // nodes start at random points of their first interval
+            let interval = simulator.interval(id);
+            let offset = Duration::milliseconds(
+                (interval.num_milliseconds() as f64 * simulator.rng.next_f64()) as i64,
+            );
+            simulator.schedule(start + Duration::seconds(2) + offset, Event::Report(id));
+        }
+        simulator
+    }
+
+    // next hop from a node towards the gateway
+    pub fn parent(&self, node: u8) -> u8 {
+        match self.config.topology {
+            Topology::Star => 0,
+            Topology::Chain => node.saturating_sub(1),
+            Topology::Tree(fanout) => {
+                let fanout = fanout.max(1);
+                if node <= fanout {
+                    0
+                } else {
+                    (node - 1) / fanout
+                }
+            }
+        }
+    }
+
+    // the node next to the gateway on the route of a node
+    pub fn first_hop(&self, node: u8) -> u8 {
+        let mut hop = node;
+        while self.parent(hop) != 0 {
+            hop = self.parent(hop);
+        }
+        hop
+    }
+
+    // number of radio hops between a node and the gateway
+    fn hops(&self, node: u8) -> u8 {
+        let (mut hop, mut hops) = (node, 1);
+        while self.parent(hop) != 0 {
+            hop = self.parent(hop);
+            hops += 1;
+        }
+        hops
+    }
+
+    fn interval(&self, node: u8) -> Duration {
+        if self.nodes[node as usize - 1].battery {
+            self.config.sleep_interval
+        } else {
+            self.config.report_interval
+        }
+    }
+
+    fn schedule(&mut self, when: DateTime<Local>, event: Event) {
+        self.sequence += 1;
+        self.queue.push(Reverse((when, self.sequence, event)));
+    }
+
+    fn debug(&mut self, when: DateTime<Local>, level: &str, message: &str) {
+        let line = match self.config.format {
+            OutputFormat::Syslog => render_syslog(&when, level, message),
+            OutputFormat::Serial => format!(
+                "0;255;{};0;{};{} {}",
+                C_INTERNAL,
+                I_LOG_MESSAGE,
+                (when - self.gateway_start).num_milliseconds(),
+                message
+            ),
+        };
+        self.lines.push_back(line);
+    }
+
+    fn debug_fields(&mut self, when: DateTime<Local>, status: SendStatus, fields: &MsgFields) {
+        let message = format!(
+            "{}{}",
+            status_prefix(status),
+            render_fields(fields).unwrap_or_default()
+        );
+        self.debug(when, "DEBUG", &message);
+    }
+
+    fn gateway_start(&mut self, when: DateTime<Local>) {
+        self.gateway_start = when;
+        if self.config.format == OutputFormat::Syslog {
+            self.debug(when, "INFO", "Starting gateway...");
+            self.debug(
+                when,
+                "INFO",
+                &format!("Protocol version - {}", self.config.version),
+            );
+        }
+        let init = MsgFields::CoreInit {
+            node: "GW".to_string(),
+            capabilities: "RNNGL---".to_string(),
+            frequency: Some("NA".to_string()),
+            release: Some(255),
+            version: self.config.version.to_string(),
+        };
+        self.debug_fields(when, SendStatus::OK, &init);
+        for message in [
+            "TSF:LRT:OK",
+            "TSM:INIT",
+            "TSF:WUR:MS=0",
+            "TSM:INIT:TSP OK",
+            "TSM:INIT:GW MODE",
+            "TSM:READY:ID=0,PAR=0,DIS=0",
+            "MCO:REG:NOT NEEDED",
+            "MCO:BGN:STP",
+            "MCO:BGN:INIT OK,TSP=1",
+        ] {
+            self.debug(when, "DEBUG", message);
+        }
+        if let Some(interval) = self.config.restart_interval {
+            self.schedule(when + interval, Event::GatewayStart);
+        }
+    }
+
+    // a message from a node arriving at the gateway, None if it got lost
+    fn read(
+        &mut self,
+        when: DateTime<Local>,
+        node: u8,
+        (sensor, command, msg_type, payload_type): (u8, u8, u8, u8),
+        payload: String,
+    ) -> Option<TransportMsg> {
+        if self.rng.chance(self.config.failure_probability) {
+            return None;
+        }
+        let msg = TransportMsg {
+            kind: MsgKind::Read,
+            sender: node,
+            last: self.first_hop(node),
+            next: None,
+            destination: 0,
+            sensor,
+            command,
+            ack: None,
+            msg_type,
+            payload_type,
+            length: payload.len() as u8,
+            signed: false,
+            failures: None,
+            send_ok: None,
+            payload,
+        };
+        self.debug_fields(when, SendStatus::OK, &MsgFields::Msg(msg.clone()));
+        Some(msg)
+    }
+
+    // hand a message from a node on to the controller
+    fn forward(&mut self, when: DateTime<Local>, msg: TransportMsg) {
+        let msg = TransportMsg {
+            kind: match self.config.format {
+                OutputFormat::Syslog => MsgKind::MqttOut,
+                OutputFormat::Serial => MsgKind::Serial,
+            },
+            last: 0,
+            ack: Some(msg.ack == Some(true)),
+            payload_type: P_STRING,
+            ..msg
+        };
+        match self.config.format {
+            OutputFormat::Syslog => {
+                if let Some(topic) = render_mqtt_topic(&msg) {
+                    self.debug(when, "DEBUG", &format!("GWT:TPS:TOPIC={},MSG SENT", topic));
+                }
+            }
+            OutputFormat::Serial => self.lines.push_back(render_serial(&msg)),
+        }
+    }
+
+    fn report(&mut self, when: DateTime<Local>, id: u8) {
+        let node = {
+            let rng = &mut self.rng;
+            let node = &mut self.nodes[id as usize - 1];
+            node.reports += 1;
+            node.temperature += 0.6 * rng.next_f64() - 0.3;
+            node.battery_level = (node.battery_level - 0.05).max(0.0);
+            node.clone()
+        };
+        let mut at = when;
+        let mut tick = |d: i64| {
+            at += Duration::milliseconds(d);
+            at
+        };
+
+        if node.battery {
+            let slept = self.config.sleep_interval.num_milliseconds().to_string();
+            let t = tick(0);
+            self.read(
+                t,
+                id,
+                (255, C_INTERNAL, I_POST_SLEEP_NOTIFICATION, P_ULONG32),
+                slept,
+            );
+        }
+        let temperature = format!("{:.1}", node.temperature);
+        let t = tick(20);
+        if let Some(msg) = self.read(t, id, (1, C_SET, V_TEMP, P_FLOAT32), temperature) {
+            self.forward(t, msg);
+        }
+        if node.battery {
+            let level = (node.battery_level as u8).to_string();
+            let t = tick(20);
+            if let Some(msg) = self.read(t, id, (255, C_INTERNAL, I_BATTERY_LEVEL, P_BYTE), level) {
+                self.forward(t, msg);
+            }
+            let t = tick(20);
+            let sleep = self.config.sleep_interval.num_milliseconds().to_string();
+            self.read(
+                t,
+                id,
+                (255, C_INTERNAL, I_PRE_SLEEP_NOTIFICATION, P_ULONG32),
+                sleep,
+            );
+        } else {
+            if node.reports % 4 == 0 {
+                let uptime = (when - self.config.start).num_milliseconds().to_string();
+                let t = tick(20);
+                self.read(
+                    t,
+                    id,
+                    (255, C_INTERNAL, I_HEARTBEAT_RESPONSE, P_ULONG32),
+                    uptime,
+                );
+            }
+            self.schedule(when + Duration::seconds(1), Event::Command(id));
+        }
+        let next = self.rng.jitter(self.interval(id));
+        self.schedule(when + next, Event::Report(id));
+    }
+
+    // the controller switches something on a powered node
+    fn command(&mut self, when: DateTime<Local>, id: u8) {
+        let value = if self.rng.chance(0.5) { "1" } else { "0" };
+        let mut msg = TransportMsg {
+            kind: MsgKind::MqttIn,
+            sender: 0,
+            last: 0,
+            next: None,
+            destination: id,
+            sensor: 2,
+            command: C_SET,
+            ack: Some(true),
+            msg_type: V_STATUS,
+            payload_type: P_STRING,
+            length: 1,
+            signed: false,
+            failures: None,
+            send_ok: None,
+            payload: value.to_string(),
+        };
+        if self.config.format == OutputFormat::Syslog {
+            if let Some(topic) = render_mqtt_topic(&msg) {
+                self.debug(
+                    when,
+                    "DEBUG",
+                    &format!("GWT:IMQ:TOPIC={}, MSG RECEIVED", topic),
+                );
+            }
+        }
+        let failed = self.rng.chance(self.config.failure_probability);
+        msg = TransportMsg {
+            kind: MsgKind::Send,
+            sender: 0,
+            next: Some(self.first_hop(id)),
+            destination: id,
+            ack: None,
+            failures: Some(if failed {
+                1 + (self.rng.next_u64() % 3) as u8
+            } else {
+                0
+            }),
+            send_ok: Some(!failed),
+            ..msg
+        };
+        let status = if failed {
+            SendStatus::ERROR
+        } else {
+            SendStatus::OK
+        };
+        self.debug_fields(
+            when + Duration::milliseconds(10),
+            status,
+            &MsgFields::Msg(msg),
+        );
+        if failed {
+            return;
+        }
+
+        // the command asked for an ack: the node echoes it back
+        let hops = self.hops(id) as i64;
+        let latency = hops * (15 + (self.rng.next_u64() % 40) as i64);
+        let t = when + Duration::milliseconds(10 + latency);
+        let echo = (2, C_SET, V_STATUS, P_STRING);
+        if let Some(mut echo) = self.read(t, id, echo, value.to_string()) {
+            self.debug(t, "DEBUG", "TSF:MSG:ACK");
+            echo.ack = Some(true);
+            self.forward(t, echo);
+        }
+    }
+}
+
+impl Iterator for Simulator {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let end = self.config.start + self.config.duration;
+        while self.lines.is_empty() {
+            let Reverse((when, _, event)) = self.queue.pop()?;
+            if when >= end {
+                self.queue.clear();
+                return None;
+            }
+            match event {
+                Event::GatewayStart => self.gateway_start(when),
+                Event::Report(id) => self.report(when, id),
+                Event::Command(id) => self.command(when, id),
+            }
+        }
+        self.lines.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::timeline::Timeline;
+    use crate::encoder::render_line;
+    use crate::parse_log_line;
+    use chrono::{Datelike, Timelike};
+
+    fn config() -> SimConfig {
+        SimConfig {
+            duration: Duration::hours(3),
+            restart_interval: Some(Duration::hours(1)),
+            failure_probability: 0.1,
+            topology: Topology::Tree(3),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_topology() {
+        let simulator = Simulator::new(config());
+        assert_eq!(simulator.parent(3), 0);
+        assert_eq!(simulator.parent(4), 1);
+        assert_eq!(simulator.parent(10), 3);
+        assert_eq!(simulator.first_hop(10), 3);
+    }
+
+    #[test]
+    fn test_syslog() {
+        let lines: Vec<String> = Simulator::new(config()).collect();
+        assert_eq!(lines, Simulator::new(config()).collect::<Vec<_>>());
+        // also without a start date
+        let start = SimConfig::default().start;
+        assert_eq!(
+            (start.year(), start.month(), start.day(), start.hour()),
+            (2021, 10, 18, 12)
+        );
+
+        let mut timeline = Timeline::new();
+        let (mut frames, mut nacks) = (0, 0);
+        for raw in &lines {
+            let line = parse_log_line(raw);
+            assert!(line.datetime.is_some(), "not parsed: {}", raw);
+            assert_eq!(&render_line(&line), raw);
+            if let Some(MsgFields::Msg(m)) = &line.fields {
+                frames += 1;
+                if m.send_ok == Some(false) {
+                    nacks += 1;
+                    assert_eq!(line.status, SendStatus::ERROR);
+                }
+                if m.kind == MsgKind::Read {
+                    assert_eq!(m.last, Simulator::new(config()).first_hop(m.sender));
+                }
+            }
+            timeline.feed(&line);
+        }
+        assert!(frames > 500);
+        assert!(nacks > 0);
+        assert_eq!(timeline.sessions().len(), 3);
+    }
+
+    #[test]
+    fn test_serial() {
+        let config = SimConfig {
+            format: OutputFormat::Serial,
+            ..config()
+        };
+        let mut inits = 0;
+        for line in Simulator::new(config) {
+            let msg = TransportMsg::from_serial(&line).unwrap();
+            if (msg.command, msg.msg_type) == (C_INTERNAL, I_LOG_MESSAGE) {
+                let (ms, message) = msg.payload.split_once(' ').unwrap();
+                assert!(ms.parse::<i64>().unwrap() >= 0);
+                if message.starts_with("MCO:BGN:INIT GW") {
+                    inits += 1;
+                }
+            }
+        }
+        assert_eq!(inits, 3);
+    }
+}